@@ -1,8 +1,10 @@
+use std::{os::raw::c_char, ptr::null};
+
 use const_cstr::{const_cstr, ConstCStr};
 use machinery::{identifier, Identifier};
 use machinery_api::{
-    foundation::{ApplicationO, VersionT},
-    plugins::ui::DockingFindTabOptT,
+    foundation::{ApplicationO, RectT, TheTruthO, TtIdT, TtTypeT, UiO, VersionT},
+    plugins::ui::{DockingFindTabOptT, UiStyleT},
     Api,
 };
 use tree_sitter::Language;
@@ -15,6 +17,206 @@ pub struct AnodeApi {
     /// Creates a new tab with this asset's contents open, or focuses the existing tab if one
     /// already exists.
     pub open_asset: unsafe extern "C" fn(app: *mut ApplicationO, opt: *const DockingFindTabOptT),
+    /// Open an asset in an editor tab like `open_asset`, with the caret at a position.
+    ///
+    /// Selects `selection_len` bytes from the position if not zero, and scrolls the position
+    /// into view. Useful for jumping to the location of an error.
+    pub open_asset_at: unsafe extern "C" fn(
+        app: *mut ApplicationO,
+        opt: *const DockingFindTabOptT,
+        position: AnodePositionT,
+        selection_len: u32,
+    ),
+    /// Create an editor that can be drawn into any UI, for embedding in other tabs.
+    ///
+    /// The editor starts out empty, open an asset in it with `editor_set_asset`.
+    pub create_editor: unsafe extern "C" fn(app: *mut ApplicationO) -> *mut AnodeEditorO,
+    /// Destroy an editor, committing any edits it hasn't committed yet.
+    pub destroy_editor: unsafe extern "C" fn(editor: *mut AnodeEditorO),
+    /// Open an asset in an editor, committing edits to the previous one.
+    ///
+    /// Returns false if the asset can't be opened, the editor is then left empty.
+    pub editor_set_asset:
+        unsafe extern "C" fn(editor: *mut AnodeEditorO, tt: *mut TheTruthO, asset: TtIdT) -> bool,
+    /// Draw an editor and handle its input, in `rect` of `ui`.
+    pub editor_ui: unsafe extern "C" fn(
+        editor: *mut AnodeEditorO,
+        ui: *mut UiO,
+        ui_style: *const UiStyleT,
+        rect: RectT,
+    ),
+    /// Show the text of a type as an inline editor in the properties view, rather than as a raw
+    /// property.
+    ///
+    /// Call this after setting [`ASPECT_ANODE`] on the type. Text on subobjects isn't supported.
+    pub set_properties_ui: unsafe extern "C" fn(tt: *mut TheTruthO, object_type: TtTypeT),
+    /// Get the document of an asset open in an editor, or null if it isn't open anywhere.
+    pub find_document:
+        unsafe extern "C" fn(tt: *mut TheTruthO, asset: TtIdT) -> *mut AnodeDocumentO,
+    /// Get the asset open in a document, and the truth it's in.
+    ///
+    /// Returns a zero id if the document has no asset open.
+    pub document_asset:
+        unsafe extern "C" fn(document: *mut AnodeDocumentO, tt: *mut *mut TheTruthO) -> TtIdT,
+    /// Get the document shown in an editor created with `create_editor`.
+    pub editor_document: unsafe extern "C" fn(editor: *mut AnodeEditorO) -> *mut AnodeDocumentO,
+    /// Copy the UTF-8 text of a document into `buffer`, without nul terminator.
+    ///
+    /// Copies at most `size` bytes and returns the full length of the text, call with a null
+    /// buffer to get the size to allocate.
+    pub document_text:
+        unsafe extern "C" fn(document: *mut AnodeDocumentO, buffer: *mut u8, size: usize) -> usize,
+    /// Replace the text from `start` up to `end` with UTF-8 `text`, and commit it to the asset.
+    ///
+    /// Returns false if the document is read-only or the range isn't valid.
+    pub document_replace: unsafe extern "C" fn(
+        document: *mut AnodeDocumentO,
+        start: AnodePositionT,
+        end: AnodePositionT,
+        text: *const u8,
+        text_len: usize,
+    ) -> bool,
+    /// Get the position of the caret.
+    pub document_caret: unsafe extern "C" fn(document: *mut AnodeDocumentO) -> AnodePositionT,
+    /// Move the caret, clearing the selection.
+    pub document_set_caret:
+        unsafe extern "C" fn(document: *mut AnodeDocumentO, position: AnodePositionT),
+    /// Get the selected range, returns false if nothing is selected.
+    pub document_selection: unsafe extern "C" fn(
+        document: *mut AnodeDocumentO,
+        start: *mut AnodePositionT,
+        end: *mut AnodePositionT,
+    ) -> bool,
+    /// Select from `start` to `end`, leaving the caret at `end`.
+    pub document_set_selection: unsafe extern "C" fn(
+        document: *mut AnodeDocumentO,
+        start: AnodePositionT,
+        end: AnodePositionT,
+    ),
+    /// Scroll a line into the middle of the editor the next time it's drawn.
+    pub document_scroll_to: unsafe extern "C" fn(document: *mut AnodeDocumentO, line: u32),
+    /// Set the diagnostics shown for an asset, replacing the ones previously set from `source`.
+    ///
+    /// Diagnostics are kept even if the asset isn't open, and shown once it's opened. Set zero
+    /// diagnostics to clear them.
+    pub set_diagnostics: unsafe extern "C" fn(
+        tt: *mut TheTruthO,
+        asset: TtIdT,
+        source: *const c_char,
+        diagnostics: *const AnodeDiagnosticT,
+        diagnostics_len: usize,
+    ),
+    /// Add a completion to the list a completion provider is filling, see
+    /// [`AnodeCompletionProviderI`].
+    pub add_completion: unsafe extern "C" fn(
+        completions: *mut AnodeCompletionsO,
+        item: *const AnodeCompletionItemT,
+    ),
+    /// Add a section of Markdown text to the tooltip a hover provider is filling, see
+    /// [`AnodeHoverProviderI`].
+    pub add_hover: unsafe extern "C" fn(hover: *mut AnodeHoverO, markdown: *const c_char),
+    /// Add a location to the list a definition provider is filling, see
+    /// [`AnodeDefinitionProviderI`].
+    pub add_location:
+        unsafe extern "C" fn(locations: *mut AnodeLocationsO, location: *const AnodeLocationT),
+}
+
+/// Handle to a document open in an editor.
+///
+/// Handles can be kept while the document is open, calls with a handle to a document that has
+/// since been closed do nothing.
+#[repr(C)]
+pub struct AnodeDocumentO {
+    _private: [u8; 0],
+}
+
+/// Position in a document.
+#[repr(C)]
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AnodePositionT {
+    /// Zero-based line.
+    pub line: u32,
+    /// Zero-based byte offset into the UTF-8 text of the line, clamped to the end of the line.
+    pub column: u32,
+}
+
+/// Error, warning or other message about a range of a document.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct AnodeDiagnosticT {
+    pub start: AnodePositionT,
+    pub end: AnodePositionT,
+    pub severity: AnodeSeverity,
+    /// Nul-terminated UTF-8 message, shown when hovering the diagnostic.
+    pub message: *const c_char,
+}
+
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AnodeSeverity {
+    Error,
+    Warning,
+    Information,
+    Hint,
+}
+
+/// Suggestion offered in the completion popup.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct AnodeCompletionItemT {
+    /// Nul-terminated UTF-8 text shown in the popup, and matched against what's typed.
+    pub label: *const c_char,
+    /// Nul-terminated UTF-8 text shown next to the label, like a type. Can be null.
+    pub detail: *const c_char,
+    /// Nul-terminated UTF-8 text the typed word is replaced with, null to use the label.
+    pub insert_text: *const c_char,
+}
+
+/// List of completions a provider adds to with [`AnodeApi::add_completion`].
+#[repr(C)]
+pub struct AnodeCompletionsO {
+    _private: [u8; 0],
+}
+
+/// Interface for plugins that provide code completions.
+///
+/// Register implementations with the API registry under [`ANODE_COMPLETION_PROVIDER_I`].
+#[repr(C)]
+pub struct AnodeCompletionProviderI {
+    pub inst: *mut AnodeCompletionProviderO,
+    /// Add completions for the word being typed before the caret at `position`, by calling
+    /// [`AnodeApi::add_completion`] for each.
+    ///
+    /// Called once when the popup opens, the editor filters them as the user keeps typing. The
+    /// document can be read through the API during the call.
+    pub complete: unsafe extern "C" fn(
+        inst: *mut AnodeCompletionProviderO,
+        document: *mut AnodeDocumentO,
+        position: AnodePositionT,
+        completions: *mut AnodeCompletionsO,
+    ),
+}
+
+unsafe impl Send for AnodeCompletionProviderI {}
+unsafe impl Sync for AnodeCompletionProviderI {}
+
+/// Opaque instance of a completion provider.
+#[repr(C)]
+pub struct AnodeCompletionProviderO {
+    _private: [u8; 0],
+}
+
+pub const ANODE_COMPLETION_PROVIDER_I: ConstCStr = const_cstr!("tm_anode_completion_provider_i");
+pub const ANODE_COMPLETION_PROVIDER_I_VERSION: VersionT = VersionT {
+    major: 0,
+    minor: 1,
+    patch: 0,
+};
+
+/// Opaque editor instance, created by [`AnodeApi::create_editor`].
+#[repr(C)]
+pub struct AnodeEditorO {
+    _private: [u8; 0],
 }
 
 unsafe impl Send for AnodeApi {}
@@ -23,24 +225,202 @@ unsafe impl Sync for AnodeApi {}
 impl Api for AnodeApi {
     const NAME: ConstCStr = const_cstr!("tm_anode_api");
     const VERSION: VersionT = VersionT {
-        major: 0,
-        minor: 6,
-        patch: 1,
+        major: 1,
+        minor: 0,
+        patch: 0,
     };
 }
 
 /// Aspect for assets opened in an anode editor.
+///
+/// Construct it with `..Default::default()` for the fields you don't set. The default is all zero:
+/// the text in buffer property zero, without highlighting, with the editor's default rulers and
+/// every option turned off.
+///
+/// Anode reads the whole struct, so adding fields changes the major version of [`AnodeApi`] along
+/// with the name of [`ASPECT_ANODE`]. Assets of plugins built against an older version then aren't
+/// opened, rather than read past the end of their aspect.
+#[repr(C)]
 pub struct AnodeAspectI {
-    /// Anode expects the asset's data to be a UTF-8 buffer **without nul terminator**.
+    /// Buffer or string property holding the text.
+    ///
+    /// Buffers are expected to not have a nul terminator, their encoding is detected. Strings are
+    /// always edited as UTF-8.
     pub property: u32,
     /// Highlighting language description.
     pub highlighting: *const Highlighting,
+    /// Columns to draw vertical rulers at, null uses the editor's default rulers.
+    ///
+    /// Set to a non-null pointer with a length of zero to disable rulers.
+    pub rulers: *const u32,
+    pub rulers_len: usize,
+    /// Shade the text past the last ruler.
+    pub shade_past_rulers: bool,
+    /// Merge concurrent edits from collaborators, rather than overwriting them.
+    pub collaborative: bool,
+    /// Buffer property the edit history and carets of collaborators are kept in.
+    ///
+    /// Only used if `collaborative` is set, the property should not be written by anything else.
+    pub history_property: u32,
+    /// Store the text as chunks, so commits only allocate buffers for the chunks that changed.
+    ///
+    /// `property` then holds the order of the chunks, which are kept in `chunks_property`.
+    pub chunked: bool,
+    /// Subobject set of [`TEXT_CHUNK`] objects. Only used if `chunked` is set.
+    pub chunks_property: u32,
+    /// Open assets read-only, for example generated code or files from a package.
+    pub read_only: bool,
+    /// Decides per object if it's read-only, overriding `read_only` if set.
+    ///
+    /// Called every frame the asset is shown, so the asset can become read-only while open, for
+    /// example when it gets locked.
+    pub is_read_only: Option<unsafe extern "C" fn(tt: *mut TheTruthO, object: TtIdT) -> bool>,
+    /// Subobject properties leading from the asset to the object the text is on.
+    ///
+    /// All properties above are then on that object rather than the asset. Null or a length of
+    /// zero if the text is on the asset itself.
+    pub subobject_path: *const u32,
+    pub subobject_path_len: usize,
+    /// Decides per object if it's edited collaboratively, overriding `collaborative` if set.
+    ///
+    /// Called when the asset is opened, for example to let users turn collaboration on per asset.
+    pub is_collaborative: Option<unsafe extern "C" fn(tt: *mut TheTruthO, object: TtIdT) -> bool>,
+}
+
+impl Default for AnodeAspectI {
+    fn default() -> Self {
+        Self {
+            property: 0,
+            highlighting: null(),
+            rulers: null(),
+            rulers_len: 0,
+            shade_past_rulers: false,
+            collaborative: false,
+            history_property: 0,
+            chunked: false,
+            chunks_property: 0,
+            read_only: false,
+            is_read_only: None,
+            subobject_path: null(),
+            subobject_path_len: 0,
+            is_collaborative: None,
+        }
+    }
 }
 
 unsafe impl Send for AnodeAspectI {}
 unsafe impl Sync for AnodeAspectI {}
 
-pub const ASPECT_ANODE: Identifier = identifier!("tm_anode_aspect_i");
+pub const ASPECT_ANODE: Identifier = identifier!("tm_anode_aspect_i_v1");
+
+/// Interface for plugins that show information about the word under the mouse, like
+/// documentation of an engine API.
+///
+/// Register implementations with the API registry under [`ANODE_HOVER_PROVIDER_I`]. Use
+/// [`AnodeApi::document_asset`] to give information depending on the asset being edited.
+#[repr(C)]
+pub struct AnodeHoverProviderI {
+    pub inst: *mut AnodeHoverProviderO,
+    /// Add information about the word at `position` of a document, by calling
+    /// [`AnodeApi::add_hover`] with Markdown text.
+    ///
+    /// Called once when the mouse rests on a word. Headings, lists, emphasis, inline code and
+    /// fenced code blocks are formatted, other Markdown is shown as is.
+    pub hover: unsafe extern "C" fn(
+        inst: *mut AnodeHoverProviderO,
+        document: *mut AnodeDocumentO,
+        position: AnodePositionT,
+        hover: *mut AnodeHoverO,
+    ),
+}
+
+unsafe impl Send for AnodeHoverProviderI {}
+unsafe impl Sync for AnodeHoverProviderI {}
+
+/// Opaque instance of a hover provider.
+#[repr(C)]
+pub struct AnodeHoverProviderO {
+    _private: [u8; 0],
+}
+
+/// Tooltip a hover provider adds to with [`AnodeApi::add_hover`].
+#[repr(C)]
+pub struct AnodeHoverO {
+    _private: [u8; 0],
+}
+
+pub const ANODE_HOVER_PROVIDER_I: ConstCStr = const_cstr!("tm_anode_hover_provider_i");
+pub const ANODE_HOVER_PROVIDER_I_VERSION: VersionT = VersionT {
+    major: 0,
+    minor: 1,
+    patch: 0,
+};
+
+/// Range of an asset's text, like where an identifier is defined.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct AnodeLocationT {
+    pub tt: *mut TheTruthO,
+    /// Asset with the [`ASPECT_ANODE`] aspect, which can be another asset than the one asked
+    /// about.
+    pub asset: TtIdT,
+    pub start: AnodePositionT,
+    pub end: AnodePositionT,
+}
+
+/// List of locations a provider adds to with [`AnodeApi::add_location`].
+#[repr(C)]
+pub struct AnodeLocationsO {
+    _private: [u8; 0],
+}
+
+/// Interface for plugins that know where identifiers are defined and used, for example across
+/// the scripts of a project.
+///
+/// Register implementations with the API registry under [`ANODE_DEFINITION_PROVIDER_I`]. When
+/// any provider adds locations, the language server and the editor's own lookup aren't asked.
+#[repr(C)]
+pub struct AnodeDefinitionProviderI {
+    pub inst: *mut AnodeDefinitionProviderO,
+    /// Add the locations the identifier at `position` of a document is defined at, by calling
+    /// [`AnodeApi::add_location`] for each.
+    ///
+    /// Called when going to the definition, the editor jumps to the first location added.
+    pub definitions: unsafe extern "C" fn(
+        inst: *mut AnodeDefinitionProviderO,
+        document: *mut AnodeDocumentO,
+        position: AnodePositionT,
+        locations: *mut AnodeLocationsO,
+    ),
+    /// Add the locations the identifier at `position` of a document is defined and used at.
+    ///
+    /// Called when finding references, the locations are listed in the order they're added.
+    pub references: unsafe extern "C" fn(
+        inst: *mut AnodeDefinitionProviderO,
+        document: *mut AnodeDocumentO,
+        position: AnodePositionT,
+        locations: *mut AnodeLocationsO,
+    ),
+}
+
+unsafe impl Send for AnodeDefinitionProviderI {}
+unsafe impl Sync for AnodeDefinitionProviderI {}
+
+/// Opaque instance of a definition provider.
+#[repr(C)]
+pub struct AnodeDefinitionProviderO {
+    _private: [u8; 0],
+}
+
+pub const ANODE_DEFINITION_PROVIDER_I: ConstCStr = const_cstr!("tm_anode_definition_provider_i");
+pub const ANODE_DEFINITION_PROVIDER_I_VERSION: VersionT = VersionT {
+    major: 0,
+    minor: 1,
+    patch: 0,
+};
+
+/// Truth type of the chunks text is stored in, for assets with [`AnodeAspectI::chunked`] set.
+pub const TEXT_CHUNK: Identifier = identifier!("tm_anode_text_chunk");
 
 /// Highlighting language description.
 ///
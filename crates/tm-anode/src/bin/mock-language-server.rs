@@ -0,0 +1,104 @@
+//! Language server for testing how anode runs servers as child processes.
+//!
+//! Answers `initialize` and `shutdown`, and exits on `exit`. Every message it receives is logged
+//! to the file passed as the first argument, one line per method, along with anything that breaks
+//! the protocol. With `--ignore-exit` it keeps running until it's killed.
+
+use std::{
+    fs::OpenOptions,
+    io::{self, BufRead, BufReader, Write},
+    process,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let log_path = args.next().expect("expected a log file path");
+    let ignore_exit = args.any(|arg| arg == "--ignore-exit");
+    let log = |line: &str| {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .unwrap();
+        writeln!(file, "{}", line).unwrap();
+    };
+
+    // Messages are timestamped as they arrive, so it's known if they were sent too early
+    let (sender, messages) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = BufReader::new(io::stdin());
+        while let Some(message) = read_message(&mut stdin) {
+            if sender.send((Instant::now(), message)).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut shutdown_response = None;
+    for (received, message) in messages {
+        let method = message["method"].as_str().unwrap_or_default();
+        log(method);
+
+        match method {
+            "initialize" => respond(&message, json!({ "capabilities": {} })),
+            "shutdown" => {
+                // Answer slowly, so a client that doesn't wait sends exit before the response
+                thread::sleep(Duration::from_millis(100));
+                shutdown_response = Some(Instant::now());
+                respond(&message, Value::Null);
+            }
+            "exit" => {
+                if shutdown_response.filter(|sent| received >= *sent).is_none() {
+                    log("exit before shutdown response");
+                }
+                if !ignore_exit {
+                    log("exited");
+                    process::exit(0);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // The input closed without an exit notification
+    if ignore_exit {
+        loop {
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+    process::exit(1);
+}
+
+fn respond(request: &Value, result: Value) {
+    let body = json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }).to_string();
+    let mut stdout = io::stdout();
+    write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    stdout.flush().unwrap();
+}
+
+fn read_message(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok()?;
+        }
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
@@ -0,0 +1,1522 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap, HashSet},
+    hash::{BuildHasher, Hasher},
+    time::{Duration, Instant},
+};
+
+use eyre::{eyre, Result};
+
+/// Text that can be edited concurrently from multiple sites, and merged without conflicts.
+///
+/// This is a replicated growable array, every character gets a unique ID and is placed after the
+/// character it was typed after. Deleted characters are kept as tombstones, so edits made
+/// concurrently still find their place. The history is exchanged through [`Self::serialize`] and
+/// [`Self::merge`].
+///
+/// Every site acknowledges the operations it has applied when it shares its history. Operations
+/// every editing site has acknowledged are folded into a snapshot of the text, and once all of
+/// them are, tombstones are dropped as no concurrent edit can need them to find its place anymore.
+pub struct TextCrdt {
+    site: u64,
+    name: String,
+    clock: u64,
+    elements: Sequence,
+    /// Operations that not every site has acknowledged yet, in the order they were applied.
+    log: Vec<Op>,
+    /// Our own operations folded before any other site acknowledged them, as we didn't know of
+    /// any. They're redone if a site we didn't know of folded its own operations too.
+    unshared: Vec<Op>,
+    /// Sequence number of the next operation expected from every site.
+    next_seq: HashMap<u64, u32>,
+    /// Operations that can't be applied yet, by site and sequence number.
+    pending: HashMap<u64, BTreeMap<u32, Op>>,
+    /// Sites whose next pending operation waits for a character to be inserted.
+    waiting: HashMap<CharId, Vec<u64>>,
+    /// Sequence numbers every editing site has applied, as they last shared them.
+    acks: HashMap<u64, HashMap<u64, u32>>,
+    /// Since when other sites have been behind without acknowledging anything, to notice sites
+    /// that are gone.
+    behind_since: HashMap<u64, Instant>,
+    /// Sites that stopped editing, ignored if they show up again in older histories.
+    departed: HashSet<u64>,
+    /// Carets of all editing sites, including our own.
+    carets: HashMap<u64, Caret>,
+}
+
+/// Identifier of a character, unique across all sites.
+///
+/// Ordered by Lamport clock first, so characters inserted later at the same place sort higher.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+struct CharId {
+    clock: u64,
+    site: u64,
+}
+
+#[derive(Clone)]
+struct Element {
+    id: CharId,
+    c: char,
+    deleted: bool,
+}
+
+#[derive(Clone)]
+struct Op {
+    site: u64,
+    seq: u32,
+    kind: OpKind,
+}
+
+#[derive(Clone)]
+enum OpKind {
+    /// Inserts text after the parent, every character after the first gets the next clock.
+    Insert {
+        id: CharId,
+        parent: CharId,
+        text: String,
+    },
+    Delete {
+        targets: Vec<CharId>,
+    },
+    /// The site stopped editing, this is its last operation.
+    Leave,
+}
+
+#[derive(Clone)]
+struct Caret {
+    name: String,
+    /// Character the caret is after.
+    anchor: CharId,
+    /// Increased on every change, the highest one wins when merging.
+    stamp: u64,
+}
+
+/// Local edits that haven't been recorded yet.
+#[derive(Default)]
+pub struct Edits(Vec<OpKind>);
+
+impl Edits {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Position in the text, see [`TextCrdt::anchor`].
+#[derive(Clone, Copy)]
+pub struct Anchor(CharId);
+
+/// Caret of another site, as a character offset into the text.
+pub struct RemoteCaret {
+    pub site: u64,
+    pub name: String,
+    pub offset: usize,
+}
+
+impl TextCrdt {
+    pub fn new(name: String) -> Self {
+        Self {
+            site: new_site(),
+            name,
+            clock: 0,
+            elements: Sequence::default(),
+            log: Vec::new(),
+            unshared: Vec::new(),
+            next_seq: HashMap::new(),
+            pending: HashMap::new(),
+            waiting: HashMap::new(),
+            acks: HashMap::new(),
+            behind_since: HashMap::new(),
+            departed: HashSet::new(),
+            carets: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.next_seq.is_empty() && self.elements.is_empty()
+    }
+
+    pub fn text(&self) -> String {
+        self.elements.text()
+    }
+
+    /// Starts the history from existing text.
+    ///
+    /// Every site starting from the same text creates the same operation, so this is safe to do
+    /// concurrently.
+    pub fn init_base(&mut self, text: &str) {
+        if !self.is_empty() || text.is_empty() {
+            return;
+        }
+
+        let op = Op {
+            site: BASE_SITE,
+            seq: 0,
+            kind: OpKind::Insert {
+                id: CharId {
+                    clock: 1,
+                    site: BASE_SITE,
+                },
+                parent: ROOT,
+                text: text.to_string(),
+            },
+        };
+        self.apply(op);
+    }
+
+    /// Records local edits turning the current text into the new text.
+    pub fn apply_local(&mut self, new: &str) {
+        let edits = self.diff(new);
+        self.apply_edits(edits);
+    }
+
+    /// Edits turning the current text into the new text.
+    ///
+    /// These stay valid when other edits are applied first, see [`Self::apply_edits`].
+    pub fn diff(&self, new: &str) -> Edits {
+        let old: Vec<char> = self.text().chars().collect();
+        let new: Vec<char> = new.chars().collect();
+
+        // Only the changed middle part needs operations
+        let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+        let max_suffix = old.len().min(new.len()) - prefix;
+        let suffix = old
+            .iter()
+            .rev()
+            .zip(new.iter().rev())
+            .take(max_suffix)
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        let visible = self.elements.visible_ids();
+        let mut edits = Edits(Vec::new());
+        if old.len() - suffix > prefix {
+            let targets = visible[prefix..old.len() - suffix].to_vec();
+            edits.0.push(OpKind::Delete { targets });
+        }
+
+        if new.len() - suffix > prefix {
+            let text: String = new[prefix..new.len() - suffix].iter().collect();
+            let parent = if prefix == 0 {
+                ROOT
+            } else {
+                visible[prefix - 1]
+            };
+
+            // The ID is assigned when applied, as the clock may have moved on by then
+            edits.0.push(OpKind::Insert {
+                id: ROOT,
+                parent,
+                text,
+            });
+        }
+
+        edits
+    }
+
+    /// Records local edits, from a diff against an earlier state of the text.
+    pub fn apply_edits(&mut self, edits: Edits) {
+        for mut kind in edits.0 {
+            if let OpKind::Insert { id, .. } = &mut kind {
+                *id = CharId {
+                    clock: self.clock + 1,
+                    site: self.site,
+                };
+            }
+
+            let op = Op {
+                site: self.site,
+                seq: self.next_seq(self.site),
+                kind,
+            };
+            self.apply(op);
+        }
+    }
+
+    /// Position after a character offset, that moves along with edits around it.
+    pub fn anchor(&self, offset: usize) -> Anchor {
+        if offset == 0 {
+            return Anchor(ROOT);
+        }
+
+        Anchor(self.elements.visible_id(offset - 1).unwrap_or(ROOT))
+    }
+
+    /// Current character offset of an anchor.
+    pub fn offset(&self, anchor: Anchor) -> usize {
+        self.elements.offset_after(anchor.0).unwrap_or(0)
+    }
+
+    /// Shares our caret, at a character offset into the text.
+    ///
+    /// Returns true if the caret changed.
+    pub fn set_caret(&mut self, offset: usize) -> bool {
+        let anchor = self.anchor(offset).0;
+        let stamp = match self.carets.get(&self.site) {
+            Some(caret) if caret.anchor == anchor => return false,
+            Some(caret) => caret.stamp + 1,
+            None => 0,
+        };
+
+        let caret = Caret {
+            name: self.name.clone(),
+            anchor,
+            stamp,
+        };
+        self.carets.insert(self.site, caret);
+        true
+    }
+
+    /// Stops editing, removing our caret and no longer holding back compaction of the history.
+    ///
+    /// Returns true if collaborators should be told, which is only needed if they know about us.
+    pub fn leave(&mut self) -> bool {
+        if self.departed.contains(&self.site) || !self.acks.contains_key(&self.site) {
+            return false;
+        }
+
+        let op = Op {
+            site: self.site,
+            seq: self.next_seq(self.site),
+            kind: OpKind::Leave,
+        };
+        self.apply(op);
+        true
+    }
+
+    /// Carets of other sites that are currently editing.
+    pub fn remote_carets(&self) -> Vec<RemoteCaret> {
+        self.carets
+            .iter()
+            .filter(|(site, _)| **site != self.site)
+            .filter_map(|(site, caret)| {
+                let offset = self.elements.offset_after(caret.anchor)?;
+                Some(RemoteCaret {
+                    site: *site,
+                    name: caret.name.clone(),
+                    offset,
+                })
+            })
+            .collect()
+    }
+
+    /// Merges the serialized history of another site into this one.
+    ///
+    /// Returns true if the other site is missing anything we have, and should get our history.
+    pub fn merge(&mut self, bytes: &[u8]) -> Result<bool> {
+        if bytes.is_empty() {
+            return Ok(!self.is_empty() || !self.carets.is_empty());
+        }
+
+        let remote = decode(bytes)?;
+
+        // Operations folded into the snapshot can't be applied anymore, if we're missing any of
+        // them we need to continue from the snapshot instead
+        let mut folded = remote.next_seq.clone();
+        for op in &remote.log {
+            let seq = folded.entry(op.site).or_insert(op.seq);
+            *seq = (*seq).min(op.seq);
+        }
+        let behind = folded
+            .iter()
+            .any(|(site, seq)| !self.departed.contains(site) && self.next_seq(*site) < *seq);
+
+        let remote_next = remote.next_seq.clone();
+        if behind {
+            self.rebase(remote.clock, remote.elements, remote.next_seq, remote.log);
+        } else {
+            for op in remote.log {
+                self.queue(op);
+            }
+            self.apply_pending();
+        }
+
+        for site in remote.departed {
+            self.depart(site);
+        }
+
+        for (site, remote_ack) in &remote.acks {
+            if *site == self.site || self.departed.contains(site) {
+                continue;
+            }
+
+            let ack = self.acks.entry(*site).or_default();
+            let mut changed = ack.is_empty();
+            for (acked_site, seq) in remote_ack {
+                let acked = ack.entry(*acked_site).or_insert(0);
+                if *seq > *acked {
+                    *acked = *seq;
+                    changed = true;
+                }
+            }
+            if changed {
+                self.behind_since.remove(site);
+            }
+        }
+        self.drop_gone_sites();
+
+        let shared = self.acked_by_others();
+        self.unshared.retain(|op| op.seq >= shared);
+
+        for (site, caret) in remote.carets {
+            // Nobody else gets to move our caret
+            let is_newer = self
+                .carets
+                .get(&site)
+                .map(|v| caret.stamp > v.stamp)
+                .unwrap_or(true);
+            if site != self.site && is_newer && self.acks.contains_key(&site) {
+                self.carets.insert(site, caret);
+            }
+        }
+
+        let ops_behind = self
+            .next_seq
+            .iter()
+            .any(|(site, next)| remote_next.get(site).copied().unwrap_or(0) < *next);
+        let remote_stamps = &remote.carets_stamps;
+        let carets_behind = self.carets.iter().any(|(site, caret)| {
+            remote_stamps
+                .get(site)
+                .map(|v| *v < caret.stamp)
+                .unwrap_or(true)
+        });
+
+        // Operations can only be folded once we acknowledge them, even if we have nothing to add
+        let acks_behind = self.acks.contains_key(&self.site)
+            && !self.departed.contains(&self.site)
+            && remote.acks.get(&self.site) != Some(&self.next_seq);
+
+        Ok(ops_behind || carets_behind || acks_behind)
+    }
+
+    /// Serializes the history to share with other sites, folding what they all acknowledged.
+    pub fn serialize(&mut self) -> Vec<u8> {
+        self.compact();
+
+        let mut writer = Writer(Vec::new());
+        writer.0.extend_from_slice(MAGIC);
+        writer.u32(FORMAT_VERSION);
+        writer.u64(self.clock);
+
+        writer.u32(self.next_seq.len() as u32);
+        for (site, next) in &self.next_seq {
+            writer.u64(*site);
+            writer.u32(*next);
+        }
+
+        writer.u32(self.departed.len() as u32);
+        for site in &self.departed {
+            writer.u64(*site);
+        }
+
+        // Characters inserted together are stored together, as long as they're deleted together
+        let runs = self.elements.runs();
+        writer.u32(runs.len() as u32);
+        for (id, deleted, text) in runs {
+            writer.id(id);
+            writer.0.push(deleted as u8);
+            writer.string(&text);
+        }
+
+        writer.u32(self.log.len() as u32);
+        for op in &self.log {
+            writer.u64(op.site);
+            writer.u32(op.seq);
+
+            match &op.kind {
+                OpKind::Insert { id, parent, text } => {
+                    writer.0.push(OP_INSERT);
+                    writer.id(*id);
+                    writer.id(*parent);
+                    writer.string(text);
+                }
+                OpKind::Delete { targets } => {
+                    writer.0.push(OP_DELETE);
+                    writer.u32(targets.len() as u32);
+                    for target in targets {
+                        writer.id(*target);
+                    }
+                }
+                OpKind::Leave => writer.0.push(OP_LEAVE),
+            }
+        }
+
+        writer.u32(self.acks.len() as u32);
+        for (site, ack) in &self.acks {
+            writer.u64(*site);
+            writer.u32(ack.len() as u32);
+            for (acked_site, seq) in ack {
+                writer.u64(*acked_site);
+                writer.u32(*seq);
+            }
+        }
+
+        writer.u32(self.carets.len() as u32);
+        for (site, caret) in &self.carets {
+            writer.u64(*site);
+            writer.string(&caret.name);
+            writer.u64(caret.stamp);
+            writer.id(caret.anchor);
+        }
+
+        writer.0
+    }
+
+    fn next_seq(&self, site: u64) -> u32 {
+        self.next_seq.get(&site).copied().unwrap_or(0)
+    }
+
+    /// Queues an operation of another site, to be applied once it can be.
+    fn queue(&mut self, op: Op) {
+        if self.departed.contains(&op.site) || op.seq < self.next_seq(op.site) {
+            return;
+        }
+        self.pending.entry(op.site).or_default().insert(op.seq, op);
+    }
+
+    /// Applies pending operations that have become applicable, until no more can be.
+    ///
+    /// Sites are only checked again when something they wait for happens, so every operation is
+    /// looked at a bounded number of times.
+    fn apply_pending(&mut self) {
+        let mut ready: Vec<u64> = self.pending.keys().copied().collect();
+        while let Some(site) = ready.pop() {
+            let next = self.next_seq(site);
+            let op = match self.pending.get(&site).and_then(|ops| ops.get(&next)) {
+                Some(op) => op,
+                None => continue,
+            };
+
+            if let Some(missing) = self.missing_dependency(op) {
+                self.waiting.entry(missing).or_default().push(site);
+                continue;
+            }
+
+            let ops = self.pending.get_mut(&site).unwrap();
+            let op = ops.remove(&next).unwrap();
+            if ops.is_empty() {
+                self.pending.remove(&site);
+            }
+
+            // Sites waiting for the inserted characters can continue
+            if let OpKind::Insert { id, text, .. } = &op.kind {
+                for i in 0..text.chars().count() as u64 {
+                    let inserted = CharId {
+                        clock: id.clock + i,
+                        site: id.site,
+                    };
+                    if let Some(sites) = self.waiting.remove(&inserted) {
+                        ready.extend(sites);
+                    }
+                }
+            }
+
+            self.apply(op);
+            ready.push(site);
+        }
+    }
+
+    /// Character an operation needs that isn't there yet.
+    fn missing_dependency(&self, op: &Op) -> Option<CharId> {
+        match &op.kind {
+            OpKind::Insert { parent, .. } => {
+                Some(*parent).filter(|v| *v != ROOT && !self.elements.contains(*v))
+            }
+            OpKind::Delete { targets } => targets
+                .iter()
+                .copied()
+                .find(|v| !self.elements.contains(*v)),
+            OpKind::Leave => None,
+        }
+    }
+
+    fn apply(&mut self, op: Op) {
+        match &op.kind {
+            OpKind::Insert { id, parent, text } => {
+                let elements = text
+                    .chars()
+                    .enumerate()
+                    .map(|(i, c)| Element {
+                        id: CharId {
+                            clock: id.clock + i as u64,
+                            site: id.site,
+                        },
+                        c,
+                        deleted: false,
+                    })
+                    .collect();
+                self.elements.insert(*parent, elements);
+
+                let last_clock = id.clock + text.chars().count() as u64 - 1;
+                self.clock = self.clock.max(last_clock);
+            }
+            OpKind::Delete { targets } => {
+                for target in targets {
+                    self.elements.delete(*target);
+                }
+            }
+            OpKind::Leave => self.depart(op.site),
+        }
+
+        self.next_seq.insert(op.site, op.seq + 1);
+        self.log.push(op);
+    }
+
+    /// Forgets everything about a site that stopped editing, except its operations.
+    fn depart(&mut self, site: u64) {
+        self.departed.insert(site);
+        self.carets.remove(&site);
+        self.acks.remove(&site);
+        self.behind_since.remove(&site);
+        self.pending.remove(&site);
+    }
+
+    /// Stops waiting for sites that haven't acknowledged anything for a long time while there was
+    /// something to acknowledge, as they likely stopped without leaving.
+    ///
+    /// If they do come back, they continue from the snapshot like a new site.
+    fn drop_gone_sites(&mut self) {
+        let mut gone = Vec::new();
+        for (site, ack) in &self.acks {
+            let is_behind = self
+                .next_seq
+                .iter()
+                .any(|(s, next)| ack.get(s).copied().unwrap_or(0) < *next);
+            if *site == self.site || !is_behind {
+                self.behind_since.remove(site);
+                continue;
+            }
+
+            let since = self.behind_since.entry(*site).or_insert_with(Instant::now);
+            if since.elapsed() >= GONE_TIMEOUT {
+                gone.push(*site);
+            }
+        }
+
+        for site in gone {
+            self.carets.remove(&site);
+            self.acks.remove(&site);
+            self.behind_since.remove(&site);
+            self.pending.remove(&site);
+        }
+    }
+
+    /// Folds operations every editing site acknowledged into the snapshot, and drops tombstones
+    /// once nothing can refer to them anymore.
+    fn compact(&mut self) {
+        self.drop_gone_sites();
+        if !self.departed.contains(&self.site) {
+            self.acks.insert(self.site, self.next_seq.clone());
+        }
+
+        // Operations are stable once every site that's still editing has applied them
+        let mut stable = self.next_seq.clone();
+        for ack in self.acks.values() {
+            for (site, seq) in &mut stable {
+                *seq = (*seq).min(ack.get(site).copied().unwrap_or(0));
+            }
+        }
+
+        let (site, shared) = (self.site, self.acked_by_others());
+        let mut left = Vec::new();
+        let mut unshared = Vec::new();
+        self.log.retain(|op| {
+            let is_stable = op.seq < stable.get(&op.site).copied().unwrap_or(0);
+            if is_stable && matches!(op.kind, OpKind::Leave) {
+                left.push(op.site);
+            }
+            if is_stable && op.site == site && op.seq >= shared {
+                unshared.push(op.clone());
+            }
+            !is_stable
+        });
+        self.unshared.extend(unshared);
+
+        // Sites that left are remembered in `departed`, so they don't need to be counted anymore
+        for site in left {
+            self.next_seq.remove(&site);
+            for ack in self.acks.values_mut() {
+                ack.remove(&site);
+            }
+        }
+
+        // Edits made concurrently are placed relative to tombstones, so they're needed until
+        // everything is stable
+        if self.log.is_empty() && self.pending.is_empty() && self.unshared.is_empty() {
+            self.waiting.clear();
+            let anchors: HashSet<CharId> = self.carets.values().map(|v| v.anchor).collect();
+            let moved = self.elements.drop_tombstones(&anchors);
+            for caret in self.carets.values_mut() {
+                if let Some(anchor) = moved.get(&caret.anchor) {
+                    caret.anchor = *anchor;
+                }
+            }
+        }
+
+        if !self.departed.contains(&self.site) {
+            self.acks.insert(self.site, self.next_seq.clone());
+        }
+    }
+
+    /// Sequence number up to which another site has acknowledged our operations.
+    fn acked_by_others(&self) -> u32 {
+        self.acks
+            .iter()
+            .filter(|(site, _)| **site != self.site)
+            .filter_map(|(_, ack)| ack.get(&self.site).copied())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Continues from the snapshot of another site, as it folded operations we never got.
+    ///
+    /// Our own operations the other site doesn't have are redone on top of it, as a new site
+    /// because the originals may refer to characters that are no longer there.
+    fn rebase(
+        &mut self,
+        clock: u64,
+        elements: Vec<Element>,
+        next_seq: HashMap<u64, u32>,
+        log: Vec<Op>,
+    ) {
+        let known = next_seq.get(&self.site).copied().unwrap_or(0);
+        let own: Vec<Op> = std::mem::take(&mut self.unshared)
+            .into_iter()
+            .chain(self.log.iter().cloned())
+            .filter(|op| op.site == self.site && op.seq >= known)
+            .collect();
+
+        let old = std::mem::replace(&mut self.elements, Sequence::from_elements(elements));
+        self.clock = self.clock.max(clock);
+        self.next_seq = next_seq;
+        self.log = log;
+
+        // Operations of other sites that weren't folded may still be applied
+        let next_seq = &self.next_seq;
+        self.pending.retain(|site, ops| {
+            let next = next_seq.get(site).copied().unwrap_or(0);
+            ops.retain(|seq, _| *seq >= next);
+            !ops.is_empty()
+        });
+        self.waiting.clear();
+
+        // Collaborators that know our old site stop waiting for it
+        if !own.is_empty() {
+            let old_site = self.site;
+            self.site = new_site();
+            if self.acks.remove(&old_site).is_some() {
+                self.departed.insert(old_site);
+            }
+            if let Some(caret) = self.carets.remove(&old_site) {
+                self.carets.insert(self.site, caret);
+            }
+        }
+
+        let mut moved = HashMap::new();
+        for op in own {
+            match op.kind {
+                OpKind::Insert { id, parent, text } => {
+                    let parent = self.rebased_id(&old, &moved, parent);
+                    let new_id = CharId {
+                        clock: self.clock + 1,
+                        site: self.site,
+                    };
+                    for i in 0..text.chars().count() as u64 {
+                        let from = CharId {
+                            clock: id.clock + i,
+                            site: id.site,
+                        };
+                        let to = CharId {
+                            clock: new_id.clock + i,
+                            site: new_id.site,
+                        };
+                        moved.insert(from, to);
+                    }
+
+                    let kind = OpKind::Insert {
+                        id: new_id,
+                        parent,
+                        text,
+                    };
+                    self.apply_edits(Edits(vec![kind]));
+                }
+                OpKind::Delete { targets } => {
+                    let targets: Vec<CharId> = targets
+                        .iter()
+                        .map(|v| moved.get(v).copied().unwrap_or(*v))
+                        .filter(|v| self.elements.contains(*v))
+                        .collect();
+                    if !targets.is_empty() {
+                        self.apply_edits(Edits(vec![OpKind::Delete { targets }]));
+                    }
+                }
+                OpKind::Leave => {}
+            }
+        }
+
+        if let Some(anchor) = self.carets.get(&self.site).map(|v| v.anchor) {
+            let anchor = self.rebased_id(&old, &moved, anchor);
+            self.carets.get_mut(&self.site).unwrap().anchor = anchor;
+        }
+
+        self.apply_pending();
+    }
+
+    /// Character that takes the place of one from before a rebase, the closest one before it
+    /// that's still there if it's gone.
+    fn rebased_id(&self, old: &Sequence, moved: &HashMap<CharId, CharId>, id: CharId) -> CharId {
+        let current = moved.get(&id).copied().unwrap_or(id);
+        if current == ROOT || self.elements.contains(current) {
+            return current;
+        }
+
+        let mut closest = ROOT;
+        for element in old.iter() {
+            if element.id == id {
+                break;
+            }
+            let current = moved.get(&element.id).copied().unwrap_or(element.id);
+            if self.elements.contains(current) {
+                closest = current;
+            }
+        }
+        closest
+    }
+}
+
+/// Name shown to collaborators, the name of the user we're running as.
+pub fn user_name() -> String {
+    std::env::var("USERNAME")
+        .or_else(|_| std::env::var("USER"))
+        .unwrap_or_else(|_| "Anonymous".to_string())
+}
+
+/// Random site ID, so sites opening the same text at the same time don't collide.
+fn new_site() -> u64 {
+    RandomState::new()
+        .build_hasher()
+        .finish()
+        .max(BASE_SITE + 1)
+}
+
+/// Characters in order, including tombstones.
+///
+/// They're kept in blocks that know how many of their characters are visible, with an index of
+/// the block every character is in. Finding characters and offsets then doesn't go through the
+/// entire text, and inserting only moves the characters in one block.
+#[derive(Default)]
+struct Sequence {
+    blocks: Vec<Block>,
+    /// Key of the block every character is in.
+    block_of: HashMap<CharId, usize>,
+    /// Index into `blocks` of every block, by key.
+    block_indices: Vec<usize>,
+}
+
+struct Block {
+    key: usize,
+    elements: Vec<Element>,
+    visible: usize,
+}
+
+impl Sequence {
+    fn from_elements(elements: Vec<Element>) -> Self {
+        let mut sequence = Self::default();
+        for chunk in elements.chunks(BLOCK_SIZE) {
+            sequence.push_block(chunk.to_vec());
+        }
+        sequence
+    }
+
+    fn is_empty(&self) -> bool {
+        self.block_of.is_empty()
+    }
+
+    fn contains(&self, id: CharId) -> bool {
+        self.block_of.contains_key(&id)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Element> {
+        self.blocks.iter().flat_map(|v| v.elements.iter())
+    }
+
+    fn text(&self) -> String {
+        self.iter().filter(|v| !v.deleted).map(|v| v.c).collect()
+    }
+
+    fn visible_ids(&self) -> Vec<CharId> {
+        self.iter().filter(|v| !v.deleted).map(|v| v.id).collect()
+    }
+
+    /// Block index and index within the block of a character.
+    fn find(&self, id: CharId) -> Option<(usize, usize)> {
+        let block = self.block_indices[*self.block_of.get(&id)?];
+        let index = self.blocks[block]
+            .elements
+            .iter()
+            .position(|v| v.id == id)?;
+        Some((block, index))
+    }
+
+    /// ID of the visible character at a character offset.
+    fn visible_id(&self, offset: usize) -> Option<CharId> {
+        let mut remaining = offset;
+        for block in &self.blocks {
+            if remaining >= block.visible {
+                remaining -= block.visible;
+                continue;
+            }
+
+            let element = block
+                .elements
+                .iter()
+                .filter(|v| !v.deleted)
+                .nth(remaining)?;
+            return Some(element.id);
+        }
+        None
+    }
+
+    /// Character offset right after a character, which stays valid if the character is deleted.
+    fn offset_after(&self, id: CharId) -> Option<usize> {
+        if id == ROOT {
+            return Some(0);
+        }
+
+        let (block, index) = self.find(id)?;
+        let before: usize = self.blocks[..block].iter().map(|v| v.visible).sum();
+        let within = self.blocks[block].elements[..=index]
+            .iter()
+            .filter(|v| !v.deleted)
+            .count();
+        Some(before + within)
+    }
+
+    /// Inserts characters after their parent, the first one's ID decides where between other
+    /// characters inserted after the same parent.
+    fn insert(&mut self, parent: CharId, elements: Vec<Element>) {
+        let id = elements[0].id;
+        let (mut block, mut index) = match self.find(parent) {
+            Some((block, index)) => (block, index + 1),
+            None => (0, 0),
+        };
+
+        // Concurrent inserts at the same place are ordered by ID, skip higher ones
+        while block < self.blocks.len() {
+            if index >= self.blocks[block].elements.len() {
+                if block + 1 == self.blocks.len() {
+                    break;
+                }
+                block += 1;
+                index = 0;
+            } else if self.blocks[block].elements[index].id > id {
+                index += 1;
+            } else {
+                break;
+            }
+        }
+
+        if self.blocks.is_empty() {
+            self.push_block(elements);
+            return;
+        }
+
+        let target = &mut self.blocks[block];
+        for element in &elements {
+            self.block_of.insert(element.id, target.key);
+        }
+        target.visible += elements.iter().filter(|v| !v.deleted).count();
+        target.elements.splice(index..index, elements);
+
+        if target.elements.len() > BLOCK_SIZE * 2 {
+            self.split(block);
+        }
+    }
+
+    /// Marks a character as deleted.
+    fn delete(&mut self, id: CharId) {
+        if let Some((block, index)) = self.find(id) {
+            let block = &mut self.blocks[block];
+            if !block.elements[index].deleted {
+                block.elements[index].deleted = true;
+                block.visible -= 1;
+            }
+        }
+    }
+
+    /// Removes deleted characters, returning where anchors on them moved to.
+    fn drop_tombstones(&mut self, anchors: &HashSet<CharId>) -> HashMap<CharId, CharId> {
+        let mut moved = HashMap::new();
+        if self.blocks.iter().all(|v| v.visible == v.elements.len()) {
+            return moved;
+        }
+
+        let mut closest = ROOT;
+        let mut elements = Vec::new();
+        for element in self.iter() {
+            if !element.deleted {
+                closest = element.id;
+                elements.push(element.clone());
+            } else if anchors.contains(&element.id) {
+                moved.insert(element.id, closest);
+            }
+        }
+
+        *self = Self::from_elements(elements);
+        moved
+    }
+
+    /// Runs of characters with consecutive clocks from the same site, that are all deleted or
+    /// all visible.
+    fn runs(&self) -> Vec<(CharId, bool, String)> {
+        let mut runs: Vec<(CharId, bool, String)> = Vec::new();
+        let mut last: Option<CharId> = None;
+        for element in self.iter() {
+            let continues = match (last, runs.last()) {
+                (Some(last), Some((_, deleted, _))) => {
+                    last.site == element.id.site
+                        && last.clock + 1 == element.id.clock
+                        && *deleted == element.deleted
+                }
+                _ => false,
+            };
+
+            if continues {
+                runs.last_mut().unwrap().2.push(element.c);
+            } else {
+                runs.push((element.id, element.deleted, element.c.to_string()));
+            }
+            last = Some(element.id);
+        }
+        runs
+    }
+
+    fn push_block(&mut self, elements: Vec<Element>) {
+        let key = self.block_indices.len();
+        for element in &elements {
+            self.block_of.insert(element.id, key);
+        }
+        self.block_indices.push(self.blocks.len());
+        self.blocks.push(Block {
+            key,
+            visible: elements.iter().filter(|v| !v.deleted).count(),
+            elements,
+        });
+    }
+
+    /// Splits a block that grew too large into blocks of the usual size.
+    fn split(&mut self, block: usize) {
+        let rest = self.blocks[block].elements.split_off(BLOCK_SIZE);
+        self.blocks[block].visible -= rest.iter().filter(|v| !v.deleted).count();
+
+        for (i, chunk) in rest.chunks(BLOCK_SIZE).enumerate() {
+            let key = self.block_indices.len();
+            for element in chunk {
+                self.block_of.insert(element.id, key);
+            }
+
+            self.block_indices.push(0);
+            self.blocks.insert(
+                block + 1 + i,
+                Block {
+                    key,
+                    elements: chunk.to_vec(),
+                    visible: chunk.iter().filter(|v| !v.deleted).count(),
+                },
+            );
+        }
+
+        for (index, block) in self.blocks.iter().enumerate().skip(block + 1) {
+            self.block_indices[block.key] = index;
+        }
+    }
+}
+
+/// Decoded history of another site.
+struct History {
+    clock: u64,
+    next_seq: HashMap<u64, u32>,
+    departed: HashSet<u64>,
+    elements: Vec<Element>,
+    log: Vec<Op>,
+    acks: HashMap<u64, HashMap<u64, u32>>,
+    carets: HashMap<u64, Caret>,
+    /// Stamps of all carets, including those that aren't kept.
+    carets_stamps: HashMap<u64, u64>,
+}
+
+fn decode(bytes: &[u8]) -> Result<History> {
+    let mut reader = Reader { bytes, offset: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(eyre!("Edit history has an unknown format"));
+    }
+    let version = reader.u32()?;
+    if version != FORMAT_VERSION && version != FORMAT_VERSION_LOG {
+        return Err(eyre!("Edit history has unsupported version {}", version));
+    }
+
+    // Histories that are only a log start from nothing
+    let mut history = History {
+        clock: 0,
+        next_seq: HashMap::new(),
+        departed: HashSet::new(),
+        elements: Vec::new(),
+        log: Vec::new(),
+        acks: HashMap::new(),
+        carets: HashMap::new(),
+        carets_stamps: HashMap::new(),
+    };
+
+    if version == FORMAT_VERSION {
+        history.clock = reader.u64()?;
+
+        for _ in 0..reader.u32()? {
+            let site = reader.u64()?;
+            let next = reader.u32()?;
+            history.next_seq.insert(site, next);
+        }
+
+        for _ in 0..reader.u32()? {
+            history.departed.insert(reader.u64()?);
+        }
+
+        for _ in 0..reader.u32()? {
+            let id = reader.id()?;
+            let deleted = reader.take(1)?[0] != 0;
+            let text = reader.string()?;
+            for (i, c) in text.chars().enumerate() {
+                history.elements.push(Element {
+                    id: CharId {
+                        clock: id.clock + i as u64,
+                        site: id.site,
+                    },
+                    c,
+                    deleted,
+                });
+            }
+        }
+    }
+
+    let op_count = reader.u32()?;
+    for _ in 0..op_count {
+        let site = reader.u64()?;
+        let seq = reader.u32()?;
+
+        let kind = match reader.take(1)?[0] {
+            OP_INSERT => {
+                let id = reader.id()?;
+                let parent = reader.id()?;
+                let text = reader.string()?;
+                if text.is_empty() {
+                    return Err(eyre!("Edit history contains an empty insert"));
+                }
+                OpKind::Insert { id, parent, text }
+            }
+            OP_DELETE => {
+                let count = reader.u32()?;
+                let targets = (0..count)
+                    .map(|_| reader.id())
+                    .collect::<Result<Vec<_>>>()?;
+                OpKind::Delete { targets }
+            }
+            OP_LEAVE if version == FORMAT_VERSION => OpKind::Leave,
+            kind => return Err(eyre!("Edit history contains unknown operation {}", kind)),
+        };
+
+        if version == FORMAT_VERSION_LOG {
+            let next = history.next_seq.entry(site).or_insert(0);
+            *next = (*next).max(seq + 1);
+        }
+        history.log.push(Op { site, seq, kind });
+    }
+
+    if version == FORMAT_VERSION {
+        for _ in 0..reader.u32()? {
+            let site = reader.u64()?;
+            let mut ack = HashMap::new();
+            for _ in 0..reader.u32()? {
+                let acked_site = reader.u64()?;
+                let seq = reader.u32()?;
+                ack.insert(acked_site, seq);
+            }
+            history.acks.insert(site, ack);
+        }
+    }
+
+    let caret_count = reader.u32()?;
+    for _ in 0..caret_count {
+        let site = reader.u64()?;
+        let name = reader.string()?;
+        let stamp = reader.u64()?;
+        let anchor = if version == FORMAT_VERSION {
+            Some(reader.id()?)
+        } else {
+            // Carets of sites that left didn't have an anchor
+            match reader.take(1)?[0] {
+                0 => None,
+                _ => Some(reader.id()?),
+            }
+        };
+
+        history.carets_stamps.insert(site, stamp);
+        if let Some(anchor) = anchor {
+            let caret = Caret {
+                name,
+                anchor,
+                stamp,
+            };
+            history.carets.insert(site, caret);
+
+            // Sites only known from a log are still editing if they have a caret
+            if version == FORMAT_VERSION_LOG {
+                history.acks.entry(site).or_default();
+            }
+        }
+    }
+
+    Ok(history)
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn id(&mut self, id: CharId) {
+        self.u64(id.clock);
+        self.u64(id.site);
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value.as_bytes());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.offset..self.offset + len)
+            .ok_or_else(|| eyre!("Edit history is truncated"))?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn id(&mut self) -> Result<CharId> {
+        let clock = self.u64()?;
+        let site = self.u64()?;
+        Ok(CharId { clock, site })
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        Ok(std::str::from_utf8(bytes)?.to_string())
+    }
+}
+
+/// Start of the text, characters at the start are inserted after this.
+const ROOT: CharId = CharId { clock: 0, site: 0 };
+
+/// Site that creates the initial text, shared by all sites.
+const BASE_SITE: u64 = 0;
+
+/// Amount of characters blocks are split into, they're split when they get twice as large.
+const BLOCK_SIZE: usize = 512;
+
+/// How long a site can go without acknowledging anything it's behind on, before it's considered
+/// gone.
+const GONE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+const MAGIC: &[u8] = b"ANOH";
+const FORMAT_VERSION: u32 = 2;
+/// Earlier format, that stored the full log of operations.
+const FORMAT_VERSION_LOG: u32 = 1;
+
+const OP_INSERT: u8 = 0;
+const OP_DELETE: u8 = 1;
+const OP_LEAVE: u8 = 2;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Shares the history of one site with another, returning if it should share back.
+    fn share(from: &mut TextCrdt, to: &mut TextCrdt) -> bool {
+        to.merge(&from.serialize()).unwrap()
+    }
+
+    /// Shares back and forth until neither site is missing anything.
+    fn sync(a: &mut TextCrdt, b: &mut TextCrdt) {
+        let mut from_a = true;
+        while if from_a { share(a, b) } else { share(b, a) } {
+            from_a = !from_a;
+        }
+        share(b, a);
+    }
+
+    fn caret_offsets(crdt: &TextCrdt) -> Vec<(String, usize)> {
+        let mut carets: Vec<_> = crdt
+            .remote_carets()
+            .into_iter()
+            .map(|v| (v.name, v.offset))
+            .collect();
+        carets.sort();
+        carets
+    }
+
+    #[test]
+    fn concurrent_edits_converge() {
+        let mut a = TextCrdt::new("a".to_string());
+        a.init_base("hello world");
+        let mut b = TextCrdt::new("b".to_string());
+        share(&mut a, &mut b);
+        assert_eq!(b.text(), "hello world");
+
+        // Both sites edit at the same time, at the same place and elsewhere
+        a.apply_local("Ahello world");
+        a.apply_local("Ahello world!");
+        a.set_caret(13);
+        b.apply_local("Bhello world");
+        b.apply_local("Bhello there world");
+        b.set_caret(12);
+
+        sync(&mut a, &mut b);
+        let text = a.text();
+        assert_eq!(text, b.text());
+        assert!(text == "ABhello there world!" || text == "BAhello there world!");
+
+        // Carets moved along with the edits of the other site
+        let a_caret = text.len();
+        let b_caret = text.find("there").unwrap() + "there".len();
+        assert_eq!(caret_offsets(&b), vec![("a".to_string(), a_caret)]);
+        assert_eq!(caret_offsets(&a), vec![("b".to_string(), b_caret)]);
+    }
+
+    #[test]
+    fn carets_survive_compaction() {
+        let mut a = TextCrdt::new("a".to_string());
+        a.init_base("aaa bbb ccc");
+        let mut b = TextCrdt::new("b".to_string());
+        share(&mut a, &mut b);
+
+        // The caret is on a character that gets deleted, and then dropped
+        b.set_caret(7);
+        sync(&mut a, &mut b);
+        a.apply_local("aaa ccc");
+        sync(&mut a, &mut b);
+        a.serialize();
+
+        assert_eq!(b.text(), "aaa ccc");
+        assert!(a.elements.iter().all(|v| !v.deleted));
+        assert_eq!(caret_offsets(&a), vec![("b".to_string(), 4)]);
+    }
+
+    #[test]
+    fn history_is_compacted_once_acknowledged() {
+        let mut a = TextCrdt::new("a".to_string());
+        a.init_base("text");
+        let mut b = TextCrdt::new("b".to_string());
+        share(&mut a, &mut b);
+
+        let edit = |a: &mut TextCrdt, b: &mut TextCrdt, i: usize| {
+            a.apply_local(&format!("text {}", i));
+            a.apply_local("text 0");
+            sync(a, b);
+        };
+        for i in 1..10 {
+            edit(&mut a, &mut b, i);
+        }
+        let size = a.serialize().len();
+
+        // Edits every site has seen don't take up space
+        for i in 10..100 {
+            edit(&mut a, &mut b, i);
+        }
+        assert_eq!(a.serialize().len(), size);
+        assert!(a.log.is_empty());
+        assert_eq!(b.text(), "text 0");
+    }
+
+    #[test]
+    fn history_is_kept_until_acknowledged() {
+        let mut a = TextCrdt::new("a".to_string());
+        a.init_base("text");
+        let mut b = TextCrdt::new("b".to_string());
+        share(&mut a, &mut b);
+        sync(&mut a, &mut b);
+
+        // B hasn't seen these yet, so they can't be folded
+        a.apply_local("text 1");
+        a.apply_local("text");
+        a.serialize();
+        assert_eq!(a.log.len(), 2);
+        assert!(a.elements.iter().any(|v| v.deleted));
+
+        sync(&mut a, &mut b);
+        a.serialize();
+        assert!(a.log.is_empty());
+        assert!(a.elements.iter().all(|v| !v.deleted));
+    }
+
+    #[test]
+    fn leaving_removes_caret() {
+        let mut a = TextCrdt::new("a".to_string());
+        a.init_base("text");
+        let mut b = TextCrdt::new("b".to_string());
+        share(&mut a, &mut b);
+        b.set_caret(2);
+        sync(&mut a, &mut b);
+        assert_eq!(caret_offsets(&a).len(), 1);
+
+        assert!(b.leave());
+        assert!(!b.leave());
+        share(&mut b, &mut a);
+        assert!(caret_offsets(&a).is_empty());
+        assert!(!a.acks.contains_key(&b.site));
+
+        // An older history of the site that left doesn't bring it back
+        let mut stale = TextCrdt::new("c".to_string());
+        stale.init_base("text");
+        stale.carets.insert(
+            b.site,
+            Caret {
+                name: "b".to_string(),
+                anchor: ROOT,
+                stamp: 10,
+            },
+        );
+        share(&mut a, &mut stale);
+        share(&mut stale, &mut a);
+        assert!(caret_offsets(&a)
+            .iter()
+            .all(|(name, _)| name.as_str() != "b"));
+    }
+
+    #[test]
+    fn new_site_continues_from_snapshot() {
+        let mut a = TextCrdt::new("a".to_string());
+        a.init_base("first");
+        a.apply_local("first second");
+        a.apply_local("second");
+
+        let mut b = TextCrdt::new("b".to_string());
+        share(&mut a, &mut b);
+        assert_eq!(b.text(), "second");
+        assert!(a.log.is_empty());
+
+        b.apply_local("second third");
+        sync(&mut a, &mut b);
+        assert_eq!(a.text(), "second third");
+    }
+
+    #[test]
+    fn site_behind_snapshot_keeps_its_edits() {
+        let mut a = TextCrdt::new("a".to_string());
+        a.init_base("one two");
+        let mut b = TextCrdt::new("b".to_string());
+        share(&mut a, &mut b);
+
+        // A doesn't know about B yet, so it folds its edits right away
+        a.apply_local("one");
+        b.apply_local("zero one two");
+        share(&mut a, &mut b);
+
+        assert_eq!(b.text(), "zero one");
+        sync(&mut a, &mut b);
+        assert_eq!(a.text(), "zero one");
+    }
+
+    #[test]
+    fn sites_folding_alone_keep_their_edits() {
+        let mut a = TextCrdt::new("a".to_string());
+        a.init_base("one two");
+        let mut b = TextCrdt::new("b".to_string());
+        b.init_base("one two");
+
+        // Neither knows about the other, so both fold their edits right away
+        a.apply_local("zero one two");
+        b.apply_local("one three");
+        a.serialize();
+        b.serialize();
+
+        sync(&mut a, &mut b);
+        assert_eq!(a.text(), "zero one three");
+        assert_eq!(b.text(), "zero one three");
+    }
+
+    #[test]
+    fn offsets_match_text_across_blocks() {
+        let mut crdt = TextCrdt::new("a".to_string());
+        let mut text: String = (0..BLOCK_SIZE * 5)
+            .map(|i| (b'a' + (i % 26) as u8) as char)
+            .collect();
+        crdt.init_base(&text);
+
+        // Edits in many places split blocks and leave tombstones in them
+        for i in 0..100 {
+            let offset = (i * 37) % (BLOCK_SIZE * 2);
+            text.insert_str(offset, "0123456789012345678901234567890123456789");
+            crdt.apply_local(&text);
+            text.remove(offset + 45);
+            crdt.apply_local(&text);
+        }
+        assert_eq!(crdt.text(), text);
+        assert!(crdt.elements.blocks.len() > 5);
+
+        for offset in [0, 1, BLOCK_SIZE, BLOCK_SIZE * 3 + 7, text.len()] {
+            let anchor = crdt.anchor(offset);
+            assert_eq!(crdt.offset(anchor), offset);
+        }
+    }
+
+    #[test]
+    fn pending_operations_wait_for_what_they_depend_on() {
+        let mut a = TextCrdt::new("a".to_string());
+        a.init_base("ab");
+        let mut b = TextCrdt::new("b".to_string());
+        share(&mut a, &mut b);
+
+        a.apply_local("a1b");
+        let mut c = TextCrdt::new("c".to_string());
+        share(&mut a, &mut c);
+        c.apply_local("a12b");
+        c.apply_local("a123b");
+
+        // B gets C's edits before A's they depend on, as C's log includes A's
+        let c_history = c.serialize();
+        let mut ops_only = decode(&c_history).unwrap();
+        ops_only.log.retain(|op| op.site == c.site);
+        for op in ops_only.log {
+            b.queue(op);
+        }
+        b.apply_pending();
+        assert_eq!(b.text(), "ab");
+
+        share(&mut a, &mut b);
+        assert_eq!(b.text(), "a123b");
+    }
+}
@@ -0,0 +1,399 @@
+use std::{collections::HashSet, ffi::CStr, os::raw::c_char};
+
+use tm_anode_api::{
+    AnodeCompletionItemT, AnodeCompletionProviderI, AnodeCompletionsO, AnodeDocumentO,
+    AnodePositionT, ANODE_COMPLETION_PROVIDER_I, ANODE_COMPLETION_PROVIDER_I_VERSION,
+};
+use tracing::{event, Level};
+
+use crate::{document::DocumentState, plugin::PluginData, text};
+
+/// Amount of completions shown at once, the popup scrolls to show the rest.
+const VISIBLE_COMPLETIONS: usize = 10;
+
+/// Suggestion offered in the completion popup.
+#[derive(Clone)]
+pub struct Completion {
+    pub label: String,
+    pub detail: String,
+    pub insert_text: String,
+}
+
+impl Completion {
+    pub unsafe fn from_raw(item: &AnodeCompletionItemT) -> Option<Self> {
+        let string = |value: *const c_char| {
+            if value.is_null() {
+                None
+            } else {
+                Some(CStr::from_ptr(value).to_string_lossy().into_owned())
+            }
+        };
+
+        let label = string(item.label)?;
+        Some(Self {
+            detail: string(item.detail).unwrap_or_default(),
+            insert_text: string(item.insert_text).unwrap_or_else(|| label.clone()),
+            label,
+        })
+    }
+}
+
+/// Asks the completion providers registered by other plugins for completions.
+///
+/// Providers can access the document through the API, so it must not be locked.
+pub unsafe fn query_providers(
+    data: &PluginData,
+    document: *mut AnodeDocumentO,
+    position: AnodePositionT,
+) -> Vec<Completion> {
+    let registry = &*data.apis.registry;
+    let providers = registry.implementations(
+        ANODE_COMPLETION_PROVIDER_I.as_ptr(),
+        ANODE_COMPLETION_PROVIDER_I_VERSION,
+    ) as *const *const AnodeCompletionProviderI;
+    let count = registry.num_implementations(
+        ANODE_COMPLETION_PROVIDER_I.as_ptr(),
+        ANODE_COMPLETION_PROVIDER_I_VERSION,
+    );
+
+    let mut completions: Vec<Completion> = Vec::new();
+    for i in 0..count as usize {
+        let provider = &**providers.add(i);
+        (provider.complete)(
+            provider.inst,
+            document,
+            position,
+            &mut completions as *mut _ as *mut AnodeCompletionsO,
+        );
+    }
+    completions
+}
+
+/// Adds a completion from a provider to the list being gathered by [`query_providers`].
+pub unsafe fn add_completion(
+    completions: *mut AnodeCompletionsO,
+    item: *const AnodeCompletionItemT,
+) {
+    if completions.is_null() || item.is_null() {
+        event!(Level::WARN, "Completion added without a list or item.");
+        return;
+    }
+
+    if let Some(completion) = Completion::from_raw(&*item) {
+        (*(completions as *mut Vec<Completion>)).push(completion);
+    }
+}
+
+/// Completions offered for the word being typed, filtered as the user keeps typing.
+pub struct CompletionPopup {
+    /// Byte offset the word being completed starts at.
+    start: usize,
+    /// Opened with a shortcut rather than by typing, so it stays open for an empty word.
+    explicit: bool,
+    items: Vec<Completion>,
+    labels: HashSet<String>,
+    /// Word typed so far, the items are filtered by.
+    filter: String,
+    /// Indices of the items matching the filter, best match first.
+    matches: Vec<usize>,
+    selected: usize,
+    /// First of the matches that's visible.
+    scroll: usize,
+    /// Caret position to ask providers for completions at, until they've been asked.
+    provider_request: Option<AnodePositionT>,
+    /// If the language server still has to be asked for completions.
+    server_request: bool,
+}
+
+impl CompletionPopup {
+    /// Opens the popup for the word before the caret, with the document's own identifiers.
+    ///
+    /// Returns `None` if there's no word to complete and the popup wasn't opened explicitly.
+    pub fn open(document: &DocumentState, explicit: bool) -> Option<Self> {
+        let caret = document.caret();
+        let start = text::word_start(document.text(), caret);
+        let word = &document.text()[start..caret];
+
+        // Numbers aren't worth completing
+        let is_number = word.chars().next().is_some_and(|c| c.is_numeric());
+        if !explicit && (word.is_empty() || is_number) {
+            return None;
+        }
+
+        let (line, column) = document.line_byte_column(caret);
+        let mut popup = Self {
+            start,
+            explicit,
+            items: Vec::new(),
+            labels: HashSet::new(),
+            filter: word.to_string(),
+            matches: Vec::new(),
+            selected: 0,
+            scroll: 0,
+            provider_request: Some(AnodePositionT {
+                line: line as u32,
+                column: column as u32,
+            }),
+            server_request: true,
+        };
+
+        let identifiers = document
+            .identifiers(start)
+            .into_iter()
+            .map(|label| Completion {
+                detail: String::new(),
+                insert_text: label.clone(),
+                label,
+            });
+        popup.add(identifiers.collect());
+
+        Some(popup)
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn take_provider_request(&mut self) -> Option<AnodePositionT> {
+        self.provider_request.take()
+    }
+
+    pub fn take_server_request(&mut self) -> bool {
+        std::mem::take(&mut self.server_request)
+    }
+
+    /// Adds completions, skipping those with a label that's already offered.
+    pub fn add(&mut self, completions: Vec<Completion>) {
+        if completions.is_empty() {
+            return;
+        }
+
+        for completion in completions {
+            if self.labels.insert(completion.label.clone()) {
+                self.items.push(completion);
+            }
+        }
+
+        // Keep the same item selected, new items shouldn't make the selection jump
+        let selected = self.matches.get(self.selected).copied();
+        self.refilter();
+        self.selected = selected
+            .and_then(|selected| self.matches.iter().position(|v| *v == selected))
+            .unwrap_or(0);
+        self.scroll_to_selected();
+    }
+
+    /// Follows the word being typed, returns false once the caret left the word.
+    pub fn update(&mut self, text: &str, caret: usize) -> bool {
+        let word = match text.get(self.start..caret) {
+            Some(word) => word,
+            None => return false,
+        };
+        if !word.chars().all(text::is_identifier_char) || (!self.explicit && word.is_empty()) {
+            return false;
+        }
+
+        if word != self.filter {
+            self.filter = word.to_string();
+            self.refilter();
+            self.selected = 0;
+            self.scroll = 0;
+        }
+        true
+    }
+
+    fn refilter(&mut self) {
+        let mut scored: Vec<_> = self
+            .items
+            .iter()
+            .enumerate()
+            .filter_map(|(i, item)| fuzzy_score(&self.filter, &item.label).map(|score| (score, i)))
+            .collect();
+
+        // Best matches first, alphabetically if they match equally well
+        let items = &self.items;
+        scored.sort_by(|(a_score, a), (b_score, b)| {
+            b_score
+                .cmp(a_score)
+                .then_with(|| items[*a].label.cmp(&items[*b].label))
+        });
+        self.matches = scored.into_iter().map(|(_, i)| i).collect();
+    }
+
+    pub fn is_visible(&self) -> bool {
+        !self.matches.is_empty()
+    }
+
+    pub fn select_next(&mut self) {
+        if self.is_visible() {
+            self.selected = (self.selected + 1) % self.matches.len();
+            self.scroll_to_selected();
+        }
+    }
+
+    pub fn select_previous(&mut self) {
+        if self.is_visible() {
+            self.selected = (self.selected + self.matches.len() - 1) % self.matches.len();
+            self.scroll_to_selected();
+        }
+    }
+
+    fn scroll_to_selected(&mut self) {
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + VISIBLE_COMPLETIONS {
+            self.scroll = self.selected + 1 - VISIBLE_COMPLETIONS;
+        }
+    }
+
+    /// The matches that are scrolled into view, and if they're selected.
+    pub fn visible_items(&self) -> impl Iterator<Item = (&Completion, bool)> {
+        self.matches
+            .iter()
+            .enumerate()
+            .skip(self.scroll)
+            .take(VISIBLE_COMPLETIONS)
+            .map(move |(i, item)| (&self.items[*item], i == self.selected))
+    }
+
+    /// Replaces the word being typed with the selected completion.
+    pub fn accept(&self, document: &mut DocumentState) {
+        let item = match self.matches.get(self.selected) {
+            Some(item) => &self.items[*item],
+            None => return,
+        };
+
+        let caret = document.caret();
+        if let Err(error) = document.replace_range(self.start, caret, &item.insert_text) {
+            event!(Level::ERROR, "Failed to insert completion: {}", error);
+        }
+    }
+}
+
+/// Scores how well a typed word matches a candidate, `None` if it doesn't match at all.
+///
+/// The typed characters have to appear in order, matches that are consecutive or at the start of
+/// a word part score higher. Characters can match in several places, so the best of those counts.
+fn fuzzy_score(pattern: &str, candidate: &str) -> Option<i32> {
+    let pattern: Vec<char> = pattern.chars().collect();
+
+    // Best scores with the first `j` typed characters matched, in total and with the last
+    // candidate character matching the last of those
+    let mut best = vec![None; pattern.len() + 1];
+    let mut ending = vec![None; pattern.len() + 1];
+    best[0] = Some(0);
+
+    let mut previous: Option<char> = None;
+    for c in candidate.chars() {
+        // Start of the candidate, or of a part like in `snake_case` or `camelCase`
+        let word_start = match previous {
+            None => true,
+            Some(previous) => previous == '_' || (previous.is_lowercase() && c.is_uppercase()),
+        };
+
+        // Go backwards so every typed character only uses the scores from before this one
+        for j in (1..=pattern.len()).rev() {
+            let expected = pattern[j - 1];
+            ending[j] = if c.to_lowercase().eq(expected.to_lowercase()) {
+                let mut bonus = 1;
+                if c == expected {
+                    bonus += 1;
+                }
+                if word_start {
+                    bonus += 6;
+                }
+
+                let consecutive = ending[j - 1].map(|score| score + 4);
+                best[j - 1].max(consecutive).map(|score| score + bonus)
+            } else {
+                None
+            };
+            best[j] = best[j].max(ending[j]);
+        }
+        previous = Some(c);
+    }
+
+    // Prefer shorter candidates, they need less that wasn't typed
+    let score = best[pattern.len()]?;
+    Some(score * 8 - candidate.chars().count() as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completion(label: &str) -> Completion {
+        Completion {
+            label: label.to_string(),
+            detail: String::new(),
+            insert_text: format!("{}()", label),
+        }
+    }
+
+    fn visible_labels(popup: &CompletionPopup) -> Vec<&str> {
+        popup
+            .visible_items()
+            .map(|(item, _)| item.label.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn characters_have_to_match_in_order() {
+        assert!(fuzzy_score("gts", "get_text_size").is_some());
+        assert!(fuzzy_score("GTS", "get_text_size").is_some());
+        assert!(fuzzy_score("stg", "get_text_size").is_none());
+        assert!(fuzzy_score("gettext", "get").is_none());
+        assert!(fuzzy_score("", "anything").is_some());
+    }
+
+    #[test]
+    fn better_matches_score_higher() {
+        let score = |pattern, candidate| fuzzy_score(pattern, candidate).unwrap();
+
+        // Consecutive characters
+        assert!(score("get", "get_text") > score("get", "gather_entities"));
+        // Starts of word parts, in both snake and camel case
+        assert!(score("gt", "get_text") > score("gt", "gather"));
+        assert!(score("gt", "getText") > score("gt", "gather"));
+        // Matching case
+        assert!(score("Text", "Text") > score("Text", "text"));
+        // Less that wasn't typed
+        assert!(score("text", "text") > score("text", "text_size"));
+    }
+
+    #[test]
+    fn popup_orders_matches_by_score() {
+        let mut document = DocumentState::new();
+        document.replace_range(0, 0, "te").unwrap();
+        let mut popup = CompletionPopup::open(&document, false).unwrap();
+        popup.add(vec![
+            completion("get_text"),
+            completion("text_size"),
+            completion("text"),
+            completion("other"),
+        ]);
+        assert_eq!(
+            visible_labels(&popup),
+            ["text", "get_text", "text_size", "other"]
+        );
+
+        // Typing further narrows the matches down
+        assert!(popup.update("tes", 3));
+        assert_eq!(visible_labels(&popup), ["text_size"]);
+
+        // The popup closes once the caret leaves the word
+        assert!(!popup.update("tes ", 4));
+    }
+
+    #[test]
+    fn accepting_replaces_the_typed_word() {
+        let mut document = DocumentState::new();
+        document.replace_range(0, 0, "let x = tx").unwrap();
+        let mut popup = CompletionPopup::open(&document, false).unwrap();
+        popup.add(vec![completion("text_size"), completion("text")]);
+        popup.select_next();
+
+        popup.accept(&mut document);
+        assert_eq!(document.text(), "let x = text_size()");
+    }
+}
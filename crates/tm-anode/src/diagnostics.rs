@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+
+use machinery_api::foundation::{TheTruthO, TtIdT};
+use tm_anode_api::AnodeSeverity;
+
+/// Message about a range of a document, set by another plugin.
+#[derive(Clone)]
+pub struct Diagnostic {
+    /// Line and byte column the range starts at.
+    pub start: (usize, usize),
+    /// Line and byte column the range ends at.
+    pub end: (usize, usize),
+    pub severity: AnodeSeverity,
+    pub message: String,
+    pub source: String,
+}
+
+impl Diagnostic {
+    /// Text to show when hovering the diagnostic.
+    pub fn tooltip(&self) -> String {
+        if self.source.is_empty() {
+            self.message.clone()
+        } else {
+            format!("{}: {}", self.source, self.message)
+        }
+    }
+}
+
+/// Diagnostics of all assets, by the source that set them.
+#[derive(Default)]
+pub struct Diagnostics {
+    assets: HashMap<(usize, u64), HashMap<String, Vec<Diagnostic>>>,
+}
+
+impl Diagnostics {
+    /// Replaces the diagnostics of an asset from a source.
+    pub fn set(
+        &mut self,
+        tt: *mut TheTruthO,
+        asset: TtIdT,
+        source: &str,
+        diagnostics: Vec<Diagnostic>,
+    ) {
+        let key = (tt as usize, unsafe { asset.__bindgen_anon_1.u64_ });
+
+        if diagnostics.is_empty() {
+            if let Some(sources) = self.assets.get_mut(&key) {
+                sources.remove(source);
+                if sources.is_empty() {
+                    self.assets.remove(&key);
+                }
+            }
+        } else {
+            self.assets
+                .entry(key)
+                .or_default()
+                .insert(source.to_string(), diagnostics);
+        }
+    }
+
+    /// All diagnostics of an asset, from every source.
+    pub fn for_asset(&self, tt: *mut TheTruthO, asset: TtIdT) -> Vec<Diagnostic> {
+        let key = (tt as usize, unsafe { asset.__bindgen_anon_1.u64_ });
+        self.assets
+            .get(&key)
+            .map(|sources| sources.values().flatten().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr::null_mut;
+
+    use machinery_api::foundation::TtIdTBindgenTy1;
+
+    use super::*;
+
+    fn asset(id: u64) -> TtIdT {
+        TtIdT {
+            __bindgen_anon_1: TtIdTBindgenTy1 { u64_: id },
+        }
+    }
+
+    fn diagnostic(line: usize, message: &str, source: &str) -> Diagnostic {
+        Diagnostic {
+            start: (line, 0),
+            end: (line, 1),
+            severity: AnodeSeverity::Error,
+            message: message.to_string(),
+            source: source.to_string(),
+        }
+    }
+
+    fn messages(diagnostics: &Diagnostics, asset: TtIdT) -> Vec<String> {
+        let mut messages: Vec<_> = diagnostics
+            .for_asset(null_mut(), asset)
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect();
+        messages.sort();
+        messages
+    }
+
+    #[test]
+    fn diagnostics_are_replaced_by_source() {
+        let mut diagnostics = Diagnostics::default();
+        diagnostics.set(
+            null_mut(),
+            asset(1),
+            "compiler",
+            vec![
+                diagnostic(0, "a", "compiler"),
+                diagnostic(1, "b", "compiler"),
+            ],
+        );
+        diagnostics.set(
+            null_mut(),
+            asset(1),
+            "linter",
+            vec![diagnostic(2, "c", "linter")],
+        );
+        assert_eq!(messages(&diagnostics, asset(1)), ["a", "b", "c"]);
+
+        // Setting a source again only replaces its own diagnostics
+        diagnostics.set(
+            null_mut(),
+            asset(1),
+            "compiler",
+            vec![diagnostic(3, "d", "compiler")],
+        );
+        assert_eq!(messages(&diagnostics, asset(1)), ["c", "d"]);
+
+        diagnostics.set(null_mut(), asset(1), "linter", Vec::new());
+        assert_eq!(messages(&diagnostics, asset(1)), ["d"]);
+        diagnostics.set(null_mut(), asset(1), "compiler", Vec::new());
+        assert!(diagnostics.assets.is_empty());
+    }
+
+    #[test]
+    fn diagnostics_are_kept_per_asset() {
+        let mut diagnostics = Diagnostics::default();
+        diagnostics.set(null_mut(), asset(1), "", vec![diagnostic(0, "a", "")]);
+        diagnostics.set(null_mut(), asset(2), "", vec![diagnostic(0, "b", "")]);
+        assert_eq!(messages(&diagnostics, asset(1)), ["a"]);
+        assert_eq!(messages(&diagnostics, asset(2)), ["b"]);
+
+        diagnostics.set(null_mut(), asset(1), "", Vec::new());
+        assert!(messages(&diagnostics, asset(1)).is_empty());
+        assert_eq!(messages(&diagnostics, asset(2)), ["b"]);
+    }
+
+    #[test]
+    fn tooltip_names_the_source() {
+        assert_eq!(diagnostic(0, "a", "rustc").tooltip(), "rustc: a");
+        assert_eq!(diagnostic(0, "a", "").tooltip(), "a");
+    }
+}
@@ -1,49 +1,143 @@
 use std::{
-    ffi::{c_void, CStr, CString},
+    collections::BTreeSet,
+    ffi::{CStr, CString},
+    ops::Range,
     os::raw::c_char,
+    time::{Duration, Instant},
 };
 
 use eyre::{eyre, Result};
-use machinery::{tt_id_eq, tt_id_type};
+use machinery::{tt_id_eq, tt_id_type, CArrayHeaderT};
 use machinery_api::{
-    foundation::{TheTruthO, TtIdT, TtUndoScopeT, TM_TT_ASPECT__FILE_EXTENSION},
-    plugins::editor_views::{AssetSaveI, TM_ASSET_SAVE_STATUS__SAVED},
+    foundation::{TheTruthO, TtIdT, TtTypeT, TtUndoScopeT, TM_TT_ASPECT__FILE_EXTENSION},
+    plugins::{
+        editor_views::{AssetSaveI, TM_ASSET_SAVE_STATUS__SAVED},
+        ui::IONICON__LOCK_CLOSED,
+    },
 };
-use tm_anode_api::{AnodeAspectI, Highlighting, ASPECT_ANODE};
+use tm_anode_api::{AnodeAspectI, AnodeSeverity, Highlighting, ASPECT_ANODE};
+use tree_sitter::{Parser, Query, QueryCursor, Tree};
 use tree_sitter_highlight::{HighlightConfiguration, HighlightEvent, Highlighter};
 
-use crate::plugin::PluginData;
+use tracing::{event, Level};
+
+use crate::{
+    collab::{self, Edits, RemoteCaret, TextCrdt},
+    diagnostics::Diagnostic,
+    encoding::{Encoding, TextFormat},
+    plugin::PluginData,
+    storage::{self, TextStorage},
+    text,
+    theme::HIGHLIGHT_SCOPES,
+};
 
 pub(crate) struct DocumentState {
     // Associated target asset
     asset: Option<(*mut TheTruthO, TtIdT, u32)>,
+    storage: TextStorage,
+    /// Subobject properties leading from the asset to the object holding the text.
+    path: Vec<u32>,
+    /// If the asset doesn't allow edits, separate from content we can't edit.
+    read_only: bool,
+    is_read_only_f: Option<unsafe extern "C" fn(*mut TheTruthO, TtIdT) -> bool>,
 
     // Metadata
     title: CString,
+    rulers: Option<Vec<u32>>,
+    shade_past_rulers: bool,
 
     // Highlighting utilities
     highlighter: Highlighter,
     highlight_config: Option<HighlightConfiguration>,
+    /// Parser for the highlighting language, to find syntax errors with.
+    parser: Option<Parser>,
+    /// Syntax tree of the text, if it has a parser.
+    tree: Option<Tree>,
+    /// Query finding scopes, definitions and references in the syntax tree.
+    locals_query: Option<Query>,
 
     // Current text state
     text: String,
+    /// How the text is stored in the asset, so it can be written back the same way.
+    format: TextFormat,
+    /// Time of the last edit that hasn't been committed to the asset yet.
+    last_edit: Option<Instant>,
+    /// Truth version of the asset the last time it was synced with.
+    version: u32,
+    /// Buffer in the asset the last time it was synced with.
+    synced_buffer: u64,
+    /// If the asset was changed externally while there were uncommitted edits.
+    conflict: bool,
+    /// Original content that couldn't be decoded as text, the document is read-only while set.
+    binary: Option<Vec<u8>>,
     highlights: Vec<HighlightEvent>,
+    syntax_errors: Vec<Diagnostic>,
+    /// Characters that can't be stored in the asset, edits aren't committed while there are any.
+    storage_errors: Vec<Diagnostic>,
+    /// Byte offset of the caret, always on a grapheme boundary.
     caret: usize,
     /// The caret column position will be preserved when moving up/down.
     caret_column: usize,
+    /// Byte offset the selection extends from to the caret, if anything is selected.
+    selection: Option<usize>,
+    /// Line to scroll into view the next time the document is drawn.
+    reveal_line: Option<usize>,
+    /// Shared edit history, if the asset is edited collaboratively.
+    collaboration: Option<Collaboration>,
+}
+
+// The truth pointer is only used through the truth API, which can be called from any thread
+unsafe impl Send for DocumentState {}
+
+/// Source of the syntax errors found by parsing the text.
+const SYNTAX_ERROR_SOURCE: &str = "syntax";
+
+/// Source of the errors about characters the asset can't store.
+const STORAGE_ERROR_SOURCE: &str = "storage";
+
+/// State of a document whose edits are merged with those of collaborators.
+struct Collaboration {
+    property: u32,
+    history: TextCrdt,
+    /// History buffer in the asset the last time it was synced with.
+    synced_history: u32,
+    /// Caret offset last seen and when it moved there, it's shared once it settles.
+    caret_moved: (usize, Instant),
+    /// Caret offset last shared with collaborators.
+    shared_caret: usize,
 }
 
 impl DocumentState {
     pub fn new() -> Self {
         Self {
             asset: None,
+            storage: TextStorage::Buffer(0),
+            path: Vec::new(),
+            read_only: false,
+            is_read_only_f: None,
             title: CString::new("untitled").unwrap(),
+            rulers: None,
+            shade_past_rulers: false,
             highlighter: Highlighter::new(),
             highlight_config: None,
+            parser: None,
+            tree: None,
+            locals_query: None,
             text: String::new(),
+            format: TextFormat::default(),
+            binary: None,
+            last_edit: None,
+            version: 0,
+            synced_buffer: 0,
+            conflict: false,
             highlights: Vec::new(),
+            syntax_errors: Vec::new(),
+            storage_errors: Vec::new(),
             caret: 0,
             caret_column: 0,
+            selection: None,
+            reveal_line: None,
+            collaboration: None,
         }
     }
 
@@ -53,7 +147,11 @@ impl DocumentState {
 
     pub fn refresh_title(&mut self, data: &PluginData, save_interface: *mut AssetSaveI) -> &CStr {
         if let Some((tt, root, _property)) = self.asset {
-            self.title = unsafe { title_from_asset(data, tt, root, save_interface) };
+            let has_uncommitted = self.last_edit.is_some();
+            let read_only = self.is_read_only();
+            self.title = unsafe {
+                title_from_asset(data, tt, root, save_interface, has_uncommitted, read_only)
+            };
         } else {
             self.title = CString::new("untitled").unwrap();
         }
@@ -61,32 +159,118 @@ impl DocumentState {
         self.title.as_c_str()
     }
 
+    /// File name of the asset, with the extension of its type if it has one.
+    pub unsafe fn file_name(&self, data: &PluginData) -> Option<String> {
+        let (tt, root, _) = self.asset?;
+        Some(file_name(data, tt, root))
+    }
+
+    /// Columns to draw rulers at in ascending order, if the asset overrides the default.
+    pub fn rulers(&self) -> Option<&[u32]> {
+        self.rulers.as_deref()
+    }
+
+    pub fn shade_past_rulers(&self) -> bool {
+        self.shade_past_rulers
+    }
+
     pub fn text(&self) -> &str {
         self.text.as_str()
     }
 
+    pub fn binary(&self) -> Option<&[u8]> {
+        self.binary.as_deref()
+    }
+
+    /// If edits aren't allowed, because the asset is read-only or the content isn't text.
+    ///
+    /// Navigating the document still works as normal.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only || self.binary.is_some()
+    }
+
+    /// Decodes binary content as text in an explicit encoding, making it editable.
+    ///
+    /// The asset isn't changed until the text is edited.
+    pub fn reinterpret(&mut self, encoding: Encoding) -> Result<()> {
+        let bytes = match &self.binary {
+            Some(bytes) => bytes,
+            None => return Ok(()),
+        };
+
+        let (text, format) = TextFormat::decode_as(bytes, encoding)?;
+        self.text = text;
+        self.format = format;
+        self.binary = None;
+        self.caret = 0;
+        self.caret_column = 0;
+        self.selection = None;
+        self.highlight();
+
+        Ok(())
+    }
+
+    pub fn format(&self) -> TextFormat {
+        self.format
+    }
+
+    /// Converts the document to another format, rewriting the asset.
+    pub fn set_format(&mut self, data: &PluginData, format: TextFormat) -> Result<()> {
+        if self.is_read_only() {
+            return Err(eyre!("Document is read-only, its format can't be changed"));
+        }
+        if !self.storage.supports_encoding(format.encoding) {
+            return Err(eyre!("Text stored in a string property can only be UTF-8"));
+        }
+
+        // Make sure the text can be represented before switching
+        format.encode(&self.text)?;
+
+        self.format = format;
+        self.commit_to_asset(data);
+        self.last_edit = None;
+
+        Ok(())
+    }
+
     pub fn highlights(&self) -> &[HighlightEvent] {
         &self.highlights
     }
 
+    /// Syntax errors found while parsing the text for highlighting.
+    pub fn syntax_errors(&self) -> &[Diagnostic] {
+        &self.syntax_errors
+    }
+
+    /// Characters in the text that can't be stored in the asset.
+    pub fn storage_errors(&self) -> &[Diagnostic] {
+        &self.storage_errors
+    }
+
+    /// Line and column of the caret, the column is in cells.
     pub fn caret_line_column(&self) -> (usize, usize) {
+        self.line_column(self.caret)
+    }
+
+    /// Line and column of a byte offset, the column is in cells.
+    pub fn line_column(&self, offset: usize) -> (usize, usize) {
         // Find the right line
         let mut last_line_index = 0;
-        let mut last_length = 0;
+        let mut last_width = 0;
         let mut index = 0;
         for (line_index, line) in self.text.split('\n').enumerate() {
-            // If the caret is within this line, return the value
-            if self.caret < index + line.len() + 1 {
-                return (line_index, self.caret - index);
+            // If the offset is within this line, return the value
+            if offset < index + line.len() + 1 {
+                return (line_index, text::line_width(&line[..offset - index]));
             }
 
             last_line_index = line_index;
-            last_length = line.len();
+            last_width = text::line_width(line);
             index += line.len() + 1;
         }
 
         // Fall back to end of the last line
-        (last_line_index, last_length - 1)
+        (last_line_index, last_width)
     }
 
     pub fn set_caret_column_to_current(&mut self) {
@@ -101,44 +285,186 @@ impl DocumentState {
     pub fn set_caret_line_column(&mut self, line: usize, column: usize) {
         // Find the starting index of the line
         let mut index = 0;
-        let mut current_line = 0;
-        let mut chars = self.text.chars();
-        loop {
-            if current_line == line {
-                // Continue until we find the column
-                let mut current_column = 0;
-                'inner: loop {
-                    if current_column == column {
-                        self.caret = index;
-                        return;
-                    }
-
-                    match chars.next() {
-                        Some('\n') => break 'inner,
-                        None => break 'inner,
-                        _ => {}
-                    }
-
-                    current_column += 1;
-                    index += 1;
+        for (line_index, text) in self.text.split('\n').enumerate() {
+            if line_index == line {
+                self.caret = index + text::column_to_offset(text, column);
+                return;
+            }
+
+            index += text.len() + 1;
+        }
+
+        // Default to end of file
+        self.caret = self.text.len();
+    }
+
+    /// Byte offset of the grapheme in a cell, `None` if the cell is past the end of its line.
+    pub fn offset_at_cell(&self, line: usize, column: usize) -> Option<usize> {
+        let mut index = 0;
+        for (line_index, text) in self.text.split('\n').enumerate() {
+            if line_index == line {
+                if column >= text::line_width(text) {
+                    return None;
                 }
+                return Some(index + text::column_to_offset(text, column));
+            }
 
-                // Default to end of line
-                self.caret = index;
-                return;
+            index += text.len() + 1;
+        }
+
+        None
+    }
+
+    /// Byte offset of a line and a byte column into it, clamped to the end of the line.
+    ///
+    /// Offsets inside a grapheme are moved to its start.
+    pub fn offset_at(&self, line: usize, column: usize) -> usize {
+        let mut index = 0;
+        for (line_index, text) in self.text.split('\n').enumerate() {
+            if line_index == line {
+                let offset = index + column.min(text.len());
+                return if text::is_boundary(&self.text, offset) {
+                    offset
+                } else {
+                    text::previous_boundary(&self.text, offset)
+                };
             }
 
-            match chars.next() {
-                Some('\n') => current_line += 1,
-                None => break,
-                _ => {}
+            index += text.len() + 1;
+        }
+
+        self.text.len()
+    }
+
+    /// Line and byte column of a byte offset.
+    pub fn line_byte_column(&self, offset: usize) -> (usize, usize) {
+        let before = &self.text[..offset.min(self.text.len())];
+        let line = before.matches('\n').count();
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        (line, before.len() - line_start)
+    }
+
+    pub fn caret(&self) -> usize {
+        self.caret
+    }
+
+    /// Moves the caret to a byte offset, clearing the selection.
+    pub fn set_caret(&mut self, offset: usize) {
+        self.set_selection(offset, offset);
+    }
+
+    /// Selected byte range, if anything is selected.
+    pub fn selection(&self) -> Option<(usize, usize)> {
+        let anchor = self.selection?;
+        if anchor == self.caret {
+            return None;
+        }
+        Some((anchor.min(self.caret), anchor.max(self.caret)))
+    }
+
+    /// Selects from one byte offset to another, leaving the caret at the end of the selection.
+    pub fn set_selection(&mut self, anchor: usize, caret: usize) {
+        let content = &self.text;
+        let boundary = |offset: usize| {
+            let offset = offset.min(content.len());
+            if text::is_boundary(content, offset) {
+                offset
+            } else {
+                text::previous_boundary(content, offset)
             }
+        };
 
-            index += 1;
+        let (anchor, caret) = (boundary(anchor), boundary(caret));
+        self.caret = caret;
+        self.selection = Some(anchor);
+        self.set_caret_column_to_current();
+    }
+
+    pub fn clear_selection(&mut self) {
+        self.selection = None;
+    }
+
+    /// Scrolls a line into view the next time the document is drawn.
+    pub fn reveal_line(&mut self, line: usize) {
+        self.reveal_line = Some(line);
+    }
+
+    pub fn take_reveal_line(&mut self) -> Option<usize> {
+        self.reveal_line.take()
+    }
+
+    /// Replaces a byte range of the text, keeping the caret on the same text where possible.
+    pub fn replace_range(&mut self, start: usize, end: usize, replacement: &str) -> Result<()> {
+        self.apply_edits(&[(start, end, replacement.to_string())])
+    }
+
+    /// Replaces several non-overlapping byte ranges of the text at once.
+    ///
+    /// All ranges refer to the text before any of them are replaced.
+    pub fn apply_edits(&mut self, edits: &[(usize, usize, String)]) -> Result<()> {
+        if self.is_read_only() {
+            return Err(eyre!("Document is read-only"));
         }
 
-        // Default to end of file
-        self.caret = self.text.len();
+        let mut edits: Vec<_> = edits.iter().collect();
+        edits.sort_by_key(|(start, end, _)| (*start, *end));
+        let mut previous_end = 0;
+        for (start, end, _) in &edits {
+            if *start < previous_end
+                || start > end
+                || *end > self.text.len()
+                || !self.text.is_char_boundary(*start)
+                || !self.text.is_char_boundary(*end)
+            {
+                return Err(eyre!(
+                    "Range {}..{} isn't valid in the document",
+                    start,
+                    end
+                ));
+            }
+            previous_end = *end;
+        }
+
+        // Replace from the back, so the earlier ranges stay valid
+        for (start, end, replacement) in edits.into_iter().rev() {
+            self.replace_unchecked(*start, *end, replacement);
+        }
+
+        self.selection = None;
+        self.set_caret_column_to_current();
+
+        self.highlight();
+        self.mark_edited();
+
+        Ok(())
+    }
+
+    fn replace_unchecked(&mut self, start: usize, end: usize, replacement: &str) {
+        self.text.replace_range(start..end, replacement);
+
+        // Move the caret along with the text after the range, into the range's end if inside it
+        if self.caret >= end {
+            self.caret = self.caret - (end - start) + replacement.len();
+        } else if self.caret > start {
+            self.caret = start + replacement.len();
+        }
+        if !text::is_boundary(&self.text, self.caret) {
+            self.caret = text::previous_boundary(&self.text, self.caret);
+        }
+    }
+
+    /// Deletes the selected text, returning if anything was selected.
+    fn delete_selection(&mut self) -> bool {
+        let (start, end) = match self.selection() {
+            Some(range) => range,
+            None => return false,
+        };
+
+        self.text.replace_range(start..end, "");
+        self.caret = start;
+        self.selection = None;
+        self.set_caret_column_to_current();
+        true
     }
 
     pub unsafe fn load_from_asset(
@@ -154,95 +480,379 @@ impl DocumentState {
             }
         }
 
-        // Get the aspect data out of the asset, which tells us how to open it
-        let aspect_i = (*data.apis.truth).get_aspect(tt, tt_id_type(root), ASPECT_ANODE.hash)
-            as *const AnodeAspectI;
-        if aspect_i.is_null() {
-            return Err(eyre!(
-                "Asset does not have required tm_anode_aspect_i aspect"
-            ));
-        }
+        // Don't lose edits to the previous asset
+        self.close(data);
+
+        // Don't stay attached to the previous asset if this one can't be opened
+        let (aspect_i, path, storage) = match find_text(data, tt, root) {
+            Ok(text) => text,
+            Err(error) => {
+                *self = Self::new();
+                return Err(error);
+            }
+        };
 
         // Reset data that's no longer valid
-        let property = (*aspect_i).property;
-        self.asset = Some((tt, root, property));
+        self.asset = Some((tt, root, (*aspect_i).property));
+        self.path = path;
+        self.storage = storage;
+        self.read_only = (*aspect_i).read_only;
+        self.is_read_only_f = (*aspect_i).is_read_only;
+        self.refresh_read_only();
         self.caret = 0;
+        self.selection = None;
+        self.conflict = false;
 
-        // Get the data out of the asset
-        let object = (*data.apis.truth).read(tt, root);
-        let buffer = (*data.apis.truth).get_buffer(tt, object, property);
-
-        let mut size = 0;
-        let buffers = (*data.apis.truth).buffers(tt);
-        let buffer_ptr = (*buffers).get.unwrap()((*buffers).inst, buffer.id, &mut size);
+        self.read_text(data);
 
-        let text_data = std::slice::from_raw_parts(buffer_ptr as *const u8, size as usize);
-        self.text = String::from_utf8_lossy(text_data).to_string();
+        // Edits from collaborators can only be merged with a history of edits to the text
+        self.collaboration = None;
+        let collaborative = match (*aspect_i).is_collaborative {
+            Some(is_collaborative) => is_collaborative(tt, root),
+            None => (*aspect_i).collaborative,
+        };
+        if collaborative && self.binary.is_none() {
+            self.join_history(data, (*aspect_i).history_property);
+        }
 
-        // Trim carriage returns just in case git mangled the file
-        self.text.retain(|v| v != '\r');
+        // Read the editor configuration for this asset
+        self.rulers = if (*aspect_i).rulers.is_null() {
+            None
+        } else {
+            let mut rulers =
+                std::slice::from_raw_parts((*aspect_i).rulers, (*aspect_i).rulers_len).to_vec();
+            rulers.sort_unstable();
+            Some(rulers)
+        };
+        self.shade_past_rulers = (*aspect_i).shade_past_rulers;
 
         // Set up code highlighting
         self.highlight_config = (*aspect_i)
             .highlighting
             .as_ref()
-            .map(|v| higlight_config_from_raw(data, v));
+            .map(|v| higlight_config_from_raw(v));
+        self.parser = (*aspect_i).highlighting.as_ref().and_then(|v| {
+            let mut parser = Parser::new();
+            parser.set_language(v.language).ok()?;
+            Some(parser)
+        });
+        self.locals_query = (*aspect_i)
+            .highlighting
+            .as_ref()
+            .and_then(|v| locals_query_from_raw(v));
         self.highlight();
 
         Ok(())
     }
 
-    pub fn apply_input_left(&mut self, skip_word: bool) {
-        if !skip_word {
-            self.caret = (self.caret as i32 - 1).max(0) as usize;
+    /// Reads the text from the asset, replacing the current text.
+    unsafe fn read_text(&mut self, data: &PluginData) {
+        let (tt, object) = match self.text_object(data) {
+            Some(object) => object,
+            None => return,
+        };
+
+        // Get the data out of the asset
+        self.version = (*data.apis.truth).version(tt, object);
+        self.last_edit = None;
+        let text_data = match self.storage.read(data, tt, object) {
+            Ok((buffer_id, text_data)) => {
+                self.synced_buffer = buffer_id;
+                text_data
+            }
+            Err(error) => {
+                // Writing would lose what's left of the text, so don't allow it
+                event!(Level::ERROR, "{}, opening read-only", error);
+                self.text = String::new();
+                self.binary = Some(Vec::new());
+                return;
+            }
+        };
+
+        match TextFormat::decode(&text_data) {
+            Ok((text, mut format)) => {
+                // Text that was stored in an unsupported encoding gets written back in one that is
+                if !self.storage.supports_encoding(format.encoding) {
+                    format.encoding = Encoding::Utf8;
+                }
+
+                self.text = text;
+                self.format = format;
+                self.binary = None;
+            }
+            Err(error) => {
+                // Editing would destroy the original data, so keep it as-is and don't allow that
+                event!(Level::WARN, "{}, opening read-only", error);
+                self.text = String::new();
+                self.binary = Some(text_data);
+            }
+        }
+    }
+
+    /// Starts merging edits with collaborators, through the edit history in the asset.
+    unsafe fn join_history(&mut self, data: &PluginData, property: u32) {
+        let (tt, object) = match self.text_object(data) {
+            Some(object) => object,
+            None => return,
+        };
+
+        let (history_id, bytes) = storage::read_buffer(data, tt, object, property);
+        let mut history = TextCrdt::new(collab::user_name());
+        if let Err(error) = history.merge(&bytes) {
+            event!(Level::ERROR, "{}, starting a new edit history", error);
+            history = TextCrdt::new(collab::user_name());
+        }
+        history.init_base(&self.text);
+
+        // The text may have been changed by something that doesn't know about the history
+        let diverged = history.text() != self.text;
+        if diverged {
+            history.apply_local(&self.text);
+        }
+
+        self.collaboration = Some(Collaboration {
+            property,
+            history,
+            synced_history: history_id,
+            caret_moved: (self.caret, Instant::now()),
+            shared_caret: self.caret,
+        });
+
+        if diverged {
+            self.commit_to_asset(data);
+        }
+    }
+
+    /// Checks if something else changed the asset, and reloads the text if so.
+    ///
+    /// If there are local edits that haven't been committed yet, the document is marked as
+    /// conflicting instead, until resolved with [`Self::resolve_conflict`].
+    pub unsafe fn sync_from_asset(&mut self, data: &PluginData) {
+        // Assets can become read-only without changing, for example when they get locked
+        self.refresh_read_only();
+
+        let (tt, object) = match self.text_object(data) {
+            Some(object) => object,
+            None => return,
+        };
+
+        // Only check the text when the object has changed at all
+        let truth = &*data.apis.truth;
+        let version = truth.version(tt, object);
+        if version == self.version {
+            return;
+        }
+        self.version = version;
+
+        // Collaborative edits can always be merged, so they never conflict
+        if self.collaboration.is_some() {
+            self.merge_history(data);
+            return;
+        }
+
+        // Changes to other properties don't affect us
+        if self.storage.id(data, tt, object) == self.synced_buffer {
+            return;
+        }
+
+        if self.last_edit.is_some() {
+            self.conflict = true;
         } else {
-            let len = self.text.len();
-            let mut iter = self.text.chars().rev().enumerate().skip(len - self.caret);
+            self.reload(data);
+        }
+    }
 
-            // Skip to start of word
-            for (i, c) in &mut iter {
-                self.caret = len - i;
+    /// Object holding the text, found by following the subobject path from the asset.
+    unsafe fn text_object(&self, data: &PluginData) -> Option<(*mut TheTruthO, TtIdT)> {
+        let (tt, root, _) = self.asset?;
+        Some((tt, follow_path(data, tt, root, &self.path)?))
+    }
+
+    fn refresh_read_only(&mut self) {
+        if let (Some((tt, root, _)), Some(is_read_only)) = (self.asset, self.is_read_only_f) {
+            self.read_only = unsafe { is_read_only(tt, root) };
+        }
+    }
+
+    pub fn has_conflict(&self) -> bool {
+        self.conflict
+    }
 
-                if c.is_alphanumeric() {
-                    break;
+    /// Resolves a conflict with external changes, by either keeping or discarding local edits.
+    pub unsafe fn resolve_conflict(&mut self, data: &PluginData, keep_local: bool) {
+        self.conflict = false;
+
+        if keep_local {
+            // Nothing else may overwrite the external changes, only choosing to keep local edits
+            if self.storage_errors.is_empty() && self.last_edit.take().is_some() {
+                self.commit_to_asset(data);
+            }
+        } else {
+            self.reload(data);
+        }
+    }
+
+    /// Merges changes to the asset with local edits, through the edit history.
+    unsafe fn merge_history(&mut self, data: &PluginData) {
+        let (tt, object) = match self.text_object(data) {
+            Some(object) => object,
+            None => return,
+        };
+        let (storage, encoding) = (self.storage, self.format.encoding);
+        let collaboration = match &mut self.collaboration {
+            Some(collaboration) => collaboration,
+            None => return,
+        };
+
+        let (history_id, history_bytes) =
+            storage::read_buffer(data, tt, object, collaboration.property);
+        let text_id = storage.id(data, tt, object);
+        let history_changed = history_id != collaboration.synced_history;
+        if !history_changed && text_id == self.synced_buffer {
+            return;
+        }
+
+        // Local edits are diffed against the last synced text, before anything else changes it
+        let history = &mut collaboration.history;
+        let local_edits = if self.last_edit.is_some() {
+            history.diff(&self.text)
+        } else {
+            Edits::default()
+        };
+        let mut share = !local_edits.is_empty();
+
+        if !history_changed {
+            // The text was written by something that doesn't know about the history
+            let external = storage
+                .read(data, tt, object)
+                .and_then(|(_, bytes)| TextFormat::decode_as(&bytes, encoding));
+            match external {
+                Ok((external, _)) => {
+                    history.apply_local(&external);
+                    share = true;
                 }
+                Err(error) => event!(Level::WARN, "{}, ignoring external change", error),
             }
+        }
 
-            // Skip to end of word
-            for (i, c) in iter {
-                self.caret = len - i;
+        history.apply_edits(local_edits);
+        let caret = history.anchor(text::char_offset(&self.text, self.caret));
 
-                if !c.is_alphanumeric() {
-                    break;
+        if history_changed {
+            match history.merge(&history_bytes) {
+                Ok(remote_behind) => share |= remote_behind,
+                Err(error) => {
+                    // Our own history will replace the one we can't read
+                    event!(Level::ERROR, "{}", error);
+                    share = true;
                 }
             }
         }
 
-        self.set_caret_column_to_current();
+        collaboration.synced_history = history_id;
+        self.synced_buffer = text_id;
+        self.last_edit = None;
+
+        let merged = history.text();
+        let caret = text::byte_offset(&merged, history.offset(caret));
+        if merged != self.text {
+            self.text = merged;
+            self.selection = None;
+            self.highlight();
+        }
+
+        // Don't leave the caret in the middle of a grapheme
+        self.caret = caret;
+        if !text::is_boundary(&self.text, self.caret) {
+            self.caret = text::previous_boundary(&self.text, self.caret);
+        }
+
+        // Moving along with edits doesn't count as moving the caret
+        if let Some(collaboration) = &mut self.collaboration {
+            collaboration.caret_moved.0 = self.caret;
+            collaboration.shared_caret = self.caret;
+        }
+
+        if share {
+            self.commit_to_asset(data);
+        }
     }
 
-    pub fn apply_input_right(&mut self, skip_word: bool) {
+    /// Carets of collaborators, with their offsets as byte offsets into the text.
+    pub fn remote_carets(&self) -> Vec<RemoteCaret> {
+        let collaboration = match &self.collaboration {
+            Some(collaboration) => collaboration,
+            None => return Vec::new(),
+        };
+
+        let mut carets = collaboration.history.remote_carets();
+        for caret in &mut carets {
+            caret.offset = text::byte_offset(&self.text, caret.offset);
+        }
+        carets
+    }
+
+    /// Reloads the text from the asset, keeping the caret where it was.
+    unsafe fn reload(&mut self, data: &PluginData) {
+        let (line, _) = self.caret_line_column();
+        let column = self.caret_column;
+
+        self.read_text(data);
+        self.set_caret_line_column(line, column);
+        self.selection = None;
+        self.highlight();
+    }
+
+    pub fn apply_input_left(&mut self, skip_word: bool) {
+        self.selection = None;
+
         if !skip_word {
-            self.caret = (self.caret + 1).min(self.text.len());
+            self.caret = text::previous_boundary(&self.text, self.caret);
         } else {
-            let mut iter = self.text.chars().enumerate().skip(self.caret);
+            let mut iter = self.text[..self.caret].char_indices().rev().peekable();
+
+            // Skip to end of word
+            while let Some((i, _)) = iter.next_if(|(_, c)| !c.is_alphanumeric()) {
+                self.caret = i;
+            }
 
             // Skip to start of word
-            for (i, c) in &mut iter {
+            while let Some((i, _)) = iter.next_if(|(_, c)| c.is_alphanumeric()) {
                 self.caret = i;
+            }
 
-                if c.is_alphanumeric() {
-                    break;
-                }
+            // Don't leave the caret in the middle of a grapheme
+            if !text::is_boundary(&self.text, self.caret) {
+                self.caret = text::previous_boundary(&self.text, self.caret);
             }
+        }
+
+        self.set_caret_column_to_current();
+    }
+
+    pub fn apply_input_right(&mut self, skip_word: bool) {
+        self.selection = None;
+
+        if !skip_word {
+            self.caret = text::next_boundary(&self.text, self.caret);
+        } else {
+            let start = self.caret;
+            let mut iter = self.text[start..].char_indices().peekable();
+
+            // Skip to start of word
+            while iter.next_if(|(_, c)| !c.is_alphanumeric()).is_some() {}
 
             // Skip to end of word
-            for (i, c) in iter {
-                self.caret = i;
+            while iter.next_if(|(_, c)| c.is_alphanumeric()).is_some() {}
 
-                if !c.is_alphanumeric() {
-                    break;
-                }
+            self.caret = iter
+                .peek()
+                .map(|(i, _)| start + i)
+                .unwrap_or_else(|| self.text.len());
+
+            // Don't leave the caret in the middle of a grapheme
+            if !text::is_boundary(&self.text, self.caret) {
+                self.caret = text::next_boundary(&self.text, self.caret);
             }
         }
 
@@ -250,6 +860,8 @@ impl DocumentState {
     }
 
     pub fn apply_input_up(&mut self) {
+        self.selection = None;
+
         let (line, _) = self.caret_line_column();
         if line > 0 {
             self.set_caret_line_column(line - 1, self.caret_column);
@@ -262,6 +874,8 @@ impl DocumentState {
     }
 
     pub fn apply_input_down(&mut self) {
+        self.selection = None;
+
         let (line, _) = self.caret_line_column();
         let lines = self.text.split('\n').count();
         if line < lines - 1 {
@@ -274,62 +888,90 @@ impl DocumentState {
         self.set_caret_column_to_current();
     }
 
-    pub fn apply_input_character(&mut self, data: &PluginData, character: char) {
+    pub fn apply_input_character(&mut self, character: char) {
+        if self.is_read_only() {
+            return;
+        }
+
+        // Typing replaces the selection
+        self.delete_selection();
+
         self.text.insert(self.caret, character);
-        self.caret += 1;
+        self.caret += character.len_utf8();
 
-        if character != '\n' {
-            self.caret_column += 1;
-        } else {
-            self.caret_column = 0;
-        }
+        // The character may have combined with the one before it, so the width can't be assumed
+        self.set_caret_column_to_current();
 
         self.highlight();
-        self.commit_to_asset(data);
+        self.mark_edited();
     }
 
-    pub fn apply_input_backspace(&mut self, data: &PluginData) {
+    pub fn apply_input_backspace(&mut self) {
+        if self.is_read_only() {
+            return;
+        }
+
+        if self.delete_selection() {
+            self.highlight();
+            self.mark_edited();
+            return;
+        }
+
         if self.caret == 0 {
             // Can't backspace at start of file
             return;
         }
 
-        let removed = self.text.remove(self.caret - 1);
-        self.caret -= 1;
+        let previous = text::previous_boundary(&self.text, self.caret);
+        self.text.replace_range(previous..self.caret, "");
+        self.caret = previous;
 
-        if removed != '\n' {
-            self.caret_column -= 1;
-        } else {
-            self.set_caret_column_to_current();
-        }
+        self.set_caret_column_to_current();
 
         self.highlight();
-        self.commit_to_asset(data);
+        self.mark_edited();
     }
 
-    pub fn apply_input_delete(&mut self, data: &PluginData) {
+    pub fn apply_input_delete(&mut self) {
+        if self.is_read_only() {
+            return;
+        }
+
+        if self.delete_selection() {
+            self.highlight();
+            self.mark_edited();
+            return;
+        }
+
         if self.caret == self.text.len() {
             // Can't delete at end of file
             return;
         }
 
-        self.text.remove(self.caret);
+        let next = text::next_boundary(&self.text, self.caret);
+        self.text.replace_range(self.caret..next, "");
 
         self.highlight();
-        self.commit_to_asset(data);
+        self.mark_edited();
     }
 
-    pub fn apply_input_tab(&mut self, data: &PluginData) {
-        // Pad to the nearest 4
+    pub fn apply_input_tab(&mut self, tab_width: usize) {
+        if self.is_read_only() {
+            return;
+        }
+
+        self.delete_selection();
+
+        // Pad to the nearest tab stop
         let (_, column) = self.caret_line_column();
-        let count = 4 - (column % 4);
+        let count = tab_width - (column % tab_width);
         for _ in 0..count {
             self.text.insert(self.caret, ' ');
         }
         self.caret += count;
 
         self.highlight();
-        self.commit_to_asset(data);
+        self.mark_edited();
     }
 
     fn highlight(&mut self) {
@@ -347,33 +989,456 @@ impl DocumentState {
                 end: self.text.len(),
             }];
         }
+
+        self.find_syntax_errors();
+        self.find_storage_errors();
+    }
+
+    /// Collects the `ERROR` and `MISSING` nodes of the parsed text as diagnostics.
+    fn find_syntax_errors(&mut self) {
+        self.syntax_errors.clear();
+        self.tree = match &mut self.parser {
+            Some(parser) => parser.parse(&self.text, None),
+            None => None,
+        };
+        let tree = match &self.tree {
+            Some(tree) => tree,
+            None => return,
+        };
+
+        let mut cursor = tree.walk();
+        loop {
+            let node = cursor.node();
+            let message = if node.is_error() {
+                Some("Syntax error".to_string())
+            } else if node.is_missing() {
+                Some(format!("Missing `{}`", node.kind()))
+            } else {
+                None
+            };
+
+            let start = node.start_position();
+            let end = node.end_position();
+            let is_error = message.is_some();
+            if let Some(message) = message {
+                self.syntax_errors.push(Diagnostic {
+                    start: (start.row, start.column),
+                    end: (end.row, end.column),
+                    severity: AnodeSeverity::Error,
+                    message,
+                    source: SYNTAX_ERROR_SOURCE.to_string(),
+                });
+            }
+
+            // Only descend into nodes containing errors, an error's own children aren't reported
+            if !is_error && node.has_error() && cursor.goto_first_child() {
+                continue;
+            }
+
+            // Move on to the next sibling, of the closest ancestor that has one
+            while !cursor.goto_next_sibling() {
+                if !cursor.goto_parent() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Marks the characters the asset can't store, so it's clear why edits aren't committed.
+    fn find_storage_errors(&mut self) {
+        self.storage_errors.clear();
+        if self.storage.check(self.text.as_bytes()).is_ok() {
+            return;
+        }
+
+        let offsets: Vec<usize> = self.text.match_indices('\0').map(|(i, _)| i).collect();
+        for offset in offsets {
+            self.storage_errors.push(Diagnostic {
+                start: self.line_byte_column(offset),
+                end: self.line_byte_column(offset + 1),
+                severity: AnodeSeverity::Error,
+                message: "Nul characters can't be stored in this asset, edits are not committed \
+                    until they're removed"
+                    .to_string(),
+                source: STORAGE_ERROR_SOURCE.to_string(),
+            });
+        }
+    }
+
+    /// Identifiers in the syntax tree, except the one starting at `skip_offset`.
+    pub fn identifiers(&self, skip_offset: usize) -> Vec<String> {
+        let identifiers: BTreeSet<_> = self
+            .identifier_ranges()
+            .into_iter()
+            .filter(|range| range.start != skip_offset)
+            .filter_map(|range| self.text.get(range))
+            .collect();
+        identifiers.into_iter().map(str::to_string).collect()
+    }
+
+    /// Byte range of the definition the identifier at a byte offset refers to, found with the
+    /// language's locals query.
+    pub fn local_definition(&self, offset: usize) -> Option<(usize, usize)> {
+        let locals = self.locals()?;
+        let word = self.word_at(offset)?;
+        let definition = self.resolve(&locals, &word)?;
+        Some((definition.start, definition.end))
+    }
+
+    /// Byte ranges of the identifiers referring to the same definition as the one at a byte
+    /// offset, including the definition itself.
+    ///
+    /// Without a locals query, or if nothing defines the identifier, all identifiers with the same
+    /// name are returned.
+    pub fn local_references(&self, offset: usize) -> Vec<(usize, usize)> {
+        let word = match self.word_at(offset) {
+            Some(word) => word,
+            None => return Vec::new(),
+        };
+        let name = &self.text[word.clone()];
+
+        let locals = match self.locals() {
+            Some(locals) => locals,
+            None => {
+                return self
+                    .identifier_ranges()
+                    .into_iter()
+                    .filter(|range| &self.text[range.clone()] == name)
+                    .map(|range| (range.start, range.end))
+                    .collect()
+            }
+        };
+
+        let definition = self.resolve(&locals, &word);
+        let mut references: Vec<_> = locals
+            .definitions
+            .iter()
+            .chain(&locals.references)
+            .filter(|range| &self.text[(*range).clone()] == name)
+            .filter(|range| definition.is_none() || self.resolve(&locals, range) == definition)
+            .map(|range| (range.start, range.end))
+            .collect();
+        references.sort_unstable();
+        references.dedup();
+        references
+    }
+
+    /// Byte range of the identifier at a byte offset.
+    fn word_at(&self, offset: usize) -> Option<Range<usize>> {
+        let on_word = self.text.get(offset..)?.chars().next()?;
+        if !text::is_identifier_char(on_word) {
+            return None;
+        }
+        Some(text::word_start(&self.text, offset)..text::word_end(&self.text, offset))
+    }
+
+    /// Runs the locals query over the syntax tree.
+    fn locals(&self) -> Option<Locals> {
+        let (tree, query) = (self.tree.as_ref()?, self.locals_query.as_ref()?);
+        let mut locals = Locals::default();
+
+        let names = query.capture_names();
+        let mut cursor = QueryCursor::new();
+        for (query_match, index) in cursor.captures(query, tree.root_node(), self.text.as_bytes()) {
+            let capture = query_match.captures[index];
+            let range = capture.node.byte_range();
+            match names[capture.index as usize].as_str() {
+                "local.scope" => locals.scopes.push(range),
+                "local.reference" => locals.references.push(range),
+                // Some grammars add the kind, like `local.definition.function`
+                name if name.starts_with("local.definition") => locals.definitions.push(range),
+                _ => {}
+            }
+        }
+
+        Some(locals)
+    }
+
+    /// Finds the definition an identifier refers to, the one with the same name in the innermost
+    /// scope containing the identifier.
+    ///
+    /// Definitions that aren't visible from the identifier are still used if there's no other,
+    /// as grammars often scope the name of a function to the function itself.
+    fn resolve(&self, locals: &Locals, identifier: &Range<usize>) -> Option<Range<usize>> {
+        let name = &self.text[identifier.clone()];
+        let definitions = locals
+            .definitions
+            .iter()
+            .filter(|definition| &self.text[(*definition).clone()] == name);
+
+        let visible = definitions
+            .clone()
+            .filter_map(|definition| {
+                let scope = locals.scope_of(definition, self.text.len());
+                let is_visible = scope.start <= identifier.start && identifier.end <= scope.end;
+                is_visible.then(|| (scope.len(), definition))
+            })
+            // The innermost scope wins, and in it the closest definition before the identifier
+            .min_by_key(|(scope_len, definition)| {
+                let after = definition.start > identifier.start;
+                let distance = identifier.start.abs_diff(definition.start);
+                (*scope_len, after, distance)
+            })
+            .map(|(_, definition)| definition);
+
+        visible
+            .or_else(|| definitions.min_by_key(|definition| definition.start))
+            .cloned()
+    }
+
+    /// Byte ranges of the identifiers in the syntax tree.
+    fn identifier_ranges(&self) -> Vec<Range<usize>> {
+        let tree = match &self.tree {
+            Some(tree) => tree,
+            None => return Vec::new(),
+        };
+
+        let mut ranges = Vec::new();
+        let mut cursor = tree.walk();
+        loop {
+            let node = cursor.node();
+
+            // Grammars name their identifier nodes differently, like `type_identifier`
+            if node.child_count() == 0 && node.kind().ends_with("identifier") {
+                ranges.push(node.byte_range());
+            }
+
+            if cursor.goto_first_child() {
+                continue;
+            }
+            while !cursor.goto_next_sibling() {
+                if !cursor.goto_parent() {
+                    return ranges;
+                }
+            }
+        }
     }
 
-    fn commit_to_asset(&self, data: &PluginData) {
-        let (tt, asset, property) = if let Some(asset) = self.asset {
-            asset
+    /// Moves the caret to the next or previous error from the caret, wrapping around the ends.
+    ///
+    /// Returns false if there are no errors to move to.
+    pub fn goto_error(&mut self, diagnostics: &[Diagnostic], forward: bool) -> bool {
+        let mut offsets: Vec<usize> = diagnostics
+            .iter()
+            .filter(|d| d.severity == AnodeSeverity::Error)
+            .map(|d| self.offset_at(d.start.0, d.start.1))
+            .collect();
+        offsets.sort_unstable();
+        offsets.dedup();
+
+        let target = if forward {
+            offsets
+                .iter()
+                .find(|offset| **offset > self.caret)
+                .or_else(|| offsets.first())
         } else {
+            offsets
+                .iter()
+                .rev()
+                .find(|offset| **offset < self.caret)
+                .or_else(|| offsets.last())
+        };
+
+        match target.copied() {
+            Some(offset) => {
+                self.set_caret(offset);
+                self.reveal_line(self.line_byte_column(offset).0);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn mark_edited(&mut self) {
+        self.last_edit = Some(Instant::now());
+    }
+
+    /// Commits edits to the asset, if there are any that haven't been yet.
+    ///
+    /// Edits stay uncommitted while the text has characters the asset can't store, or while they
+    /// conflict with external changes.
+    pub fn commit(&mut self, data: &PluginData) {
+        if !self.storage_errors.is_empty() || self.conflict {
+            return;
+        }
+        if self.last_edit.take().is_some() {
+            self.commit_to_asset(data);
+        }
+    }
+
+    /// Commits edits to the asset once no more edits have been made for the delay.
+    ///
+    /// Edits are batched like this, as every commit creates a new buffer of the entire text.
+    pub fn commit_if_idle(&mut self, data: &PluginData, delay: Duration) {
+        // Committing would overwrite the external changes
+        if self.conflict {
             return;
+        }
+
+        if let Some(last_edit) = self.last_edit {
+            if last_edit.elapsed() >= delay {
+                self.commit(data);
+            }
+        }
+
+        // Let collaborators know where we are, once the caret settles
+        let collaboration = match &mut self.collaboration {
+            Some(collaboration) => collaboration,
+            None => return,
         };
+        if collaboration.caret_moved.0 != self.caret {
+            collaboration.caret_moved = (self.caret, Instant::now());
+        } else if self.caret != collaboration.shared_caret
+            && self.last_edit.is_none()
+            && collaboration.caret_moved.1.elapsed() >= delay
+        {
+            collaboration.shared_caret = self.caret;
+            let offset = text::char_offset(&self.text, self.caret);
+            if collaboration.history.set_caret(offset) {
+                self.share_history(data);
+            }
+        }
+    }
+
+    /// Commits pending edits, and leaves the edit history shared with collaborators.
+    pub fn close(&mut self, data: &PluginData) {
+        self.commit(data);
 
-        let bytes = self.text.as_bytes();
+        let left = match &mut self.collaboration {
+            Some(collaboration) => collaboration.history.leave(),
+            None => false,
+        };
+        if left {
+            self.share_history(data);
+        }
+    }
+
+    /// Writes only the edit history to the asset, for changes that don't affect the text.
+    fn share_history(&mut self, data: &PluginData) {
+        let (tt, asset) = match unsafe { self.text_object(data) } {
+            Some(object) => object,
+            None => return,
+        };
+        let collaboration = match &mut self.collaboration {
+            Some(collaboration) => collaboration,
+            None => return,
+        };
 
-        // Create a buffer holding the data
+        let bytes = collaboration.history.serialize();
         unsafe {
-            let buffers = (*data.apis.truth).buffers(tt);
-            let buffer_ptr = (*buffers).allocate.unwrap()(
-                (*buffers).inst,
-                bytes.len() as u64,
-                bytes.as_ptr() as *const c_void,
-            );
-            let buffer_id =
-                (*buffers).add.unwrap()((*buffers).inst, buffer_ptr, bytes.len() as u64, 0);
-
-            // Write the buffer to the truth data for the asset
+            let truth = &*data.apis.truth;
+            let buffer_id = storage::add_buffer(data, tt, &bytes);
+
+            let object = truth.write(tt, asset);
+            truth.set_buffer(tt, object, collaboration.property, buffer_id);
+            truth.commit(tt, object, TtUndoScopeT { u64_: 0 });
+
+            collaboration.synced_history = buffer_id;
+            self.version = truth.version(tt, asset);
+        }
+    }
+
+    fn commit_to_asset(&mut self, data: &PluginData) {
+        let (tt, asset) = match unsafe { self.text_object(data) } {
+            Some(object) if !self.is_read_only() => object,
+            _ => return,
+        };
+
+        // If the text can no longer be represented in its encoding, UTF-8 can represent anything
+        let bytes = self.format.encode(&self.text).unwrap_or_else(|error| {
+            event!(Level::WARN, "{}, switching to UTF-8", error);
+            self.format.encoding = Encoding::Utf8;
+            self.text.as_bytes().to_vec()
+        });
+
+        if let Err(error) = self.storage.check(&bytes) {
+            event!(Level::ERROR, "{}, not committing edits", error);
+            return;
+        }
+
+        // Record the edits in the history, so collaborators can merge them
+        let history = match &mut self.collaboration {
+            Some(collaboration) => {
+                collaboration.history.apply_local(&self.text);
+                let offset = text::char_offset(&self.text, self.caret);
+                collaboration.history.set_caret(offset);
+                collaboration.shared_caret = self.caret;
+                Some((collaboration.property, collaboration.history.serialize()))
+            }
+            None => None,
+        };
+
+        // Write the data to the truth data for the asset, along with the history
+        unsafe {
+            let undo_scope = TtUndoScopeT { u64_: 0 };
             let object = (*data.apis.truth).write(tt, asset);
-            (*data.apis.truth).set_buffer(tt, object, property, buffer_id);
-            (*data.apis.truth).commit(tt, object, TtUndoScopeT { u64_: 0 });
+            let buffer_id = self.storage.write(data, tt, object, &bytes, undo_scope);
+            if let Some((history_property, history)) = history {
+                let history_id = storage::add_buffer(data, tt, &history);
+                (*data.apis.truth).set_buffer(tt, object, history_property, history_id);
+                if let Some(collaboration) = &mut self.collaboration {
+                    collaboration.synced_history = history_id;
+                }
+            }
+            (*data.apis.truth).commit(tt, object, undo_scope);
+
+            // Our own changes shouldn't be seen as external changes
+            self.version = (*data.apis.truth).version(tt, asset);
+            self.synced_buffer = buffer_id;
+        }
+    }
+}
+
+/// Finds how the text of an asset is stored, from the aspect telling anode how to open it.
+unsafe fn find_text(
+    data: &PluginData,
+    tt: *mut TheTruthO,
+    root: TtIdT,
+) -> Result<(*const AnodeAspectI, Vec<u32>, TextStorage)> {
+    let aspect_i = (*data.apis.truth).get_aspect(tt, tt_id_type(root), ASPECT_ANODE.hash)
+        as *const AnodeAspectI;
+    if aspect_i.is_null() {
+        return Err(eyre!(
+            "Asset does not have required tm_anode_aspect_i_v1 aspect"
+        ));
+    }
+
+    let path = if (*aspect_i).subobject_path.is_null() {
+        Vec::new()
+    } else {
+        std::slice::from_raw_parts((*aspect_i).subobject_path, (*aspect_i).subobject_path_len)
+            .to_vec()
+    };
+
+    // Find out how the text is stored, which depends on the property's type
+    let object = follow_path(data, tt, root, &path)
+        .ok_or_else(|| eyre!("Asset does not have the subobject holding the text"))?;
+    let storage = TextStorage::detect(data, tt, object, &*aspect_i)?;
+
+    Ok((aspect_i, path, storage))
+}
+
+/// Follows subobject properties from an object, if all objects along the way are alive.
+unsafe fn follow_path(
+    data: &PluginData,
+    tt: *mut TheTruthO,
+    root: TtIdT,
+    path: &[u32],
+) -> Option<TtIdT> {
+    let truth = &*data.apis.truth;
+
+    let mut object = root;
+    for property in path {
+        if !truth.is_alive(tt, object) {
+            return None;
         }
+        object = truth.get_subobject(tt, truth.read(tt, object), *property);
+    }
+
+    if truth.is_alive(tt, object) {
+        Some(object)
+    } else {
+        None
     }
 }
 
@@ -382,20 +1447,20 @@ unsafe fn title_from_asset(
     tt: *mut TheTruthO,
     root: TtIdT,
     save_interface: *mut AssetSaveI,
+    has_uncommitted: bool,
+    read_only: bool,
 ) -> CString {
-    // Fetch the name for the asset
-    let mut buffer = vec![0u8; 128];
-    (*data.apis.properties_view).get_display_name(tt, root, buffer.as_mut_ptr() as *mut i8, 128);
-
-    // Check if this asset type has an extension defined, if so add that
-    let extension_i =
-        (*data.apis.truth).get_aspect(tt, tt_id_type(root), TM_TT_ASPECT__FILE_EXTENSION)
-            as *const c_char;
-    buffer.truncate(buffer.iter().position(|v| *v == 0).unwrap_or(128));
-
-    if !extension_i.is_null() {
-        let extension = CStr::from_ptr(extension_i);
+    let (mut buffer, extension) = name_and_extension(data, tt, root);
+
+    // Show a lock in front of the name if the asset can't be edited
+    if read_only {
+        let lock = std::char::from_u32(IONICON__LOCK_CLOSED as u32).unwrap_or('#');
+        let mut prefix = [0; 4];
+        let prefix = lock.encode_utf8(&mut prefix);
+        buffer.splice(0..0, prefix.bytes().chain(std::iter::once(b' ')));
+    }
 
+    if let Some(extension) = extension {
         buffer.push(b'.');
         buffer.extend_from_slice(extension.to_bytes());
 
@@ -403,7 +1468,7 @@ unsafe fn title_from_asset(
         let owner = (*data.apis.truth).owner(tt, root);
         let is_unsaved = (*save_interface).status.unwrap()((*save_interface).inst, owner)
             != TM_ASSET_SAVE_STATUS__SAVED;
-        if is_unsaved {
+        if is_unsaved || has_uncommitted {
             buffer.push(b'*');
         }
     }
@@ -411,10 +1476,139 @@ unsafe fn title_from_asset(
     CString::new(buffer).unwrap()
 }
 
-unsafe fn higlight_config_from_raw(
+/// Scopes, definitions and references of a syntax tree, as byte ranges.
+#[derive(Default)]
+struct Locals {
+    scopes: Vec<Range<usize>>,
+    definitions: Vec<Range<usize>>,
+    references: Vec<Range<usize>>,
+}
+
+impl Locals {
+    /// Innermost scope containing a range, the whole text if no scope does.
+    fn scope_of(&self, range: &Range<usize>, text_len: usize) -> Range<usize> {
+        self.scopes
+            .iter()
+            .filter(|scope| scope.start <= range.start && range.end <= scope.end)
+            .min_by_key(|scope| scope.len())
+            .cloned()
+            .unwrap_or(0..text_len)
+    }
+}
+
+/// File name of an asset, with the extension of its type if it has one.
+pub unsafe fn file_name(data: &PluginData, tt: *mut TheTruthO, root: TtIdT) -> String {
+    let (name, extension) = name_and_extension(data, tt, root);
+
+    let mut name = String::from_utf8_lossy(&name).into_owned();
+    if let Some(extension) = extension {
+        name.push('.');
+        name.push_str(&extension.to_string_lossy());
+    }
+    name
+}
+
+/// Path of an asset in the project's directories, with its file extension.
+///
+/// Assets that aren't in a directory, like ones not saved yet, only have their file name.
+pub unsafe fn asset_path(data: &PluginData, tt: *mut TheTruthO, root: TtIdT) -> String {
+    let mut buffer = vec![0u8; 1024];
+    (*data.apis.truth_assets).get_asset_path_with_extension(
+        tt,
+        root,
+        buffer.as_mut_ptr() as *mut c_char,
+        buffer.len() as u32,
+    );
+    buffer.truncate(buffer.iter().position(|v| *v == 0).unwrap_or(buffer.len()));
+
+    let path = String::from_utf8_lossy(&buffer);
+    match path.trim_start_matches('/') {
+        "" => file_name(data, tt, root),
+        path => path.to_string(),
+    }
+}
+
+/// Finds an asset that can be opened in an editor, by its path from [`asset_path`].
+pub unsafe fn find_asset(
     data: &PluginData,
-    highlighting: &Highlighting,
-) -> HighlightConfiguration {
+    tt: *mut TheTruthO,
+    matches: impl Fn(&str) -> bool,
+) -> Option<TtIdT> {
+    let truth = &*data.apis.truth;
+    let temp_allocator = &*data.apis.temp_allocator;
+    let ta = temp_allocator.create(temp_allocator.frame_allocator());
+
+    // Type zero is reserved, so the first type that can have the aspect is one
+    let mut found = None;
+    for index in 1..truth.num_types(tt) {
+        let object_type = TtTypeT { u64_: index as u64 };
+        if truth
+            .get_aspect(tt, object_type, ASPECT_ANODE.hash)
+            .is_null()
+        {
+            continue;
+        }
+
+        let objects = truth.all_objects_of_type(tt, object_type, ta);
+        if objects.is_null() {
+            continue;
+        }
+        let header = (objects as *const CArrayHeaderT).offset(-1);
+        found = std::slice::from_raw_parts(objects, (*header).size as usize)
+            .iter()
+            .find(|object| matches(&asset_path(data, tt, **object)))
+            .copied();
+        if found.is_some() {
+            break;
+        }
+    }
+
+    temp_allocator.destroy(ta);
+    found
+}
+
+/// Display name of an asset, and the file extension of its type if it has one.
+unsafe fn name_and_extension(
+    data: &PluginData,
+    tt: *mut TheTruthO,
+    root: TtIdT,
+) -> (Vec<u8>, Option<&'static CStr>) {
+    // Fetch the name for the asset
+    let mut buffer = vec![0u8; 128];
+    (*data.apis.properties_view).get_display_name(tt, root, buffer.as_mut_ptr() as *mut i8, 128);
+    buffer.truncate(buffer.iter().position(|v| *v == 0).unwrap_or(128));
+
+    // Check if this asset type has an extension defined
+    let extension_i =
+        (*data.apis.truth).get_aspect(tt, tt_id_type(root), TM_TT_ASPECT__FILE_EXTENSION)
+            as *const c_char;
+    let extension = if extension_i.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(extension_i))
+    };
+
+    (buffer, extension)
+}
+
+unsafe fn locals_query_from_raw(highlighting: &Highlighting) -> Option<Query> {
+    let source =
+        std::slice::from_raw_parts(highlighting.locals_query, highlighting.locals_query_len);
+    let source = std::str::from_utf8(source).ok()?;
+    if source.trim().is_empty() {
+        return None;
+    }
+
+    match Query::new(highlighting.language, source) {
+        Ok(query) => Some(query),
+        Err(error) => {
+            event!(Level::WARN, "Invalid locals query: {:?}", error);
+            None
+        }
+    }
+}
+
+unsafe fn higlight_config_from_raw(highlighting: &Highlighting) -> HighlightConfiguration {
     // Load the config from the aspect
     let highlight_query = std::slice::from_raw_parts(
         highlighting.highlight_query,
@@ -434,12 +1628,255 @@ unsafe fn higlight_config_from_raw(
         std::str::from_utf8(locals_query).unwrap(),
     )
     .unwrap();
-    let highlight_names: Vec<String> = data
-        .token_colors
-        .iter()
-        .map(|v| v.scope.to_string())
-        .collect();
-    highlight_config.configure(&highlight_names);
+    highlight_config.configure(&HIGHLIGHT_SCOPES);
 
     highlight_config
 }
+
+#[cfg(test)]
+mod tests {
+    use machinery_api::foundation::{
+        TM_THE_TRUTH_PROPERTY_TYPE_BOOL, TM_THE_TRUTH_PROPERTY_TYPE_BUFFER,
+    };
+
+    use super::*;
+    use crate::fake_truth::{self, FakeTruth};
+
+    fn document_with(text: &str) -> DocumentState {
+        let mut document = DocumentState::new();
+        document.replace_range(0, 0, text).unwrap();
+        document.set_caret(0);
+        document
+    }
+
+    /// Creates an asset storing the text in a buffer, like a text file.
+    fn text_asset(truth: &FakeTruth, text: &str) -> TtIdT {
+        let aspect = AnodeAspectI {
+            property: 0,
+            ..Default::default()
+        };
+        let object_type = truth.add_type(&[TM_THE_TRUTH_PROPERTY_TYPE_BUFFER], Some(aspect));
+        let asset = truth.create_object(object_type);
+        truth.set_buffer(asset, 0, text.as_bytes());
+        asset
+    }
+
+    #[test]
+    fn caret_moves_over_whole_graphemes() {
+        let mut document = document_with("e\u{301}🦀x");
+
+        document.apply_input_right(false);
+        assert_eq!(document.caret(), 3);
+        document.apply_input_right(false);
+        assert_eq!(document.caret(), 7);
+        assert_eq!(document.caret_line_column(), (0, 3));
+
+        document.apply_input_left(false);
+        assert_eq!(document.caret(), 3);
+        document.apply_input_left(false);
+        assert_eq!(document.caret(), 0);
+    }
+
+    #[test]
+    fn caret_keeps_its_column_across_wide_characters() {
+        let mut document = document_with("abcd\n漢字\nabcd");
+        document.set_caret(3);
+        document.set_caret_column_to_current();
+
+        // Column three is in the second half of 字, so the caret goes after it
+        document.apply_input_down();
+        assert_eq!(document.caret_line_column(), (1, 4));
+        assert_eq!(document.caret(), "abcd\n漢字".len());
+
+        // The column it was moved from is kept, rather than the one it ended up at
+        document.apply_input_down();
+        assert_eq!(document.caret_line_column(), (2, 4));
+        document.apply_input_up();
+        document.apply_input_up();
+        assert_eq!(document.caret_line_column(), (0, 4));
+    }
+
+    #[test]
+    fn backspace_and_delete_remove_whole_graphemes() {
+        let mut document = document_with("ae\u{301}b");
+        document.set_caret(4);
+        document.apply_input_backspace();
+        assert_eq!(document.text(), "ab");
+        assert_eq!(document.caret(), 1);
+
+        let mut document = document_with("a🦀b");
+        document.set_caret(1);
+        document.apply_input_delete();
+        assert_eq!(document.text(), "ab");
+    }
+
+    #[test]
+    fn combining_characters_join_the_grapheme_before_them() {
+        let mut document = document_with("e");
+        document.set_caret(1);
+        document.apply_input_character('\u{301}');
+        assert_eq!(document.caret_line_column(), (0, 1));
+        document.apply_input_left(false);
+        assert_eq!(document.caret(), 0);
+    }
+
+    /// Creates an asset edited collaboratively in two truths, like on two machines.
+    fn collaborative_asset(text: &str) -> (Box<FakeTruth>, Box<FakeTruth>, TtIdT) {
+        let properties = [
+            TM_THE_TRUTH_PROPERTY_TYPE_BUFFER,
+            TM_THE_TRUTH_PROPERTY_TYPE_BUFFER,
+        ];
+        let aspect = || AnodeAspectI {
+            property: 0,
+            collaborative: true,
+            history_property: 1,
+            ..Default::default()
+        };
+
+        let (a, b) = (FakeTruth::new(), FakeTruth::new());
+        let object_type = a.add_type(&properties, Some(aspect()));
+        b.add_type(&properties, Some(aspect()));
+        let asset = a.create_object(object_type);
+        a.set_buffer(asset, 0, text.as_bytes());
+        b.sync_from(&a, asset);
+        (a, b, asset)
+    }
+
+    fn open(data: &PluginData, truth: &FakeTruth, asset: TtIdT) -> DocumentState {
+        let mut document = DocumentState::new();
+        unsafe { document.load_from_asset(data, truth.tt(), asset).unwrap() };
+        document
+    }
+
+    #[test]
+    fn concurrent_edits_in_two_truths_are_merged() {
+        let data = fake_truth::plugin_data();
+        let (a, b, asset) = collaborative_asset("one two\n");
+        let mut document_a = open(&data, &a, asset);
+        let mut document_b = open(&data, &b, asset);
+
+        document_a.replace_range(0, 0, "zero ").unwrap();
+        document_a.commit(&data);
+        document_b.replace_range(7, 7, " three").unwrap();
+        document_b.commit(&data);
+
+        // Syncing replaces the asset, the history brings back the edits it overwrote
+        b.sync_from(&a, asset);
+        unsafe { document_b.sync_from_asset(&data) };
+        assert_eq!(document_b.text(), "zero one two three\n");
+        assert_eq!(b.buffer(asset, 0), b"zero one two three\n");
+
+        a.sync_from(&b, asset);
+        unsafe { document_a.sync_from_asset(&data) };
+        assert_eq!(document_a.text(), "zero one two three\n");
+        assert!(!document_a.has_conflict() && !document_b.has_conflict());
+    }
+
+    #[test]
+    fn carets_move_along_with_edits_from_another_truth() {
+        let data = fake_truth::plugin_data();
+        let (a, b, asset) = collaborative_asset("one two\n");
+        let mut document_a = open(&data, &a, asset);
+        let mut document_b = open(&data, &b, asset);
+        document_b.set_caret(4);
+
+        document_a.replace_range(0, 0, "zero ").unwrap();
+        document_a.commit(&data);
+        b.sync_from(&a, asset);
+        unsafe { document_b.sync_from_asset(&data) };
+        assert_eq!(document_b.caret(), 9);
+
+        let carets = document_b.remote_carets();
+        assert_eq!(carets.len(), 1);
+        assert_eq!(carets[0].offset, 5);
+
+        // Closing shares only the history, which removes the caret
+        document_a.close(&data);
+        b.sync_from(&a, asset);
+        unsafe { document_b.sync_from_asset(&data) };
+        assert!(document_b.remote_carets().is_empty());
+        assert_eq!(document_b.text(), "zero one two\n");
+    }
+
+    #[test]
+    fn external_writes_without_history_are_merged_with_local_edits() {
+        let data = fake_truth::plugin_data();
+        let (a, _, asset) = collaborative_asset("one two\n");
+        let mut document = open(&data, &a, asset);
+
+        document.replace_range(0, 0, "zero ").unwrap();
+        a.set_buffer(asset, 0, b"one two three\n");
+        unsafe { document.sync_from_asset(&data) };
+
+        assert!(!document.has_conflict());
+        assert_eq!(document.text(), "zero one two three\n");
+        assert_eq!(a.buffer(asset, 0), b"zero one two three\n");
+    }
+
+    #[test]
+    fn reloading_a_conflict_discards_local_edits() {
+        let data = fake_truth::plugin_data();
+        let truth = FakeTruth::new();
+        let asset = text_asset(&truth, "original\n");
+        let mut document = DocumentState::new();
+        unsafe { document.load_from_asset(&data, truth.tt(), asset).unwrap() };
+
+        document.replace_range(0, 0, "local ").unwrap();
+        truth.set_buffer(asset, 0, b"external\n");
+        unsafe { document.sync_from_asset(&data) };
+        assert!(document.has_conflict());
+
+        // Pressing reload takes focus away from the editor, which commits
+        document.commit(&data);
+        assert_eq!(truth.buffer(asset, 0), b"external\n");
+
+        unsafe { document.resolve_conflict(&data, false) };
+        assert!(!document.has_conflict());
+        assert_eq!(document.text(), "external\n");
+        assert_eq!(truth.buffer(asset, 0), b"external\n");
+    }
+
+    #[test]
+    fn keeping_local_edits_overwrites_a_conflict() {
+        let data = fake_truth::plugin_data();
+        let truth = FakeTruth::new();
+        let asset = text_asset(&truth, "original\n");
+        let mut document = DocumentState::new();
+        unsafe { document.load_from_asset(&data, truth.tt(), asset).unwrap() };
+
+        document.replace_range(0, 0, "local ").unwrap();
+        truth.set_buffer(asset, 0, b"external\n");
+        unsafe { document.sync_from_asset(&data) };
+
+        unsafe { document.resolve_conflict(&data, true) };
+        assert!(!document.has_conflict());
+        assert_eq!(truth.buffer(asset, 0), b"local original\n");
+    }
+
+    #[test]
+    fn failing_to_load_an_asset_leaves_the_document_empty() {
+        let data = fake_truth::plugin_data();
+        let truth = FakeTruth::new();
+        let asset = text_asset(&truth, "text\n");
+        let aspect = AnodeAspectI {
+            property: 0,
+            ..Default::default()
+        };
+        let not_text = truth.add_type(&[TM_THE_TRUTH_PROPERTY_TYPE_BOOL], Some(aspect));
+        let not_text = truth.create_object(not_text);
+
+        let mut document = DocumentState::new();
+        unsafe {
+            document.load_from_asset(&data, truth.tt(), asset).unwrap();
+            assert!(document
+                .load_from_asset(&data, truth.tt(), not_text)
+                .is_err());
+        }
+        assert!(document.asset().is_none());
+        assert_eq!(document.text(), "");
+
+        // The asset that can be opened still opens afterwards
+        unsafe { document.load_from_asset(&data, truth.tt(), asset).unwrap() };
+        assert_eq!(document.text(), "text\n");
+    }
+}
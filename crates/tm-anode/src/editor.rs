@@ -0,0 +1,2765 @@
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU32, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use const_cstr::{const_cstr, ConstCStr};
+use eyre::Result;
+use machinery::{identifier, Identifier};
+use machinery_api::{
+    foundation::{
+        ApplicationO, ColorSrgbT, RectT, TheTruthO, TtIdT, UiO, Vec2T, TM_INPUT_KEYBOARD_ITEM_0,
+        TM_INPUT_KEYBOARD_ITEM_EQUAL, TM_INPUT_KEYBOARD_ITEM_F12, TM_INPUT_KEYBOARD_ITEM_F8,
+        TM_INPUT_KEYBOARD_ITEM_MINUS, TM_INPUT_KEYBOARD_ITEM_NUMPAD0,
+        TM_INPUT_KEYBOARD_ITEM_NUMPADMINUS, TM_INPUT_KEYBOARD_ITEM_NUMPADPLUS,
+        TM_INPUT_KEYBOARD_ITEM_S, TM_INPUT_KEYBOARD_ITEM_SPACE,
+    },
+    plugins::ui::{
+        DockingFindTabOptT, Draw2dIbufferT, Draw2dStyleT, UiApi, UiBuffersT, UiButtonT, UiColor,
+        UiDropdownT, UiFontT, UiInputStateT, UiMenuItemT, UiMenuT, UiScrollbarT, UiStyleT, UiTextT,
+        IONICON__CLOSE_CIRCLE, IONICON__INFORMATION_CIRCLE, IONICON__WARNING, TM_UI_ALIGN_CENTER,
+        TM_UI_ALIGN_LEFT, TM_UI_ALIGN_RIGHT, TM_UI_COLOR_DISABLED_TEXT, TM_UI_COLOR_ERROR_TEXT,
+        TM_UI_COLOR_MENU_BACKGROUND, TM_UI_COLOR_MENU_SELECTED, TM_UI_COLOR_TEXT,
+        TM_UI_COLOR_THIN_LINES, TM_UI_COLOR_TOOLTIP_BACKGROUND, TM_UI_COLOR_TOOLTIP_BORDER,
+        TM_UI_COLOR_TOOLTIP_TEXT, TM_UI_COLOR_WINDOW_SELECTION, TM_UI_COLOR_WINDOW_STATUS_BAR,
+        TM_UI_COLOR_WINDOW_STATUS_BAR_TEXT, TM_UI_CURSOR_TEXT, TM_UI_EDIT_KEY_DELETE,
+        TM_UI_EDIT_KEY_DOWN, TM_UI_EDIT_KEY_ESCAPE, TM_UI_EDIT_KEY_LEFT, TM_UI_EDIT_KEY_RIGHT,
+        TM_UI_EDIT_KEY_UP, TM_UI_METRIC_MARGIN, TM_UI_METRIC_MENU_ITEM_HEIGHT,
+        TM_UI_METRIC_SCROLLBAR_WIDTH, TM_UI_MODIFIERS_ALT_CTRL, TM_UI_MODIFIERS_CTRL,
+        TM_UI_MODIFIERS_SHIFT,
+    },
+};
+use tm_anode_api::{AnodeDocumentO, AnodeSeverity};
+use tracing::{event, Level};
+use tree_sitter_highlight::HighlightEvent;
+use ultraviolet::IVec2;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::{
+    completion::{self, CompletionPopup},
+    diagnostics::Diagnostic,
+    document::DocumentState,
+    encoding::{Encoding, LineEnding, TextFormat},
+    fonts::{self, CodeFont},
+    hover::{self, Hover, HOVER_DELAY},
+    markdown::{self, LineKind},
+    navigation::{self, Location, Navigation, NavigationKind, References},
+    plugin::{self, PluginData},
+    settings::{self, EditorSettings},
+    text,
+    theme::Theme,
+};
+
+/// Code editor widget, drawing a document and handling input for it.
+///
+/// This is used by the code editor tab, but can be drawn into any UI.
+pub struct CodeEditor {
+    data: Arc<PluginData>,
+    app: *mut ApplicationO,
+    auto_activate: AtomicBool,
+    /// If the editor was active last frame.
+    active: AtomicBool,
+    document: Arc<Mutex<DocumentState>>,
+    /// Handle other plugins refer to the document by.
+    document_handle: *mut AnodeDocumentO,
+    options: Mutex<EditorOptions>,
+    scroll_y: AtomicU32,
+    /// Font size offset from the settings' font size.
+    zoom: AtomicI32,
+    /// Offset from the top of the minimap viewport the mouse grabbed it at, while dragging.
+    minimap_drag: Mutex<Option<f32>>,
+    context_menu: Mutex<Option<Vec2T>>,
+    completion: Mutex<Option<CompletionPopup>>,
+    /// Where the mouse last stopped moving over the text, and when.
+    mouse_rest: Mutex<Option<(Vec2T, Instant)>>,
+    hover: Mutex<Option<Hover>>,
+    /// Definition or references being looked up.
+    navigation: Mutex<Option<Navigation>>,
+    /// References panel, while it's open.
+    references: Mutex<Option<References>>,
+}
+
+impl CodeEditor {
+    pub fn new(data: Arc<PluginData>, app: *mut ApplicationO) -> Self {
+        let document = Arc::new(Mutex::new(DocumentState::new()));
+        let document_handle = data.open_documents.lock().unwrap().add(&document);
+
+        Self {
+            data,
+            app,
+            auto_activate: AtomicBool::new(false),
+            active: AtomicBool::new(false),
+            document,
+            document_handle,
+            options: Mutex::new(EditorOptions::default()),
+            scroll_y: AtomicU32::new(0),
+            zoom: AtomicI32::new(0),
+            minimap_drag: Mutex::new(None),
+            context_menu: Mutex::new(None),
+            completion: Mutex::new(None),
+            mouse_rest: Mutex::new(None),
+            hover: Mutex::new(None),
+            navigation: Mutex::new(None),
+            references: Mutex::new(None),
+        }
+    }
+
+    pub fn document(&self) -> MutexGuard<'_, DocumentState> {
+        self.document.lock().unwrap()
+    }
+
+    /// Handle other plugins can refer to the document by.
+    pub fn document_handle(&self) -> *mut AnodeDocumentO {
+        self.document_handle
+    }
+
+    /// Opens an asset in the editor, committing edits to the previous one.
+    pub unsafe fn set_asset(&self, tt: *mut TheTruthO, root: TtIdT) -> Result<()> {
+        self.document().load_from_asset(&self.data, tt, root)
+    }
+
+    /// Commits pending edits, for when the editor won't be drawn for a while.
+    pub fn commit(&self) {
+        self.document().commit(&self.data);
+    }
+
+    /// Commits pending edits, stops showing our caret to collaborators and closes the document
+    /// on its language server.
+    ///
+    /// This is also done when the editor is dropped, closing again does nothing.
+    pub fn close(&self) {
+        self.document().close(&self.data);
+
+        let handle = self.document_handle as usize;
+        self.data
+            .language_servers
+            .lock()
+            .unwrap()
+            .close(&self.data, handle);
+    }
+
+    pub unsafe fn ui(&self, ui: *mut UiO, ui_style: *const UiStyleT, rect: RectT) {
+        let ui_api = &*self.data.apis.ui;
+
+        // Other plugins' providers may read the document, so ask them before locking it
+        let provider_request = self
+            .completion
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(CompletionPopup::take_provider_request);
+        if let Some(position) = provider_request {
+            let completions =
+                completion::query_providers(&self.data, self.document_handle(), position);
+            if let Some(popup) = &mut *self.completion.lock().unwrap() {
+                popup.add(completions);
+            }
+        }
+        let provider_request = self
+            .hover
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(Hover::take_provider_request);
+        if let Some(position) = provider_request {
+            let sections = hover::query_providers(&self.data, self.document_handle(), position);
+            if let Some(hover) = &mut *self.hover.lock().unwrap() {
+                hover.add(sections);
+            }
+        }
+        let provider_request = self
+            .navigation
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(Navigation::take_provider_request);
+        if let Some((position, kind)) = provider_request {
+            let locations =
+                navigation::query_providers(&self.data, self.document_handle(), position, kind);
+            if let Some(navigation) = &mut *self.navigation.lock().unwrap() {
+                navigation.add(locations);
+            }
+        }
+
+        // Saving works from anywhere, so make sure every document is up-to-date when it happens
+        let buffers = ui_api.buffers(ui);
+        let input = &*buffers.input;
+        let ctrl_only =
+            (input.modifiers & TM_UI_MODIFIERS_ALT_CTRL as u32) == TM_UI_MODIFIERS_CTRL as u32;
+        if ctrl_only && *input.key_pressed.offset(TM_INPUT_KEYBOARD_ITEM_S as isize) {
+            self.data.commit_documents();
+        }
+
+        let mut document = self.document.lock().unwrap();
+        document.sync_from_asset(&self.data);
+
+        let ibuffer = *buffers.ibuffers.offset((*ui_style).buffer as isize);
+        let settings = EditorSettings::read(&self.data, self.app);
+        let font_size = self.font_size(&settings);
+        let code_font = fonts::code_font(
+            &self.data,
+            ui,
+            &settings.font_family,
+            font_size,
+            document.text(),
+        )
+        .unwrap_or_else(|| {
+            // Without a code font we can still fall back to the UI's font
+            CodeFont::from_ui_font(UiFontT {
+                size: font_size,
+                font: (*ui_style).font as *mut _,
+                ..Default::default()
+            })
+        });
+        let options = *self.options.lock().unwrap();
+
+        // The status bar takes up the bottom of the tab, the editor gets the rest
+        let status_bar_height = *buffers
+            .metrics
+            .offset(TM_UI_METRIC_MENU_ITEM_HEIGHT as isize);
+        let status_bar_rect = RectT {
+            y: rect.y + rect.h - status_bar_height,
+            h: status_bar_height,
+            ..rect
+        };
+        let rect = RectT {
+            h: rect.h - status_bar_height,
+            ..rect
+        };
+
+        // The references panel goes above the status bar, with a header and its rows
+        let references_rows = self
+            .references
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|references| references.visible_items(REFERENCES_MAX_ROWS).count() + 1);
+        let references_rect = references_rows.map(|rows| {
+            let height = (rows as f32 * status_bar_height).min(rect.h * 0.5);
+            RectT {
+                y: rect.y + rect.h - height,
+                h: height,
+                ..rect
+            }
+        });
+        let rect = RectT {
+            h: rect.h - references_rect.map_or(0.0, |v| v.h),
+            ..rect
+        };
+
+        // Sync with the language server, and apply formatting it responded with
+        let handle = self.document_handle as usize;
+        let edits = {
+            let mut language_servers = self.data.language_servers.lock().unwrap();
+            language_servers.poll(&self.data);
+            language_servers.update(&self.data, handle, &document, &settings);
+
+            // The server has seen the current text now, so it can be asked for completions
+            let completions = language_servers.take_completions(handle);
+            if let Some(popup) = &mut *self.completion.lock().unwrap() {
+                if popup.take_server_request() {
+                    language_servers.complete(handle, document.caret());
+                }
+                popup.add(completions);
+            }
+            let hover_section = language_servers.take_hover(handle);
+            if let Some(hover) = &mut *self.hover.lock().unwrap() {
+                if let Some(offset) = hover.take_server_request() {
+                    language_servers.hover(handle, offset);
+                }
+                hover.add(hover_section.into_iter().collect());
+            }
+            if let Some(navigation) = &mut *self.navigation.lock().unwrap() {
+                if let Some(offset) = navigation.take_server_request() {
+                    let asked = match navigation.kind() {
+                        NavigationKind::Definition => language_servers.definition(handle, offset),
+                        NavigationKind::References => language_servers.references(handle, offset),
+                    };
+                    if asked {
+                        navigation.wait_for_server();
+                    }
+                }
+
+                // Taken after asking, as asking discards responses to earlier requests
+                if let Some(locations) = language_servers.take_locations(handle) {
+                    navigation.add_server(locations);
+                }
+            }
+
+            language_servers.take_edits(handle)
+        };
+        if !edits.is_empty() {
+            if let Err(error) = document.apply_edits(&edits) {
+                event!(Level::ERROR, "Failed to apply formatting: {}", error);
+            }
+        }
+
+        // Go to the definition or show the references once they're found, definitions in other
+        // assets can only be opened once this document is unlocked
+        let mut open_location = self.finish_navigation(&mut document);
+
+        let mut diagnostics = document
+            .asset()
+            .map(|(tt, root, _)| self.data.diagnostics.lock().unwrap().for_asset(tt, root))
+            .unwrap_or_default();
+        diagnostics.extend(document.syntax_errors().iter().cloned());
+        diagnostics.extend(document.storage_errors().iter().cloned());
+
+        let metrics = EditorMetrics::calculate(&buffers, rect, &code_font.font, &options);
+        let ctx = UiCtx {
+            ui,
+            ui_style,
+            buffers,
+            ibuffer,
+            metrics,
+            settings,
+            code_font,
+            diagnostics,
+        };
+        let theme = ctx.settings.theme(&self.data);
+
+        // Scroll to lines revealed through the API, centering them
+        if let Some(line) = document.take_reveal_line() {
+            let lines_per_height = ctx.metrics.textarea_rect.h / ctx.metrics.line_stride;
+            let scroll_y =
+                (line as f32 - (lines_per_height * 0.5).floor()) * ctx.metrics.line_stride;
+            let line_count = document.text().split('\n').count();
+            self.set_scroll_y(scroll_y.clamp(0.0, Self::max_scroll_y(&ctx.metrics, line_count)));
+        }
+
+        let textarea_clip =
+            (*self.data.apis.draw2d).add_clip_rect(ctx.buffers.vbuffer, ctx.metrics.textarea_rect);
+
+        // Process input affecting the UI
+        let line_count = match document.binary() {
+            Some(bytes) => hex_line_count(bytes),
+            None => document.text().split('\n').count(),
+        };
+        let minimap_hovering = self.handle_minimap_input(ui_api, &ctx, line_count);
+        let active = self.handle_input(ui_api, &ctx, &mut document, line_count, minimap_hovering);
+        if !active {
+            *self.completion.lock().unwrap() = None;
+        }
+
+        // Commit edits once the user stops typing or moves on to something else
+        if self.active.swap(active, Ordering::Relaxed) && !active {
+            document.commit(&self.data);
+        } else {
+            let delay = Duration::try_from_secs_f32(ctx.settings.commit_delay).unwrap_or_default();
+            document.commit_if_idle(&self.data, delay);
+        }
+
+        // Fill the style for drawing
+        let mut style = Draw2dStyleT {
+            font: ctx.code_font.font.font,
+            clip: (*ui_style).clip,
+            font_scale: 1.0,
+            ..Default::default()
+        };
+
+        // Draw the background
+        style.color = theme.background;
+        (*self.data.apis.draw2d).fill_rect(buffers.vbuffer, ibuffer, &style, rect);
+
+        // Draw parts
+        let mut glyphs = Vec::new();
+        if let Some(bytes) = document.binary() {
+            // Content that isn't text is shown as a read-only hex dump instead
+            self.draw_hex(ui_api, &ctx, style, textarea_clip, &mut glyphs, bytes);
+        } else {
+            style.clip = textarea_clip;
+            if options.current_line {
+                let (line, _) = document.caret_line_column();
+                self.draw_current_line(&ctx, &mut style, line);
+            }
+            self.draw_selection(&ctx, &mut style, &document);
+            if options.indent_guides {
+                self.draw_indent_guides(&ctx, &mut style, &document);
+            }
+            if options.whitespace {
+                self.draw_whitespace(&ctx, &mut style, &document);
+            }
+            style.clip = (*ui_style).clip;
+
+            self.draw_decorations(
+                &ctx,
+                &mut style,
+                textarea_clip,
+                &mut glyphs,
+                &document,
+                line_count,
+            );
+            self.draw_minimap(&ctx, &mut style, &document, line_count);
+            self.draw_code(ui_api, &ctx, style, textarea_clip, &mut glyphs, &document);
+            self.draw_ruler_shading(&ctx, textarea_clip, &document);
+
+            self.draw_remote_carets(ui_api, &ctx, &document, textarea_clip);
+            if active {
+                self.draw_caret(&ctx, &document, (*ui_style).clip);
+            }
+        }
+
+        self.draw_scrollbar(ui_api, &ctx, line_count);
+        if document.has_conflict() {
+            self.draw_conflict_bar(ui_api, &ctx, &mut document);
+        }
+        if let Some(references_rect) = references_rect {
+            let clicked = self.draw_references(ui_api, &ctx, references_rect);
+            if let Some(location) = clicked {
+                open_location = self.go_to(&mut document, location);
+            }
+        }
+        self.draw_status_bar(ui_api, &ctx, &mut document, status_bar_rect);
+        self.draw_completion(&ctx, &document, &mut glyphs);
+        self.draw_hover(&ctx, &document, &mut glyphs);
+        self.draw_context_menu(ui_api, &ctx, &mut document);
+
+        // The tab the asset opens in may be this one, or look this document up
+        drop(document);
+        if let Some(location) = open_location {
+            let opt = DockingFindTabOptT {
+                in_ui: ui,
+                find_asset_tt: location.tt,
+                find_asset: location.asset,
+                ..Default::default()
+            };
+            let (position, selection_len) = location.position();
+            plugin::open_asset_at(&self.data, self.app, &opt, position, selection_len);
+        }
+    }
+
+    /// Takes the locations of a finished definition or references lookup, going to the
+    /// definition or opening the references panel.
+    ///
+    /// Returns the definition if it's in another asset, which the caller has to open.
+    unsafe fn finish_navigation(&self, document: &mut DocumentState) -> Option<Location> {
+        let mut navigation = self.navigation.lock().unwrap();
+        let locations = navigation.as_mut()?.finish(document)?;
+        let navigation = navigation.take()?;
+
+        match navigation.kind() {
+            NavigationKind::Definition => match locations.into_iter().next() {
+                Some(location) => self.go_to(document, location),
+                None => {
+                    event!(
+                        Level::INFO,
+                        "No definition found for \"{}\".",
+                        navigation.name()
+                    );
+                    None
+                }
+            },
+            NavigationKind::References => {
+                let references = References::new(&self.data, navigation.name(), locations);
+                *self.references.lock().unwrap() = Some(references);
+                None
+            }
+        }
+    }
+
+    /// Selects a location if it's in this document, otherwise returns it to be opened.
+    fn go_to(&self, document: &mut DocumentState, location: Location) -> Option<Location> {
+        match document.asset() {
+            Some((tt, asset, _)) if location.is_in(tt, asset) => {
+                let start = document.offset_at(location.start.0, location.start.1);
+                let end = document.offset_at(location.end.0, location.end.1);
+                document.set_selection(start, end.max(start));
+                document.reveal_line(location.start.0);
+                None
+            }
+            _ => Some(location),
+        }
+    }
+    fn scroll_y(&self) -> f32 {
+        f32::from_bits(self.scroll_y.load(Ordering::Relaxed))
+    }
+
+    fn set_scroll_y(&self, value: f32) {
+        self.scroll_y.store(value.to_bits(), Ordering::Relaxed)
+    }
+
+    fn theme<'a>(&'a self, ctx: &UiCtx) -> &'a Theme {
+        ctx.settings.theme(&self.data)
+    }
+
+    fn font_size(&self, settings: &EditorSettings) -> u32 {
+        (settings.font_size as i32 + self.zoom.load(Ordering::Relaxed))
+            .clamp(MIN_FONT_SIZE, MAX_FONT_SIZE) as u32
+    }
+
+    /// Changes the zoom level, keeping the same lines in view.
+    fn zoom_by(&self, settings: &EditorSettings, delta: i32) {
+        let old_size = self.font_size(settings);
+
+        let base = settings.font_size as i32;
+        let zoom = self.zoom.load(Ordering::Relaxed) + delta;
+        let zoom = zoom.clamp(MIN_FONT_SIZE - base, MAX_FONT_SIZE - base);
+        self.zoom.store(zoom, Ordering::Relaxed);
+
+        let new_size = self.font_size(settings);
+        self.set_scroll_y(self.scroll_y() * (new_size as f32 / old_size as f32));
+    }
+
+    fn reset_zoom(&self, settings: &EditorSettings) {
+        let zoom = self.zoom.load(Ordering::Relaxed);
+        self.zoom_by(settings, -zoom);
+    }
+
+    fn max_scroll_y(metrics: &EditorMetrics, line_count: usize) -> f32 {
+        (line_count - 1) as f32 * metrics.line_stride
+    }
+
+    unsafe fn handle_input(
+        &self,
+        ui_api: &UiApi,
+        ctx: &UiCtx,
+        document: &mut DocumentState,
+        line_count: usize,
+        minimap_hovering: bool,
+    ) -> bool {
+        let input = &*ctx.buffers.input;
+
+        let id = ui_api.make_id(ctx.ui);
+        let mut active = ui_api.is_active(ctx.ui, id, ANODE_CODE_EDITOR_ACTIVE_DATA.hash);
+
+        // Handle mouse input
+        if ui_api.is_hovering(ctx.ui, ctx.metrics.textarea_rect, (*ctx.ui_style).clip) {
+            (*ctx.buffers.activation).next_hover = id;
+        }
+
+        let is_hovering = (*ctx.buffers.activation).hover == id;
+        let mut should_activate = self.auto_activate.swap(false, Ordering::SeqCst);
+
+        if is_hovering {
+            ui_api.set_cursor(ctx.ui, TM_UI_CURSOR_TEXT);
+        }
+        self.update_hover(ctx, document, input, is_hovering);
+
+        let ctrl = (input.modifiers & TM_UI_MODIFIERS_CTRL as u32) != 0;
+        if is_hovering && ctrl && input.mouse_wheel != 0.0 {
+            self.zoom_by(&ctx.settings, input.mouse_wheel.signum() as i32);
+        } else if (is_hovering || minimap_hovering) && input.mouse_wheel != 0.0 {
+            let new_scroll_y = self.scroll_y() - input.mouse_wheel * ctx.settings.scroll_speed;
+            self.set_scroll_y(
+                new_scroll_y
+                    .max(0.0)
+                    .min(Self::max_scroll_y(&ctx.metrics, line_count)),
+            );
+        }
+
+        // Open the context menu where the user right clicks
+        if is_hovering && input.right_mouse_pressed {
+            *self.context_menu.lock().unwrap() = Some(input.mouse_pos);
+        }
+
+        // Activate or de-activate the component on mouse press
+        if input.left_mouse_pressed || input.right_mouse_pressed {
+            if is_hovering {
+                should_activate = true;
+            } else if (*ctx.buffers.activation).active == id {
+                ui_api.clear_active(ctx.ui);
+                active = null_mut();
+            }
+        }
+
+        // If this component should be activated, check if it isn't already and then activate
+        if should_activate && active.is_null() {
+            active = ui_api.set_active(ctx.ui, id, ANODE_CODE_EDITOR_ACTIVE_DATA.hash);
+            ui_api.set_responder_chain(ctx.ui, id);
+        }
+
+        // If the text area is active
+        if !active.is_null() {
+            self.handle_active_input(document, ctx, input);
+        }
+
+        !active.is_null()
+    }
+
+    /// Starts showing information about the word under the mouse once it rests there, and stops
+    /// once it leaves the word.
+    fn update_hover(
+        &self,
+        ctx: &UiCtx,
+        document: &DocumentState,
+        input: &UiInputStateT,
+        is_hovering: bool,
+    ) {
+        let mut hover = self.hover.lock().unwrap();
+        let mut mouse_rest = self.mouse_rest.lock().unwrap();
+
+        // Typing or clicking dismisses the tooltip, the text it's about may change
+        let interacting = input.num_text_input > 0
+            || input.left_mouse_is_down
+            || input.right_mouse_is_down
+            || self.context_menu.lock().unwrap().is_some();
+        let offset = if is_hovering && !interacting && document.binary().is_none() {
+            self.offset_at_position(ctx, document, input.mouse_pos)
+        } else {
+            None
+        };
+        let offset = match offset {
+            Some(offset) => offset,
+            None => {
+                *hover = None;
+                *mouse_rest = None;
+                return;
+            }
+        };
+
+        if let Some(current) = &*hover {
+            if current.contains(offset) {
+                return;
+            }
+            *hover = None;
+        }
+
+        let pos = input.mouse_pos;
+        match *mouse_rest {
+            Some((rest_pos, since)) if rest_pos.x == pos.x && rest_pos.y == pos.y => {
+                if since.elapsed() >= HOVER_DELAY {
+                    *hover = Hover::new(document, offset);
+                }
+            }
+            _ => *mouse_rest = Some((pos, Instant::now())),
+        }
+    }
+
+    /// Starts looking up the definition or references of the identifier at a byte offset.
+    fn navigate(&self, document: &DocumentState, offset: usize, kind: NavigationKind) {
+        let navigation = Navigation::new(document, offset, kind);
+        if navigation.is_some() {
+            *self.navigation.lock().unwrap() = navigation;
+        }
+    }
+
+    /// Byte offset of the grapheme under a position in the text area, if there's one there.
+    fn offset_at_position(
+        &self,
+        ctx: &UiCtx,
+        document: &DocumentState,
+        pos: Vec2T,
+    ) -> Option<usize> {
+        let metrics = &ctx.metrics;
+        let relative_x = pos.x - metrics.textarea_rect.x;
+        let relative_y = pos.y - metrics.textarea_rect.y + self.scroll_y() - metrics.caret_start;
+        if relative_x < 0.0 || relative_y < 0.0 {
+            return None;
+        }
+
+        let line = (relative_y / metrics.line_stride).floor() as usize;
+        let column = (relative_x / metrics.char_width).floor() as usize;
+        document.offset_at_cell(line, column)
+    }
+
+    unsafe fn handle_active_input(
+        &self,
+        document: &mut DocumentState,
+        ctx: &UiCtx,
+        input: &UiInputStateT,
+    ) {
+        let metrics = &ctx.metrics;
+        let mut completion = self.completion.lock().unwrap();
+        let popup_visible = |completion: &Option<CompletionPopup>| {
+            completion.as_ref().is_some_and(|v| v.is_visible())
+        };
+
+        if input.left_mouse_pressed {
+            *completion = None;
+
+            // Move the caret to the position the cursor is hovering over
+            let relative_x = input.mouse_pos.x - metrics.textarea_rect.x;
+            let relative_y = input.mouse_pos.y - metrics.textarea_rect.y + self.scroll_y();
+            let line = ((relative_y - metrics.caret_start) / metrics.line_stride)
+                .floor()
+                .max(0.0) as usize;
+            let offset = 4.0; // Feels just a bit better to have it offset a little
+            let column = ((relative_x + offset) / metrics.char_width)
+                .floor()
+                .max(0.0) as usize;
+
+            document.clear_selection();
+            document.set_caret_line_column(line, column);
+            document.set_caret_column_to_current();
+
+            // Control clicking an identifier goes to its definition
+            let ctrl = (input.modifiers & TM_UI_MODIFIERS_CTRL as u32) != 0;
+            if ctrl {
+                if let Some(offset) = self.offset_at_position(ctx, document, input.mouse_pos) {
+                    self.navigate(document, offset, NavigationKind::Definition);
+                }
+            }
+        }
+
+        // Handle text input, holding only control means it's a shortcut and not text
+        let ctrl_only =
+            (input.modifiers & TM_UI_MODIFIERS_ALT_CTRL as u32) == TM_UI_MODIFIERS_CTRL as u32;
+        let end = input.num_text_input as usize;
+        for codepoint in &input.text_input[0..end] {
+            match *codepoint {
+                // Tab and enter insert the selected completion instead, if there is one
+                9 | 13 if popup_visible(&completion) => {
+                    if let Some(popup) = completion.take() {
+                        popup.accept(document);
+                    }
+                }
+                8 => document.apply_input_backspace(),
+                9 => document.apply_input_tab(ctx.settings.tab_width),
+                13 => document.apply_input_character('\n'),
+                // Ignore all other control characters
+                v if v < 32 => continue,
+                _ if ctrl_only => continue,
+                // Any text input
+                _ => {
+                    let character = std::char::from_u32(*codepoint).unwrap_or(' ');
+                    document.apply_input_character(character);
+
+                    // Offer completions once an identifier is being typed
+                    if completion.is_none() && text::is_identifier_char(character) {
+                        *completion = CompletionPopup::open(document, false);
+                    }
+                }
+            }
+        }
+
+        // Handle special edit input
+        let ctrl = (input.modifiers & TM_UI_MODIFIERS_CTRL as u32) != 0;
+        if input.edit_key_pressed[TM_UI_EDIT_KEY_LEFT as usize] {
+            document.apply_input_left(ctrl);
+        }
+        if input.edit_key_pressed[TM_UI_EDIT_KEY_RIGHT as usize] {
+            document.apply_input_right(ctrl);
+        }
+        if input.edit_key_pressed[TM_UI_EDIT_KEY_UP as usize] {
+            match completion.as_mut() {
+                Some(popup) if popup.is_visible() => popup.select_previous(),
+                _ => document.apply_input_up(),
+            }
+        }
+        if input.edit_key_pressed[TM_UI_EDIT_KEY_DOWN as usize] {
+            match completion.as_mut() {
+                Some(popup) if popup.is_visible() => popup.select_next(),
+                _ => document.apply_input_down(),
+            }
+        }
+        if input.edit_key_pressed[TM_UI_EDIT_KEY_ESCAPE as usize] {
+            if completion.is_some() {
+                *completion = None;
+            } else {
+                *self.references.lock().unwrap() = None;
+            }
+        }
+
+        if input.edit_key_pressed[TM_UI_EDIT_KEY_DELETE as usize] {
+            document.apply_input_delete();
+        }
+
+        // Jump between errors
+        let key_pressed = |key| *input.key_pressed.offset(key as isize);
+        if key_pressed(TM_INPUT_KEYBOARD_ITEM_F8) {
+            let shift = (input.modifiers & TM_UI_MODIFIERS_SHIFT as u32) != 0;
+            document.goto_error(&ctx.diagnostics, !shift);
+        }
+
+        // Go to the definition of the identifier at the caret, or find its references
+        if key_pressed(TM_INPUT_KEYBOARD_ITEM_F12) {
+            let shift = (input.modifiers & TM_UI_MODIFIERS_SHIFT as u32) != 0;
+            let kind = if shift {
+                NavigationKind::References
+            } else {
+                NavigationKind::Definition
+            };
+            self.navigate(document, document.caret(), kind);
+        }
+
+        // Handle zoom shortcuts
+        if ctrl_only {
+            if key_pressed(TM_INPUT_KEYBOARD_ITEM_EQUAL)
+                || key_pressed(TM_INPUT_KEYBOARD_ITEM_NUMPADPLUS)
+            {
+                self.zoom_by(&ctx.settings, 1);
+            }
+            if key_pressed(TM_INPUT_KEYBOARD_ITEM_MINUS)
+                || key_pressed(TM_INPUT_KEYBOARD_ITEM_NUMPADMINUS)
+            {
+                self.zoom_by(&ctx.settings, -1);
+            }
+            if key_pressed(TM_INPUT_KEYBOARD_ITEM_0) || key_pressed(TM_INPUT_KEYBOARD_ITEM_NUMPAD0)
+            {
+                self.reset_zoom(&ctx.settings);
+            }
+
+            if key_pressed(TM_INPUT_KEYBOARD_ITEM_SPACE) {
+                *completion = CompletionPopup::open(document, true);
+            }
+        }
+
+        // Follow the word being completed, closing the popup once the caret leaves it
+        let keep_completion = match completion.as_mut() {
+            Some(popup) => popup.update(document.text(), document.caret()),
+            None => true,
+        };
+        if !keep_completion {
+            *completion = None;
+        }
+    }
+
+    unsafe fn draw_decorations(
+        &self,
+        ctx: &UiCtx,
+        style: &mut Draw2dStyleT,
+        textarea_clip: u32,
+        glyphs: &mut Vec<u16>,
+        document: &DocumentState,
+        line_count: usize,
+    ) {
+        let theme = self.theme(ctx);
+        style.color = theme.line_number;
+
+        for i in 0..line_count {
+            // Draw the gutter (left side line numbers)
+            let digits = digits(i as u32 + 1);
+            let pos = Vec2T {
+                x: ctx.metrics.tab_rect.x,
+                y: ctx.metrics.tab_rect.y
+                    + ctx.metrics.first_baseline
+                    + (ctx.metrics.line_stride * i as f32)
+                    - self.scroll_y(),
+            };
+            self.draw_text(ctx, style, pos, glyphs, &digits);
+        }
+        self.draw_diagnostic_icons(ctx);
+
+        // Draw the rulers, clipped to the text area so they don't overlap the minimap
+        style.color = theme.ruler;
+        style.clip = textarea_clip;
+        for column in document.rulers().unwrap_or(&ctx.settings.rulers) {
+            let rect = RectT {
+                x: ruler_x(&ctx.metrics, *column),
+                y: ctx.metrics.textarea_rect.y,
+                w: 1.0,
+                h: ctx.metrics.textarea_rect.h,
+            };
+            (*self.data.apis.draw2d).fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, style, rect);
+        }
+        style.clip = (*ctx.ui_style).clip;
+    }
+
+    /// Draws an icon in the gutter for the most severe diagnostic on each line, with the messages
+    /// of all diagnostics on the line as tooltip.
+    unsafe fn draw_diagnostic_icons(&self, ctx: &UiCtx) {
+        let ui_api = &*self.data.apis.ui;
+
+        let mut lines: BTreeMap<usize, Vec<&Diagnostic>> = BTreeMap::new();
+        for diagnostic in &ctx.diagnostics {
+            lines
+                .entry(diagnostic.start.0)
+                .or_default()
+                .push(diagnostic);
+        }
+
+        for (line, diagnostics) in lines {
+            let severity = diagnostics.iter().map(|d| d.severity).min().unwrap();
+            let icon = match severity {
+                AnodeSeverity::Error => IONICON__CLOSE_CIRCLE,
+                AnodeSeverity::Warning => IONICON__WARNING,
+                AnodeSeverity::Information | AnodeSeverity::Hint => IONICON__INFORMATION_CIRCLE,
+            };
+            let icon = std::char::from_u32(icon as u32).unwrap_or('!').to_string();
+            let icon = CString::new(icon).unwrap_or_default();
+
+            let tooltip: Vec<String> = diagnostics.iter().map(|d| d.tooltip()).collect();
+            let tooltip = ui_string(&tooltip.join("\n"));
+
+            let color = self.theme(ctx).severity_color(severity);
+            let text = UiTextT {
+                id: ui_api.make_id(ctx.ui),
+                rect: RectT {
+                    x: ctx.metrics.textarea_rect.x - ctx.metrics.char_width * 2.0,
+                    y: ctx.metrics.textarea_rect.y
+                        + ctx.metrics.caret_start
+                        + (ctx.metrics.line_stride * line as f32)
+                        - self.scroll_y(),
+                    w: ctx.metrics.char_width * 2.0,
+                    h: ctx.metrics.line_stride,
+                },
+                text: icon.as_ptr(),
+                tooltip: tooltip.as_ptr(),
+                color: &color,
+                align: TM_UI_ALIGN_CENTER,
+                ..Default::default()
+            };
+            ui_api.text(ctx.ui, ctx.ui_style, &text);
+        }
+    }
+
+    /// Shades the text past the last ruler, if the document wants it.
+    unsafe fn draw_ruler_shading(&self, ctx: &UiCtx, textarea_clip: u32, document: &DocumentState) {
+        let rulers = document.rulers().unwrap_or(&ctx.settings.rulers);
+        let last = match rulers.last() {
+            Some(last) if document.shade_past_rulers() => *last,
+            _ => return,
+        };
+
+        let x = ruler_x(&ctx.metrics, last) + 1.0;
+        let textarea_end = ctx.metrics.textarea_rect.x + ctx.metrics.textarea_rect.w;
+        let rect = RectT {
+            x,
+            y: ctx.metrics.textarea_rect.y,
+            w: (textarea_end - x).max(0.0),
+            h: ctx.metrics.textarea_rect.h,
+        };
+        let style = Draw2dStyleT {
+            color: ColorSrgbT {
+                a: RULER_SHADING_ALPHA,
+                ..self.theme(ctx).background
+            },
+            clip: textarea_clip,
+            ..Default::default()
+        };
+        (*self.data.apis.draw2d).fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, &style, rect);
+    }
+
+    unsafe fn draw_caret(&self, ctx: &UiCtx, document: &DocumentState, clip: u32) {
+        let (line, column) = document.caret_line_column();
+        let color = self.theme(ctx).caret;
+        self.draw_caret_at(ctx, line, column, color, clip);
+    }
+
+    /// Draws the carets of collaborators, labeled with their names.
+    unsafe fn draw_remote_carets(
+        &self,
+        ui_api: &UiApi,
+        ctx: &UiCtx,
+        document: &DocumentState,
+        clip: u32,
+    ) {
+        for caret in document.remote_carets() {
+            let (line, column) = document.line_column(caret.offset);
+            let color = self.theme(ctx).collaborator_color(caret.site);
+            let pos = self.draw_caret_at(ctx, line, column, color, clip);
+
+            // The name goes above the line, so it doesn't cover the text being edited
+            let name = CString::new(caret.name).unwrap_or_default();
+            let text = UiTextT {
+                rect: RectT {
+                    x: pos.x,
+                    y: pos.y - ctx.metrics.line_stride,
+                    w: ctx.metrics.textarea_rect.w,
+                    h: ctx.metrics.line_stride,
+                },
+                text: name.as_ptr(),
+                color: &color,
+                align: TM_UI_ALIGN_LEFT,
+                ..Default::default()
+            };
+            ui_api.text(ctx.ui, ctx.ui_style, &text);
+        }
+    }
+
+    /// Draws a caret before a line and column, returning its top left position.
+    unsafe fn draw_caret_at(
+        &self,
+        ctx: &UiCtx,
+        line: usize,
+        column: usize,
+        color: ColorSrgbT,
+        clip: u32,
+    ) -> Vec2T {
+        let pos = Vec2T {
+            x: ctx.metrics.textarea_rect.x + (column as f32 * ctx.metrics.char_width),
+            y: ctx.metrics.textarea_rect.y
+                + ctx.metrics.caret_start
+                + (ctx.metrics.line_stride * line as f32)
+                - self.scroll_y(),
+        };
+
+        let caret = RectT {
+            x: pos.x - 1.0,
+            y: pos.y,
+            w: 2.0,
+            h: ctx.metrics.line_stride,
+        };
+        let style = Draw2dStyleT {
+            color,
+            clip,
+            ..Default::default()
+        };
+        (*self.data.apis.draw2d).fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, &style, caret);
+
+        pos
+    }
+
+    unsafe fn draw_scrollbar(&self, ui_api: &UiApi, ctx: &UiCtx, line_count: usize) {
+        let mut scroll_y = self.scroll_y();
+
+        let lines_per_height = ctx.metrics.textarea_rect.h / ctx.metrics.line_stride;
+        let rect = RectT {
+            x: ctx.metrics.scrollbar_x,
+            y: ctx.metrics.tab_rect.y,
+            w: ctx.metrics.scrollbar_width,
+            h: ctx.metrics.tab_rect.h,
+        };
+        let scrollbar = UiScrollbarT {
+            rect,
+            min: 0.0,
+            max: ((line_count - 1) as f32 + lines_per_height) * ctx.metrics.line_stride,
+            size: lines_per_height * ctx.metrics.line_stride,
+            ..Default::default()
+        };
+        ui_api.scrollbar_y(ctx.ui, ctx.ui_style, &scrollbar, &mut scroll_y);
+        self.set_scroll_y(scroll_y);
+    }
+
+    /// Draws the caret position and the document's format, which can be changed from here.
+    unsafe fn draw_status_bar(
+        &self,
+        ui_api: &UiApi,
+        ctx: &UiCtx,
+        document: &mut DocumentState,
+        rect: RectT,
+    ) {
+        let style = Draw2dStyleT {
+            color: theme_color(ctx, TM_UI_COLOR_WINDOW_STATUS_BAR, 255),
+            clip: (*ctx.ui_style).clip,
+            ..Default::default()
+        };
+        (*self.data.apis.draw2d).fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, &style, rect);
+
+        let margin = *ctx.buffers.metrics.offset(TM_UI_METRIC_MARGIN as isize);
+
+        // Caret position, or the size of content we can't show the caret in
+        let position = match document.binary() {
+            Some(bytes) => format!("Binary, read-only ({} bytes)", bytes.len()),
+            None => {
+                let (line, column) = document.caret_line_column();
+                let read_only = if document.is_read_only() {
+                    ", read-only"
+                } else {
+                    ""
+                };
+                let mut position = format!("Ln {}, Col {}{}", line + 1, column + 1, read_only);
+
+                // Count problems by severity, only showing the icons of those that occur
+                let count = |severity| {
+                    ctx.diagnostics
+                        .iter()
+                        .filter(|d| d.severity == severity)
+                        .count()
+                };
+                let counts = [
+                    (IONICON__CLOSE_CIRCLE, count(AnodeSeverity::Error)),
+                    (IONICON__WARNING, count(AnodeSeverity::Warning)),
+                ];
+                for (icon, count) in counts.iter().filter(|(_, count)| *count > 0) {
+                    let icon = std::char::from_u32(*icon as u32).unwrap_or('!');
+                    position.push_str(&format!("    {} {}", icon, count));
+                }
+
+                // Symbols the caret is in, as reported by the language server
+                let handle = self.document_handle as usize;
+                let language_servers = self.data.language_servers.lock().unwrap();
+                let symbols = language_servers.symbols_at(handle, document.caret());
+                if !symbols.is_empty() {
+                    position.push_str(&format!("    {}", symbols.join(" > ")));
+                }
+
+                position
+            }
+        };
+        let position = ui_string(&position);
+        let text_color = theme_color(ctx, TM_UI_COLOR_WINDOW_STATUS_BAR_TEXT, 255);
+        let text = UiTextT {
+            rect: RectT {
+                x: rect.x + margin,
+                w: rect.w * 0.5,
+                ..rect
+            },
+            text: position.as_ptr(),
+            color: &text_color,
+            align: TM_UI_ALIGN_LEFT,
+            ..Default::default()
+        };
+        ui_api.text(ctx.ui, ctx.ui_style, &text);
+
+        if document.binary().is_some() {
+            self.draw_reinterpret_dropdown(ui_api, ctx, document, rect, margin);
+            return;
+        }
+
+        // Format dropdowns, aligned to the right
+        let format = document.format();
+        if document.is_read_only() {
+            // The format can't be changed, so only show it
+            let names = format!(
+                "{}    {}",
+                format.encoding.name().as_cstr().to_string_lossy(),
+                format.line_ending.name().as_cstr().to_string_lossy()
+            );
+            let names = CString::new(names).unwrap();
+            let text = UiTextT {
+                rect: RectT {
+                    x: rect.x + rect.w * 0.5,
+                    w: rect.w * 0.5 - margin,
+                    ..rect
+                },
+                text: names.as_ptr(),
+                color: &text_color,
+                align: TM_UI_ALIGN_RIGHT,
+                ..Default::default()
+            };
+            ui_api.text(ctx.ui, ctx.ui_style, &text);
+            return;
+        }
+
+        let line_ending_rect = RectT {
+            x: rect.x + rect.w - STATUS_BAR_DROPDOWN_WIDTH - margin,
+            w: STATUS_BAR_DROPDOWN_WIDTH,
+            ..rect
+        };
+        let encoding_rect = RectT {
+            x: line_ending_rect.x - STATUS_BAR_DROPDOWN_WIDTH - margin,
+            ..line_ending_rect
+        };
+
+        let mut encoding_names = Encoding::ALL.map(|v| v.name().as_ptr());
+        let mut encoding = Encoding::ALL
+            .iter()
+            .position(|v| *v == format.encoding)
+            .unwrap_or(0) as u32;
+        let encoding_dropdown = UiDropdownT {
+            id: ui_api.make_id(ctx.ui),
+            rect: encoding_rect,
+            items: encoding_names.as_mut_ptr(),
+            num_items: encoding_names.len() as u32,
+            ..Default::default()
+        };
+        let encoding_changed =
+            ui_api.dropdown(ctx.ui, ctx.ui_style, &encoding_dropdown, &mut encoding);
+
+        let mut line_ending_names = LineEnding::ALL.map(|v| v.name().as_ptr());
+        let mut line_ending = LineEnding::ALL
+            .iter()
+            .position(|v| *v == format.line_ending)
+            .unwrap_or(0) as u32;
+        let line_ending_dropdown = UiDropdownT {
+            id: ui_api.make_id(ctx.ui),
+            rect: line_ending_rect,
+            items: line_ending_names.as_mut_ptr(),
+            num_items: line_ending_names.len() as u32,
+            ..Default::default()
+        };
+        let line_ending_changed = ui_api.dropdown(
+            ctx.ui,
+            ctx.ui_style,
+            &line_ending_dropdown,
+            &mut line_ending,
+        );
+
+        if encoding_changed || line_ending_changed {
+            let format = TextFormat {
+                encoding: Encoding::ALL[encoding as usize],
+                line_ending: LineEnding::ALL[line_ending as usize],
+            };
+            if let Err(error) = document.set_format(&self.data, format) {
+                event!(Level::ERROR, "{}", error);
+            }
+        }
+    }
+
+    /// Draws the references panel, returns the reference that was clicked.
+    unsafe fn draw_references(&self, ui_api: &UiApi, ctx: &UiCtx, rect: RectT) -> Option<Location> {
+        let mut references_lock = self.references.lock().unwrap();
+        let references = references_lock.as_mut()?;
+        let input = &*ctx.buffers.input;
+        let margin = *ctx.buffers.metrics.offset(TM_UI_METRIC_MARGIN as isize);
+        let row_height = *ctx
+            .buffers
+            .metrics
+            .offset(TM_UI_METRIC_MENU_ITEM_HEIGHT as isize);
+        let draw2d = &*self.data.apis.draw2d;
+
+        let mut style = Draw2dStyleT {
+            color: theme_color(ctx, TM_UI_COLOR_MENU_BACKGROUND, 255),
+            clip: (*ctx.ui_style).clip,
+            ..Default::default()
+        };
+        draw2d.fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, &style, rect);
+
+        // Header with the title and a button to close the panel
+        let header = RectT {
+            h: row_height,
+            ..rect
+        };
+        style.color = theme_color(ctx, TM_UI_COLOR_WINDOW_STATUS_BAR, 255);
+        draw2d.fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, &style, header);
+
+        let text_color = theme_color(ctx, TM_UI_COLOR_WINDOW_STATUS_BAR_TEXT, 255);
+        let title = ui_string(references.title());
+        let text = UiTextT {
+            rect: RectT {
+                x: header.x + margin,
+                w: header.w - REFERENCES_BUTTON_WIDTH - margin * 3.0,
+                ..header
+            },
+            text: title.as_ptr(),
+            color: &text_color,
+            align: TM_UI_ALIGN_LEFT,
+            ..Default::default()
+        };
+        ui_api.text(ctx.ui, ctx.ui_style, &text);
+
+        let close = UiButtonT {
+            id: ui_api.make_id(ctx.ui),
+            rect: RectT {
+                x: header.x + header.w - REFERENCES_BUTTON_WIDTH - margin,
+                w: REFERENCES_BUTTON_WIDTH,
+                ..header
+            },
+            text: const_cstr!("Close").as_ptr(),
+            ..Default::default()
+        };
+        if ui_api.button(ctx.ui, ctx.ui_style, &close) {
+            *references_lock = None;
+            return None;
+        }
+
+        // The rows, which scroll with the mouse wheel and open the reference they're clicked on
+        let list = RectT {
+            y: header.y + header.h,
+            h: rect.h - header.h,
+            ..rect
+        };
+        let rows = (list.h / row_height).floor().max(1.0) as usize;
+        let id = ui_api.make_id(ctx.ui);
+        if ui_api.is_hovering(ctx.ui, list, (*ctx.ui_style).clip) {
+            (*ctx.buffers.activation).next_hover = id;
+        }
+        let is_hovering = (*ctx.buffers.activation).hover == id;
+        if is_hovering && input.mouse_wheel != 0.0 {
+            references.scroll_by(-input.mouse_wheel.signum() as isize, rows);
+        }
+        let hovered_row = if is_hovering {
+            Some(((input.mouse_pos.y - list.y) / row_height).floor() as usize)
+        } else {
+            None
+        };
+
+        let text_color = theme_color(ctx, TM_UI_COLOR_TEXT, 255);
+        let mut clicked = None;
+        for (i, (location, label)) in references.visible_items(rows).enumerate() {
+            let row = RectT {
+                y: list.y + i as f32 * row_height,
+                h: row_height,
+                ..list
+            };
+            if hovered_row == Some(i) {
+                style.color = theme_color(ctx, TM_UI_COLOR_MENU_SELECTED, 255);
+                draw2d.fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, &style, row);
+                if input.left_mouse_pressed {
+                    clicked = Some(location.clone());
+                }
+            }
+
+            let label = ui_string(label);
+            let text = UiTextT {
+                rect: RectT {
+                    x: row.x + margin,
+                    w: row.w - margin * 2.0,
+                    ..row
+                },
+                text: label.as_ptr(),
+                color: &text_color,
+                align: TM_UI_ALIGN_LEFT,
+                ..Default::default()
+            };
+            ui_api.text(ctx.ui, ctx.ui_style, &text);
+        }
+
+        clicked
+    }
+
+    /// Draws the completion popup below the word being completed, or above it if there's no room.
+    unsafe fn draw_completion(&self, ctx: &UiCtx, document: &DocumentState, glyphs: &mut Vec<u16>) {
+        let completion = self.completion.lock().unwrap();
+        let popup = match &*completion {
+            Some(popup) if popup.is_visible() => popup,
+            _ => return,
+        };
+        let metrics = &ctx.metrics;
+        let items: Vec<_> = popup.visible_items().collect();
+
+        // Size the popup to fit the labels, with the details right aligned after them
+        let padding = metrics.char_width;
+        let label_width = items
+            .iter()
+            .map(|(item, _)| item.label.chars().count())
+            .max()
+            .unwrap_or(0);
+        let detail_width = items
+            .iter()
+            .map(|(item, _)| item.detail.chars().count())
+            .max()
+            .unwrap_or(0);
+        let columns = if detail_width > 0 {
+            label_width + 2 + detail_width
+        } else {
+            label_width
+        };
+        let width = (columns as f32 * metrics.char_width + padding * 2.0)
+            .min(metrics.textarea_rect.w * COMPLETION_MAX_WIDTH);
+        let max_columns = ((width - padding * 2.0) / metrics.char_width).floor() as usize;
+        let height = items.len() as f32 * metrics.line_stride;
+
+        let (line, column) = document.line_column(popup.start());
+        let line_y =
+            metrics.textarea_rect.y + metrics.caret_start + (metrics.line_stride * line as f32)
+                - self.scroll_y();
+        let below = line_y + metrics.line_stride;
+        let textarea_bottom = metrics.textarea_rect.y + metrics.textarea_rect.h;
+        let rect = RectT {
+            x: (metrics.textarea_rect.x + column as f32 * metrics.char_width - padding)
+                .min(metrics.textarea_rect.x + metrics.textarea_rect.w - width)
+                .max(metrics.textarea_rect.x),
+            y: if below + height > textarea_bottom && line_y - height >= metrics.textarea_rect.y {
+                line_y - height
+            } else {
+                below
+            },
+            w: width,
+            h: height,
+        };
+
+        let draw2d = &*self.data.apis.draw2d;
+        let mut style = Draw2dStyleT {
+            color: theme_color(ctx, TM_UI_COLOR_THIN_LINES, 255),
+            clip: (*ctx.ui_style).clip,
+            font: ctx.code_font.font.font,
+            font_scale: 1.0,
+            ..Default::default()
+        };
+        let border = RectT {
+            x: rect.x - 1.0,
+            y: rect.y - 1.0,
+            w: rect.w + 2.0,
+            h: rect.h + 2.0,
+        };
+        draw2d.fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, &style, border);
+        style.color = theme_color(ctx, TM_UI_COLOR_MENU_BACKGROUND, 255);
+        draw2d.fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, &style, rect);
+
+        let text_color = self.theme(ctx).text;
+        let detail_color = theme_color(ctx, TM_UI_COLOR_DISABLED_TEXT, 255);
+        for (i, (item, selected)) in items.iter().enumerate() {
+            let row_y = rect.y + i as f32 * metrics.line_stride;
+            if *selected {
+                style.color = theme_color(ctx, TM_UI_COLOR_MENU_SELECTED, 255);
+                let row = RectT {
+                    y: row_y,
+                    h: metrics.line_stride,
+                    ..rect
+                };
+                draw2d.fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, &style, row);
+            }
+
+            // Labels get priority over details when there isn't room for both
+            let baseline = row_y + metrics.first_baseline - metrics.caret_start;
+            let label: Vec<u32> = item
+                .label
+                .chars()
+                .take(max_columns)
+                .map(u32::from)
+                .collect();
+            style.color = text_color;
+            let pos = Vec2T {
+                x: rect.x + padding,
+                y: baseline,
+            };
+            self.draw_text(ctx, &style, pos, glyphs, &label);
+
+            let detail_columns = max_columns.saturating_sub(label.len() + 2);
+            let detail: Vec<u32> = item
+                .detail
+                .chars()
+                .take(detail_columns)
+                .map(u32::from)
+                .collect();
+            if !detail.is_empty() {
+                style.color = detail_color;
+                let pos = Vec2T {
+                    x: rect.x + rect.w - padding - detail.len() as f32 * metrics.char_width,
+                    y: baseline,
+                };
+                self.draw_text(ctx, &style, pos, glyphs, &detail);
+            }
+        }
+    }
+
+    /// Draws the information about the word under the mouse, below the word.
+    unsafe fn draw_hover(&self, ctx: &UiCtx, document: &DocumentState, glyphs: &mut Vec<u16>) {
+        let hover = self.hover.lock().unwrap();
+        let hover = match &*hover {
+            Some(hover) if !hover.sections().is_empty() => hover,
+            _ => return,
+        };
+        let metrics = &ctx.metrics;
+        let padding = metrics.char_width;
+
+        // Wrap to fit the text area, sections from different providers are separated by a line
+        let max_columns = (((metrics.textarea_rect.w - padding * 2.0) / metrics.char_width).floor()
+            as usize)
+            .min(HOVER_MAX_COLUMNS);
+        let mut lines = Vec::new();
+        for section in hover.sections() {
+            if !lines.is_empty() {
+                lines.push(markdown::Line {
+                    kind: LineKind::Rule,
+                    text: String::new(),
+                });
+            }
+            lines.extend(markdown::layout(section, max_columns));
+        }
+        lines.truncate(HOVER_MAX_LINES);
+
+        let columns = lines
+            .iter()
+            .map(|line| line.text.chars().count())
+            .max()
+            .unwrap_or(0)
+            .min(max_columns);
+        let width = columns as f32 * metrics.char_width + padding * 2.0;
+        let height = lines.len() as f32 * metrics.line_stride + padding;
+
+        // Below the word, or above it if there's no room
+        let (line, column) = document.line_column(hover.start());
+        let line_y =
+            metrics.textarea_rect.y + metrics.caret_start + (metrics.line_stride * line as f32)
+                - self.scroll_y();
+        let below = line_y + metrics.line_stride;
+        let textarea_bottom = metrics.textarea_rect.y + metrics.textarea_rect.h;
+        let rect = RectT {
+            x: (metrics.textarea_rect.x + column as f32 * metrics.char_width - padding)
+                .min(metrics.textarea_rect.x + metrics.textarea_rect.w - width)
+                .max(metrics.textarea_rect.x),
+            y: if below + height > textarea_bottom && line_y - height >= metrics.textarea_rect.y {
+                line_y - height
+            } else {
+                below
+            },
+            w: width,
+            h: height,
+        };
+
+        let draw2d = &*self.data.apis.draw2d;
+        let mut style = Draw2dStyleT {
+            color: theme_color(ctx, TM_UI_COLOR_TOOLTIP_BORDER, 255),
+            clip: (*ctx.ui_style).clip,
+            font: ctx.code_font.font.font,
+            font_scale: 1.0,
+            ..Default::default()
+        };
+        let border = RectT {
+            x: rect.x - 1.0,
+            y: rect.y - 1.0,
+            w: rect.w + 2.0,
+            h: rect.h + 2.0,
+        };
+        draw2d.fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, &style, border);
+        style.color = theme_color(ctx, TM_UI_COLOR_TOOLTIP_BACKGROUND, 255);
+        draw2d.fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, &style, rect);
+
+        let text_color = theme_color(ctx, TM_UI_COLOR_TOOLTIP_TEXT, 255);
+        let theme = self.theme(ctx);
+        for (i, line) in lines.iter().enumerate() {
+            let row = RectT {
+                y: rect.y + padding * 0.5 + i as f32 * metrics.line_stride,
+                h: metrics.line_stride,
+                ..rect
+            };
+
+            // Code is shown like in the editor, headings are underlined
+            style.color = match line.kind {
+                LineKind::Code => {
+                    style.color = theme.background;
+                    draw2d.fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, &style, row);
+                    theme.text
+                }
+                LineKind::Heading | LineKind::Rule => {
+                    let y = if line.kind == LineKind::Rule {
+                        row.y + (row.h * 0.5).floor()
+                    } else {
+                        row.y + row.h - 1.0
+                    };
+                    style.color = theme_color(ctx, TM_UI_COLOR_TOOLTIP_BORDER, 255);
+                    let underline = RectT {
+                        x: row.x + padding,
+                        y,
+                        w: row.w - padding * 2.0,
+                        h: 1.0,
+                    };
+                    draw2d.fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, &style, underline);
+                    text_color
+                }
+                LineKind::Text => text_color,
+            };
+
+            let codepoints: Vec<u32> = line.text.chars().take(columns).map(u32::from).collect();
+            let pos = Vec2T {
+                x: row.x + padding,
+                y: row.y + metrics.first_baseline - metrics.caret_start,
+            };
+            self.draw_text(ctx, &style, pos, glyphs, &codepoints);
+        }
+    }
+
+    /// Asks the user what to do about external changes conflicting with local edits.
+    unsafe fn draw_conflict_bar(&self, ui_api: &UiApi, ctx: &UiCtx, document: &mut DocumentState) {
+        let height = *ctx
+            .buffers
+            .metrics
+            .offset(TM_UI_METRIC_MENU_ITEM_HEIGHT as isize);
+        let margin = *ctx.buffers.metrics.offset(TM_UI_METRIC_MARGIN as isize);
+        let rect = RectT {
+            h: height,
+            ..ctx.metrics.textarea_rect
+        };
+
+        let style = Draw2dStyleT {
+            color: theme_color(ctx, TM_UI_COLOR_WINDOW_STATUS_BAR, 255),
+            clip: (*ctx.ui_style).clip,
+            ..Default::default()
+        };
+        (*self.data.apis.draw2d).fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, &style, rect);
+
+        let text_color = theme_color(ctx, TM_UI_COLOR_ERROR_TEXT, 255);
+        let text = UiTextT {
+            rect: RectT {
+                x: rect.x + margin,
+                ..rect
+            },
+            text: const_cstr!("The asset was changed outside of this editor.").as_ptr(),
+            color: &text_color,
+            align: TM_UI_ALIGN_LEFT,
+            ..Default::default()
+        };
+        ui_api.text(ctx.ui, ctx.ui_style, &text);
+
+        let keep_rect = RectT {
+            x: rect.x + rect.w - CONFLICT_BUTTON_WIDTH - margin,
+            w: CONFLICT_BUTTON_WIDTH,
+            ..rect
+        };
+        let reload_rect = RectT {
+            x: keep_rect.x - CONFLICT_BUTTON_WIDTH - margin,
+            ..keep_rect
+        };
+
+        let reload = UiButtonT {
+            id: ui_api.make_id(ctx.ui),
+            rect: reload_rect,
+            text: const_cstr!("Reload").as_ptr(),
+            tooltip: const_cstr!("Discard local edits and show the changed asset.").as_ptr(),
+            ..Default::default()
+        };
+        if ui_api.button(ctx.ui, ctx.ui_style, &reload) {
+            document.resolve_conflict(&self.data, false);
+        }
+
+        let keep = UiButtonT {
+            id: ui_api.make_id(ctx.ui),
+            rect: keep_rect,
+            text: const_cstr!("Keep Mine").as_ptr(),
+            tooltip: const_cstr!("Overwrite the changed asset with local edits.").as_ptr(),
+            ..Default::default()
+        };
+        if ui_api.button(ctx.ui, ctx.ui_style, &keep) {
+            document.resolve_conflict(&self.data, true);
+        }
+    }
+
+    /// Dropdown to decode binary content as text in an explicit encoding.
+    unsafe fn draw_reinterpret_dropdown(
+        &self,
+        ui_api: &UiApi,
+        ctx: &UiCtx,
+        document: &mut DocumentState,
+        rect: RectT,
+        margin: f32,
+    ) {
+        let mut names = vec![const_cstr!("Hex").as_ptr()];
+        names.extend(Encoding::ALL.iter().map(|v| v.name().as_ptr()));
+        let mut selected = 0;
+        let dropdown = UiDropdownT {
+            id: ui_api.make_id(ctx.ui),
+            rect: RectT {
+                x: rect.x + rect.w - STATUS_BAR_DROPDOWN_WIDTH - margin,
+                w: STATUS_BAR_DROPDOWN_WIDTH,
+                ..rect
+            },
+            items: names.as_mut_ptr(),
+            num_items: names.len() as u32,
+            ..Default::default()
+        };
+
+        let changed = ui_api.dropdown(ctx.ui, ctx.ui_style, &dropdown, &mut selected);
+        if changed && selected != 0 {
+            let encoding = Encoding::ALL[selected as usize - 1];
+            if let Err(error) = document.reinterpret(encoding) {
+                event!(Level::ERROR, "{}", error);
+            }
+        }
+    }
+
+    /// Handles scrolling by clicking and dragging on the minimap, returns if it's hovered.
+    unsafe fn handle_minimap_input(&self, ui_api: &UiApi, ctx: &UiCtx, line_count: usize) -> bool {
+        let minimap_rect = match ctx.metrics.minimap_rect {
+            Some(rect) => rect,
+            None => return false,
+        };
+        let input = &*ctx.buffers.input;
+
+        let id = ui_api.make_id(ctx.ui);
+        if ui_api.is_hovering(ctx.ui, minimap_rect, (*ctx.ui_style).clip) {
+            (*ctx.buffers.activation).next_hover = id;
+        }
+        let is_hovering = (*ctx.buffers.activation).hover == id;
+
+        let mut drag = self.minimap_drag.lock().unwrap();
+        if is_hovering && input.left_mouse_pressed {
+            // Grab the viewport where the user clicked it, or center it on the click otherwise
+            let layout = MinimapLayout::calculate(&ctx.metrics, line_count, self.scroll_y());
+            let viewport = layout.viewport;
+            let grab_y = input.mouse_pos.y - viewport.y;
+            *drag = if grab_y >= 0.0 && grab_y <= viewport.h {
+                Some(grab_y)
+            } else {
+                Some(viewport.h * 0.5)
+            };
+        }
+
+        if !input.left_mouse_is_down {
+            *drag = None;
+        }
+
+        if let Some(grab_y) = *drag {
+            // Solve the scroll position that puts the viewport's top at the wanted position, the
+            // viewport position is linear relative to the scroll position
+            let max_scroll_y = Self::max_scroll_y(&ctx.metrics, line_count);
+            let end = MinimapLayout::calculate(&ctx.metrics, line_count, max_scroll_y);
+            let target = input.mouse_pos.y - grab_y - minimap_rect.y;
+            let travel = end.viewport.y - minimap_rect.y;
+
+            if travel > 0.0 {
+                let scroll_y = (target / travel) * max_scroll_y;
+                self.set_scroll_y(scroll_y.max(0.0).min(max_scroll_y));
+            }
+        }
+
+        is_hovering || drag.is_some()
+    }
+
+    unsafe fn draw_minimap(
+        &self,
+        ctx: &UiCtx,
+        style: &mut Draw2dStyleT,
+        document: &DocumentState,
+        line_count: usize,
+    ) {
+        let minimap_rect = match ctx.metrics.minimap_rect {
+            Some(rect) => rect,
+            None => return,
+        };
+        let draw2d = &*self.data.apis.draw2d;
+        let layout = MinimapLayout::calculate(&ctx.metrics, line_count, self.scroll_y());
+
+        style.clip = draw2d.add_clip_rect(ctx.buffers.vbuffer, minimap_rect);
+
+        // Draw the background
+        let theme = self.theme(ctx);
+        style.color = theme.minimap_background;
+        draw2d.fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, style, minimap_rect);
+
+        // Only lines that are visible in the minimap need to be drawn
+        let first_line = (layout.offset / MINIMAP_LINE_HEIGHT).floor() as usize;
+        let last_line = first_line + (minimap_rect.h / MINIMAP_LINE_HEIGHT).ceil() as usize;
+        let max_columns = (minimap_rect.w / MINIMAP_CHAR_WIDTH).ceil() as usize;
+
+        // Draw every run of non-whitespace characters as a block in its highlight color
+        let mut color = theme.text;
+        let mut line = 0;
+        let mut column = 0;
+        for event in document.highlights() {
+            match event {
+                HighlightEvent::Source { start, end } => {
+                    let mut run_start = None;
+                    for grapheme in document.text()[*start..*end].graphemes(true) {
+                        let is_block = !grapheme.trim_start().is_empty() && column < max_columns;
+                        let is_visible = line >= first_line && line < last_line;
+
+                        if is_block && run_start.is_none() {
+                            run_start = Some(column);
+                        }
+
+                        let width = text::grapheme_width(grapheme);
+                        if !is_block || column + width >= max_columns {
+                            if let Some(run_start) = run_start.take() {
+                                if is_visible {
+                                    let end = if is_block { column + width } else { column };
+                                    self.draw_minimap_run(
+                                        ctx, style, &layout, color, line, run_start, end,
+                                    );
+                                }
+                            }
+                        }
+
+                        if grapheme == "\n" {
+                            line += 1;
+                            column = 0;
+                        } else {
+                            column += width;
+                        }
+                    }
+
+                    // Segments may end halfway through a word
+                    if let Some(run_start) = run_start {
+                        if line >= first_line && line < last_line {
+                            self.draw_minimap_run(
+                                ctx, style, &layout, color, line, run_start, column,
+                            );
+                        }
+                    }
+                }
+                HighlightEvent::HighlightStart(higlight) => {
+                    color = theme.token_color(higlight.0);
+                }
+                HighlightEvent::HighlightEnd => {
+                    color = theme.text;
+                }
+            }
+        }
+
+        // Draw the viewport overlay
+        let is_dragging = self.minimap_drag.lock().unwrap().is_some();
+        style.color = if is_dragging {
+            MINIMAP_VIEWPORT_ACTIVE_COLOR
+        } else {
+            MINIMAP_VIEWPORT_COLOR
+        };
+        draw2d.fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, style, layout.viewport);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn draw_minimap_run(
+        &self,
+        ctx: &UiCtx,
+        style: &mut Draw2dStyleT,
+        layout: &MinimapLayout,
+        color: ColorSrgbT,
+        line: usize,
+        start: usize,
+        end: usize,
+    ) {
+        style.color = ColorSrgbT {
+            a: MINIMAP_TEXT_ALPHA,
+            ..color
+        };
+
+        let rect = RectT {
+            x: layout.rect.x + start as f32 * MINIMAP_CHAR_WIDTH,
+            y: layout.rect.y + line as f32 * MINIMAP_LINE_HEIGHT - layout.offset,
+            w: (end - start) as f32 * MINIMAP_CHAR_WIDTH,
+            h: MINIMAP_LINE_HEIGHT - 1.0,
+        };
+        (*self.data.apis.draw2d).fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, style, rect);
+    }
+
+    unsafe fn draw_context_menu(&self, ui_api: &UiApi, ctx: &UiCtx, document: &mut DocumentState) {
+        let mut context_menu = self.context_menu.lock().unwrap();
+        let pos = match *context_menu {
+            Some(pos) => pos,
+            None => return,
+        };
+        let input = &*ctx.buffers.input;
+        let mut options = self.options.lock().unwrap();
+        let has_errors = ctx
+            .diagnostics
+            .iter()
+            .any(|d| d.severity == AnodeSeverity::Error);
+        let handle = self.document_handle as usize;
+        let on_identifier = self
+            .context_menu_offset(ctx, document, pos)
+            .is_some_and(|offset| {
+                Navigation::new(document, offset, NavigationKind::Definition).is_some()
+            });
+        let can_format = !document.is_read_only()
+            && self
+                .data
+                .language_servers
+                .lock()
+                .unwrap()
+                .can_format(handle);
+
+        let items = [
+            menu_toggle(
+                const_cstr!("Show Minimap"),
+                MENU_ITEM_MINIMAP,
+                options.minimap,
+            ),
+            menu_toggle(
+                const_cstr!("Highlight Current Line"),
+                MENU_ITEM_CURRENT_LINE,
+                options.current_line,
+            ),
+            menu_toggle(
+                const_cstr!("Show Whitespace"),
+                MENU_ITEM_WHITESPACE,
+                options.whitespace,
+            ),
+            menu_toggle(
+                const_cstr!("Show Indent Guides"),
+                MENU_ITEM_INDENT_GUIDES,
+                options.indent_guides,
+            ),
+            UiMenuItemT {
+                text: const_cstr!("Next Error").as_ptr(),
+                accelerator: const_cstr!("F8").as_ptr(),
+                item_id: MENU_ITEM_NEXT_ERROR,
+                is_disabled: !has_errors,
+                ..Default::default()
+            },
+            UiMenuItemT {
+                text: const_cstr!("Previous Error").as_ptr(),
+                accelerator: const_cstr!("Shift+F8").as_ptr(),
+                item_id: MENU_ITEM_PREVIOUS_ERROR,
+                is_disabled: !has_errors,
+                ..Default::default()
+            },
+            UiMenuItemT {
+                text: const_cstr!("Go to Definition").as_ptr(),
+                accelerator: const_cstr!("F12").as_ptr(),
+                item_id: MENU_ITEM_DEFINITION,
+                is_disabled: !on_identifier,
+                ..Default::default()
+            },
+            UiMenuItemT {
+                text: const_cstr!("Find References").as_ptr(),
+                accelerator: const_cstr!("Shift+F12").as_ptr(),
+                item_id: MENU_ITEM_REFERENCES,
+                is_disabled: !on_identifier,
+                ..Default::default()
+            },
+            UiMenuItemT {
+                text: const_cstr!("Format Document").as_ptr(),
+                item_id: MENU_ITEM_FORMAT,
+                is_disabled: !can_format,
+                ..Default::default()
+            },
+            UiMenuItemT {
+                text: const_cstr!("Editor Settings...").as_ptr(),
+                item_id: MENU_ITEM_SETTINGS,
+                ..Default::default()
+            },
+        ];
+        let menu = UiMenuT {
+            pos,
+            items: items.as_ptr(),
+            num_items: items.len() as u32,
+            ..Default::default()
+        };
+        let result = ui_api.menu(ctx.ui, ctx.ui_style, &menu);
+
+        match result.selected_item_id {
+            0 => {
+                // Close the menu when clicking anywhere else, right clicking re-opens it instead
+                let clicked = input.left_mouse_pressed && result.highlighted_item_id == 0;
+                let escape = input.edit_key_pressed[TM_UI_EDIT_KEY_ESCAPE as usize];
+                if clicked || escape {
+                    *context_menu = None;
+                }
+                return;
+            }
+            MENU_ITEM_MINIMAP => options.minimap = !options.minimap,
+            MENU_ITEM_CURRENT_LINE => options.current_line = !options.current_line,
+            MENU_ITEM_WHITESPACE => options.whitespace = !options.whitespace,
+            MENU_ITEM_INDENT_GUIDES => options.indent_guides = !options.indent_guides,
+            MENU_ITEM_NEXT_ERROR => {
+                document.goto_error(&ctx.diagnostics, true);
+            }
+            MENU_ITEM_PREVIOUS_ERROR => {
+                document.goto_error(&ctx.diagnostics, false);
+            }
+            MENU_ITEM_DEFINITION | MENU_ITEM_REFERENCES => {
+                let kind = if result.selected_item_id == MENU_ITEM_DEFINITION {
+                    NavigationKind::Definition
+                } else {
+                    NavigationKind::References
+                };
+                if let Some(offset) = self.context_menu_offset(ctx, document, pos) {
+                    self.navigate(document, offset, kind);
+                }
+            }
+            MENU_ITEM_FORMAT => {
+                let mut language_servers = self.data.language_servers.lock().unwrap();
+                language_servers.format(handle, ctx.settings.tab_width);
+            }
+            MENU_ITEM_SETTINGS => settings::open_settings(&self.data, self.app, ctx.ui),
+            _ => {}
+        }
+
+        *context_menu = None;
+    }
+
+    /// Byte offset the context menu acts on, the identifier it was opened on or the caret.
+    fn context_menu_offset(
+        &self,
+        ctx: &UiCtx,
+        document: &DocumentState,
+        pos: Vec2T,
+    ) -> Option<usize> {
+        if document.binary().is_some() {
+            return None;
+        }
+        Some(
+            self.offset_at_position(ctx, document, pos)
+                .unwrap_or_else(|| document.caret()),
+        )
+    }
+
+    unsafe fn draw_current_line(&self, ctx: &UiCtx, style: &mut Draw2dStyleT, line: usize) {
+        style.color = theme_color(ctx, TM_UI_COLOR_WINDOW_SELECTION, CURRENT_LINE_ALPHA);
+
+        let rect = RectT {
+            x: ctx.metrics.textarea_rect.x,
+            y: ctx.metrics.textarea_rect.y
+                + ctx.metrics.caret_start
+                + (ctx.metrics.line_stride * line as f32)
+                - self.scroll_y(),
+            w: ctx.metrics.textarea_rect.w,
+            h: ctx.metrics.line_stride,
+        };
+        (*self.data.apis.draw2d).fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, style, rect);
+    }
+
+    unsafe fn draw_selection(
+        &self,
+        ctx: &UiCtx,
+        style: &mut Draw2dStyleT,
+        document: &DocumentState,
+    ) {
+        let (start, end) = match document.selection() {
+            Some(range) => range,
+            None => return,
+        };
+        style.color = theme_color(ctx, TM_UI_COLOR_WINDOW_SELECTION, 255);
+
+        let (start_line, start_column) = document.line_column(start);
+        let (end_line, end_column) = document.line_column(end);
+        for line in start_line..=end_line {
+            // Lines the selection continues past are selected up to the edge of the text area
+            let from = if line == start_line { start_column } else { 0 };
+            let x = ctx.metrics.textarea_rect.x + from as f32 * ctx.metrics.char_width;
+            let w = if line == end_line {
+                (end_column - from) as f32 * ctx.metrics.char_width
+            } else {
+                ctx.metrics.textarea_rect.x + ctx.metrics.textarea_rect.w - x
+            };
+
+            let rect = RectT {
+                x,
+                y: ctx.metrics.textarea_rect.y
+                    + ctx.metrics.caret_start
+                    + (ctx.metrics.line_stride * line as f32)
+                    - self.scroll_y(),
+                w,
+                h: ctx.metrics.line_stride,
+            };
+            (*self.data.apis.draw2d).fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, style, rect);
+        }
+    }
+
+    /// Draws vertical lines at every indentation level, continuing through blank lines.
+    unsafe fn draw_indent_guides(
+        &self,
+        ctx: &UiCtx,
+        style: &mut Draw2dStyleT,
+        document: &DocumentState,
+    ) {
+        style.color = theme_color(ctx, TM_UI_COLOR_THIN_LINES, 255);
+        let (first_line, last_line) = self.visible_lines(ctx);
+
+        let guides = indent_guides(document.text(), ctx.settings.tab_width);
+        let visible = guides.iter().enumerate().skip(first_line);
+        for (line, columns) in visible.take(last_line - first_line) {
+            for column in columns {
+                let rect = RectT {
+                    x: ctx.metrics.textarea_rect.x
+                        + (ctx.metrics.char_width * *column as f32).round(),
+                    y: ctx.metrics.textarea_rect.y
+                        + ctx.metrics.caret_start
+                        + (ctx.metrics.line_stride * line as f32)
+                        - self.scroll_y(),
+                    w: 1.0,
+                    h: ctx.metrics.line_stride,
+                };
+                (*self.data.apis.draw2d).fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, style, rect);
+            }
+        }
+    }
+
+    /// Draws markers for tabs and trailing spaces.
+    unsafe fn draw_whitespace(
+        &self,
+        ctx: &UiCtx,
+        style: &mut Draw2dStyleT,
+        document: &DocumentState,
+    ) {
+        style.color = theme_color(ctx, TM_UI_COLOR_DISABLED_TEXT, WHITESPACE_ALPHA);
+        let (first_line, last_line) = self.visible_lines(ctx);
+        let metrics = &ctx.metrics;
+
+        let lines = document.text().split('\n').enumerate().skip(first_line);
+        for (line, text) in lines.take(last_line - first_line) {
+            let trailing_start = text.trim_end().len();
+            let center_y = metrics.textarea_rect.y
+                + metrics.caret_start
+                + (metrics.line_stride * (line as f32 + 0.5))
+                - self.scroll_y();
+
+            let mut column = 0;
+            for (offset, grapheme) in text.grapheme_indices(true) {
+                let x = metrics.textarea_rect.x + (metrics.char_width * column as f32);
+                column += text::grapheme_width(grapheme);
+
+                let rect = match grapheme {
+                    "\t" => RectT {
+                        x: x + 1.0,
+                        y: center_y.round(),
+                        w: metrics.char_width - 2.0,
+                        h: 1.0,
+                    },
+                    " " if offset >= trailing_start => RectT {
+                        x: (x + (metrics.char_width * 0.5) - 1.0).round(),
+                        y: (center_y - 1.0).round(),
+                        w: 2.0,
+                        h: 2.0,
+                    },
+                    _ => continue,
+                };
+                (*self.data.apis.draw2d).fill_rect(ctx.buffers.vbuffer, ctx.ibuffer, style, rect);
+            }
+        }
+    }
+
+    /// Range of lines currently visible in the text area.
+    fn visible_lines(&self, ctx: &UiCtx) -> (usize, usize) {
+        let first = (self.scroll_y() / ctx.metrics.line_stride).floor() as usize;
+        let count = (ctx.metrics.textarea_rect.h / ctx.metrics.line_stride).ceil() as usize + 1;
+        (first, first + count)
+    }
+
+    unsafe fn draw_code(
+        &self,
+        ui_api: &UiApi,
+        ctx: &UiCtx,
+        mut style: Draw2dStyleT,
+        textarea_clip: u32,
+        glyphs: &mut Vec<u16>,
+        document: &DocumentState,
+    ) {
+        let mut codepoints = Vec::new();
+        let theme = self.theme(ctx);
+        style.clip = textarea_clip;
+        style.color = theme.text;
+
+        // Text position cursor for rendering, this is how we layout the text
+        let mut position = IVec2::new(0, 0);
+
+        for event in document.highlights() {
+            match event {
+                HighlightEvent::Source { start, end } => {
+                    let segment = &document.text()[*start..*end];
+                    self.draw_segment(
+                        ctx,
+                        &mut style,
+                        glyphs,
+                        &mut codepoints,
+                        &mut position,
+                        segment,
+                    );
+                    ui_api.reserve_draw_memory(ctx.ui);
+                }
+                HighlightEvent::HighlightStart(higlight) => {
+                    style.color = theme.token_color(higlight.0);
+                }
+                HighlightEvent::HighlightEnd => {
+                    style.color = theme.text;
+                }
+            }
+        }
+
+        self.draw_diagnostic_underlines(ui_api, ctx, textarea_clip, document);
+    }
+
+    /// Underlines the ranges of diagnostics with squiggly lines, showing the message on hover.
+    unsafe fn draw_diagnostic_underlines(
+        &self,
+        ui_api: &UiApi,
+        ctx: &UiCtx,
+        textarea_clip: u32,
+        document: &DocumentState,
+    ) {
+        let draw2d = &*self.data.apis.draw2d;
+        let input = &*ctx.buffers.input;
+        let hovering_text =
+            ui_api.is_hovering(ctx.ui, ctx.metrics.textarea_rect, (*ctx.ui_style).clip);
+        let mut hovered = Vec::new();
+
+        for diagnostic in &ctx.diagnostics {
+            let start = document.offset_at(diagnostic.start.0, diagnostic.start.1);
+            let end = document.offset_at(diagnostic.end.0, diagnostic.end.1);
+            let (start_line, start_column) = document.line_column(start);
+            let (end_line, end_column) = document.line_column(end.max(start));
+
+            let style = Draw2dStyleT {
+                color: self.theme(ctx).severity_color(diagnostic.severity),
+                line_width: 1.0,
+                clip: textarea_clip,
+                ..Default::default()
+            };
+
+            for line in start_line..=end_line {
+                let from = if line == start_line { start_column } else { 0 };
+                let to = if line == end_line {
+                    end_column
+                } else {
+                    document.line_column(document.offset_at(line, usize::MAX)).1
+                };
+
+                // Empty ranges still get underlined, so they can be seen
+                let to = to.max(from + 1);
+                let rect = RectT {
+                    x: ctx.metrics.textarea_rect.x + from as f32 * ctx.metrics.char_width,
+                    y: ctx.metrics.textarea_rect.y
+                        + ctx.metrics.caret_start
+                        + (ctx.metrics.line_stride * line as f32)
+                        - self.scroll_y(),
+                    w: (to - from) as f32 * ctx.metrics.char_width,
+                    h: ctx.metrics.line_stride,
+                };
+
+                let points = squiggle_points(rect.x, rect.x + rect.w, rect.y + rect.h - 2.0);
+                draw2d.stroke_polyline(
+                    ctx.buffers.vbuffer,
+                    ctx.ibuffer,
+                    &style,
+                    points.as_ptr(),
+                    points.len() as u32,
+                    false,
+                );
+
+                if hovering_text && contains(rect, input.mouse_pos) {
+                    hovered.push(diagnostic.tooltip());
+                }
+            }
+        }
+
+        if !hovered.is_empty() {
+            let tooltip = ui_string(&hovered.join("\n"));
+            ui_api.tooltip(ctx.ui, ctx.ui_style, tooltip.as_ptr());
+        }
+    }
+
+    unsafe fn draw_segment(
+        &self,
+        ctx: &UiCtx,
+        style: &mut Draw2dStyleT,
+        glyphs: &mut Vec<u16>,
+        codepoints: &mut Vec<u32>,
+        position: &mut IVec2,
+        segment: &str,
+    ) {
+        // Start column of the codepoints accumulated so far
+        let mut run_start = position.x;
+
+        for grapheme in segment.graphemes(true) {
+            // Draw the accumulated text on newlines, and start the next line
+            if grapheme == "\n" {
+                self.draw_run(ctx, style, glyphs, codepoints, run_start, position.y);
+                position.x = 0;
+                position.y += 1;
+                run_start = 0;
+                continue;
+            }
+
+            let width = text::grapheme_width(grapheme);
+            let mut chars = grapheme.chars();
+            let first = chars.next().unwrap_or(' ');
+
+            // Simple single-cell characters in the code font can be drawn in one go, anything else
+            // gets placed in its cell separately, as the font's advance doesn't match
+            let is_simple = width == 1
+                && chars.as_str().is_empty()
+                && !ctx.code_font.fallback_chars.contains_key(&first);
+            if is_simple {
+                codepoints.push(first as u32);
+            } else {
+                self.draw_run(ctx, style, glyphs, codepoints, run_start, position.y);
+                self.draw_grapheme(ctx, style, glyphs, grapheme, *position);
+            }
+
+            position.x += width as i32;
+            if !is_simple {
+                run_start = position.x;
+            }
+        }
+
+        self.draw_run(ctx, style, glyphs, codepoints, run_start, position.y);
+    }
+
+    /// Draws and clears a run of single-cell codepoints.
+    unsafe fn draw_run(
+        &self,
+        ctx: &UiCtx,
+        style: &Draw2dStyleT,
+        glyphs: &mut Vec<u16>,
+        codepoints: &mut Vec<u32>,
+        column: i32,
+        line: i32,
+    ) {
+        if codepoints.is_empty() {
+            return;
+        }
+
+        let pos = self.cell_position(ctx, IVec2::new(column, line));
+        self.draw_text(ctx, style, pos, glyphs, codepoints);
+        codepoints.clear();
+    }
+
+    /// Draws a grapheme in its cell, using fallback fonts for characters the code font lacks.
+    unsafe fn draw_grapheme(
+        &self,
+        ctx: &UiCtx,
+        style: &mut Draw2dStyleT,
+        glyphs: &mut Vec<u16>,
+        grapheme: &str,
+        position: IVec2,
+    ) {
+        let primary = style.font;
+        let pos = self.cell_position(ctx, position);
+
+        // Combining characters are drawn on top of the base character
+        for c in grapheme.chars() {
+            style.font = ctx.code_font.font_for_char(c).font;
+            self.draw_text(ctx, style, pos, glyphs, &[c as u32]);
+        }
+
+        style.font = primary;
+    }
+
+    /// Position of the baseline at the start of a cell.
+    fn cell_position(&self, ctx: &UiCtx, position: IVec2) -> Vec2T {
+        Vec2T {
+            x: ctx.metrics.textarea_rect.x + (position.x as f32 * ctx.metrics.char_width),
+            y: ctx.metrics.textarea_rect.y
+                + ctx.metrics.first_baseline
+                + (position.y as f32 * ctx.metrics.line_stride)
+                - self.scroll_y(),
+        }
+    }
+
+    /// Draws the visible lines of a hex dump of the bytes.
+    unsafe fn draw_hex(
+        &self,
+        ui_api: &UiApi,
+        ctx: &UiCtx,
+        mut style: Draw2dStyleT,
+        textarea_clip: u32,
+        glyphs: &mut Vec<u16>,
+        bytes: &[u8],
+    ) {
+        style.clip = textarea_clip;
+        style.color = self.theme(ctx).text;
+
+        let (first_line, last_line) = self.visible_lines(ctx);
+        let chunks = bytes
+            .chunks(HEX_BYTES_PER_LINE)
+            .enumerate()
+            .skip(first_line);
+        let mut codepoints = Vec::new();
+        for (line, chunk) in chunks.take(last_line - first_line) {
+            codepoints.extend(
+                hex_line(line * HEX_BYTES_PER_LINE, chunk)
+                    .chars()
+                    .map(u32::from),
+            );
+            self.draw_run(ctx, &style, glyphs, &mut codepoints, 0, line as i32);
+            ui_api.reserve_draw_memory(ctx.ui);
+        }
+    }
+
+    unsafe fn draw_text(
+        &self,
+        ctx: &UiCtx,
+        style: &Draw2dStyleT,
+        mut pos: Vec2T,
+        glyphs: &mut Vec<u16>,
+        codepoints: &[u32],
+    ) {
+        // Hack to improve blurryness issues
+        pos.y = pos.y.round();
+
+        // Convert codepoints into glyph IDs
+        glyphs.resize(codepoints.len(), 0);
+        (*self.data.apis.font).glyphs(
+            (*style.font).info,
+            glyphs.as_mut_ptr(),
+            codepoints.as_ptr(),
+            codepoints.len() as u32,
+        );
+
+        // Draw the glyphs
+        (*self.data.apis.draw2d).draw_glyphs(
+            ctx.buffers.vbuffer,
+            ctx.ibuffer,
+            style,
+            pos,
+            glyphs.as_ptr(),
+            glyphs.len() as u32,
+        );
+    }
+}
+
+impl Drop for CodeEditor {
+    fn drop(&mut self) {
+        // Tabs and embedded editors can be destroyed without being asked to close first
+        self.close();
+    }
+}
+
+/// Convert digits of an integer to unicode codepoints, right aligned.
+fn digits(value: u32) -> [u32; 5] {
+    let mut codepoints = [32u32; 5];
+
+    let mut write = false;
+    for (i, codepoint) in codepoints.iter_mut().enumerate() {
+        let div = 10u32.pow(4 - i as u32);
+        let digit = (value / div) % 10;
+
+        if digit != 0 || write {
+            *codepoint = 48 + digit;
+            write = true;
+        }
+    }
+
+    codepoints
+}
+
+fn hex_line_count(bytes: &[u8]) -> usize {
+    bytes.len().div_ceil(HEX_BYTES_PER_LINE).max(1)
+}
+
+/// Formats a line of a hex dump, with the offset, the bytes and their printable characters.
+fn hex_line(offset: usize, bytes: &[u8]) -> String {
+    let mut line = format!("{:08X}  ", offset);
+
+    for i in 0..HEX_BYTES_PER_LINE {
+        match bytes.get(i) {
+            Some(byte) => line.push_str(&format!("{:02X} ", byte)),
+            None => line.push_str("   "),
+        }
+
+        // Extra gap between the two halves
+        if i == HEX_BYTES_PER_LINE / 2 - 1 {
+            line.push(' ');
+        }
+    }
+
+    line.push_str(" |");
+    line.extend(bytes.iter().map(|v| {
+        if v.is_ascii_graphic() || *v == b' ' {
+            *v as char
+        } else {
+            '.'
+        }
+    }));
+    line.push('|');
+
+    line
+}
+
+/// Calculates the columns of the indent guides on every line.
+///
+/// Every tab and every run of `tab_width` spaces is a level, with tabs taking up a single cell
+/// like they're laid out in the text. Blank lines take the guides of the surrounding line with
+/// the fewest, so guides continue through them.
+fn indent_guides(text: &str, tab_width: usize) -> Vec<Vec<usize>> {
+    let lines: Vec<Option<Vec<usize>>> = text
+        .split('\n')
+        .map(|line| {
+            if line.trim().is_empty() {
+                return None;
+            }
+
+            let mut guides = Vec::new();
+            let mut spaces = 0;
+            let indentation = line.chars().take_while(|c| c.is_whitespace());
+            for (column, c) in indentation.enumerate() {
+                if c == '\t' {
+                    if spaces % tab_width != 0 {
+                        guides.pop();
+                    }
+                    guides.push(column);
+                    spaces = 0;
+                } else {
+                    if spaces % tab_width == 0 {
+                        guides.push(column);
+                    }
+                    spaces += 1;
+                }
+            }
+
+            // A partial run of spaces is alignment rather than a level
+            if spaces % tab_width != 0 {
+                guides.pop();
+            }
+            Some(guides)
+        })
+        .collect();
+
+    // Blank lines take the fewest guides of the closest lines above and below
+    let mut result = vec![Vec::new(); lines.len()];
+    let mut next = Vec::new();
+    for i in (0..lines.len()).rev() {
+        if let Some(guides) = &lines[i] {
+            next = guides.clone();
+        }
+        result[i] = next.clone();
+    }
+    let mut previous: &[usize] = &[];
+    for (i, guides) in lines.iter().enumerate() {
+        match guides {
+            Some(guides) => previous = guides,
+            None if previous.len() < result[i].len() => result[i] = previous.to_vec(),
+            None => {}
+        }
+    }
+
+    result
+}
+
+/// Horizontal position of the ruler at a column.
+fn ruler_x(metrics: &EditorMetrics, column: u32) -> f32 {
+    metrics.textarea_rect.x + (metrics.char_width * column as f32).round()
+}
+
+fn menu_toggle(text: ConstCStr, item_id: u64, is_checked: bool) -> UiMenuItemT {
+    UiMenuItemT {
+        text: text.as_ptr(),
+        item_id,
+        is_checked,
+        ..Default::default()
+    }
+}
+
+/// Points of a zig-zag line from one x to another, centered around a y.
+fn squiggle_points(from: f32, to: f32, y: f32) -> Vec<Vec2T> {
+    let mut points = Vec::new();
+    let mut x = from;
+    let mut up = true;
+    while x < to {
+        let offset = if up {
+            -SQUIGGLE_HEIGHT
+        } else {
+            SQUIGGLE_HEIGHT
+        } * 0.5;
+        points.push(Vec2T { x, y: y + offset });
+        x += SQUIGGLE_WIDTH * 0.5;
+        up = !up;
+    }
+    points.push(Vec2T { x: to, y });
+    points
+}
+
+/// Converts text that may come from outside sources, like language servers, for the UI.
+///
+/// Nul characters can't be passed on, so they're removed rather than failing.
+fn ui_string(text: &str) -> CString {
+    CString::new(text.replace('\0', "")).unwrap_or_default()
+}
+
+fn contains(rect: RectT, pos: Vec2T) -> bool {
+    pos.x >= rect.x && pos.x < rect.x + rect.w && pos.y >= rect.y && pos.y < rect.y + rect.h
+}
+
+/// Get a color from the UI theme, with a custom alpha.
+unsafe fn theme_color(ctx: &UiCtx, color: UiColor, alpha: u8) -> ColorSrgbT {
+    ColorSrgbT {
+        a: alpha,
+        ..*ctx.buffers.colors.offset(color as isize)
+    }
+}
+
+/// Per-tab toggleable editor features.
+#[derive(Clone, Copy)]
+struct EditorOptions {
+    minimap: bool,
+    current_line: bool,
+    whitespace: bool,
+    indent_guides: bool,
+}
+
+impl Default for EditorOptions {
+    fn default() -> Self {
+        Self {
+            minimap: true,
+            current_line: true,
+            whitespace: false,
+            indent_guides: true,
+        }
+    }
+}
+
+struct EditorMetrics {
+    first_baseline: f32,
+    line_stride: f32,
+    char_width: f32,
+    caret_start: f32,
+    tab_rect: RectT,
+    textarea_rect: RectT,
+    minimap_rect: Option<RectT>,
+    scrollbar_x: f32,
+    scrollbar_width: f32,
+}
+
+impl EditorMetrics {
+    pub unsafe fn calculate(
+        buffers: &UiBuffersT,
+        tab_rect: RectT,
+        font: &UiFontT,
+        options: &EditorOptions,
+    ) -> Self {
+        let font_info = &*(*font.font).info;
+
+        let scrollbar_width = *buffers
+            .metrics
+            .offset(TM_UI_METRIC_SCROLLBAR_WIDTH as isize);
+
+        // Font metrics
+        let padding = 4.0;
+        let first_line = padding + font_info.ascent[0];
+        let line_stride = font_info.ascent[0] + font_info.descent[0] + font_info.line_gap[0];
+        let char_width = (*font_info.glyphs).xadvance;
+        let caret_start = padding - (font_info.line_gap[0] * 0.5);
+
+        // Layouting sizes
+        let line_offset = char_width * 7.0;
+        let mut textarea_rect = tab_rect;
+        textarea_rect.x += line_offset;
+        textarea_rect.w -= line_offset + scrollbar_width - 1.0;
+
+        let scrollbar_x = tab_rect.x + tab_rect.w - scrollbar_width;
+
+        // The minimap sits between the text area and the scrollbar
+        let minimap_rect = if options.minimap {
+            textarea_rect.w -= MINIMAP_WIDTH;
+            Some(RectT {
+                x: scrollbar_x - MINIMAP_WIDTH,
+                y: tab_rect.y,
+                w: MINIMAP_WIDTH,
+                h: tab_rect.h,
+            })
+        } else {
+            None
+        };
+
+        Self {
+            first_baseline: first_line,
+            line_stride,
+            char_width,
+            caret_start,
+            tab_rect,
+            textarea_rect,
+            minimap_rect,
+            scrollbar_x,
+            scrollbar_width,
+        }
+    }
+}
+
+/// Positioning of the minimap's contents for the current scroll position.
+struct MinimapLayout {
+    rect: RectT,
+    /// How far the minimap's contents are scrolled, if they don't fit in the rect.
+    offset: f32,
+    viewport: RectT,
+}
+
+impl MinimapLayout {
+    fn calculate(metrics: &EditorMetrics, line_count: usize, scroll_y: f32) -> Self {
+        let rect = metrics.minimap_rect.unwrap_or_default();
+
+        // If the minimap is taller than the tab, scroll it proportionally with the document
+        let content_height = line_count as f32 * MINIMAP_LINE_HEIGHT;
+        let max_scroll_y = CodeEditor::max_scroll_y(metrics, line_count);
+        let scroll_factor = if max_scroll_y > 0.0 {
+            scroll_y / max_scroll_y
+        } else {
+            0.0
+        };
+        let offset = (content_height - rect.h).max(0.0) * scroll_factor;
+
+        let scale = MINIMAP_LINE_HEIGHT / metrics.line_stride;
+        let viewport = RectT {
+            x: rect.x,
+            y: rect.y + (scroll_y * scale) - offset,
+            w: rect.w,
+            h: metrics.textarea_rect.h * scale,
+        };
+
+        Self {
+            rect,
+            offset,
+            viewport,
+        }
+    }
+}
+
+struct UiCtx {
+    ui: *mut UiO,
+    ui_style: *const UiStyleT,
+    buffers: UiBuffersT,
+    ibuffer: *mut Draw2dIbufferT,
+    metrics: EditorMetrics,
+    settings: EditorSettings,
+    code_font: CodeFont,
+    /// Diagnostics set for the document's asset by other plugins.
+    diagnostics: Vec<Diagnostic>,
+}
+
+const SQUIGGLE_WIDTH: f32 = 4.0;
+const SQUIGGLE_HEIGHT: f32 = 2.0;
+const MINIMAP_WIDTH: f32 = 100.0;
+const MINIMAP_CHAR_WIDTH: f32 = 1.0;
+const MINIMAP_LINE_HEIGHT: f32 = 3.0;
+const MINIMAP_TEXT_ALPHA: u8 = 160;
+
+const MINIMAP_VIEWPORT_COLOR: ColorSrgbT = ColorSrgbT {
+    r: 255,
+    g: 255,
+    b: 255,
+    a: 20,
+};
+
+const MINIMAP_VIEWPORT_ACTIVE_COLOR: ColorSrgbT = ColorSrgbT {
+    r: 255,
+    g: 255,
+    b: 255,
+    a: 40,
+};
+
+const RULER_SHADING_ALPHA: u8 = 140;
+
+const CURRENT_LINE_ALPHA: u8 = 40;
+const WHITESPACE_ALPHA: u8 = 120;
+
+const MIN_FONT_SIZE: i32 = 6;
+const MAX_FONT_SIZE: i32 = 72;
+
+const STATUS_BAR_DROPDOWN_WIDTH: f32 = 90.0;
+const HEX_BYTES_PER_LINE: usize = 16;
+const CONFLICT_BUTTON_WIDTH: f32 = 80.0;
+
+/// Maximum size of hover tooltips, in characters and lines.
+const HOVER_MAX_COLUMNS: usize = 80;
+const HOVER_MAX_LINES: usize = 30;
+
+/// Maximum width of the completion popup, relative to the text area.
+const COMPLETION_MAX_WIDTH: f32 = 0.6;
+/// Rows of the references panel, it scrolls to show the rest.
+const REFERENCES_MAX_ROWS: usize = 8;
+const REFERENCES_BUTTON_WIDTH: f32 = 60.0;
+
+const MENU_ITEM_MINIMAP: u64 = 1;
+const MENU_ITEM_CURRENT_LINE: u64 = 2;
+const MENU_ITEM_WHITESPACE: u64 = 3;
+const MENU_ITEM_INDENT_GUIDES: u64 = 4;
+const MENU_ITEM_SETTINGS: u64 = 5;
+const MENU_ITEM_NEXT_ERROR: u64 = 6;
+const MENU_ITEM_PREVIOUS_ERROR: u64 = 7;
+const MENU_ITEM_FORMAT: u64 = 8;
+const MENU_ITEM_DEFINITION: u64 = 9;
+const MENU_ITEM_REFERENCES: u64 = 10;
+
+const ANODE_CODE_EDITOR_ACTIVE_DATA: Identifier = identifier!("tm_anode_code_editor_data_t");
+
+#[cfg(test)]
+mod tests {
+    use machinery_api::foundation::TM_THE_TRUTH_PROPERTY_TYPE_BUFFER;
+    use tm_anode_api::AnodeAspectI;
+
+    use super::*;
+    use crate::fake_truth::{self, FakeTruth};
+
+    #[test]
+    fn indent_guides_count_tabs_as_one_cell() {
+        let guides = indent_guides("a\n\tb\n\t\tc\n    d\n        e\n      f\n\t  g", 4);
+        assert_eq!(
+            guides,
+            vec![
+                vec![],
+                vec![0],
+                vec![0, 1],
+                vec![0],
+                vec![0, 4],
+                vec![0],
+                vec![0]
+            ]
+        );
+    }
+
+    #[test]
+    fn indent_guides_continue_through_blank_lines() {
+        let guides = indent_guides("\tif a {\n\t\tb\n\n\t\tc\n\n\t}", 4);
+        assert_eq!(
+            guides,
+            vec![
+                vec![0],
+                vec![0, 1],
+                vec![0, 1],
+                vec![0, 1],
+                vec![0],
+                vec![0]
+            ]
+        );
+    }
+
+    #[test]
+    fn failing_to_open_an_asset_leaves_the_editor_empty() {
+        let data = fake_truth::plugin_data();
+        let truth = FakeTruth::new();
+        let aspect = AnodeAspectI {
+            property: 0,
+            ..Default::default()
+        };
+        let text_type = truth.add_type(&[TM_THE_TRUTH_PROPERTY_TYPE_BUFFER], Some(aspect));
+        let text = truth.create_object(text_type);
+        truth.set_buffer(text, 0, b"text\n");
+        let no_aspect = truth.add_type(&[TM_THE_TRUTH_PROPERTY_TYPE_BUFFER], None);
+        let no_aspect = truth.create_object(no_aspect);
+
+        let editor = CodeEditor::new(data, null_mut());
+        unsafe {
+            editor.set_asset(truth.tt(), text).unwrap();
+            assert_eq!(editor.document().text(), "text\n");
+            assert!(editor.set_asset(truth.tt(), no_aspect).is_err());
+        }
+        assert!(editor.document().asset().is_none());
+        assert_eq!(editor.document().text(), "");
+    }
+}
@@ -0,0 +1,302 @@
+use std::convert::TryFrom;
+
+use const_cstr::{const_cstr, ConstCStr};
+use eyre::{eyre, Result};
+use tracing::{event, Level};
+
+/// How the text of a document is stored in its asset.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct TextFormat {
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+impl TextFormat {
+    /// Detects the format of the bytes, and decodes them into text with only `\n` line endings.
+    ///
+    /// Fails if the bytes don't look like text in any supported encoding.
+    pub fn decode(bytes: &[u8]) -> Result<(String, Self)> {
+        Self::decode_as(bytes, Encoding::detect(bytes)?)
+    }
+
+    /// Decodes the bytes with an explicit encoding, detecting only the line ending.
+    pub fn decode_as(bytes: &[u8], encoding: Encoding) -> Result<(String, Self)> {
+        let text = encoding.decode(bytes)?;
+
+        let line_ending = LineEnding::detect(&text);
+        let text = text.replace("\r\n", "\n");
+
+        let format = Self {
+            encoding,
+            line_ending,
+        };
+        Ok((text, format))
+    }
+
+    /// Encodes text with `\n` line endings in this format.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>> {
+        match self.line_ending {
+            LineEnding::Lf => self.encoding.encode(text),
+            LineEnding::Crlf => self.encoding.encode(&text.replace('\n', "\r\n")),
+        }
+    }
+}
+
+impl Default for TextFormat {
+    fn default() -> Self {
+        Self {
+            encoding: Encoding::Utf8,
+            line_ending: LineEnding::Lf,
+        }
+    }
+}
+
+impl Encoding {
+    pub const ALL: [Self; 5] = [
+        Self::Utf8,
+        Self::Utf8Bom,
+        Self::Utf16Le,
+        Self::Utf16Be,
+        Self::Latin1,
+    ];
+
+    pub fn name(self) -> ConstCStr {
+        match self {
+            Self::Utf8 => const_cstr!("UTF-8"),
+            Self::Utf8Bom => const_cstr!("UTF-8 BOM"),
+            Self::Utf16Le => const_cstr!("UTF-16 LE"),
+            Self::Utf16Be => const_cstr!("UTF-16 BE"),
+            Self::Latin1 => const_cstr!("Latin-1"),
+        }
+    }
+
+    /// Detects the encoding from the byte order mark, or from whether the bytes are valid UTF-8.
+    pub fn detect(bytes: &[u8]) -> Result<Self> {
+        if bytes.starts_with(UTF8_BOM) {
+            Ok(Self::Utf8Bom)
+        } else if bytes.starts_with(UTF16_LE_BOM) {
+            Ok(Self::Utf16Le)
+        } else if bytes.starts_with(UTF16_BE_BOM) {
+            Ok(Self::Utf16Be)
+        } else if looks_binary(bytes) {
+            Err(eyre!("Content looks like binary data"))
+        } else if std::str::from_utf8(bytes).is_ok() {
+            Ok(Self::Utf8)
+        } else if !bytes.iter().any(|v| (0x80..0xA0).contains(v)) {
+            // Every byte is valid Latin-1, but C1 control codes don't appear in legacy text
+            Ok(Self::Latin1)
+        } else {
+            Err(eyre!("Content is not valid UTF-8"))
+        }
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> Result<String> {
+        match self {
+            Self::Utf8 | Self::Utf8Bom => {
+                let bytes = bytes.strip_prefix(UTF8_BOM).unwrap_or(bytes);
+                let text = std::str::from_utf8(bytes)?;
+                Ok(text.to_string())
+            }
+            Self::Utf16Le | Self::Utf16Be => {
+                let bom = if self == Self::Utf16Le {
+                    UTF16_LE_BOM
+                } else {
+                    UTF16_BE_BOM
+                };
+                let bytes = bytes.strip_prefix(bom).unwrap_or(bytes);
+                if bytes.len() % 2 != 0 {
+                    return Err(eyre!("Text has an odd amount of bytes for UTF-16"));
+                }
+
+                let units = bytes.chunks_exact(2).map(|v| {
+                    if self == Self::Utf16Le {
+                        u16::from_le_bytes([v[0], v[1]])
+                    } else {
+                        u16::from_be_bytes([v[0], v[1]])
+                    }
+                });
+                std::char::decode_utf16(units)
+                    .collect::<Result<String, _>>()
+                    .map_err(|error| eyre!("Text is not valid UTF-16: {}", error))
+            }
+            Self::Latin1 => Ok(bytes.iter().map(|v| *v as char).collect()),
+        }
+    }
+
+    pub fn encode(self, text: &str) -> Result<Vec<u8>> {
+        match self {
+            Self::Utf8 => Ok(text.as_bytes().to_vec()),
+            Self::Utf8Bom => Ok([UTF8_BOM, text.as_bytes()].concat()),
+            Self::Utf16Le => {
+                let mut bytes = UTF16_LE_BOM.to_vec();
+                bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+                Ok(bytes)
+            }
+            Self::Utf16Be => {
+                let mut bytes = UTF16_BE_BOM.to_vec();
+                bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+                Ok(bytes)
+            }
+            Self::Latin1 => text
+                .chars()
+                .map(|c| {
+                    u8::try_from(c as u32)
+                        .map_err(|_| eyre!("Character '{}' can't be represented in Latin-1", c))
+                })
+                .collect(),
+        }
+    }
+}
+
+impl LineEnding {
+    pub const ALL: [Self; 2] = [Self::Lf, Self::Crlf];
+
+    pub fn name(self) -> ConstCStr {
+        match self {
+            Self::Lf => const_cstr!("LF"),
+            Self::Crlf => const_cstr!("CRLF"),
+        }
+    }
+
+    /// Detects the most common line ending in the text.
+    pub fn detect(text: &str) -> Self {
+        let lines = text.matches('\n').count();
+        let crlf = text.matches("\r\n").count();
+
+        if crlf != 0 && crlf != lines {
+            event!(
+                Level::WARN,
+                "Text has mixed line endings, these will be normalized on edit"
+            );
+        }
+
+        if crlf * 2 > lines {
+            Self::Crlf
+        } else {
+            Self::Lf
+        }
+    }
+}
+
+/// Checks for control characters that practically never appear in text.
+fn looks_binary(bytes: &[u8]) -> bool {
+    let sample = &bytes[..bytes.len().min(BINARY_SAMPLE_SIZE)];
+    let controls = sample
+        .iter()
+        .filter(|v| **v < 0x20 && !b"\t\n\r\x0C\x1B".contains(v))
+        .count();
+
+    sample.contains(&0) || controls * 10 > sample.len()
+}
+
+/// Amount of bytes at the start that are checked for binary data.
+const BINARY_SAMPLE_SIZE: usize = 8192;
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+const UTF16_LE_BOM: &[u8] = &[0xFF, 0xFE];
+const UTF16_BE_BOM: &[u8] = &[0xFE, 0xFF];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_encoding_round_trips() {
+        let text = "first line\nsecond line, café\n";
+        for &encoding in &Encoding::ALL {
+            for &line_ending in &LineEnding::ALL {
+                let format = TextFormat {
+                    encoding,
+                    line_ending,
+                };
+                let bytes = format.encode(text).unwrap();
+                let (decoded, detected) = TextFormat::decode(&bytes).unwrap();
+
+                assert_eq!(decoded, text, "{:?} {:?}", encoding, line_ending);
+                assert!(detected == format, "{:?} {:?}", encoding, line_ending);
+                assert_eq!(detected.encode(&decoded).unwrap(), bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn byte_order_marks_are_kept() {
+        let bytes = [UTF8_BOM, b"text"].concat();
+        let (text, format) = TextFormat::decode(&bytes).unwrap();
+        assert_eq!(text, "text");
+        assert_eq!(format.encoding, Encoding::Utf8Bom);
+        assert_eq!(format.encode(&text).unwrap(), bytes);
+
+        let bytes = [UTF16_BE_BOM, &[0, b'a', 0xD8, 0x3E, 0xDD, 0x80]].concat();
+        let (text, format) = TextFormat::decode(&bytes).unwrap();
+        assert_eq!(text, "a🦀");
+        assert_eq!(format.encoding, Encoding::Utf16Be);
+        assert_eq!(format.encode(&text).unwrap(), bytes);
+    }
+
+    #[test]
+    fn line_endings_are_normalized_and_restored() {
+        let (text, format) = TextFormat::decode(b"a\r\nb\r\nc").unwrap();
+        assert_eq!(text, "a\nb\nc");
+        assert_eq!(format.line_ending, LineEnding::Crlf);
+        assert_eq!(format.encode("a\nb\nc\nd").unwrap(), b"a\r\nb\r\nc\r\nd");
+
+        // Mixed line endings use the most common one
+        let (_, format) = TextFormat::decode(b"a\r\nb\nc\n").unwrap();
+        assert_eq!(format.line_ending, LineEnding::Lf);
+    }
+
+    #[test]
+    fn legacy_text_is_read_as_latin1() {
+        let (text, format) = TextFormat::decode(b"caf\xE9\n").unwrap();
+        assert_eq!(text, "café\n");
+        assert_eq!(format.encoding, Encoding::Latin1);
+        assert_eq!(format.encode(&text).unwrap(), b"caf\xE9\n");
+
+        assert!(Encoding::Latin1.encode("🦀").is_err());
+    }
+
+    #[test]
+    fn binary_content_is_refused() {
+        assert!(TextFormat::decode(b"\x7FELF\x02\x01\x01\0\0\0").is_err());
+        assert!(TextFormat::decode(&[0x01, 0x02, 0x03, 0x04, b'a', b'b']).is_err());
+        // C1 control codes mean it's neither UTF-8 nor legacy text
+        assert!(TextFormat::decode(b"abc\x85\x90").is_err());
+
+        // Text with the odd control character, or nul bytes of UTF-16, is still text
+        assert!(TextFormat::decode(b"\x1B[1mbold\x1B[0m\x0C\n").is_ok());
+        let utf16 = TextFormat {
+            encoding: Encoding::Utf16Le,
+            line_ending: LineEnding::Lf,
+        };
+        assert!(TextFormat::decode(&utf16.encode("text").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn binary_content_can_be_reinterpreted() {
+        let bytes = b"abc\0def";
+        assert!(TextFormat::decode(bytes).is_err());
+
+        let (text, format) = TextFormat::decode_as(bytes, Encoding::Latin1).unwrap();
+        assert_eq!(text, "abc\0def");
+        assert_eq!(format.encode(&text).unwrap(), bytes);
+
+        assert!(TextFormat::decode_as(b"\xFF\xFE\0", Encoding::Utf16Le).is_err());
+        assert!(TextFormat::decode_as(b"\xC3", Encoding::Utf8).is_err());
+    }
+}
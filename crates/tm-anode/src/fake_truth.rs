@@ -0,0 +1,430 @@
+//! In-memory stand-in for the truth, for testing documents without the engine.
+//!
+//! Only the functions documents use to read and write their text are implemented. A truth pointer
+//! handed to those functions points at a [`FakeTruth`], so several of them can be used at once
+//! like separate truths synced between machines.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    ffi::{c_void, CStr, CString},
+    os::raw::c_char,
+    ptr::{null, null_mut},
+    sync::{Arc, Mutex},
+};
+
+use machinery::RegistryStorage;
+use machinery_api::foundation::{
+    BuffersI, BuffersO, StrhashT, TheTruthApi, TheTruthO, TheTruthObjectO,
+    TheTruthPropertyDefinitionT, TheTruthPropertyType, TtBufferT, TtIdT, TtIdTBindgenTy1,
+    TtIdTBindgenTy1BindgenTy1, TtTypeT, TtUndoScopeT,
+};
+use tm_anode_api::{AnodeAspectI, ASPECT_ANODE};
+
+use crate::{
+    diagnostics::Diagnostics,
+    fonts::CodeFonts,
+    handles::OpenDocuments,
+    lsp::LanguageServers,
+    plugin::{Apis, PluginData},
+    properties::PropertyEditors,
+};
+
+/// Plugin data with only the truth API, implemented by [`FakeTruth`].
+pub fn plugin_data() -> Arc<PluginData> {
+    let truth = Box::new(TheTruthApi {
+        buffers: Some(buffers),
+        get_aspect: Some(get_aspect),
+        num_properties: Some(num_properties),
+        properties: Some(properties),
+        read: Some(read),
+        write: Some(write),
+        commit: Some(commit),
+        version: Some(version),
+        is_alive: Some(is_alive),
+        get_buffer: Some(get_buffer),
+        set_buffer: Some(set_buffer),
+        get_string: Some(get_string),
+        set_string: Some(set_string),
+        ..Default::default()
+    });
+
+    Arc::new(PluginData {
+        apis: Apis {
+            registry: null(),
+            application: null(),
+            truth: Box::leak(truth),
+            truth_assets: null(),
+            ui: null(),
+            docking: null(),
+            draw2d: null(),
+            font: null(),
+            properties_view: null(),
+            machinery: null(),
+            temp_allocator: null(),
+            code_editor_tab_vtable: null(),
+        },
+        registry_storage: Mutex::new(RegistryStorage::new()),
+        themes: Vec::new(),
+        settings_object: Mutex::new(None),
+        code_fonts: Mutex::new(CodeFonts::new()),
+        property_editors: Mutex::new(PropertyEditors::default()),
+        open_documents: Mutex::new(OpenDocuments::default()),
+        diagnostics: Mutex::new(Diagnostics::default()),
+        language_servers: Mutex::new(LanguageServers::default()),
+    })
+}
+
+#[derive(Clone)]
+enum Value {
+    None,
+    Buffer(u32),
+    String(CString),
+}
+
+#[derive(Clone)]
+struct Object {
+    version: u32,
+    values: Vec<Value>,
+}
+
+struct Type {
+    properties: Vec<TheTruthPropertyDefinitionT>,
+    aspect: Option<Box<AnodeAspectI>>,
+}
+
+pub struct FakeTruth {
+    /// Types by their index, zero is reserved like in the truth.
+    types: RefCell<Vec<Type>>,
+    objects: RefCell<HashMap<u64, Object>>,
+    /// Objects being written, by the pointer handed out for them, and the object they're for.
+    writes: RefCell<HashMap<usize, (u64, Object)>>,
+    next_write: Cell<usize>,
+    next_version: Cell<u32>,
+    /// Buffers by their ID minus one, zero is no buffer.
+    buffers: RefCell<Vec<Vec<u8>>>,
+    /// Allocated buffers that weren't added yet, by their address.
+    allocations: RefCell<HashMap<usize, Vec<u8>>>,
+    buffers_i: RefCell<BuffersI>,
+}
+
+impl FakeTruth {
+    pub fn new() -> Box<Self> {
+        let truth = Box::new(Self {
+            types: RefCell::new(vec![Type {
+                properties: Vec::new(),
+                aspect: None,
+            }]),
+            objects: RefCell::new(HashMap::new()),
+            writes: RefCell::new(HashMap::new()),
+            next_write: Cell::new(1),
+            next_version: Cell::new(1),
+            buffers: RefCell::new(Vec::new()),
+            allocations: RefCell::new(HashMap::new()),
+            buffers_i: RefCell::new(BuffersI::default()),
+        });
+
+        let mut buffers_i = truth.buffers_i.borrow_mut();
+        buffers_i.inst = &*truth as *const Self as *mut BuffersO;
+        buffers_i.allocate = Some(allocate_buffer);
+        buffers_i.add = Some(add_buffer);
+        buffers_i.get = Some(get_buffer_data);
+        drop(buffers_i);
+
+        truth
+    }
+
+    pub fn tt(&self) -> *mut TheTruthO {
+        self as *const Self as *mut TheTruthO
+    }
+
+    /// Adds a type with properties of the types, opened in anode if it has an aspect.
+    pub fn add_type(
+        &self,
+        properties: &[TheTruthPropertyType],
+        aspect: Option<AnodeAspectI>,
+    ) -> TtTypeT {
+        let mut types = self.types.borrow_mut();
+        types.push(Type {
+            properties: properties
+                .iter()
+                .map(|type_| TheTruthPropertyDefinitionT {
+                    type_: *type_,
+                    ..Default::default()
+                })
+                .collect(),
+            aspect: aspect.map(Box::new),
+        });
+        TtTypeT {
+            u64_: types.len() as u64 - 1,
+        }
+    }
+
+    /// Creates an object, IDs are handed out in order so they match between fake truths.
+    pub fn create_object(&self, object_type: TtTypeT) -> TtIdT {
+        let mut objects = self.objects.borrow_mut();
+        let mut bits = TtIdTBindgenTy1BindgenTy1::default();
+        bits.set_type(object_type.u64_);
+        bits.set_index(objects.len() as u64 + 1);
+        let id = TtIdT {
+            __bindgen_anon_1: TtIdTBindgenTy1 {
+                __bindgen_anon_1: bits,
+            },
+        };
+
+        let properties = self.types.borrow()[object_type.u64_ as usize]
+            .properties
+            .len();
+        objects.insert(
+            id_bits(id),
+            Object {
+                version: self.bump_version(),
+                values: vec![Value::None; properties],
+            },
+        );
+        id
+    }
+
+    /// Writes a buffer, like something that doesn't know about anode would.
+    pub fn set_buffer(&self, object: TtIdT, property: u32, bytes: &[u8]) {
+        let id = self.add_bytes(bytes.to_vec());
+        self.set_value(object, property, Value::Buffer(id));
+    }
+
+    pub fn buffer(&self, object: TtIdT, property: u32) -> Vec<u8> {
+        match &self.objects.borrow()[&id_bits(object)].values[property as usize] {
+            Value::Buffer(id) => self.buffers.borrow()[*id as usize - 1].clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Copies an object from another fake truth, like syncing it over the network.
+    pub fn sync_from(&self, other: &FakeTruth, object: TtIdT) {
+        let mut copy = other.objects.borrow()[&id_bits(object)].clone();
+        for value in &mut copy.values {
+            if let Value::Buffer(id) = value {
+                let bytes = other.buffers.borrow()[*id as usize - 1].clone();
+                *id = self.add_bytes(bytes);
+            }
+        }
+        copy.version = self.bump_version();
+        self.objects.borrow_mut().insert(id_bits(object), copy);
+    }
+
+    fn set_value(&self, object: TtIdT, property: u32, value: Value) {
+        let version = self.bump_version();
+        let mut objects = self.objects.borrow_mut();
+        let object = objects.get_mut(&id_bits(object)).unwrap();
+        object.values[property as usize] = value;
+        object.version = version;
+    }
+
+    fn add_bytes(&self, bytes: Vec<u8>) -> u32 {
+        let mut buffers = self.buffers.borrow_mut();
+        buffers.push(bytes);
+        buffers.len() as u32
+    }
+
+    fn bump_version(&self) -> u32 {
+        let version = self.next_version.get();
+        self.next_version.set(version + 1);
+        version
+    }
+
+    fn read_object<T>(&self, object: *const TheTruthObjectO, f: impl FnOnce(&Object) -> T) -> T {
+        f(&self.objects.borrow()[&(object as u64)])
+    }
+
+    fn write_object(&self, object: *mut TheTruthObjectO, f: impl FnOnce(&mut Object)) {
+        f(&mut self
+            .writes
+            .borrow_mut()
+            .get_mut(&(object as usize))
+            .unwrap()
+            .1)
+    }
+}
+
+fn id_bits(id: TtIdT) -> u64 {
+    unsafe { id.__bindgen_anon_1.u64_ }
+}
+
+unsafe fn fake<'a>(tt: *const TheTruthO) -> &'a FakeTruth {
+    &*(tt as *const FakeTruth)
+}
+
+unsafe extern "C" fn buffers(tt: *mut TheTruthO) -> *mut BuffersI {
+    fake(tt).buffers_i.as_ptr()
+}
+
+unsafe extern "C" fn get_aspect(
+    tt: *const TheTruthO,
+    object_type: TtTypeT,
+    aspect: StrhashT,
+) -> *mut c_void {
+    if aspect.u64_ != ASPECT_ANODE.hash.u64_ {
+        return null_mut();
+    }
+    match &fake(tt).types.borrow()[object_type.u64_ as usize].aspect {
+        Some(aspect) => &**aspect as *const AnodeAspectI as *mut c_void,
+        None => null_mut(),
+    }
+}
+
+unsafe extern "C" fn num_properties(tt: *const TheTruthO, object_type: TtTypeT) -> u32 {
+    fake(tt).types.borrow()[object_type.u64_ as usize]
+        .properties
+        .len() as u32
+}
+
+unsafe extern "C" fn properties(
+    tt: *const TheTruthO,
+    object_type: TtTypeT,
+) -> *const TheTruthPropertyDefinitionT {
+    fake(tt).types.borrow()[object_type.u64_ as usize]
+        .properties
+        .as_ptr()
+}
+
+unsafe extern "C" fn read(_tt: *const TheTruthO, object: TtIdT) -> *const TheTruthObjectO {
+    id_bits(object) as *const TheTruthObjectO
+}
+
+unsafe extern "C" fn write(tt: *mut TheTruthO, object: TtIdT) -> *mut TheTruthObjectO {
+    let truth = fake(tt);
+    let copy = truth.objects.borrow()[&id_bits(object)].clone();
+    let write = truth.next_write.get();
+    truth.next_write.set(write + 1);
+    truth
+        .writes
+        .borrow_mut()
+        .insert(write, (id_bits(object), copy));
+    write as *mut TheTruthObjectO
+}
+
+unsafe extern "C" fn commit(
+    tt: *mut TheTruthO,
+    object: *mut TheTruthObjectO,
+    _undo_scope: TtUndoScopeT,
+) {
+    let truth = fake(tt);
+    let (id, mut copy) = truth
+        .writes
+        .borrow_mut()
+        .remove(&(object as usize))
+        .unwrap();
+    copy.version = truth.bump_version();
+    truth.objects.borrow_mut().insert(id, copy);
+}
+
+unsafe extern "C" fn version(tt: *const TheTruthO, object: TtIdT) -> u32 {
+    fake(tt).objects.borrow()[&id_bits(object)].version
+}
+
+unsafe extern "C" fn is_alive(tt: *const TheTruthO, object: TtIdT) -> bool {
+    fake(tt).objects.borrow().contains_key(&id_bits(object))
+}
+
+unsafe extern "C" fn get_buffer(
+    tt: *const TheTruthO,
+    object: *const TheTruthObjectO,
+    property: u32,
+) -> TtBufferT {
+    let id = fake(tt).read_object(object, |object| match object.values[property as usize] {
+        Value::Buffer(id) => id,
+        _ => 0,
+    });
+    TtBufferT {
+        id,
+        _padding_627: [0; 4],
+        size: 0,
+        data: null(),
+        hash: 0,
+    }
+}
+
+unsafe extern "C" fn set_buffer(
+    tt: *mut TheTruthO,
+    object: *mut TheTruthObjectO,
+    property: u32,
+    value: u32,
+) {
+    fake(tt).write_object(object, |object| {
+        object.values[property as usize] = Value::Buffer(value)
+    });
+}
+
+unsafe extern "C" fn get_string(
+    tt: *const TheTruthO,
+    object: *const TheTruthObjectO,
+    property: u32,
+) -> *const c_char {
+    fake(tt).read_object(object, |object| match &object.values[property as usize] {
+        Value::String(value) => value.as_ptr(),
+        _ => null(),
+    })
+}
+
+unsafe extern "C" fn set_string(
+    tt: *mut TheTruthO,
+    object: *mut TheTruthObjectO,
+    property: u32,
+    value: *const c_char,
+) {
+    let value = CStr::from_ptr(value).to_owned();
+    fake(tt).write_object(object, |object| {
+        object.values[property as usize] = Value::String(value)
+    });
+}
+
+unsafe extern "C" fn allocate_buffer(
+    inst: *mut BuffersO,
+    size: u64,
+    initialize: *const c_void,
+) -> *mut c_void {
+    let mut bytes = vec![0; size as usize];
+    if !initialize.is_null() {
+        bytes.copy_from_slice(std::slice::from_raw_parts(
+            initialize as *const u8,
+            size as usize,
+        ));
+    }
+
+    // The address stays the same when the vector is moved into the map
+    let address = bytes.as_mut_ptr();
+    fake(inst as *const TheTruthO)
+        .allocations
+        .borrow_mut()
+        .insert(address as usize, bytes);
+    address as *mut c_void
+}
+
+unsafe extern "C" fn add_buffer(
+    inst: *mut BuffersO,
+    data: *const c_void,
+    _size: u64,
+    _hash: u64,
+) -> u32 {
+    let truth = fake(inst as *const TheTruthO);
+    let bytes = truth
+        .allocations
+        .borrow_mut()
+        .remove(&(data as usize))
+        .unwrap();
+    truth.add_bytes(bytes)
+}
+
+unsafe extern "C" fn get_buffer_data(
+    inst: *const BuffersO,
+    id: u32,
+    size: *mut u64,
+) -> *const c_void {
+    if id == 0 {
+        return null();
+    }
+
+    let buffers = fake(inst as *const TheTruthO).buffers.borrow();
+    let bytes = &buffers[id as usize - 1];
+    *size = bytes.len() as u64;
+    bytes.as_ptr() as *const c_void
+}
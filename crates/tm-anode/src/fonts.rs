@@ -1,60 +1,444 @@
-use std::ffi::CString;
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    path::{Path, PathBuf},
+};
 
 use const_cstr::const_cstr;
+use eyre::{eyre, Result};
 use font_kit::{
-    family_name::FamilyName, handle::Handle, properties::Properties, source::SystemSource,
+    family_name::FamilyName, font::Font, handle::Handle, properties::Properties,
+    source::SystemSource,
 };
-use machinery::{identifier, Identifier, RegistryStorage};
+use machinery::{identifier, Identifier};
 use machinery_api::{
-    foundation::ApiRegistryApi,
-    plugins::ui::{FontDescriptorT, FontProviderT, TtfRangeT, TM_FONT_PROVIDER_T_VERSION},
+    foundation::{ApiRegistryApi, StrhashT, UiO},
+    plugins::ui::{FontDescriptorT, FontProviderT, TtfRangeT, UiFontT, TM_FONT_PROVIDER_T_VERSION},
 };
+use tracing::{event, Level};
 
-pub fn register(registry: &ApiRegistryApi, registry_storage: &mut RegistryStorage) {
-    unsafe {
-        let range = TtfRangeT { start: 32, n: 95 };
-
-        // Lookup the font using font-kit
-        let source = SystemSource::new();
-        let font = source
-            .select_best_match(
-                &[
-                    FamilyName::Title("Consolas".to_string()),
-                    FamilyName::Monospace,
-                ],
-                &Properties::new(),
-            )
-            .unwrap();
-        let font_path = if let Handle::Path { path, .. } = font {
-            path
-        } else {
-            panic!("Unable to locate any font.");
-        };
-
-        let font_path_c = CString::new(font_path.to_str().unwrap()).unwrap();
-
-        let descriptor = FontDescriptorT {
-            path: font_path_c.as_ptr(),
-            ranges: registry_storage.add(range),
-            num_ranges: 1,
-            ..Default::default()
-        };
-        registry_storage.add(font_path_c);
+use crate::plugin::PluginData;
 
-        let provider = FontProviderT {
-            font_id: ANODE_CODE_FONT.hash,
-            font_size: 10,
-            descriptor: registry_storage.add(descriptor),
-            ..Default::default()
-        };
+/// Font providers for the code font, registered on demand for every requested family and size.
+pub struct CodeFonts {
+    /// The family the current fonts were looked up for.
+    family: Option<String>,
+    /// Source of new font IDs, every change to a face's glyph ranges needs a new ID so the UI
+    /// doesn't re-use glyphs cached for the old ranges.
+    next_font_id: u64,
+    /// The primary face first, followed by fallback faces for glyphs it doesn't have.
+    faces: Vec<FontFace>,
+    /// Fallback families that haven't been looked up yet.
+    pending_fallbacks: Vec<&'static str>,
+    /// Face index for every non-ASCII character seen so far.
+    char_faces: HashMap<char, usize>,
+    /// Descriptors replaced by new ranges, the UI may still reference these.
+    retired: Vec<FontData>,
+}
+
+struct FontFace {
+    path: PathBuf,
+    font_index: u32,
+    /// Used to check which characters the face has glyphs for.
+    font: Font,
+    font_id: StrhashT,
+    data: FontData,
+    /// Boxed, the registry keeps pointers to the providers.
+    #[allow(clippy::vec_box)]
+    providers: Vec<Box<FontProviderT>>,
+}
+
+struct FontData {
+    _path: CString,
+    ranges: Vec<TtfRangeT>,
+    descriptor: Box<FontDescriptorT>,
+}
+
+/// Code font faces for one size, ready for drawing.
+pub struct CodeFont {
+    /// The primary font, metrics are always based on this one.
+    pub font: UiFontT,
+    /// Fallback fonts, indexed by `face - 1`.
+    pub fallbacks: Vec<UiFontT>,
+    /// Characters that need a fallback font, and the index of the face to use.
+    pub fallback_chars: HashMap<char, usize>,
+}
+
+unsafe impl Send for CodeFonts {}
+
+impl CodeFonts {
+    pub fn new() -> Self {
+        Self {
+            family: None,
+            next_font_id: ANODE_CODE_FONT.hash.u64_,
+            faces: Vec::new(),
+            pending_fallbacks: Vec::new(),
+            char_faces: HashMap::new(),
+            retired: Vec::new(),
+        }
+    }
+
+    /// Unregisters all font providers.
+    pub fn clear(&mut self, registry: &ApiRegistryApi) {
+        for face in &mut self.faces {
+            face.clear(registry);
+        }
+    }
+
+    unsafe fn set_family(&mut self, registry: &ApiRegistryApi, family: &str) {
+        if self.family.as_deref() == Some(family) {
+            return;
+        }
+
+        // Providers for the old fonts are no longer valid
+        self.clear(registry);
+        let faces = std::mem::take(&mut self.faces);
+        self.retired.extend(faces.into_iter().map(|face| face.data));
+        self.char_faces.clear();
+        self.family = Some(family.to_string());
+        self.pending_fallbacks = GLYPH_FALLBACK_FAMILIES.to_vec();
+
+        let result = locate_font(family).and_then(|(path, font_index)| {
+            event!(Level::INFO, "Using code font {:?}", path);
+            self.load_face(path, font_index)
+        });
+        match result {
+            Ok(face) => self.faces.push(face),
+            Err(error) => event!(Level::ERROR, "{}", error),
+        }
+    }
+
+    fn load_face(&mut self, path: PathBuf, font_index: u32) -> Result<FontFace> {
+        let font = Font::from_path(&path, font_index)
+            .map_err(|error| eyre!("Failed to load font {:?}: {:?}", path, error))?;
+        let data = FontData::new(&path, font_index, DEFAULT_RANGES.to_vec());
+
+        Ok(FontFace {
+            path,
+            font_index,
+            font,
+            font_id: self.new_font_id(),
+            data,
+            providers: Vec::new(),
+        })
+    }
+
+    fn new_font_id(&mut self) -> StrhashT {
+        self.next_font_id = self.next_font_id.wrapping_add(1);
+        StrhashT {
+            u64_: self.next_font_id,
+        }
+    }
+
+    /// Makes sure the faces have glyph ranges for all characters in the text.
+    fn ensure_glyphs(&mut self, registry: &ApiRegistryApi, text: &str) {
+        if self.faces.is_empty() {
+            return;
+        }
+
+        for c in text.chars() {
+            // ASCII is always in the primary font's ranges
+            if (c as u32) < 127 || self.char_faces.contains_key(&c) {
+                continue;
+            }
+
+            let face = self.face_for_char(c);
+            self.char_faces.insert(c, face);
+
+            if !contains(&self.faces[face].data.ranges, c) {
+                self.add_range(registry, face, c);
+            }
+        }
+    }
+
+    /// Finds the face that has a glyph for the character, loading fallbacks if necessary.
+    fn face_for_char(&mut self, c: char) -> usize {
+        if let Some(face) = self
+            .faces
+            .iter()
+            .position(|face| face.font.glyph_for_char(c).is_some())
+        {
+            return face;
+        }
+
+        while let Some(family) = self.pending_fallbacks.pop() {
+            let handle = SystemSource::new()
+                .select_best_match(&[FamilyName::Title(family.to_string())], &Properties::new());
+            let (path, font_index) = match handle {
+                Ok(Handle::Path { path, font_index }) => (path, font_index),
+                _ => continue,
+            };
+
+            // Different family names may resolve to the same file
+            if self
+                .faces
+                .iter()
+                .any(|face| face.path == path && face.font_index == font_index)
+            {
+                continue;
+            }
+
+            match self.load_face(path, font_index) {
+                Ok(face) => {
+                    event!(Level::INFO, "Using fallback font {:?}", face.path);
+                    self.faces.push(face);
+                }
+                Err(error) => {
+                    event!(Level::WARN, "{}", error);
+                    continue;
+                }
+            }
+
+            let face = self.faces.len() - 1;
+            if self.faces[face].font.glyph_for_char(c).is_some() {
+                return face;
+            }
+        }
+
+        // Nothing has it, let the primary font draw its missing glyph
+        0
+    }
+
+    /// Adds the block of characters around the character to the face's glyph ranges.
+    fn add_range(&mut self, registry: &ApiRegistryApi, face: usize, c: char) {
+        let font_id = self.new_font_id();
+        let face = &mut self.faces[face];
+
+        // Shrink the block so it doesn't overlap existing ranges
+        let c = c as u32;
+        let mut start = c & !(RANGE_BLOCK_SIZE - 1);
+        let mut end = start + RANGE_BLOCK_SIZE;
+        for range in &face.data.ranges {
+            let range_end = range.start + range.n;
+            if range_end <= c && range_end > start {
+                start = range_end;
+            }
+            if range.start > c && range.start < end {
+                end = range.start;
+            }
+        }
+
+        let mut ranges = face.data.ranges.clone();
+        ranges.push(TtfRangeT {
+            start,
+            n: end - start,
+        });
+        ranges.sort_unstable_by_key(|range| range.start);
+
+        // The ranges are baked into the registered providers, so those have to be replaced
+        face.clear(registry);
+        let data = FontData::new(&face.path, face.font_index, ranges);
+        self.retired.push(std::mem::replace(&mut face.data, data));
+        face.font_id = font_id;
+    }
+}
+
+impl FontFace {
+    fn clear(&mut self, registry: &ApiRegistryApi) {
+        for provider in self.providers.drain(..) {
+            unsafe {
+                registry.remove_implementation(
+                    const_cstr!("tm_font_provider_t").as_ptr(),
+                    TM_FONT_PROVIDER_T_VERSION,
+                    provider.as_ref() as *const _ as *const _,
+                );
+            }
+        }
+    }
+
+    unsafe fn ensure_provider(&mut self, registry: &ApiRegistryApi, size: u32) {
+        if self.providers.iter().any(|v| v.font_size == size) {
+            return;
+        }
 
-        registry_storage.add_implementation(
-            registry,
+        let provider = Box::new(FontProviderT {
+            font_id: self.font_id,
+            font_size: size,
+            descriptor: self.data.descriptor.as_ref(),
+            ..Default::default()
+        });
+        registry.add_implementation(
             const_cstr!("tm_font_provider_t").as_ptr(),
             TM_FONT_PROVIDER_T_VERSION,
-            provider,
+            provider.as_ref() as *const _ as *const _,
         );
+        self.providers.push(provider);
+    }
+}
+
+impl FontData {
+    fn new(path: &Path, font_index: u32, ranges: Vec<TtfRangeT>) -> Self {
+        let path = CString::new(path.to_string_lossy().as_bytes()).unwrap();
+
+        let descriptor = Box::new(FontDescriptorT {
+            path: path.as_ptr(),
+            ranges: ranges.as_ptr(),
+            num_ranges: ranges.len() as u32,
+            font_index,
+            ..Default::default()
+        });
+
+        Self {
+            _path: path,
+            ranges,
+            descriptor,
+        }
+    }
+}
+
+impl CodeFont {
+    /// Creates a code font without fallbacks, from an already loaded UI font.
+    pub fn from_ui_font(font: UiFontT) -> Self {
+        Self {
+            font,
+            fallbacks: Vec::new(),
+            fallback_chars: HashMap::new(),
+        }
+    }
+
+    /// Gets the font to draw the character with.
+    pub fn font_for_char(&self, c: char) -> &UiFontT {
+        self.fallback_chars
+            .get(&c)
+            .and_then(|face| self.fallbacks.get(face - 1))
+            .unwrap_or(&self.font)
+    }
+}
+
+/// Gets the code font for a family and size, registering it with the UI if necessary.
+///
+/// Glyphs for all characters in `text` are made available, either in the code font itself or in a
+/// fallback font. Returns `None` if no usable font could be found on the system.
+pub unsafe fn code_font(
+    data: &PluginData,
+    ui: *mut UiO,
+    family: &str,
+    size: u32,
+    text: &str,
+) -> Option<CodeFont> {
+    let registry = &*data.apis.registry;
+    let ui_api = &*data.apis.ui;
+    let mut fonts = data.code_fonts.lock().unwrap();
+
+    fonts.set_family(registry, family);
+    fonts.ensure_glyphs(registry, text);
+
+    let mut faces = Vec::with_capacity(fonts.faces.len());
+    for face in &mut fonts.faces {
+        face.ensure_provider(registry, size);
+        faces.push(ui_api.font(ui, face.font_id, size));
+    }
+
+    if faces.is_empty() {
+        return None;
+    }
+    let font = faces.remove(0);
+
+    let fallback_chars = fonts
+        .char_faces
+        .iter()
+        .filter(|(_, face)| **face != 0)
+        .map(|(c, face)| (*c, *face))
+        .collect();
+
+    Some(CodeFont {
+        font,
+        fallbacks: faces,
+        fallback_chars,
+    })
+}
+
+/// Finds a font file for the family, falling back to common monospace fonts.
+fn locate_font(family: &str) -> Result<(PathBuf, u32)> {
+    let source = SystemSource::new();
+
+    let mut families = Vec::new();
+    if !family.is_empty() {
+        families.push(FamilyName::Title(family.to_string()));
     }
+    families.extend(
+        FALLBACK_FAMILIES
+            .iter()
+            .map(|v| FamilyName::Title(v.to_string())),
+    );
+    families.push(FamilyName::Monospace);
+
+    for (i, candidate) in families.into_iter().enumerate() {
+        // Fonts that are only available in-memory can't be passed to the UI, skip those
+        let handle = source.select_best_match(&[candidate], &Properties::new());
+        if let Ok(Handle::Path { path, font_index }) = handle {
+            if i != 0 && !family.is_empty() {
+                event!(Level::WARN, "Font \"{}\" not found, falling back", family);
+            }
+
+            return Ok((path, font_index));
+        }
+    }
+
+    Err(eyre!("Unable to locate any monospace font"))
+}
+
+fn contains(ranges: &[TtfRangeT], c: char) -> bool {
+    let c = c as u32;
+    ranges
+        .iter()
+        .any(|range| c >= range.start && c < range.start + range.n)
 }
 
+const FALLBACK_FAMILIES: [&str; 5] = [
+    "Consolas",
+    "Cascadia Mono",
+    "DejaVu Sans Mono",
+    "Menlo",
+    "Liberation Mono",
+];
+
+/// Families checked for glyphs missing from the code font, popped from the back.
+const GLYPH_FALLBACK_FAMILIES: [&str; 13] = [
+    "Arial Unicode MS",
+    "Noto Color Emoji",
+    "Apple Color Emoji",
+    "Segoe UI Emoji",
+    "Noto Sans Symbols2",
+    "Noto Sans Symbols",
+    "Segoe UI Symbol",
+    "DejaVu Sans",
+    "Noto Sans CJK SC",
+    "PingFang SC",
+    "Microsoft YaHei",
+    "MS Gothic",
+    "Noto Sans Mono",
+];
+
+/// Ranges registered by default, covering most text that isn't CJK or symbols.
+const DEFAULT_RANGES: [TtfRangeT; 6] = [
+    // Basic Latin
+    TtfRangeT { start: 32, n: 95 },
+    // Latin-1 Supplement
+    TtfRangeT { start: 160, n: 96 },
+    // Latin Extended-A and B
+    TtfRangeT {
+        start: 0x100,
+        n: 0x150,
+    },
+    // Greek and Coptic
+    TtfRangeT {
+        start: 0x370,
+        n: 0x90,
+    },
+    // Cyrillic
+    TtfRangeT {
+        start: 0x400,
+        n: 0x100,
+    },
+    // Box Drawing and Block Elements
+    TtfRangeT {
+        start: 0x2500,
+        n: 0xA0,
+    },
+];
+
+/// Size of the aligned blocks of characters added to the ranges on demand.
+const RANGE_BLOCK_SIZE: u32 = 128;
+
 pub const ANODE_CODE_FONT: Identifier = identifier!("tm_anode_code_font");
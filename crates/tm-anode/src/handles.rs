@@ -0,0 +1,110 @@
+use std::sync::{Arc, Mutex, Weak};
+
+use machinery::tt_id_eq;
+use machinery_api::foundation::{TheTruthO, TtIdT};
+use tm_anode_api::AnodeDocumentO;
+
+use crate::document::DocumentState;
+
+/// Documents open in editors, which other plugins can get handles to.
+///
+/// Handles are ids that are never reused, so a handle to a closed document is simply ignored
+/// rather than accessing freed memory or resolving to a document opened after it.
+#[derive(Default)]
+pub struct OpenDocuments {
+    next_id: u64,
+    documents: Vec<(u64, Weak<Mutex<DocumentState>>)>,
+}
+
+impl OpenDocuments {
+    /// Adds a document, returning the handle other plugins can refer to it by.
+    pub fn add(&mut self, document: &Arc<Mutex<DocumentState>>) -> *mut AnodeDocumentO {
+        self.documents
+            .retain(|(_, document)| document.strong_count() > 0);
+
+        // Start at 1, so handles are never null
+        self.next_id += 1;
+        self.documents
+            .push((self.next_id, Arc::downgrade(document)));
+        self.next_id as *mut AnodeDocumentO
+    }
+
+    /// Gets the document a handle refers to, if it's still open.
+    pub fn get(&self, handle: *mut AnodeDocumentO) -> Option<Arc<Mutex<DocumentState>>> {
+        self.documents
+            .iter()
+            .find(|(id, _)| *id as *mut AnodeDocumentO == handle)
+            .and_then(|(_, document)| document.upgrade())
+    }
+
+    /// All documents that are still open.
+    pub fn all(&self) -> Vec<Arc<Mutex<DocumentState>>> {
+        self.documents
+            .iter()
+            .filter_map(|(_, document)| document.upgrade())
+            .collect()
+    }
+
+    /// Finds the handle of a document that has an asset open.
+    pub fn find(&self, tt: *mut TheTruthO, asset: TtIdT) -> Option<*mut AnodeDocumentO> {
+        self.documents.iter().find_map(|(id, document)| {
+            let document = document.upgrade()?;
+            let document = document.lock().unwrap();
+            let (document_tt, root, _) = document.asset()?;
+            (document_tt == tt && tt_id_eq(root, asset)).then_some(*id as *mut AnodeDocumentO)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use machinery_api::foundation::TM_THE_TRUTH_PROPERTY_TYPE_BUFFER;
+    use tm_anode_api::AnodeAspectI;
+
+    use super::*;
+    use crate::fake_truth::{self, FakeTruth};
+
+    #[test]
+    fn every_open_document_is_committed() {
+        let data = fake_truth::plugin_data();
+        let truth = FakeTruth::new();
+        let aspect = AnodeAspectI {
+            property: 0,
+            ..Default::default()
+        };
+        let text_type = truth.add_type(&[TM_THE_TRUTH_PROPERTY_TYPE_BUFFER], Some(aspect));
+
+        let mut documents = Vec::new();
+        for text in ["one\n", "two\n"] {
+            let asset = truth.create_object(text_type);
+            truth.set_buffer(asset, 0, text.as_bytes());
+
+            let mut document = DocumentState::new();
+            unsafe { document.load_from_asset(&data, truth.tt(), asset).unwrap() };
+            document.replace_range(0, 0, "edited ").unwrap();
+            let document = Arc::new(Mutex::new(document));
+            data.open_documents.lock().unwrap().add(&document);
+            documents.push((asset, document));
+        }
+
+        data.commit_documents();
+        assert_eq!(truth.buffer(documents[0].0, 0), b"edited one\n");
+        assert_eq!(truth.buffer(documents[1].0, 0), b"edited two\n");
+    }
+    #[test]
+    fn handles_to_closed_documents_stay_closed() {
+        let mut open_documents = OpenDocuments::default();
+
+        let closed = Arc::new(Mutex::new(DocumentState::new()));
+        let closed_handle = open_documents.add(&closed);
+        drop(closed);
+
+        // Even if the new document is allocated where the closed one was
+        let document = Arc::new(Mutex::new(DocumentState::new()));
+        let handle = open_documents.add(&document);
+
+        assert_ne!(handle, closed_handle);
+        assert!(open_documents.get(closed_handle).is_none());
+        assert!(Arc::ptr_eq(&open_documents.get(handle).unwrap(), &document));
+    }
+}
@@ -0,0 +1,130 @@
+use std::{ffi::CStr, os::raw::c_char, time::Duration};
+
+use tm_anode_api::{
+    AnodeDocumentO, AnodeHoverO, AnodeHoverProviderI, AnodePositionT, ANODE_HOVER_PROVIDER_I,
+    ANODE_HOVER_PROVIDER_I_VERSION,
+};
+use tracing::{event, Level};
+
+use crate::{document::DocumentState, plugin::PluginData, text};
+
+/// How long the mouse has to rest on a word before information about it is shown.
+pub const HOVER_DELAY: Duration = Duration::from_millis(500);
+
+/// Asks the hover providers registered by other plugins for information about a word.
+///
+/// Providers can access the document through the API, so it must not be locked.
+pub unsafe fn query_providers(
+    data: &PluginData,
+    document: *mut AnodeDocumentO,
+    position: AnodePositionT,
+) -> Vec<String> {
+    let registry = &*data.apis.registry;
+    let providers = registry.implementations(
+        ANODE_HOVER_PROVIDER_I.as_ptr(),
+        ANODE_HOVER_PROVIDER_I_VERSION,
+    ) as *const *const AnodeHoverProviderI;
+    let count = registry.num_implementations(
+        ANODE_HOVER_PROVIDER_I.as_ptr(),
+        ANODE_HOVER_PROVIDER_I_VERSION,
+    );
+
+    let mut sections: Vec<String> = Vec::new();
+    for i in 0..count as usize {
+        let provider = &**providers.add(i);
+        (provider.hover)(
+            provider.inst,
+            document,
+            position,
+            &mut sections as *mut _ as *mut AnodeHoverO,
+        );
+    }
+    sections
+}
+
+/// Adds a section from a provider to the tooltip being gathered by [`query_providers`].
+pub unsafe fn add_hover(hover: *mut AnodeHoverO, markdown: *const c_char) {
+    if hover.is_null() || markdown.is_null() {
+        event!(Level::WARN, "Hover added without a tooltip or text.");
+        return;
+    }
+
+    let markdown = CStr::from_ptr(markdown).to_string_lossy().into_owned();
+    (*(hover as *mut Vec<String>)).push(markdown);
+}
+
+/// Information about the word under the mouse, gathered from providers.
+pub struct Hover {
+    /// Byte range of the word.
+    start: usize,
+    end: usize,
+    /// Byte offset the mouse is on.
+    offset: usize,
+    /// Position to ask providers about, until they've been asked.
+    provider_request: Option<AnodePositionT>,
+    /// If the language server still has to be asked about the word.
+    server_request: bool,
+    /// Markdown text of each provider that had something to show.
+    sections: Vec<String>,
+}
+
+impl Hover {
+    /// Starts gathering information about the word at a byte offset, if there is one.
+    pub fn new(document: &DocumentState, offset: usize) -> Option<Self> {
+        let content = document.text();
+        let on_word = content[offset..]
+            .chars()
+            .next()
+            .is_some_and(text::is_identifier_char);
+        if !on_word {
+            return None;
+        }
+
+        let (line, column) = document.line_byte_column(offset);
+        Some(Self {
+            start: text::word_start(content, offset),
+            end: text::word_end(content, offset),
+            offset,
+            provider_request: Some(AnodePositionT {
+                line: line as u32,
+                column: column as u32,
+            }),
+            server_request: true,
+            sections: Vec::new(),
+        })
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// If a byte offset is on the same word.
+    pub fn contains(&self, offset: usize) -> bool {
+        self.start <= offset && offset < self.end
+    }
+
+    pub fn take_provider_request(&mut self) -> Option<AnodePositionT> {
+        self.provider_request.take()
+    }
+
+    /// Takes the offset to ask the language server about, if it hasn't been asked yet.
+    pub fn take_server_request(&mut self) -> Option<usize> {
+        if std::mem::take(&mut self.server_request) {
+            Some(self.offset)
+        } else {
+            None
+        }
+    }
+
+    pub fn add(&mut self, sections: Vec<String>) {
+        self.sections.extend(
+            sections
+                .into_iter()
+                .filter(|section| !section.trim().is_empty()),
+        );
+    }
+
+    pub fn sections(&self) -> &[String] {
+        &self.sections
+    }
+}
@@ -1,7 +1,24 @@
+mod collab;
+mod completion;
+mod diagnostics;
 mod document;
+mod editor;
+mod encoding;
+#[cfg(test)]
+mod fake_truth;
 mod fonts;
+mod handles;
+mod hover;
+mod lsp;
+mod markdown;
+mod navigation;
 mod plugin;
+mod properties;
+mod settings;
+mod storage;
 mod tabs;
+mod text;
+mod theme;
 
 use machinery_api::foundation::ColorSrgbT;
 
@@ -11,14 +28,18 @@ struct TokenColor {
 }
 
 const fn hex_token_color(scope: &'static str, color: u32) -> TokenColor {
-    let bytes = color.to_le_bytes();
     TokenColor {
         scope,
-        color: ColorSrgbT {
-            r: bytes[3],
-            g: bytes[2],
-            b: bytes[1],
-            a: bytes[0],
-        },
+        color: hex_color(color),
+    }
+}
+
+const fn hex_color(color: u32) -> ColorSrgbT {
+    let bytes = color.to_le_bytes();
+    ColorSrgbT {
+        r: bytes[3],
+        g: bytes[2],
+        b: bytes[1],
+        a: bytes[0],
     }
 }
@@ -0,0 +1,469 @@
+use std::{
+    io::{BufReader, Read, Write},
+    process::{Child, Command, Stdio},
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use eyre::{eyre, Result, WrapErr};
+use serde_json::{json, Value};
+use tracing::{event, Level};
+
+use super::transport;
+
+/// How long a server gets to respond to the shutdown request before it's told to exit anyway.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a server gets to exit by itself before it's killed.
+const EXIT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Message received from a language server.
+pub enum Incoming {
+    Response {
+        id: u64,
+        /// The result, or the error message if the request failed.
+        result: Result<Value, String>,
+    },
+    Notification {
+        method: String,
+        params: Value,
+    },
+    Request {
+        id: Value,
+        method: String,
+        params: Value,
+    },
+}
+
+/// JSON-RPC connection to a language server.
+///
+/// Messages are read and written on background threads, so the UI never waits on the server.
+pub struct Client {
+    name: String,
+    child: Option<Child>,
+    outgoing: Option<Sender<Value>>,
+    incoming: Receiver<Value>,
+    next_id: u64,
+}
+
+impl Client {
+    /// Starts a language server process, talking to it over its standard input and output.
+    pub fn spawn(command: &[String]) -> Result<Self> {
+        let (program, args) = command
+            .split_first()
+            .ok_or_else(|| eyre!("Language server command is empty"))?;
+
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .wrap_err_with(|| format!("Failed to start language server \"{}\"", program))?;
+
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let mut client = Self::connect(program, stdout, stdin);
+        client.child = Some(child);
+
+        Ok(client)
+    }
+
+    /// Connects to a language server over existing streams.
+    pub fn connect(
+        name: &str,
+        reader: impl Read + Send + 'static,
+        mut writer: impl Write + Send + 'static,
+    ) -> Self {
+        let (outgoing, outgoing_rx) = mpsc::channel::<Value>();
+        let (incoming_tx, incoming) = mpsc::channel();
+
+        let reader_name = name.to_string();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            loop {
+                match transport::read_message(&mut reader) {
+                    Ok(Some(message)) => {
+                        if incoming_tx.send(message).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(error) => {
+                        event!(
+                            Level::ERROR,
+                            "Failed to read from language server \"{}\": {}",
+                            reader_name,
+                            error
+                        );
+                        break;
+                    }
+                }
+            }
+        });
+
+        let writer_name = name.to_string();
+        thread::spawn(move || {
+            for message in outgoing_rx {
+                if let Err(error) = transport::write_message(&mut writer, &message) {
+                    event!(
+                        Level::ERROR,
+                        "Failed to write to language server \"{}\": {}",
+                        writer_name,
+                        error
+                    );
+                    break;
+                }
+            }
+        });
+
+        Self {
+            name: name.to_string(),
+            child: None,
+            outgoing: Some(outgoing),
+            incoming,
+            next_id: 0,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Sends a request, returning its id to match the response with.
+    pub fn request(&mut self, method: &str, params: Value) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        }));
+        id
+    }
+
+    pub fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+        }));
+    }
+
+    /// Responds to a request from the server.
+    pub fn respond(&mut self, id: Value, result: Value) {
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "result": result,
+        }));
+    }
+
+    fn send(&mut self, message: Value) {
+        if let Some(outgoing) = &self.outgoing {
+            // If the writer stopped, the reader will notice the server is gone too
+            let _ = outgoing.send(message);
+        }
+    }
+
+    /// Takes the messages received since the last poll.
+    ///
+    /// Returns an error once the server has closed the connection and all messages are taken.
+    pub fn poll(&mut self) -> Result<Vec<Incoming>> {
+        let mut messages = Vec::new();
+        loop {
+            match self.incoming.try_recv() {
+                Ok(message) => {
+                    if let Some(message) = parse_incoming(message) {
+                        messages.push(message);
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    if messages.is_empty() {
+                        return Err(eyre!("Language server \"{}\" stopped", self.name));
+                    }
+                    break;
+                }
+            }
+        }
+
+        Ok(messages)
+    }
+
+    /// Asks the server to exit, killing it if it doesn't do so shortly.
+    ///
+    /// The server is told to exit once it responded to the shutdown request, which is waited for
+    /// on a background thread that also reaps the server. Returns that thread.
+    pub fn shutdown(&mut self) -> JoinHandle<()> {
+        let id = self.request("shutdown", Value::Null);
+
+        // The connection is only used by the background thread from here on
+        let outgoing = self.outgoing.take();
+        let (_, closed) = mpsc::channel();
+        let incoming = std::mem::replace(&mut self.incoming, closed);
+        let mut child = self.child.take();
+
+        thread::spawn(move || {
+            // Anything else the server still sends is no longer of interest
+            let start = Instant::now();
+            while let Some(timeout) = SHUTDOWN_TIMEOUT.checked_sub(start.elapsed()) {
+                match incoming.recv_timeout(timeout) {
+                    Ok(message) if message.get("method").is_none() && message["id"] == id => break,
+                    Ok(_) => {}
+                    Err(_) => break,
+                }
+            }
+
+            // Closing the channel stops the writer, which closes the server's input
+            if let Some(outgoing) = outgoing {
+                let _ = outgoing.send(json!({
+                    "jsonrpc": "2.0",
+                    "method": "exit",
+                    "params": null,
+                }));
+            }
+
+            if let Some(child) = &mut child {
+                let start = Instant::now();
+                while let Ok(None) = child.try_wait() {
+                    if start.elapsed() > EXIT_TIMEOUT {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+        })
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if self.outgoing.is_some() {
+            self.shutdown();
+        }
+    }
+}
+
+fn parse_incoming(mut message: Value) -> Option<Incoming> {
+    let method = message
+        .get("method")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    let params = message
+        .get_mut("params")
+        .map(Value::take)
+        .unwrap_or_default();
+
+    match (message.get_mut("id").map(Value::take), method) {
+        (Some(id), Some(method)) => Some(Incoming::Request { id, method, params }),
+        (None, Some(method)) => Some(Incoming::Notification { method, params }),
+        (Some(id), None) => {
+            let result = match message.get("error") {
+                Some(error) => Err(error
+                    .get("message")
+                    .and_then(Value::as_str)
+                    .unwrap_or("Unknown error")
+                    .to_string()),
+                None => Ok(message
+                    .get_mut("result")
+                    .map(Value::take)
+                    .unwrap_or_default()),
+            };
+
+            // We only send numeric ids, anything else isn't a response to us
+            Some(Incoming::Response {
+                id: id.as_u64()?,
+                result,
+            })
+        }
+        (None, None) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use super::{super::mock, *};
+
+    /// Polls the client until it received a message.
+    fn receive(client: &mut Client) -> Incoming {
+        let start = Instant::now();
+        loop {
+            if let Some(message) = client.poll().unwrap().pop() {
+                return message;
+            }
+            assert!(
+                start.elapsed() < mock::TIMEOUT,
+                "server didn't send a message"
+            );
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn responses_are_matched_by_id() {
+        let (mut client, mut server) = mock::connect();
+        let first = client.request("first", Value::Null);
+        let second = client.request("second", json!({ "value": 1 }));
+        assert_ne!(first, second);
+
+        let request = server.expect("first");
+        assert_eq!(request["jsonrpc"], "2.0");
+        assert_eq!(request["id"], first);
+        let request = server.expect("second");
+        assert_eq!(request["params"], json!({ "value": 1 }));
+
+        server.send(json!({
+            "jsonrpc": "2.0",
+            "id": second,
+            "error": { "code": -32601, "message": "Unknown method" },
+        }));
+        match receive(&mut client) {
+            Incoming::Response { id, result } => {
+                assert_eq!(id, second);
+                assert_eq!(result.unwrap_err(), "Unknown method");
+            }
+            _ => panic!("expected a response"),
+        }
+
+        server.respond(&json!({ "id": first }), json!([1, 2]));
+        match receive(&mut client) {
+            Incoming::Response { id, result } => {
+                assert_eq!(id, first);
+                assert_eq!(result.unwrap(), json!([1, 2]));
+            }
+            _ => panic!("expected a response"),
+        }
+    }
+
+    #[test]
+    fn notifications_and_requests_from_the_server() {
+        let (mut client, mut server) = mock::connect();
+
+        server.notify("window/logMessage", json!({ "message": "hello" }));
+        match receive(&mut client) {
+            Incoming::Notification { method, params } => {
+                assert_eq!(method, "window/logMessage");
+                assert_eq!(params["message"], "hello");
+            }
+            _ => panic!("expected a notification"),
+        }
+
+        server.send(json!({
+            "jsonrpc": "2.0",
+            "id": "configuration",
+            "method": "workspace/configuration",
+            "params": { "items": [] },
+        }));
+        let id = match receive(&mut client) {
+            Incoming::Request { id, method, .. } => {
+                assert_eq!(method, "workspace/configuration");
+                id
+            }
+            _ => panic!("expected a request"),
+        };
+
+        client.respond(id, Value::Null);
+        let response = server.receive();
+        assert_eq!(response["id"], "configuration");
+        assert_eq!(response["result"], Value::Null);
+    }
+
+    #[test]
+    fn shutdown_waits_for_the_response_before_exiting() {
+        let (mut client, mut server) = mock::connect();
+        client.shutdown();
+        let request = server.expect("shutdown");
+        server.expect_nothing();
+        server.respond(&request, Value::Null);
+        server.expect("exit");
+
+        // The connection is closed, so nothing else is sent
+        client.notify("textDocument/didClose", Value::Null);
+        server.expect_nothing();
+    }
+
+    #[test]
+    fn servers_that_dont_respond_to_shutdown_are_told_to_exit() {
+        let (mut client, mut server) = mock::connect();
+        client.shutdown();
+        server.expect("shutdown");
+        server.expect("exit");
+    }
+
+    /// Command running the mock language server, logging what it receives to a file.
+    ///
+    /// It's built by cargo along with the integration test that checks it, next to the directory
+    /// of the test executables.
+    fn mock_server(log: &Path, args: &[&str]) -> Vec<String> {
+        let executable = std::env::current_exe().unwrap();
+        let server = executable
+            .parent()
+            .and_then(Path::parent)
+            .unwrap()
+            .join(format!(
+                "mock-language-server{}",
+                std::env::consts::EXE_SUFFIX
+            ));
+        assert!(server.exists(), "mock language server isn't built");
+
+        let mut command = vec![server.display().to_string(), log.display().to_string()];
+        command.extend(args.iter().map(|arg| arg.to_string()));
+        command
+    }
+
+    fn log_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("anode-{}-{}.log", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn spawned_servers_are_shut_down_in_order() {
+        let log = log_path("shutdown");
+        let mut client = Client::spawn(&mock_server(&log, &[])).unwrap();
+
+        let id = client.request("initialize", json!({}));
+        match receive(&mut client) {
+            Incoming::Response {
+                id: response,
+                result,
+            } => {
+                assert_eq!(response, id);
+                assert_eq!(result.unwrap(), json!({ "capabilities": {} }));
+            }
+            _ => panic!("expected a response"),
+        }
+        client.notify("initialized", json!({}));
+
+        client.shutdown().join().unwrap();
+        let log = fs::read_to_string(&log).unwrap();
+        assert_eq!(
+            log.lines().collect::<Vec<_>>(),
+            ["initialize", "initialized", "shutdown", "exit", "exited"]
+        );
+    }
+
+    #[test]
+    fn spawned_servers_that_dont_exit_are_killed() {
+        let log = log_path("kill");
+        let mut client = Client::spawn(&mock_server(&log, &["--ignore-exit"])).unwrap();
+
+        let start = Instant::now();
+        client.shutdown().join().unwrap();
+        assert!(start.elapsed() < SHUTDOWN_TIMEOUT + EXIT_TIMEOUT * 2);
+
+        let log = fs::read_to_string(&log).unwrap();
+        assert_eq!(log.lines().collect::<Vec<_>>(), ["shutdown", "exit"]);
+    }
+}
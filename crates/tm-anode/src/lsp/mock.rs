@@ -0,0 +1,129 @@
+//! In-process language server for tests, talking to a [`Client`] over pipes.
+
+use std::{
+    io::{self, BufReader, Read, Write},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+
+use super::{client::Client, transport};
+
+/// How long to wait for a message before failing the test.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Creates a connected client and server.
+pub fn connect() -> (Client, MockServer) {
+    let (client_writer, server_reader) = pipe();
+    let (server_writer, client_reader) = pipe();
+    let client = Client::connect("mock", client_reader, client_writer);
+    (client, MockServer::new(server_reader, server_writer))
+}
+
+/// Language server side of a connection, sending and receiving raw messages.
+pub struct MockServer {
+    messages: Receiver<Value>,
+    writer: PipeWriter,
+}
+
+impl MockServer {
+    fn new(reader: PipeReader, writer: PipeWriter) -> Self {
+        let (sender, messages) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            while let Ok(Some(message)) = transport::read_message(&mut reader) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self { messages, writer }
+    }
+
+    /// Waits for the next message from the client.
+    pub fn receive(&mut self) -> Value {
+        self.messages
+            .recv_timeout(TIMEOUT)
+            .expect("client didn't send a message")
+    }
+
+    /// Waits for the next message, which should be a request or notification with the method.
+    pub fn expect(&mut self, method: &str) -> Value {
+        let message = self.receive();
+        assert_eq!(message["method"], method, "unexpected message {}", message);
+        message
+    }
+
+    /// Checks the client doesn't send anything for a moment.
+    pub fn expect_nothing(&mut self) {
+        if let Ok(message) = self.messages.recv_timeout(Duration::from_millis(50)) {
+            panic!("unexpected message {}", message);
+        }
+    }
+
+    pub fn send(&mut self, message: Value) {
+        transport::write_message(&mut self.writer, &message).unwrap();
+    }
+
+    pub fn respond(&mut self, request: &Value, result: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }));
+    }
+
+    pub fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+}
+
+fn pipe() -> (PipeWriter, PipeReader) {
+    let (sender, receiver) = mpsc::channel();
+    (
+        PipeWriter(sender),
+        PipeReader {
+            receiver,
+            buffer: Vec::new(),
+            position: 0,
+        },
+    )
+}
+
+struct PipeWriter(Sender<Vec<u8>>);
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct PipeReader {
+    receiver: Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.buffer.len() {
+            // The writer closing is the end of the stream
+            match self.receiver.recv() {
+                Ok(buffer) => self.buffer = buffer,
+                Err(_) => return Ok(0),
+            }
+            self.position = 0;
+        }
+
+        let length = buf.len().min(self.buffer.len() - self.position);
+        buf[..length].copy_from_slice(&self.buffer[self.position..self.position + length]);
+        self.position += length;
+        Ok(length)
+    }
+}
@@ -0,0 +1,1513 @@
+//! Client for language servers, which provide diagnostics and other language features.
+//!
+//! Each configured server is started once and shared by all documents of its languages. Documents
+//! are synced by sending their full text whenever it changes, as servers see them by the asset's
+//! path under the configured root directory.
+
+mod client;
+#[cfg(test)]
+mod mock;
+mod transport;
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use machinery::tt_id_eq;
+use machinery_api::foundation::{TheTruthO, TtIdT};
+use serde_json::{json, Value};
+use tm_anode_api::AnodeSeverity;
+use tracing::{event, Level};
+
+use crate::{
+    completion::Completion,
+    diagnostics::Diagnostic,
+    document::{self, DocumentState},
+    navigation::Location,
+    plugin::PluginData,
+    settings::EditorSettings,
+};
+
+use self::client::{Client, Incoming};
+
+/// Language server to start for documents with one of the extensions.
+pub struct LanguageServerConfig {
+    /// Lowercase file extensions, without the dot.
+    pub extensions: Vec<String>,
+    /// Program and its arguments.
+    pub command: Vec<String>,
+}
+
+/// Running language servers, and the documents synced with them.
+#[derive(Default)]
+pub struct LanguageServers {
+    servers: HashMap<Vec<String>, Server>,
+    /// Open documents, by their handle.
+    documents: HashMap<usize, OpenDocument>,
+    /// Assets by the URI servers know them by, both synced ones and ones found in locations.
+    uris: HashMap<String, (*mut TheTruthO, TtIdT)>,
+}
+
+// The truth pointers are only used through the truth API, which can be called from any thread
+unsafe impl Send for LanguageServers {}
+
+struct Server {
+    /// Connection to the server, `None` if it failed so it isn't restarted every frame.
+    client: Option<Client>,
+    /// Id of the initialize request, while waiting for the server to respond to it.
+    initialize: Option<u64>,
+    /// Notifications to send once the server is initialized.
+    queued: Vec<(String, Value)>,
+    capabilities: Value,
+    pending: HashMap<u64, PendingRequest>,
+    /// Directory the server was started in, which documents are found in by their path.
+    root: PathBuf,
+}
+
+/// Request waiting for a response, with the document version it was sent for.
+enum PendingRequest {
+    Formatting { document: usize, version: i32 },
+    Symbols { document: usize, version: i32 },
+    Completion { document: usize },
+    Hover { document: usize },
+    Locations { document: usize },
+}
+
+struct OpenDocument {
+    server: Vec<String>,
+    uri: String,
+    tt: *mut TheTruthO,
+    asset: TtIdT,
+    version: i32,
+    /// Text last sent to the server, which the positions it sends refer to.
+    text: String,
+    /// Version the symbols were last requested for.
+    symbols_version: Option<i32>,
+    symbols: Vec<Symbol>,
+    /// Edits from formatting, waiting to be applied by the editor.
+    edits: Vec<(usize, usize, String)>,
+    /// Latest completion request, responses to earlier ones are outdated.
+    completion_request: Option<u64>,
+    /// Completions the server responded with, waiting to be taken by the editor.
+    completions: Vec<Completion>,
+    /// Latest hover request, responses to earlier ones are outdated.
+    hover_request: Option<u64>,
+    /// Markdown text the server responded to the hover request with.
+    hover: Option<String>,
+    /// Latest definition or references request, responses to earlier ones are outdated.
+    locations_request: Option<u64>,
+    /// Locations the server responded with, waiting to be taken by the editor.
+    locations: Option<Vec<Location>>,
+}
+
+/// Named range of a document, like a function or type.
+struct Symbol {
+    name: String,
+    start: usize,
+    end: usize,
+}
+
+impl LanguageServers {
+    /// Syncs a document with its language server, starting the server if needed.
+    ///
+    /// This is cheap if nothing changed, so it can be done every frame.
+    pub unsafe fn update(
+        &mut self,
+        data: &PluginData,
+        handle: usize,
+        document: &DocumentState,
+        settings: &EditorSettings,
+    ) {
+        let asset = document.asset();
+        let file_name = document.file_name(data);
+        let (tt, asset, file_name) = match (asset, file_name) {
+            (Some((tt, asset, _)), Some(file_name)) if document.binary().is_none() => {
+                (tt, asset, file_name)
+            }
+            _ => {
+                self.close(data, handle);
+                return;
+            }
+        };
+
+        // Find the server for the document's extension
+        let extension = match file_name.rsplit_once('.') {
+            Some((_, extension)) => extension.to_lowercase(),
+            None => String::new(),
+        };
+        let config = match settings
+            .language_servers
+            .iter()
+            .find(|config| config.extensions.contains(&extension))
+        {
+            Some(config) => config,
+            None => {
+                self.close(data, handle);
+                return;
+            }
+        };
+
+        let root = if settings.language_server_root.is_empty() {
+            std::env::current_dir().unwrap_or_default()
+        } else {
+            PathBuf::from(&settings.language_server_root)
+        };
+        let uri = self.document_uri(data, tt, asset, &root);
+
+        // The document moved to another asset or server, start over
+        if let Some(open) = self.documents.get(&handle) {
+            if open.uri != uri || open.server != config.command {
+                self.close(data, handle);
+            }
+        }
+
+        self.servers
+            .entry(config.command.clone())
+            .or_insert_with(|| Server::start(&config.command, &root));
+        self.sync(
+            handle,
+            (tt, asset),
+            uri,
+            language_id(&extension),
+            &config.command,
+            document.text(),
+        );
+    }
+
+    /// Sends the text of a document to its server, if it's new or changed since it was last sent.
+    fn sync(
+        &mut self,
+        handle: usize,
+        (tt, asset): (*mut TheTruthO, TtIdT),
+        uri: String,
+        language_id: &str,
+        command: &[String],
+        text: &str,
+    ) {
+        let server = match self.servers.get_mut(command) {
+            Some(server) if server.client.is_some() => server,
+            _ => return,
+        };
+
+        match self.documents.get_mut(&handle) {
+            None => {
+                server.notify(
+                    "textDocument/didOpen",
+                    json!({
+                        "textDocument": {
+                            "uri": uri,
+                            "languageId": language_id,
+                            "version": 1,
+                            "text": text,
+                        }
+                    }),
+                );
+                self.uris.insert(uri.clone(), (tt, asset));
+                self.documents.insert(
+                    handle,
+                    OpenDocument {
+                        server: command.to_vec(),
+                        uri,
+                        tt,
+                        asset,
+                        version: 1,
+                        text: text.to_string(),
+                        symbols_version: None,
+                        symbols: Vec::new(),
+                        edits: Vec::new(),
+                        completion_request: None,
+                        completions: Vec::new(),
+                        hover_request: None,
+                        hover: None,
+                        locations_request: None,
+                        locations: None,
+                    },
+                );
+            }
+            Some(open) if open.text != text => {
+                open.version += 1;
+                open.text = text.to_string();
+
+                // Formatting edits are for the old text, they'd garble the new one
+                open.edits.clear();
+                server.pending.retain(|_, pending| {
+                    !matches!(pending, PendingRequest::Formatting { document, .. } if *document == handle)
+                });
+
+                server.notify(
+                    "textDocument/didChange",
+                    json!({
+                        "textDocument": { "uri": open.uri, "version": open.version },
+                        "contentChanges": [{ "text": open.text }],
+                    }),
+                );
+            }
+            Some(_) => {}
+        }
+
+        // Keep the symbols up to date with the text
+        let open = self.documents.get_mut(&handle).unwrap();
+        if server.initialize.is_none()
+            && server.has_capability("documentSymbolProvider")
+            && open.symbols_version != Some(open.version)
+        {
+            open.symbols_version = Some(open.version);
+            let version = open.version;
+            let params = json!({ "textDocument": { "uri": open.uri } });
+            server.request(
+                "textDocument/documentSymbol",
+                params,
+                PendingRequest::Symbols {
+                    document: handle,
+                    version,
+                },
+            );
+        }
+    }
+
+    /// Handles messages received from all servers.
+    pub fn poll(&mut self, data: &PluginData) {
+        let mut stopped = Vec::new();
+
+        for (command, server) in &mut self.servers {
+            let client = match &mut server.client {
+                Some(client) => client,
+                None => continue,
+            };
+
+            let messages = match client.poll() {
+                Ok(messages) => messages,
+                Err(error) => {
+                    event!(Level::ERROR, "{}", error);
+                    stopped.push(command.clone());
+                    continue;
+                }
+            };
+
+            for message in messages {
+                server.handle(data, &mut self.documents, &mut self.uris, message);
+            }
+        }
+
+        // Documents can't be synced with servers that stopped
+        for command in stopped {
+            let server = self.servers.get_mut(&command).unwrap();
+            let source = server.client.take().unwrap().name().to_string();
+
+            let uris = &mut self.uris;
+            self.documents.retain(|_, open| {
+                if open.server != command {
+                    return true;
+                }
+                uris.remove(&open.uri);
+                data.diagnostics
+                    .lock()
+                    .unwrap()
+                    .set(open.tt, open.asset, &source, Vec::new());
+                false
+            });
+        }
+    }
+
+    /// Stops syncing a document, clearing the diagnostics its server set.
+    pub fn close(&mut self, data: &PluginData, handle: usize) {
+        let open = match self.documents.remove(&handle) {
+            Some(open) => open,
+            None => return,
+        };
+        self.uris.remove(&open.uri);
+
+        if let Some(server) = self.servers.get_mut(&open.server) {
+            server.notify(
+                "textDocument/didClose",
+                json!({ "textDocument": { "uri": open.uri } }),
+            );
+
+            if let Some(client) = &server.client {
+                data.diagnostics.lock().unwrap().set(
+                    open.tt,
+                    open.asset,
+                    client.name(),
+                    Vec::new(),
+                );
+            }
+        }
+    }
+
+    /// Stops all servers.
+    pub fn clear(&mut self) {
+        self.documents.clear();
+        self.uris.clear();
+        self.servers.clear();
+    }
+
+    /// If the server of a document can format it.
+    pub fn can_format(&self, handle: usize) -> bool {
+        self.server_of(handle)
+            .map(|server| server.has_capability("documentFormattingProvider"))
+            .unwrap_or(false)
+    }
+
+    /// Asks the server to format a document, the edits can be taken once it responds.
+    pub fn format(&mut self, handle: usize, tab_width: usize) {
+        let open = match self.documents.get(&handle) {
+            Some(open) => open,
+            None => return,
+        };
+        let server = match self.servers.get_mut(&open.server) {
+            Some(server) if server.initialize.is_none() => server,
+            _ => return,
+        };
+
+        let params = json!({
+            "textDocument": { "uri": open.uri },
+            "options": { "tabSize": tab_width, "insertSpaces": true },
+        });
+        server.request(
+            "textDocument/formatting",
+            params,
+            PendingRequest::Formatting {
+                document: handle,
+                version: open.version,
+            },
+        );
+    }
+
+    /// Takes the edits the server responded with to format a document, as byte ranges.
+    pub fn take_edits(&mut self, handle: usize) -> Vec<(usize, usize, String)> {
+        self.documents
+            .get_mut(&handle)
+            .map(|open| std::mem::take(&mut open.edits))
+            .unwrap_or_default()
+    }
+
+    /// Asks the server for completions at a byte offset, they can be taken once it responds.
+    pub fn complete(&mut self, handle: usize, offset: usize) {
+        let open = match self.documents.get_mut(&handle) {
+            Some(open) => open,
+            None => return,
+        };
+        let server = match self.servers.get_mut(&open.server) {
+            Some(server)
+                if server.initialize.is_none() && server.has_capability("completionProvider") =>
+            {
+                server
+            }
+            _ => return,
+        };
+
+        let params = json!({
+            "textDocument": { "uri": open.uri },
+            "position": lsp_position(&open.text, offset),
+        });
+        open.completion_request = server.request(
+            "textDocument/completion",
+            params,
+            PendingRequest::Completion { document: handle },
+        );
+        open.completions.clear();
+    }
+
+    /// Takes the completions the server responded with.
+    pub fn take_completions(&mut self, handle: usize) -> Vec<Completion> {
+        self.documents
+            .get_mut(&handle)
+            .map(|open| std::mem::take(&mut open.completions))
+            .unwrap_or_default()
+    }
+
+    /// Asks the server about the word at a byte offset, the answer can be taken once it responds.
+    pub fn hover(&mut self, handle: usize, offset: usize) {
+        let open = match self.documents.get_mut(&handle) {
+            Some(open) => open,
+            None => return,
+        };
+        let server = match self.servers.get_mut(&open.server) {
+            Some(server)
+                if server.initialize.is_none() && server.has_capability("hoverProvider") =>
+            {
+                server
+            }
+            _ => return,
+        };
+
+        let params = json!({
+            "textDocument": { "uri": open.uri },
+            "position": lsp_position(&open.text, offset),
+        });
+        open.hover_request = server.request(
+            "textDocument/hover",
+            params,
+            PendingRequest::Hover { document: handle },
+        );
+        open.hover = None;
+    }
+
+    /// Takes the Markdown text the server responded to a hover request with.
+    pub fn take_hover(&mut self, handle: usize) -> Option<String> {
+        self.documents.get_mut(&handle)?.hover.take()
+    }
+
+    /// Asks the server where the identifier at a byte offset is defined.
+    ///
+    /// Returns false if the server can't be asked, otherwise the locations can be taken once it
+    /// responds.
+    pub fn definition(&mut self, handle: usize, offset: usize) -> bool {
+        self.request_locations(
+            handle,
+            offset,
+            "textDocument/definition",
+            "definitionProvider",
+            json!({}),
+        )
+    }
+
+    /// Asks the server where the identifier at a byte offset is defined and used, like
+    /// [`definition`](Self::definition).
+    pub fn references(&mut self, handle: usize, offset: usize) -> bool {
+        self.request_locations(
+            handle,
+            offset,
+            "textDocument/references",
+            "referencesProvider",
+            json!({ "context": { "includeDeclaration": true } }),
+        )
+    }
+
+    fn request_locations(
+        &mut self,
+        handle: usize,
+        offset: usize,
+        method: &str,
+        capability: &str,
+        mut params: Value,
+    ) -> bool {
+        let open = match self.documents.get_mut(&handle) {
+            Some(open) => open,
+            None => return false,
+        };
+        let server = match self.servers.get_mut(&open.server) {
+            Some(server) if server.initialize.is_none() && server.has_capability(capability) => {
+                server
+            }
+            _ => return false,
+        };
+
+        params["textDocument"] = json!({ "uri": open.uri });
+        params["position"] = lsp_position(&open.text, offset);
+        open.locations_request = server.request(
+            method,
+            params,
+            PendingRequest::Locations { document: handle },
+        );
+        open.locations = None;
+        open.locations_request.is_some()
+    }
+
+    /// Takes the locations the server responded to a definition or references request with.
+    pub fn take_locations(&mut self, handle: usize) -> Option<Vec<Location>> {
+        self.documents.get_mut(&handle)?.locations.take()
+    }
+
+    /// Names of the symbols containing a byte offset, from the outermost in.
+    pub fn symbols_at(&self, handle: usize, offset: usize) -> Vec<&str> {
+        let open = match self.documents.get(&handle) {
+            Some(open) => open,
+            None => return Vec::new(),
+        };
+
+        let mut symbols: Vec<_> = open
+            .symbols
+            .iter()
+            .filter(|symbol| symbol.start <= offset && offset <= symbol.end)
+            .collect();
+        symbols.sort_by_key(|symbol| (symbol.start, std::cmp::Reverse(symbol.end)));
+        symbols
+            .into_iter()
+            .map(|symbol| symbol.name.as_str())
+            .collect()
+    }
+
+    /// URI of an asset, from its path under the root directory.
+    ///
+    /// Assets at the same path, like the same asset in another truth, are told apart by their id.
+    unsafe fn document_uri(
+        &self,
+        data: &PluginData,
+        tt: *mut TheTruthO,
+        asset: TtIdT,
+        root: &Path,
+    ) -> String {
+        let uri = file_uri(&root.join(document::asset_path(data, tt, asset)));
+        let taken = self
+            .documents
+            .values()
+            .any(|open| open.uri == uri && !(open.tt == tt && tt_id_eq(open.asset, asset)));
+        if taken {
+            format!("{}?id={}", uri, asset.__bindgen_anon_1.u64_)
+        } else {
+            uri
+        }
+    }
+
+    fn server_of(&self, handle: usize) -> Option<&Server> {
+        let open = self.documents.get(&handle)?;
+        self.servers
+            .get(&open.server)
+            .filter(|server| server.client.is_some() && server.initialize.is_none())
+    }
+}
+
+impl Server {
+    /// Starts a server, and sends it the initialize request.
+    fn start(command: &[String], root: &Path) -> Self {
+        let client = match Client::spawn(command) {
+            Ok(client) => client,
+            Err(error) => {
+                event!(Level::ERROR, "{:?}", error);
+                return Self {
+                    client: None,
+                    initialize: None,
+                    queued: Vec::new(),
+                    capabilities: Value::Null,
+                    pending: HashMap::new(),
+                    root: root.to_path_buf(),
+                };
+            }
+        };
+        event!(
+            Level::INFO,
+            "Started language server \"{}\".",
+            client.name()
+        );
+
+        Self::connect(client, root)
+    }
+
+    /// Sends the initialize request to a server that's already running.
+    fn connect(mut client: Client, root: &Path) -> Self {
+        let root_uri = file_uri(root);
+        let params = json!({
+            "processId": std::process::id(),
+            "clientInfo": { "name": "anode" },
+            "rootUri": root_uri,
+            "workspaceFolders": [{ "uri": root_uri, "name": "root" }],
+            "capabilities": {
+                "textDocument": {
+                    "synchronization": { "dynamicRegistration": false },
+                    "publishDiagnostics": {},
+                    "completion": { "completionItem": { "snippetSupport": false } },
+                    "hover": { "contentFormat": ["plaintext", "markdown"] },
+                    "definition": {},
+                    "references": {},
+                    "formatting": {},
+                    "documentSymbol": { "hierarchicalDocumentSymbolSupport": true },
+                },
+                "general": { "positionEncodings": ["utf-16"] },
+            },
+        });
+        Self {
+            initialize: Some(client.request("initialize", params)),
+            client: Some(client),
+            queued: Vec::new(),
+            capabilities: Value::Null,
+            pending: HashMap::new(),
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn has_capability(&self, name: &str) -> bool {
+        match self.capabilities.get(name) {
+            None | Some(Value::Null) => false,
+            Some(Value::Bool(value)) => *value,
+            Some(_) => true,
+        }
+    }
+
+    /// Sends a notification, or queues it if the server isn't initialized yet.
+    fn notify(&mut self, method: &str, params: Value) {
+        let client = match &mut self.client {
+            Some(client) => client,
+            None => return,
+        };
+
+        if self.initialize.is_some() {
+            self.queued.push((method.to_string(), params));
+        } else {
+            client.notify(method, params);
+        }
+    }
+
+    fn request(&mut self, method: &str, params: Value, pending: PendingRequest) -> Option<u64> {
+        let client = self.client.as_mut()?;
+        let id = client.request(method, params);
+        self.pending.insert(id, pending);
+        Some(id)
+    }
+
+    fn handle(
+        &mut self,
+        data: &PluginData,
+        documents: &mut HashMap<usize, OpenDocument>,
+        uris: &mut HashMap<String, (*mut TheTruthO, TtIdT)>,
+        message: Incoming,
+    ) {
+        let client = self.client.as_mut().unwrap();
+
+        match message {
+            Incoming::Response { id, result } if Some(id) == self.initialize => {
+                self.initialize = None;
+                match result {
+                    Ok(mut result) => {
+                        self.capabilities = result
+                            .get_mut("capabilities")
+                            .map(Value::take)
+                            .unwrap_or_default();
+                    }
+                    Err(error) => event!(
+                        Level::ERROR,
+                        "Language server \"{}\" failed to initialize: {}",
+                        client.name(),
+                        error
+                    ),
+                }
+
+                client.notify("initialized", json!({}));
+                for (method, params) in self.queued.drain(..) {
+                    client.notify(&method, params);
+                }
+            }
+            Incoming::Response { id, result } => {
+                let pending = match self.pending.remove(&id) {
+                    Some(pending) => pending,
+                    None => return,
+                };
+                let result = match result {
+                    Ok(result) => result,
+                    Err(error) => {
+                        event!(
+                            Level::WARN,
+                            "Language server \"{}\" request failed: {}",
+                            client.name(),
+                            error
+                        );
+                        return;
+                    }
+                };
+
+                match pending {
+                    PendingRequest::Formatting { document, version } => {
+                        if let Some(open) = documents.get_mut(&document) {
+                            // The edits can't be applied if the text changed in the meantime
+                            if open.version == version {
+                                open.edits = text_edits(&open.text, &result);
+                            }
+                        }
+                    }
+                    PendingRequest::Symbols { document, version } => {
+                        if let Some(open) = documents.get_mut(&document) {
+                            if open.version == version {
+                                open.symbols.clear();
+                                collect_symbols(&open.text, &result, &mut open.symbols);
+                            }
+                        }
+                    }
+                    PendingRequest::Hover { document } => {
+                        if let Some(open) = documents.get_mut(&document) {
+                            if open.hover_request == Some(id) {
+                                open.hover = result.get("contents").map(hover_markdown);
+                            }
+                        }
+                    }
+                    PendingRequest::Locations { document } => {
+                        let tt = match documents.get(&document) {
+                            Some(open) if open.locations_request == Some(id) => open.tt,
+                            _ => return,
+                        };
+                        let locations = locations(data, documents, uris, tt, &self.root, &result);
+                        if let Some(open) = documents.get_mut(&document) {
+                            open.locations = Some(locations);
+                        }
+                    }
+                    PendingRequest::Completion { document } => {
+                        if let Some(open) = documents.get_mut(&document) {
+                            if open.completion_request == Some(id) {
+                                open.completions = completions(&result);
+                            }
+                        }
+                    }
+                }
+            }
+            Incoming::Notification { method, params } => {
+                if method == "textDocument/publishDiagnostics" {
+                    let uri = params
+                        .get("uri")
+                        .and_then(Value::as_str)
+                        .unwrap_or_default();
+                    let open = match synced(documents, uris, uri) {
+                        Some(open) => open,
+                        None => return,
+                    };
+
+                    let diagnostics = params
+                        .get("diagnostics")
+                        .and_then(Value::as_array)
+                        .map(|diagnostics| {
+                            diagnostics
+                                .iter()
+                                .filter_map(|diagnostic| convert_diagnostic(&open.text, diagnostic))
+                                .collect()
+                        })
+                        .unwrap_or_default();
+
+                    data.diagnostics.lock().unwrap().set(
+                        open.tt,
+                        open.asset,
+                        client.name(),
+                        diagnostics,
+                    );
+                }
+            }
+            Incoming::Request { id, method, params } => {
+                // We don't have anything to configure servers with, but they expect a response
+                let result = if method == "workspace/configuration" {
+                    let items = params
+                        .get("items")
+                        .and_then(Value::as_array)
+                        .map(Vec::len)
+                        .unwrap_or(0);
+                    Value::Array(vec![Value::Null; items])
+                } else {
+                    Value::Null
+                };
+                client.respond(id, result);
+            }
+        }
+    }
+}
+
+fn convert_diagnostic(text: &str, diagnostic: &Value) -> Option<Diagnostic> {
+    let range = diagnostic.get("range")?;
+    let start = offset_at(text, range.get("start")?);
+    let end = offset_at(text, range.get("end")?);
+
+    let severity = match diagnostic.get("severity").and_then(Value::as_u64) {
+        Some(2) => AnodeSeverity::Warning,
+        Some(3) => AnodeSeverity::Information,
+        Some(4) => AnodeSeverity::Hint,
+        _ => AnodeSeverity::Error,
+    };
+
+    Some(Diagnostic {
+        start: line_byte_column(text, start),
+        end: line_byte_column(text, end.max(start)),
+        severity,
+        message: diagnostic.get("message")?.as_str()?.to_string(),
+        source: diagnostic
+            .get("source")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+    })
+}
+
+fn text_edits(text: &str, edits: &Value) -> Vec<(usize, usize, String)> {
+    edits
+        .as_array()
+        .map(|edits| {
+            edits
+                .iter()
+                .filter_map(|edit| {
+                    let range = edit.get("range")?;
+                    let start = offset_at(text, range.get("start")?);
+                    let end = offset_at(text, range.get("end")?);
+                    let new_text = edit.get("newText")?.as_str()?;
+                    Some((start, end.max(start), new_text.to_string()))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Converts a completion response, either a list of items or an object holding them.
+fn completions(result: &Value) -> Vec<Completion> {
+    let items = result
+        .as_array()
+        .or_else(|| result.get("items").and_then(Value::as_array));
+    let items = match items {
+        Some(items) => items,
+        None => return Vec::new(),
+    };
+
+    items
+        .iter()
+        .filter_map(|item| {
+            let label = item.get("label")?.as_str()?.to_string();
+            let string = |pointer| item.pointer(pointer).and_then(Value::as_str);
+            let insert_text = string("/textEdit/newText")
+                .or_else(|| string("/insertText"))
+                .unwrap_or(&label)
+                .to_string();
+
+            Some(Completion {
+                detail: string("/detail").unwrap_or_default().to_string(),
+                insert_text,
+                label,
+            })
+        })
+        .collect()
+}
+
+/// Converts hover contents to Markdown, they're either markup or one or more marked strings.
+fn hover_markdown(contents: &Value) -> String {
+    match contents {
+        Value::String(text) => text.clone(),
+        Value::Array(items) => items
+            .iter()
+            .map(hover_markdown)
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+        Value::Object(object) => {
+            let value = object
+                .get("value")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            match object.get("language").and_then(Value::as_str) {
+                Some(language) => format!("```{}\n{}\n```", language, value),
+                None => value.to_string(),
+            }
+        }
+        _ => String::new(),
+    }
+}
+
+/// Converts a definition or references response, either one location or a list of locations or
+/// location links.
+///
+/// Locations are found in documents synced with the server, or in the truth by their path. Files
+/// that aren't an asset, like system headers, are left out.
+fn locations(
+    data: &PluginData,
+    documents: &HashMap<usize, OpenDocument>,
+    uris: &mut HashMap<String, (*mut TheTruthO, TtIdT)>,
+    tt: *mut TheTruthO,
+    root: &Path,
+    result: &Value,
+) -> Vec<Location> {
+    let items = match result {
+        Value::Array(items) => items.iter().collect(),
+        Value::Null => Vec::new(),
+        item => vec![item],
+    };
+
+    items
+        .into_iter()
+        .filter_map(|item| {
+            let uri = item
+                .get("targetUri")
+                .or_else(|| item.get("uri"))?
+                .as_str()?;
+            let range = item
+                .get("targetSelectionRange")
+                .or_else(|| item.get("range"))?;
+
+            let (asset, text) = match synced(documents, uris, uri).filter(|open| open.tt == tt) {
+                Some(open) => (open.asset, Some(open.text.as_str())),
+                None => (unsafe { find_uri(data, uris, tt, root, uri)? }, None),
+            };
+
+            // Without the text, characters are taken as bytes, which is right for ASCII
+            let position = |position: &Value| match text {
+                Some(text) => Some(line_byte_column(text, offset_at(text, position))),
+                None => Some((
+                    position.get("line")?.as_u64()? as usize,
+                    position.get("character")?.as_u64()? as usize,
+                )),
+            };
+            let start = position(range.get("start")?)?;
+            let end = position(range.get("end")?)?;
+
+            Some(Location {
+                tt,
+                asset,
+                start,
+                end,
+                preview: text
+                    .and_then(|text| text.split('\n').nth(start.0))
+                    .map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+/// Finds the synced document a server refers to by its URI.
+fn synced<'a>(
+    documents: &'a HashMap<usize, OpenDocument>,
+    uris: &HashMap<String, (*mut TheTruthO, TtIdT)>,
+    uri: &str,
+) -> Option<&'a OpenDocument> {
+    let (tt, asset) = *uris.get(uri)?;
+    documents
+        .values()
+        .find(|open| open.tt == tt && tt_id_eq(open.asset, asset))
+}
+
+/// Finds the asset a server refers to by its URI, remembering it for the next lookup.
+unsafe fn find_uri(
+    data: &PluginData,
+    uris: &mut HashMap<String, (*mut TheTruthO, TtIdT)>,
+    tt: *mut TheTruthO,
+    root: &Path,
+    uri: &str,
+) -> Option<TtIdT> {
+    let matches = |path: &str| file_uri(&root.join(path)) == uri;
+
+    // The asset could have been moved or deleted since it was found
+    if let Some(&(found_tt, asset)) = uris.get(uri) {
+        if found_tt == tt
+            && (*data.apis.truth).is_alive(tt, asset)
+            && matches(&document::asset_path(data, tt, asset))
+        {
+            return Some(asset);
+        }
+    }
+
+    let asset = document::find_asset(data, tt, matches)?;
+    uris.insert(uri.to_string(), (tt, asset));
+    Some(asset)
+}
+
+/// Flattens symbols, either hierarchical document symbols or flat symbol information.
+fn collect_symbols(text: &str, symbols: &Value, out: &mut Vec<Symbol>) {
+    let symbols = match symbols.as_array() {
+        Some(symbols) => symbols,
+        None => return,
+    };
+
+    for symbol in symbols {
+        let range = symbol
+            .get("range")
+            .or_else(|| symbol.pointer("/location/range"));
+        let name = symbol.get("name").and_then(Value::as_str);
+
+        if let (Some(range), Some(name)) = (range, name) {
+            if let (Some(start), Some(end)) = (range.get("start"), range.get("end")) {
+                out.push(Symbol {
+                    name: name.to_string(),
+                    start: offset_at(text, start),
+                    end: offset_at(text, end),
+                });
+            }
+        }
+
+        if let Some(children) = symbol.get("children") {
+            collect_symbols(text, children, out);
+        }
+    }
+}
+
+/// Byte offset of an LSP position, whose character is counted in UTF-16 code units.
+fn offset_at(text: &str, position: &Value) -> usize {
+    let line = position.get("line").and_then(Value::as_u64).unwrap_or(0) as usize;
+    let character = position
+        .get("character")
+        .and_then(Value::as_u64)
+        .unwrap_or(0) as usize;
+
+    let line_start: usize = text.split_inclusive('\n').take(line).map(str::len).sum();
+    let line_text = text[line_start..].split('\n').next().unwrap_or_default();
+
+    let mut units = 0;
+    for (index, c) in line_text.char_indices() {
+        if units >= character {
+            return line_start + index;
+        }
+        units += c.len_utf16();
+    }
+    line_start + line_text.len()
+}
+
+/// LSP position of a byte offset, with the character counted in UTF-16 code units.
+fn lsp_position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map(|v| v + 1).unwrap_or(0);
+    let character: usize = before[line_start..].chars().map(char::len_utf16).sum();
+    json!({ "line": before.matches('\n').count(), "character": character })
+}
+
+/// Line and byte column of a byte offset.
+fn line_byte_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map(|v| v + 1).unwrap_or(0);
+    (before.matches('\n').count(), offset - line_start)
+}
+
+/// Converts a path to a `file://` URI, percent-encoding characters that aren't allowed.
+fn file_uri(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+
+    let mut uri = String::from("file://");
+    if !path.starts_with('/') {
+        uri.push('/');
+    }
+    for byte in path.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(byte as char)
+            }
+            // Drive letters are conventionally left as is
+            b':' if uri.len() == "file:///".len() + 1 => uri.push(':'),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+/// Language identifier servers expect for a file extension.
+fn language_id(extension: &str) -> &str {
+    match extension {
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => "cpp",
+        "cs" => "csharp",
+        "js" => "javascript",
+        "ts" => "typescript",
+        "md" => "markdown",
+        "py" => "python",
+        "rs" => "rust",
+        "sh" => "shellscript",
+        extension => extension,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ptr::{null, null_mut},
+        sync::Mutex,
+        thread,
+        time::Instant,
+    };
+
+    use machinery::RegistryStorage;
+    use machinery_api::foundation::TtIdTBindgenTy1;
+
+    use super::{mock::MockServer, *};
+    use crate::{
+        diagnostics::Diagnostics, fonts::CodeFonts, handles::OpenDocuments, plugin::Apis,
+        properties::PropertyEditors,
+    };
+
+    const HANDLE: usize = 1;
+    const URI: &str = "file:///project/src/main.rs";
+    const TEXT: &str = "let é = x;\nfoo();\n";
+
+    /// Plugin data without any APIs, which the servers only need for diagnostics.
+    fn plugin_data() -> PluginData {
+        PluginData {
+            apis: Apis {
+                registry: null(),
+                application: null(),
+                truth: null(),
+                truth_assets: null(),
+                ui: null(),
+                docking: null(),
+                draw2d: null(),
+                font: null(),
+                properties_view: null(),
+                machinery: null(),
+                temp_allocator: null(),
+                code_editor_tab_vtable: null(),
+            },
+            registry_storage: Mutex::new(RegistryStorage::new()),
+            themes: Vec::new(),
+            settings_object: Mutex::new(None),
+            code_fonts: Mutex::new(CodeFonts::new()),
+            property_editors: Mutex::new(PropertyEditors::default()),
+            open_documents: Mutex::new(OpenDocuments::default()),
+            diagnostics: Mutex::new(Diagnostics::default()),
+            language_servers: Mutex::new(LanguageServers::default()),
+        }
+    }
+
+    fn command() -> Vec<String> {
+        vec!["mock".to_string()]
+    }
+
+    fn asset() -> TtIdT {
+        TtIdT {
+            __bindgen_anon_1: TtIdTBindgenTy1 { u64_: 1 },
+        }
+    }
+
+    fn sync(servers: &mut LanguageServers, text: &str) {
+        servers.sync(
+            HANDLE,
+            (null_mut(), asset()),
+            URI.to_string(),
+            "rust",
+            &command(),
+            text,
+        );
+    }
+
+    /// Polls the servers until the condition is met.
+    fn poll_until(
+        servers: &mut LanguageServers,
+        data: &PluginData,
+        mut condition: impl FnMut(&mut LanguageServers) -> bool,
+    ) {
+        let start = Instant::now();
+        loop {
+            servers.poll(data);
+            if condition(servers) {
+                return;
+            }
+            assert!(start.elapsed() < mock::TIMEOUT, "server didn't respond");
+            thread::yield_now();
+        }
+    }
+
+    /// Starts a mock server with the capabilities, and opens a document with it.
+    fn open(data: &PluginData, capabilities: Value) -> (LanguageServers, MockServer) {
+        let (client, mut mock) = mock::connect();
+        let mut servers = LanguageServers::default();
+        servers
+            .servers
+            .insert(command(), Server::connect(client, Path::new("/project")));
+
+        let initialize = mock.expect("initialize");
+        mock.respond(&initialize, json!({ "capabilities": capabilities }));
+        poll_until(&mut servers, data, |servers| {
+            servers.servers[&command()].initialize.is_none()
+        });
+        mock.expect("initialized");
+
+        sync(&mut servers, TEXT);
+        mock.expect("textDocument/didOpen");
+        (servers, mock)
+    }
+
+    fn range(start: (u32, u32), end: (u32, u32)) -> Value {
+        json!({
+            "start": { "line": start.0, "character": start.1 },
+            "end": { "line": end.0, "character": end.1 },
+        })
+    }
+
+    #[test]
+    fn initialize_handshake() {
+        let data = plugin_data();
+        let (client, mut mock) = mock::connect();
+        let mut servers = LanguageServers::default();
+        servers
+            .servers
+            .insert(command(), Server::connect(client, Path::new("/project")));
+
+        let initialize = mock.expect("initialize");
+        assert_eq!(initialize["params"]["rootUri"], "file:///project");
+        assert_eq!(initialize["params"]["processId"], std::process::id());
+
+        // Documents opened before the server is initialized wait for it
+        sync(&mut servers, TEXT);
+        mock.expect_nothing();
+
+        mock.respond(
+            &initialize,
+            json!({ "capabilities": { "hoverProvider": true } }),
+        );
+        poll_until(&mut servers, &data, |servers| {
+            servers.servers[&command()].initialize.is_none()
+        });
+        mock.expect("initialized");
+        mock.expect("textDocument/didOpen");
+
+        let server = &servers.servers[&command()];
+        assert!(server.has_capability("hoverProvider"));
+        assert!(!server.has_capability("completionProvider"));
+    }
+
+    #[test]
+    fn documents_are_synced() {
+        let data = plugin_data();
+        let (client, mut mock) = mock::connect();
+        let mut servers = LanguageServers::default();
+        servers
+            .servers
+            .insert(command(), Server::connect(client, Path::new("/project")));
+        let initialize = mock.expect("initialize");
+        mock.respond(&initialize, json!({ "capabilities": {} }));
+        poll_until(&mut servers, &data, |servers| {
+            servers.servers[&command()].initialize.is_none()
+        });
+        mock.expect("initialized");
+
+        sync(&mut servers, TEXT);
+        let open = mock.expect("textDocument/didOpen");
+        assert_eq!(
+            open["params"]["textDocument"],
+            json!({ "uri": URI, "languageId": "rust", "version": 1, "text": TEXT })
+        );
+
+        // Unchanged text isn't sent again
+        sync(&mut servers, TEXT);
+        mock.expect_nothing();
+
+        sync(&mut servers, "let x = 1;\n");
+        let change = mock.expect("textDocument/didChange");
+        assert_eq!(
+            change["params"],
+            json!({
+                "textDocument": { "uri": URI, "version": 2 },
+                "contentChanges": [{ "text": "let x = 1;\n" }],
+            })
+        );
+
+        servers.close(&data, HANDLE);
+        let close = mock.expect("textDocument/didClose");
+        assert_eq!(close["params"], json!({ "textDocument": { "uri": URI } }));
+    }
+
+    #[test]
+    fn diagnostics_are_converted_to_byte_columns() {
+        let data = plugin_data();
+        let (mut servers, mut mock) = open(&data, json!({}));
+
+        mock.notify(
+            "textDocument/publishDiagnostics",
+            json!({
+                "uri": URI,
+                "diagnostics": [{
+                    "range": range((0, 8), (0, 9)),
+                    "severity": 2,
+                    "message": "unknown name",
+                    "source": "rustc",
+                }],
+            }),
+        );
+        let diagnostics = || {
+            data.diagnostics
+                .lock()
+                .unwrap()
+                .for_asset(null_mut(), asset())
+        };
+        poll_until(&mut servers, &data, |_| !diagnostics().is_empty());
+
+        let diagnostic = &diagnostics()[0];
+        assert_eq!(diagnostic.start, (0, 9));
+        assert_eq!(diagnostic.end, (0, 10));
+        assert_eq!(diagnostic.severity, AnodeSeverity::Warning);
+        assert_eq!(diagnostic.message, "unknown name");
+        assert_eq!(diagnostic.source, "rustc");
+
+        // Closing the document clears the diagnostics of its server
+        servers.close(&data, HANDLE);
+        assert!(diagnostics().is_empty());
+    }
+
+    #[test]
+    fn completions_are_parsed() {
+        let data = plugin_data();
+        let (mut servers, mut mock) = open(&data, json!({ "completionProvider": {} }));
+
+        servers.complete(HANDLE, TEXT.find("foo").unwrap() + 3);
+        let request = mock.expect("textDocument/completion");
+        assert_eq!(
+            request["params"]["position"],
+            json!({ "line": 1, "character": 3 })
+        );
+        mock.respond(
+            &request,
+            json!({
+                "isIncomplete": false,
+                "items": [
+                    { "label": "foo", "detail": "fn()", "textEdit": { "newText": "foo()" } },
+                    { "label": "format", "insertText": "format!" },
+                    { "label": "for" },
+                ],
+            }),
+        );
+
+        let mut completions = Vec::new();
+        poll_until(&mut servers, &data, |servers| {
+            completions = servers.take_completions(HANDLE);
+            !completions.is_empty()
+        });
+        let parsed: Vec<_> = completions
+            .iter()
+            .map(|v| (v.label.as_str(), v.detail.as_str(), v.insert_text.as_str()))
+            .collect();
+        assert_eq!(
+            parsed,
+            [
+                ("foo", "fn()", "foo()"),
+                ("format", "", "format!"),
+                ("for", "", "for"),
+            ]
+        );
+    }
+
+    #[test]
+    fn hover_is_parsed_as_markdown() {
+        let data = plugin_data();
+        let (mut servers, mut mock) = open(&data, json!({ "hoverProvider": true }));
+
+        servers.hover(HANDLE, TEXT.find('x').unwrap());
+        let request = mock.expect("textDocument/hover");
+        assert_eq!(
+            request["params"]["position"],
+            json!({ "line": 0, "character": 8 })
+        );
+        mock.respond(
+            &request,
+            json!({
+                "contents": [
+                    { "language": "rust", "value": "let x: i32" },
+                    "A *number*.",
+                ],
+            }),
+        );
+
+        let mut hover = None;
+        poll_until(&mut servers, &data, |servers| {
+            hover = servers.take_hover(HANDLE);
+            hover.is_some()
+        });
+        assert_eq!(hover.unwrap(), "```rust\nlet x: i32\n```\n\nA *number*.");
+    }
+
+    #[test]
+    fn definitions_are_found_in_synced_documents() {
+        let data = plugin_data();
+        let (mut servers, mut mock) = open(&data, json!({ "definitionProvider": true }));
+
+        assert!(servers.definition(HANDLE, TEXT.find('x').unwrap()));
+        let request = mock.expect("textDocument/definition");
+        mock.respond(
+            &request,
+            json!([{
+                "targetUri": URI,
+                "targetRange": range((0, 0), (0, 10)),
+                "targetSelectionRange": range((0, 4), (0, 5)),
+            }]),
+        );
+
+        let mut locations = None;
+        poll_until(&mut servers, &data, |servers| {
+            locations = servers.take_locations(HANDLE);
+            locations.is_some()
+        });
+        let locations = locations.unwrap();
+        assert_eq!(locations.len(), 1);
+        assert!(locations[0].is_in(null_mut(), asset()));
+        assert_eq!(locations[0].start, (0, 4));
+        assert_eq!(locations[0].end, (0, 6));
+        assert_eq!(locations[0].preview.as_deref(), Some("let é = x;"));
+    }
+
+    #[test]
+    fn formatting_edits_are_parsed() {
+        let data = plugin_data();
+        let (mut servers, mut mock) = open(&data, json!({ "documentFormattingProvider": true }));
+        assert!(servers.can_format(HANDLE));
+
+        servers.format(HANDLE, 2);
+        let request = mock.expect("textDocument/formatting");
+        assert_eq!(
+            request["params"]["options"],
+            json!({ "tabSize": 2, "insertSpaces": true })
+        );
+        mock.respond(
+            &request,
+            json!([
+                { "range": range((0, 0), (0, 3)), "newText": "const" },
+                { "range": range((1, 5), (1, 6)), "newText": "" },
+            ]),
+        );
+
+        let mut edits = Vec::new();
+        poll_until(&mut servers, &data, |servers| {
+            edits = servers.take_edits(HANDLE);
+            !edits.is_empty()
+        });
+        let semicolon = TEXT.rfind(';').unwrap();
+        assert_eq!(
+            edits,
+            [
+                (0, 3, "const".to_string()),
+                (semicolon, semicolon + 1, String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn formatting_edits_are_dropped_when_the_text_changes() {
+        let data = plugin_data();
+        let (mut servers, mut mock) = open(&data, json!({ "documentFormattingProvider": true }));
+
+        servers.format(HANDLE, 4);
+        let request = mock.expect("textDocument/formatting");
+        sync(&mut servers, "let é = y;\nfoo();\n");
+        mock.expect("textDocument/didChange");
+        mock.respond(
+            &request,
+            json!([{ "range": range((0, 0), (0, 3)), "newText": "const" }]),
+        );
+
+        // A response to a later request shows the formatting response was handled
+        servers.format(HANDLE, 4);
+        let request = mock.expect("textDocument/formatting");
+        mock.respond(&request, json!([]));
+        poll_until(&mut servers, &data, |servers| {
+            servers
+                .servers
+                .values()
+                .all(|server| server.pending.is_empty())
+        });
+        assert!(servers.take_edits(HANDLE).is_empty());
+
+        // Edits that arrived before the change are dropped too
+        servers.format(HANDLE, 4);
+        let request = mock.expect("textDocument/formatting");
+        mock.respond(
+            &request,
+            json!([{ "range": range((0, 0), (0, 3)), "newText": "const" }]),
+        );
+        poll_until(&mut servers, &data, |servers| {
+            !servers.documents[&HANDLE].edits.is_empty()
+        });
+        sync(&mut servers, TEXT);
+        assert!(servers.take_edits(HANDLE).is_empty());
+    }
+
+    #[test]
+    fn document_symbols_are_flattened() {
+        let data = plugin_data();
+        let (mut servers, mut mock) = open(&data, json!({ "documentSymbolProvider": true }));
+
+        let request = mock.expect("textDocument/documentSymbol");
+        mock.respond(
+            &request,
+            json!([{
+                "name": "main",
+                "range": range((0, 0), (1, 6)),
+                "selectionRange": range((0, 0), (0, 3)),
+                "children": [{
+                    "name": "x",
+                    "range": range((0, 8), (0, 9)),
+                    "selectionRange": range((0, 8), (0, 9)),
+                }],
+            }]),
+        );
+
+        let x = TEXT.find('x').unwrap();
+        poll_until(&mut servers, &data, |servers| {
+            !servers.symbols_at(HANDLE, x).is_empty()
+        });
+        assert_eq!(servers.symbols_at(HANDLE, x), ["main", "x"]);
+        assert_eq!(
+            servers.symbols_at(HANDLE, TEXT.find("foo").unwrap()),
+            ["main"]
+        );
+    }
+}
@@ -0,0 +1,74 @@
+use std::io::{BufRead, Write};
+
+use eyre::{eyre, Result};
+use serde_json::Value;
+
+/// Writes a message, framed with its `Content-Length` header.
+pub fn write_message(writer: &mut impl Write, message: &Value) -> Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the next message, returning `None` once the stream ends.
+pub fn read_message(reader: &mut impl BufRead) -> Result<Option<Value>> {
+    // Headers are terminated by an empty line, we only need the length
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = Some(value.trim().parse::<usize>()?);
+            }
+        }
+    }
+
+    let length = length.ok_or_else(|| eyre!("Message is missing its Content-Length header"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn messages_are_framed_with_their_length() {
+        let message = json!({ "jsonrpc": "2.0", "method": "initialized", "params": { "é": 1 } });
+        let mut bytes = Vec::new();
+        write_message(&mut bytes, &message).unwrap();
+
+        let body = message.to_string();
+        let header = format!("Content-Length: {}\r\n\r\n", body.len());
+        assert_eq!(bytes, [header.as_bytes(), body.as_bytes()].concat());
+
+        // Other headers are ignored, and the stream ends after the last message
+        let mut stream = b"Content-Type: application/vscode-jsonrpc\r\n".to_vec();
+        stream.extend_from_slice(&bytes);
+        stream.extend_from_slice(&bytes);
+        let mut reader = BufReader::new(&stream[..]);
+        assert_eq!(read_message(&mut reader).unwrap(), Some(message.clone()));
+        assert_eq!(read_message(&mut reader).unwrap(), Some(message));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn messages_without_length_are_refused() {
+        let mut reader = BufReader::new(&b"Content-Type: text\r\n\r\n{}"[..]);
+        assert!(read_message(&mut reader).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+//! Layout of the Markdown text shown in hover tooltips.
+//!
+//! Only a small part of Markdown is supported, as everything is drawn with the code font. Markup
+//! that can't be shown, like emphasis, is removed and anything else is left as is.
+
+/// How a line of laid out text is drawn.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LineKind {
+    Text,
+    Heading,
+    Code,
+    /// Horizontal line, the text is empty.
+    Rule,
+}
+
+pub struct Line {
+    pub kind: LineKind,
+    pub text: String,
+}
+
+/// Lays out Markdown text into lines, wrapping paragraphs at a width in characters.
+pub fn layout(markdown: &str, columns: usize) -> Vec<Line> {
+    let columns = columns.max(8);
+    let mut lines = Vec::new();
+    let mut paragraph = String::new();
+    let mut in_code = false;
+
+    for line in markdown.lines() {
+        let trimmed = line.trim();
+
+        // Code blocks are kept verbatim
+        if trimmed.starts_with("```") {
+            flush_paragraph(&mut lines, &mut paragraph, columns);
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            lines.push(Line {
+                kind: LineKind::Code,
+                text: line.trim_end().replace('\t', "    "),
+            });
+            continue;
+        }
+
+        if trimmed.is_empty() {
+            flush_paragraph(&mut lines, &mut paragraph, columns);
+            push_blank(&mut lines);
+        } else if let Some(heading) = heading(trimmed) {
+            flush_paragraph(&mut lines, &mut paragraph, columns);
+            lines.push(Line {
+                kind: LineKind::Heading,
+                text: inline(heading),
+            });
+        } else if is_rule(trimmed) {
+            flush_paragraph(&mut lines, &mut paragraph, columns);
+            lines.push(Line {
+                kind: LineKind::Rule,
+                text: String::new(),
+            });
+        } else if let Some(item) = list_item(trimmed) {
+            flush_paragraph(&mut lines, &mut paragraph, columns);
+            wrap(
+                &mut lines,
+                &format!("\u{2022} {}", inline(item)),
+                columns,
+                "  ",
+            );
+        } else {
+            if !paragraph.is_empty() {
+                paragraph.push(' ');
+            }
+            paragraph.push_str(trimmed);
+        }
+    }
+    flush_paragraph(&mut lines, &mut paragraph, columns);
+
+    while lines
+        .last()
+        .is_some_and(|line| line.kind == LineKind::Text && line.text.is_empty())
+    {
+        lines.pop();
+    }
+    lines
+}
+
+fn flush_paragraph(lines: &mut Vec<Line>, paragraph: &mut String, columns: usize) {
+    if !paragraph.is_empty() {
+        wrap(lines, &inline(paragraph), columns, "");
+        paragraph.clear();
+    }
+}
+
+/// Adds an empty line between blocks, but never more than one.
+fn push_blank(lines: &mut Vec<Line>) {
+    let previous_blank = lines
+        .last()
+        .is_none_or(|line| line.kind == LineKind::Text && line.text.is_empty());
+    if !previous_blank {
+        lines.push(Line {
+            kind: LineKind::Text,
+            text: String::new(),
+        });
+    }
+}
+
+fn heading(line: &str) -> Option<&str> {
+    let text = line.trim_start_matches('#');
+    let level = line.len() - text.len();
+    if (1..=6).contains(&level) && text.starts_with(' ') {
+        Some(text.trim())
+    } else {
+        None
+    }
+}
+
+fn is_rule(line: &str) -> bool {
+    let marker = match line.chars().next() {
+        Some(marker @ ('-' | '*' | '_')) => marker,
+        _ => return false,
+    };
+    line.chars().all(|v| v == marker || v == ' ') && line.matches(marker).count() >= 3
+}
+
+fn list_item(line: &str) -> Option<&str> {
+    ["- ", "* ", "+ "]
+        .iter()
+        .find_map(|marker| line.strip_prefix(marker))
+}
+
+/// Removes inline markup, keeping the text of code spans and links.
+fn inline(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let chars: Vec<char> = text.chars().collect();
+    let mut in_code = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let previous = if i > 0 { Some(chars[i - 1]) } else { None };
+        let next = chars.get(i + 1).copied();
+
+        match c {
+            '`' => in_code = !in_code,
+            _ if in_code => result.push(c),
+            '\\' if next.is_some_and(|v| v.is_ascii_punctuation()) => {
+                result.push(next.unwrap());
+                i += 1;
+            }
+            // Emphasis, a lone `*` between spaces is left alone
+            '*' if !(previous.is_none_or(char::is_whitespace)
+                && next.is_none_or(char::is_whitespace)) => {}
+            // Links show only their text
+            ']' if next == Some('(') => match chars[i..].iter().position(|v| *v == ')') {
+                Some(end) => i += end,
+                None => result.push(c),
+            },
+            '[' if chars[i..].windows(2).any(|v| v == [']', '(']) => {}
+            _ => result.push(c),
+        }
+        i += 1;
+    }
+
+    result
+}
+
+/// Word wraps text into lines, indenting the lines after the first.
+fn wrap(lines: &mut Vec<Line>, text: &str, columns: usize, indent: &str) {
+    let indent_len = indent.chars().count();
+    let mut line = String::new();
+    let mut line_len = 0;
+    let mut at_start = true;
+
+    for word in text.split_whitespace() {
+        let mut word: Vec<char> = word.chars().collect();
+
+        if !at_start && line_len + 1 + word.len() > columns {
+            lines.push(Line {
+                kind: LineKind::Text,
+                text: std::mem::replace(&mut line, indent.to_string()),
+            });
+            line_len = indent_len;
+            at_start = true;
+        }
+        if !at_start {
+            line.push(' ');
+            line_len += 1;
+        }
+
+        // Words that don't fit on a line by themselves are split
+        while line_len + word.len() > columns {
+            let split = columns.saturating_sub(line_len).max(1);
+            line.extend(word.drain(..split));
+            lines.push(Line {
+                kind: LineKind::Text,
+                text: std::mem::replace(&mut line, indent.to_string()),
+            });
+            line_len = indent_len;
+        }
+        line_len += word.len();
+        line.extend(word);
+        at_start = false;
+    }
+
+    if !at_start {
+        lines.push(Line {
+            kind: LineKind::Text,
+            text: line,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(lines: &[Line]) -> Vec<&str> {
+        lines.iter().map(|line| line.text.as_str()).collect()
+    }
+
+    fn kinds(lines: &[Line]) -> Vec<LineKind> {
+        lines.iter().map(|line| line.kind).collect()
+    }
+
+    #[test]
+    fn blocks_are_laid_out_by_kind() {
+        let lines = layout(
+            "# Title\n\n```rust\nfn  main() {\n\tx\n}\n```\n\n---\n\n- one\n* two\n",
+            80,
+        );
+        assert_eq!(
+            texts(&lines),
+            [
+                "Title",
+                "",
+                "fn  main() {",
+                "    x",
+                "}",
+                "",
+                "",
+                "",
+                "\u{2022} one",
+                "\u{2022} two"
+            ]
+        );
+        assert_eq!(
+            kinds(&lines),
+            [
+                LineKind::Heading,
+                LineKind::Text,
+                LineKind::Code,
+                LineKind::Code,
+                LineKind::Code,
+                LineKind::Text,
+                LineKind::Rule,
+                LineKind::Text,
+                LineKind::Text,
+                LineKind::Text,
+            ]
+        );
+    }
+
+    #[test]
+    fn paragraphs_are_joined_and_blank_lines_collapsed() {
+        let lines = layout("\n\nfirst\nline\n\n\n\nsecond\n\n", 80);
+        assert_eq!(texts(&lines), ["first line", "", "second"]);
+    }
+
+    #[test]
+    fn inline_markup_is_removed() {
+        assert_eq!(
+            inline("some *emphasis* and **strong**"),
+            "some emphasis and strong"
+        );
+        assert_eq!(inline("a * b"), "a * b");
+        assert_eq!(inline("`*ptr` stays"), "*ptr stays");
+        assert_eq!(
+            inline("see [the docs](https://example.com)."),
+            "see the docs."
+        );
+        assert_eq!(inline("\\*not emphasis\\*"), "*not emphasis*");
+        assert_eq!(inline("[not a link]"), "[not a link]");
+    }
+
+    #[test]
+    fn text_is_wrapped_at_the_columns() {
+        let lines = layout("one two three four five six", 9);
+        assert_eq!(texts(&lines), ["one two", "three", "four five", "six"]);
+
+        // Words longer than a line are split
+        let lines = layout("abcdefghijklmnopqrst", 8);
+        assert_eq!(texts(&lines), ["abcdefgh", "ijklmnop", "qrst"]);
+
+        // List items are indented past their bullet
+        let lines = layout("- one two three", 9);
+        assert_eq!(texts(&lines), ["\u{2022} one two", "  three"]);
+    }
+}
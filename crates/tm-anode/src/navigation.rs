@@ -0,0 +1,322 @@
+//! Going to the definition of identifiers and finding their references, across assets.
+//!
+//! Locations are asked from providers registered by other plugins first, then from the language
+//! server, and finally looked up in the document itself with the language's locals query.
+
+use std::time::{Duration, Instant};
+
+use machinery::tt_id_eq;
+use machinery_api::foundation::{TheTruthO, TtIdT};
+use tm_anode_api::{
+    AnodeDefinitionProviderI, AnodeDocumentO, AnodeLocationT, AnodeLocationsO, AnodePositionT,
+    ANODE_DEFINITION_PROVIDER_I, ANODE_DEFINITION_PROVIDER_I_VERSION,
+};
+use tracing::{event, Level};
+
+use crate::{
+    document::{self, DocumentState},
+    plugin::PluginData,
+    text,
+};
+
+/// How long the language server gets to respond, before the document's own lookup is used.
+const SERVER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Range of an asset's text.
+#[derive(Clone)]
+pub struct Location {
+    pub tt: *mut TheTruthO,
+    pub asset: TtIdT,
+    /// Line and byte column.
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    /// Text of the line the location starts on, if it's known.
+    pub preview: Option<String>,
+}
+
+impl Location {
+    pub fn is_in(&self, tt: *mut TheTruthO, asset: TtIdT) -> bool {
+        self.tt == tt && tt_id_eq(self.asset, asset)
+    }
+
+    /// Position to open the asset at, and the length to select from it.
+    pub fn position(&self) -> (AnodePositionT, u32) {
+        let position = AnodePositionT {
+            line: self.start.0 as u32,
+            column: self.start.1 as u32,
+        };
+        let len = if self.end.0 == self.start.0 {
+            self.end.1.saturating_sub(self.start.1)
+        } else {
+            0
+        };
+        (position, len as u32)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum NavigationKind {
+    Definition,
+    References,
+}
+
+/// Asks the definition providers registered by other plugins for locations.
+///
+/// Providers can access the document through the API, so it must not be locked.
+pub unsafe fn query_providers(
+    data: &PluginData,
+    document: *mut AnodeDocumentO,
+    position: AnodePositionT,
+    kind: NavigationKind,
+) -> Vec<Location> {
+    let registry = &*data.apis.registry;
+    let providers = registry.implementations(
+        ANODE_DEFINITION_PROVIDER_I.as_ptr(),
+        ANODE_DEFINITION_PROVIDER_I_VERSION,
+    ) as *const *const AnodeDefinitionProviderI;
+    let count = registry.num_implementations(
+        ANODE_DEFINITION_PROVIDER_I.as_ptr(),
+        ANODE_DEFINITION_PROVIDER_I_VERSION,
+    );
+
+    let mut locations: Vec<Location> = Vec::new();
+    for i in 0..count as usize {
+        let provider = &**providers.add(i);
+        let query = match kind {
+            NavigationKind::Definition => provider.definitions,
+            NavigationKind::References => provider.references,
+        };
+        query(
+            provider.inst,
+            document,
+            position,
+            &mut locations as *mut _ as *mut AnodeLocationsO,
+        );
+    }
+    locations
+}
+
+/// Adds a location from a provider to the list being gathered by [`query_providers`].
+pub unsafe fn add_location(locations: *mut AnodeLocationsO, location: *const AnodeLocationT) {
+    if locations.is_null() || location.is_null() {
+        event!(Level::WARN, "Location added without a list or location.");
+        return;
+    }
+
+    let location = &*location;
+    (*(locations as *mut Vec<Location>)).push(Location {
+        tt: location.tt,
+        asset: location.asset,
+        start: (location.start.line as usize, location.start.column as usize),
+        end: (location.end.line as usize, location.end.column as usize),
+        preview: None,
+    });
+}
+
+/// Request to go to the definition of an identifier or find its references, gathering locations
+/// until one of the sources has some.
+pub struct Navigation {
+    kind: NavigationKind,
+    /// Byte offset of the identifier.
+    offset: usize,
+    name: String,
+    /// Position to ask providers about, until they've been asked.
+    provider_request: Option<AnodePositionT>,
+    /// If the language server still has to be asked.
+    server_request: bool,
+    /// When the language server was asked, while waiting for it to respond.
+    server_asked: Option<Instant>,
+    locations: Vec<Location>,
+}
+
+impl Navigation {
+    /// Starts looking for the identifier at a byte offset, if there is one.
+    pub fn new(document: &DocumentState, offset: usize, kind: NavigationKind) -> Option<Self> {
+        let content = document.text();
+        let on_word = content
+            .get(offset..)?
+            .chars()
+            .next()
+            .is_some_and(text::is_identifier_char);
+        if !on_word {
+            return None;
+        }
+
+        let start = text::word_start(content, offset);
+        let end = text::word_end(content, offset);
+        let (line, column) = document.line_byte_column(offset);
+        Some(Self {
+            kind,
+            offset,
+            name: content[start..end].to_string(),
+            provider_request: Some(AnodePositionT {
+                line: line as u32,
+                column: column as u32,
+            }),
+            server_request: true,
+            server_asked: None,
+            locations: Vec::new(),
+        })
+    }
+
+    pub fn kind(&self) -> NavigationKind {
+        self.kind
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Takes the position and kind of request to ask providers, if they haven't been asked yet.
+    pub fn take_provider_request(&mut self) -> Option<(AnodePositionT, NavigationKind)> {
+        let position = self.provider_request.take()?;
+        Some((position, self.kind))
+    }
+
+    /// Takes the offset to ask the language server about, if it hasn't been asked yet.
+    ///
+    /// Nothing is asked if providers already gave locations.
+    pub fn take_server_request(&mut self) -> Option<usize> {
+        let request = std::mem::take(&mut self.server_request);
+        if request && self.locations.is_empty() {
+            Some(self.offset)
+        } else {
+            None
+        }
+    }
+
+    /// Waits for the language server to respond, after it was asked.
+    pub fn wait_for_server(&mut self) {
+        self.server_asked = Some(Instant::now());
+    }
+
+    /// Adds locations from providers or the language server.
+    pub fn add(&mut self, locations: Vec<Location>) {
+        self.locations.extend(locations);
+    }
+
+    /// Adds the locations the language server responded with.
+    pub fn add_server(&mut self, locations: Vec<Location>) {
+        self.server_asked = None;
+        self.add(locations);
+    }
+
+    /// Takes the locations once they're gathered, looking them up in the document if no provider
+    /// or language server had any.
+    ///
+    /// Returns `None` while still waiting for providers or the language server.
+    pub fn finish(&mut self, document: &DocumentState) -> Option<Vec<Location>> {
+        if self.provider_request.is_some() {
+            return None;
+        }
+
+        if self.locations.is_empty() {
+            if self.server_request {
+                return None;
+            }
+            let waiting = self
+                .server_asked
+                .is_some_and(|asked| asked.elapsed() < SERVER_TIMEOUT);
+            if waiting {
+                return None;
+            }
+
+            self.locations = self.find_in_document(document);
+        }
+
+        // Show the lines of locations in this document, if the source didn't know them
+        let mut locations = std::mem::take(&mut self.locations);
+        if let Some((tt, asset, _)) = document.asset() {
+            for location in &mut locations {
+                if location.preview.is_none() && location.is_in(tt, asset) {
+                    location.preview = document
+                        .text()
+                        .split('\n')
+                        .nth(location.start.0)
+                        .map(str::to_string);
+                }
+            }
+        }
+        Some(locations)
+    }
+
+    fn find_in_document(&self, document: &DocumentState) -> Vec<Location> {
+        let (tt, asset) = match document.asset() {
+            Some((tt, asset, _)) => (tt, asset),
+            None => return Vec::new(),
+        };
+
+        let ranges = match self.kind {
+            NavigationKind::Definition => {
+                document.local_definition(self.offset).into_iter().collect()
+            }
+            NavigationKind::References => document.local_references(self.offset),
+        };
+        ranges
+            .into_iter()
+            .map(|(start, end)| Location {
+                tt,
+                asset,
+                start: document.line_byte_column(start),
+                end: document.line_byte_column(end),
+                preview: None,
+            })
+            .collect()
+    }
+}
+
+/// Panel listing the references found to an identifier.
+pub struct References {
+    title: String,
+    /// Locations, and the text they're listed with.
+    items: Vec<(Location, String)>,
+    /// First item that's visible.
+    scroll: usize,
+}
+
+impl References {
+    pub unsafe fn new(data: &PluginData, name: &str, locations: Vec<Location>) -> Self {
+        let title = match locations.len() {
+            1 => format!("1 reference to {}", name),
+            count => format!("{} references to {}", count, name),
+        };
+
+        let items = locations
+            .into_iter()
+            .map(|location| {
+                let mut label = format!(
+                    "{}:{}:{}",
+                    document::file_name(data, location.tt, location.asset),
+                    location.start.0 + 1,
+                    location.start.1 + 1
+                );
+                if let Some(preview) = &location.preview {
+                    label.push_str("    ");
+                    label.push_str(preview.trim());
+                }
+                (location, label)
+            })
+            .collect();
+
+        Self {
+            title,
+            items,
+            scroll: 0,
+        }
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    /// Scrolls by a number of items, keeping a number of rows filled.
+    pub fn scroll_by(&mut self, delta: isize, rows: usize) {
+        let max = self.items.len().saturating_sub(rows);
+        self.scroll = (self.scroll as isize + delta).clamp(0, max as isize) as usize;
+    }
+
+    /// The items scrolled into view.
+    pub fn visible_items(&self, rows: usize) -> impl Iterator<Item = &(Location, String)> {
+        self.items.iter().skip(self.scroll).take(rows)
+    }
+}
@@ -1,6 +1,8 @@
 use std::{
+    ffi::CStr,
     mem::size_of,
-    ptr::null,
+    os::raw::c_char,
+    ptr::{null, null_mut},
     sync::{Arc, Mutex},
 };
 
@@ -8,18 +10,38 @@ use machinery::{
     export_singleton_fns, get_api, plugin, tt_id_eq, Plugin, RegistryStorage, Singleton,
 };
 use machinery_api::{
-    foundation::{ApiRegistryApi, ApplicationO, TheTruthApi, TheTruthO, TtIdT},
+    foundation::{
+        ApiRegistryApi, ApplicationApi, ApplicationO, RectT, TempAllocatorApi, TheTruthApi,
+        TheTruthAssetsApi, TheTruthO, TtIdT, TtTypeT, UiO,
+    },
     plugins::{
         editor_views::PropertiesViewApi,
-        ui::{DockingApi, DockingFindTabOptT, Draw2dApi, FontApi, TabI, TabVt, UiApi},
+        ui::{DockingApi, DockingFindTabOptT, Draw2dApi, FontApi, TabI, TabVt, UiApi, UiStyleT},
     },
     the_machinery::TheMachineryApi,
     Api,
 };
-use tm_anode_api::AnodeApi;
+use tm_anode_api::{
+    AnodeApi, AnodeCompletionItemT, AnodeCompletionsO, AnodeDiagnosticT, AnodeDocumentO,
+    AnodeEditorO, AnodeHoverO, AnodeLocationT, AnodeLocationsO, AnodePositionT,
+};
 use tracing::{event, Level};
 
-use crate::{hex_token_color, tabs::code_editor::ANODE_CODE_EDITOR_TAB, TokenColor};
+use crate::{
+    completion,
+    diagnostics::{Diagnostic, Diagnostics},
+    document::DocumentState,
+    editor::CodeEditor,
+    fonts::CodeFonts,
+    handles::OpenDocuments,
+    hover,
+    lsp::LanguageServers,
+    navigation,
+    properties::PropertyEditors,
+    settings::SettingsObject,
+    tabs::code_editor::{CodeEditorTab, ANODE_CODE_EDITOR_TAB},
+    theme::Theme,
+};
 
 plugin!(AnodePlugin);
 
@@ -39,28 +61,23 @@ impl Plugin for AnodePlugin {
         let mut registry_storage = RegistryStorage::new();
 
         let code_editor_tab_vtable = crate::tabs::register(registry, &mut registry_storage);
-        crate::fonts::register(registry, &mut registry_storage);
-
-        let token_colors = vec![
-            hex_token_color("comment", 0x6A9955FF),
-            hex_token_color("function", 0xDCDCAAFF),
-            hex_token_color("string", 0xCE9178FF),
-            hex_token_color("number", 0xB5CEA8FF),
-            hex_token_color("type", 0x4EC9B0FF),
-            hex_token_color("variable", 0x9CDCFEFF),
-            hex_token_color("property", 0x9CDCFEFF),
-            hex_token_color("keyword", 0x569CD6FF),
-        ];
+        crate::settings::register(registry, &mut registry_storage);
+        crate::storage::register(registry, &mut registry_storage);
+
+        let themes = vec![Theme::dark(), Theme::light()];
 
         let apis = Apis {
             registry,
+            application: get_api(registry),
             truth: get_api(registry),
+            truth_assets: get_api(registry),
             ui: get_api(registry),
             docking: get_api(registry),
             draw2d: get_api(registry),
             font: get_api(registry),
             properties_view: get_api(registry),
             machinery: get_api(registry),
+            temp_allocator: get_api(registry),
             code_editor_tab_vtable,
         };
 
@@ -68,6 +85,26 @@ impl Plugin for AnodePlugin {
         unsafe {
             let api = registry_storage.add(AnodeApi {
                 open_asset: Self::open_asset,
+                open_asset_at: Self::open_asset_at,
+                create_editor: Self::create_editor,
+                destroy_editor: Self::destroy_editor,
+                editor_set_asset: Self::editor_set_asset,
+                editor_ui: Self::editor_ui,
+                set_properties_ui: Self::set_properties_ui,
+                find_document: Self::find_document,
+                document_asset: Self::document_asset,
+                editor_document: Self::editor_document,
+                document_text: Self::document_text,
+                document_replace: Self::document_replace,
+                document_caret: Self::document_caret,
+                document_set_caret: Self::document_set_caret,
+                document_selection: Self::document_selection,
+                document_set_selection: Self::document_set_selection,
+                document_scroll_to: Self::document_scroll_to,
+                set_diagnostics: Self::set_diagnostics,
+                add_completion: Self::add_completion,
+                add_hover: Self::add_hover,
+                add_location: Self::add_location,
             });
             registry.set(
                 AnodeApi::NAME.as_ptr(),
@@ -80,7 +117,13 @@ impl Plugin for AnodePlugin {
         let data = PluginData {
             apis,
             registry_storage: Mutex::new(registry_storage),
-            token_colors,
+            themes,
+            settings_object: Mutex::new(None),
+            code_fonts: Mutex::new(CodeFonts::new()),
+            property_editors: Mutex::new(PropertyEditors::default()),
+            open_documents: Mutex::new(OpenDocuments::default()),
+            diagnostics: Mutex::new(Diagnostics::default()),
+            language_servers: Mutex::new(LanguageServers::default()),
         };
 
         Self {
@@ -94,11 +137,11 @@ impl Drop for AnodePlugin {
         event!(Level::INFO, "Unloading anode.");
 
         unsafe {
-            self.data
-                .registry_storage
-                .lock()
-                .unwrap()
-                .clear(&*self.data.apis.registry);
+            let registry = &*self.data.apis.registry;
+            self.data.property_editors.lock().unwrap().clear();
+            self.data.language_servers.lock().unwrap().clear();
+            self.data.code_fonts.lock().unwrap().clear(registry);
+            self.data.registry_storage.lock().unwrap().clear(registry);
         }
     }
 }
@@ -106,48 +149,378 @@ impl Drop for AnodePlugin {
 #[export_singleton_fns]
 impl AnodePlugin {
     unsafe fn open_asset(&self, app: *mut ApplicationO, opt: *const DockingFindTabOptT) {
-        // Try to find an existing tab
-        let mut tab = (*self.data.apis.docking)
-            .find_tab(ANODE_CODE_EDITOR_TAB.hash, opt)
-            .tab;
-
-        // If we couldn't find a tab that's already open with this script, create one
-        if tab.is_null() || !is_open_in(&*tab, (*opt).find_asset_tt, (*opt).find_asset) {
-            tab = (*self.data.apis.machinery).create_or_select_tab(
-                app,
-                (*opt).in_ui,
-                ANODE_CODE_EDITOR_TAB.name.as_ptr(),
-                null(),
-            );
+        open_asset(&self.data, app, opt);
+    }
+
+    unsafe fn open_asset_at(
+        &self,
+        app: *mut ApplicationO,
+        opt: *const DockingFindTabOptT,
+        position: AnodePositionT,
+        selection_len: u32,
+    ) {
+        open_asset_at(&self.data, app, opt, position, selection_len);
+    }
+
+    unsafe fn set_properties_ui(&self, tt: *mut TheTruthO, object_type: TtTypeT) {
+        crate::properties::set_properties_ui(&self.data, tt, object_type);
+    }
+
+    fn create_editor(&self, app: *mut ApplicationO) -> *mut AnodeEditorO {
+        let editor = Box::new(CodeEditor::new(self.data.clone(), app));
+        Box::into_raw(editor) as *mut AnodeEditorO
+    }
+
+    unsafe fn destroy_editor(&self, editor: *mut AnodeEditorO) {
+        let _editor: Box<CodeEditor> = Box::from_raw(editor as *mut _);
+    }
+
+    unsafe fn editor_set_asset(
+        &self,
+        editor: *mut AnodeEditorO,
+        tt: *mut TheTruthO,
+        asset: TtIdT,
+    ) -> bool {
+        let editor = &*(editor as *const CodeEditor);
+
+        match editor.set_asset(tt, asset) {
+            Ok(()) => true,
+            Err(error) => {
+                event!(Level::ERROR, "{}", error);
+                false
+            }
+        }
+    }
+
+    unsafe fn editor_ui(
+        &self,
+        editor: *mut AnodeEditorO,
+        ui: *mut UiO,
+        ui_style: *const UiStyleT,
+        rect: RectT,
+    ) {
+        let editor = &*(editor as *const CodeEditor);
+        editor.ui(ui, ui_style, rect);
+    }
+
+    unsafe fn find_document(&self, tt: *mut TheTruthO, asset: TtIdT) -> *mut AnodeDocumentO {
+        let open_documents = self.data.open_documents.lock().unwrap();
+        open_documents.find(tt, asset).unwrap_or(null_mut())
+    }
+
+    unsafe fn document_asset(
+        &self,
+        document: *mut AnodeDocumentO,
+        tt: *mut *mut TheTruthO,
+    ) -> TtIdT {
+        let asset = self
+            .data
+            .document(document)
+            .and_then(|document| document.lock().unwrap().asset());
+
+        let (asset_tt, asset) = match asset {
+            Some((asset_tt, asset, _)) => (asset_tt, asset),
+            None => (null_mut(), TtIdT::default()),
+        };
+        if !tt.is_null() {
+            *tt = asset_tt;
+        }
+        asset
+    }
+
+    unsafe fn editor_document(&self, editor: *mut AnodeEditorO) -> *mut AnodeDocumentO {
+        let editor = &*(editor as *const CodeEditor);
+        editor.document_handle()
+    }
+
+    unsafe fn document_text(
+        &self,
+        document: *mut AnodeDocumentO,
+        buffer: *mut u8,
+        size: usize,
+    ) -> usize {
+        let document = match self.data.document(document) {
+            Some(document) => document,
+            None => return 0,
+        };
+        let document = document.lock().unwrap();
+
+        let text = document.text().as_bytes();
+        if !buffer.is_null() {
+            let len = text.len().min(size);
+            std::ptr::copy_nonoverlapping(text.as_ptr(), buffer, len);
+        }
+        text.len()
+    }
+
+    unsafe fn document_replace(
+        &self,
+        document: *mut AnodeDocumentO,
+        start: AnodePositionT,
+        end: AnodePositionT,
+        text: *const u8,
+        text_len: usize,
+    ) -> bool {
+        let document = match self.data.document(document) {
+            Some(document) => document,
+            None => return false,
+        };
+        let mut document = document.lock().unwrap();
+
+        let text = if text_len == 0 {
+            ""
+        } else {
+            match std::str::from_utf8(std::slice::from_raw_parts(text, text_len)) {
+                Ok(text) => text,
+                Err(error) => {
+                    event!(Level::ERROR, "Replacement isn't valid UTF-8: {}", error);
+                    return false;
+                }
+            }
+        };
+
+        let start = document.offset_at(start.line as usize, start.column as usize);
+        let end = document.offset_at(end.line as usize, end.column as usize);
+        if let Err(error) = document.replace_range(start, end, text) {
+            event!(Level::ERROR, "{}", error);
+            return false;
         }
 
-        // Focus the tab and tell it to (re)open the file
-        (*self.data.apis.docking).set_focus_tab((*opt).in_ui, tab);
-        (*(*tab).vt).set_root.unwrap()((*tab).inst, (*opt).find_asset_tt, (*opt).find_asset);
+        // Nothing else makes up an edit, so it doesn't need to wait for more
+        document.commit(&self.data);
+        true
+    }
+
+    unsafe fn document_caret(&self, document: *mut AnodeDocumentO) -> AnodePositionT {
+        let document = match self.data.document(document) {
+            Some(document) => document,
+            None => return AnodePositionT::default(),
+        };
+        let document = document.lock().unwrap();
+
+        position(&document, document.caret())
+    }
+
+    unsafe fn document_set_caret(&self, document: *mut AnodeDocumentO, position: AnodePositionT) {
+        if let Some(document) = self.data.document(document) {
+            let mut document = document.lock().unwrap();
+            let offset = document.offset_at(position.line as usize, position.column as usize);
+            document.set_caret(offset);
+        }
+    }
+
+    unsafe fn document_selection(
+        &self,
+        document: *mut AnodeDocumentO,
+        start: *mut AnodePositionT,
+        end: *mut AnodePositionT,
+    ) -> bool {
+        let document = match self.data.document(document) {
+            Some(document) => document,
+            None => return false,
+        };
+        let document = document.lock().unwrap();
+
+        match document.selection() {
+            Some((selection_start, selection_end)) => {
+                *start = position(&document, selection_start);
+                *end = position(&document, selection_end);
+                true
+            }
+            None => false,
+        }
+    }
+
+    unsafe fn document_set_selection(
+        &self,
+        document: *mut AnodeDocumentO,
+        start: AnodePositionT,
+        end: AnodePositionT,
+    ) {
+        if let Some(document) = self.data.document(document) {
+            let mut document = document.lock().unwrap();
+            let start = document.offset_at(start.line as usize, start.column as usize);
+            let end = document.offset_at(end.line as usize, end.column as usize);
+            document.set_selection(start, end);
+        }
+    }
+
+    unsafe fn document_scroll_to(&self, document: *mut AnodeDocumentO, line: u32) {
+        if let Some(document) = self.data.document(document) {
+            document.lock().unwrap().reveal_line(line as usize);
+        }
+    }
+
+    unsafe fn set_diagnostics(
+        &self,
+        tt: *mut TheTruthO,
+        asset: TtIdT,
+        source: *const c_char,
+        diagnostics: *const AnodeDiagnosticT,
+        diagnostics_len: usize,
+    ) {
+        let source = if source.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(source).to_string_lossy().into_owned()
+        };
+
+        let diagnostics = if diagnostics_len == 0 {
+            &[]
+        } else {
+            std::slice::from_raw_parts(diagnostics, diagnostics_len)
+        };
+        let diagnostics = diagnostics
+            .iter()
+            .map(|diagnostic| Diagnostic {
+                start: (
+                    diagnostic.start.line as usize,
+                    diagnostic.start.column as usize,
+                ),
+                end: (diagnostic.end.line as usize, diagnostic.end.column as usize),
+                severity: diagnostic.severity,
+                message: if diagnostic.message.is_null() {
+                    String::new()
+                } else {
+                    CStr::from_ptr(diagnostic.message)
+                        .to_string_lossy()
+                        .into_owned()
+                },
+                source: source.clone(),
+            })
+            .collect();
+
+        let mut all_diagnostics = self.data.diagnostics.lock().unwrap();
+        all_diagnostics.set(tt, asset, &source, diagnostics);
+    }
+
+    unsafe fn add_completion(
+        &self,
+        completions: *mut AnodeCompletionsO,
+        item: *const AnodeCompletionItemT,
+    ) {
+        completion::add_completion(completions, item);
+    }
+
+    unsafe fn add_hover(&self, hover: *mut AnodeHoverO, markdown: *const c_char) {
+        hover::add_hover(hover, markdown);
+    }
+
+    unsafe fn add_location(
+        &self,
+        locations: *mut AnodeLocationsO,
+        location: *const AnodeLocationT,
+    ) {
+        navigation::add_location(locations, location);
+    }
+}
+
+fn position(document: &DocumentState, offset: usize) -> AnodePositionT {
+    let (line, column) = document.line_byte_column(offset);
+    AnodePositionT {
+        line: line as u32,
+        column: column as u32,
     }
 }
 
 pub(crate) struct PluginData {
     pub apis: Apis,
     pub registry_storage: Mutex<RegistryStorage>,
-    pub token_colors: Vec<TokenColor>,
+    pub themes: Vec<Theme>,
+    pub settings_object: Mutex<Option<SettingsObject>>,
+    pub code_fonts: Mutex<CodeFonts>,
+    pub property_editors: Mutex<PropertyEditors>,
+    pub open_documents: Mutex<OpenDocuments>,
+    pub diagnostics: Mutex<Diagnostics>,
+    pub language_servers: Mutex<LanguageServers>,
+}
+
+impl PluginData {
+    /// Gets the document a handle from another plugin refers to, if it's still open.
+    pub fn document(&self, handle: *mut AnodeDocumentO) -> Option<Arc<Mutex<DocumentState>>> {
+        self.open_documents.lock().unwrap().get(handle)
+    }
+
+    /// Commits pending edits of every open document, so saving doesn't miss any.
+    pub fn commit_documents(&self) {
+        // Documents are locked one at a time, without holding on to the list
+        let documents = self.open_documents.lock().unwrap().all();
+        for document in documents {
+            document.lock().unwrap().commit(self);
+        }
+    }
 }
 
 pub struct Apis {
     pub registry: *const ApiRegistryApi,
+    pub application: *const ApplicationApi,
     pub truth: *const TheTruthApi,
+    pub truth_assets: *const TheTruthAssetsApi,
     pub ui: *const UiApi,
     pub docking: *const DockingApi,
     pub draw2d: *const Draw2dApi,
     pub font: *const FontApi,
     pub properties_view: *const PropertiesViewApi,
     pub machinery: *const TheMachineryApi,
+    pub temp_allocator: *const TempAllocatorApi,
     pub code_editor_tab_vtable: *const TabVt,
 }
 
 unsafe impl Send for Apis {}
 unsafe impl Sync for Apis {}
 
+/// Opens an asset in a code editor tab, or focuses the tab it's already open in.
+///
+/// Returns the tab, or `None` if the asset couldn't be opened.
+pub unsafe fn open_asset(
+    data: &PluginData,
+    app: *mut ApplicationO,
+    opt: *const DockingFindTabOptT,
+) -> Option<*const CodeEditorTab> {
+    // Try to find an existing tab
+    let mut tab = (*data.apis.docking)
+        .find_tab(ANODE_CODE_EDITOR_TAB.hash, opt)
+        .tab;
+
+    // If we couldn't find a tab that's already open with this script, create one
+    if tab.is_null() || !is_open_in(&*tab, (*opt).find_asset_tt, (*opt).find_asset) {
+        tab = (*data.apis.machinery).create_or_select_tab(
+            app,
+            (*opt).in_ui,
+            ANODE_CODE_EDITOR_TAB.name.as_ptr(),
+            null(),
+        );
+    }
+
+    // Focus the tab and tell it to (re)open the file
+    (*data.apis.docking).set_focus_tab((*opt).in_ui, tab);
+    let tab = (*tab).inst as *const CodeEditorTab;
+    if (*tab).open((*opt).find_asset_tt, (*opt).find_asset) {
+        Some(tab)
+    } else {
+        None
+    }
+}
+
+/// Opens an asset like [`open_asset`], selecting `selection_len` bytes from a position.
+pub unsafe fn open_asset_at(
+    data: &PluginData,
+    app: *mut ApplicationO,
+    opt: *const DockingFindTabOptT,
+    position: AnodePositionT,
+    selection_len: u32,
+) {
+    let tab = match open_asset(data, app, opt) {
+        Some(tab) => tab,
+        None => return,
+    };
+
+    let mut document = (*tab).editor().document();
+    let start = document.offset_at(position.line as usize, position.column as usize);
+    document.set_selection(start, start + selection_len as usize);
+    document.reveal_line(position.line as usize);
+}
+
 pub unsafe fn is_open_in(tab: &TabI, tt: *mut TheTruthO, asset: TtIdT) -> bool {
     let root = (*tab.vt).root.unwrap()(tab.inst);
     root.tt == tt && tt_id_eq(root.root, asset)
@@ -0,0 +1,238 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    ffi::c_void,
+    time::{Duration, Instant},
+};
+
+use const_cstr::const_cstr;
+use machinery::export_singleton_fns;
+use machinery_api::{
+    foundation::{RectT, TheTruthO, TtIdT, TtTypeT},
+    plugins::{
+        editor_views::{
+            PropertiesUiArgsT, TM_PROPERTIES_METRIC_ITEM_HEIGHT, TM_PROPERTIES_METRIC_LABEL_WIDTH,
+            TM_PROPERTIES_METRIC_MARGIN, TM_TT_PROP_ASPECT__PROPERTIES__CUSTOM_UI,
+        },
+        ui::{
+            DockingFindTabOptT, Draw2dStyleT, UiButtonT, TM_UI_COLOR_THIN_LINES,
+            TM_UI_CURSOR_ROW_RESIZE,
+        },
+    },
+};
+use tm_anode_api::{AnodeAspectI, ASPECT_ANODE};
+use tracing::{event, Level};
+
+use crate::{
+    editor::CodeEditor,
+    plugin::{self, AnodePlugin, PluginData},
+};
+
+/// Lines of text the inline editor shows before it's resized.
+const DEFAULT_LINES: f32 = 12.0;
+const MIN_LINES: f32 = 3.0;
+const RESIZE_HANDLE_HEIGHT: f32 = 6.0;
+const OPEN_BUTTON_WIDTH: f32 = 100.0;
+/// How long an inline editor is kept after its object stops being shown.
+const EVICT_DELAY: Duration = Duration::from_secs(5);
+
+/// Inline editors shown in properties views, by the truth and object they're editing.
+#[derive(Default)]
+pub struct PropertyEditors {
+    editors: HashMap<(usize, u64), PropertyEditor>,
+}
+
+impl PropertyEditors {
+    /// Closes all editors, committing their edits.
+    pub fn clear(&mut self) {
+        for (_, editor) in self.editors.drain() {
+            editor.editor.close();
+        }
+    }
+
+    /// Closes editors that haven't been shown for a while.
+    fn evict_unused(&mut self) {
+        let now = Instant::now();
+        self.editors.retain(|_, editor| {
+            let keep = now - editor.last_shown < EVICT_DELAY;
+            if !keep {
+                editor.editor.close();
+            }
+            keep
+        });
+    }
+}
+
+struct PropertyEditor {
+    editor: CodeEditor,
+    height: Option<f32>,
+    /// Mouse position and height when the resize handle was grabbed, while dragging.
+    resize_drag: Option<(f32, f32)>,
+    last_shown: Instant,
+}
+
+// Only accessed from the UI thread, the mutex around it is just for the shared plugin data
+unsafe impl Send for PropertyEditor {}
+
+/// Sets the custom properties UI on the text property of a type with an anode aspect.
+pub unsafe fn set_properties_ui(data: &PluginData, tt: *mut TheTruthO, object_type: TtTypeT) {
+    let truth = &*data.apis.truth;
+    let aspect = truth.get_aspect(tt, object_type, ASPECT_ANODE.hash) as *const AnodeAspectI;
+    if aspect.is_null() {
+        event!(
+            Level::WARN,
+            "Type has no anode aspect, not setting properties UI."
+        );
+        return;
+    }
+
+    let aspect = &*aspect;
+    if !aspect.subobject_path.is_null() && aspect.subobject_path_len != 0 {
+        event!(
+            Level::WARN,
+            "Properties UI isn't supported for text on subobjects."
+        );
+        return;
+    }
+
+    truth.set_property_aspect(
+        tt,
+        object_type,
+        aspect.property,
+        TM_TT_PROP_ASPECT__PROPERTIES__CUSTOM_UI,
+        AnodePlugin::properties_ui as *const c_void,
+    );
+}
+
+#[export_singleton_fns]
+impl AnodePlugin {
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn properties_ui(
+        &self,
+        args: *mut PropertiesUiArgsT,
+        item_rect: RectT,
+        name: *const i8,
+        tooltip: *const i8,
+        object: TtIdT,
+        indent: u32,
+        property: u32,
+    ) -> f32 {
+        let properties_view = &*self.data.apis.properties_view;
+        let ui_api = &*self.data.apis.ui;
+        let args = &mut *args;
+        let metrics = |metric| *args.metrics.offset(metric as isize);
+        let item_height = metrics(TM_PROPERTIES_METRIC_ITEM_HEIGHT);
+        let margin = metrics(TM_PROPERTIES_METRIC_MARGIN);
+        let label_width = metrics(TM_PROPERTIES_METRIC_LABEL_WIDTH);
+
+        let mut editors = self.data.property_editors.lock().unwrap();
+        editors.evict_unused();
+
+        // Keep one editor per object, so carets and scrolling survive between frames
+        let key = (args.tt as usize, object.__bindgen_anon_1.u64_);
+        let editor = match editors.editors.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let app = (*self.data.apis.application).application();
+                let editor = CodeEditor::new(self.data.clone(), app);
+                if let Err(error) = editor.set_asset(args.tt, object) {
+                    event!(Level::ERROR, "{}", error);
+                    return properties_view.ui_property_default_with_name(
+                        args, item_rect, name, tooltip, object, indent, property,
+                    );
+                }
+
+                entry.insert(PropertyEditor {
+                    editor,
+                    height: None,
+                    resize_drag: None,
+                    last_shown: Instant::now(),
+                })
+            }
+        };
+        editor.last_shown = Instant::now();
+
+        // Label, with a button to open the asset in a full editor next to it
+        let row_rect = RectT {
+            h: item_height,
+            ..item_rect
+        };
+        properties_view.ui_label(args, row_rect, name, tooltip);
+
+        let open_button = UiButtonT {
+            id: ui_api.make_id(args.ui),
+            rect: RectT {
+                x: (row_rect.x + label_width).max(row_rect.x + row_rect.w - OPEN_BUTTON_WIDTH),
+                w: OPEN_BUTTON_WIDTH.min(row_rect.w - label_width),
+                ..row_rect
+            },
+            text: const_cstr!("Open in Tab").as_ptr(),
+            tooltip: const_cstr!("Open the text in a code editor tab.").as_ptr(),
+            ..Default::default()
+        };
+        if ui_api.button(args.ui, args.uistyle, &open_button) {
+            let app = (*self.data.apis.application).application();
+            let opt = DockingFindTabOptT {
+                from_tab: args.tab,
+                in_ui: args.ui,
+                find_asset_tt: args.tt,
+                find_asset: object,
+                ..Default::default()
+            };
+            plugin::open_asset(&self.data, app, &opt);
+        }
+
+        // The editor itself, below the label
+        let height = editor
+            .height
+            .unwrap_or(item_height * DEFAULT_LINES)
+            .max(item_height * MIN_LINES);
+        let editor_rect = RectT {
+            y: row_rect.y + row_rect.h + margin,
+            h: height,
+            ..item_rect
+        };
+        editor.editor.ui(args.ui, args.uistyle, editor_rect);
+
+        // Handle to drag the editor's height
+        let handle_rect = RectT {
+            y: editor_rect.y + editor_rect.h,
+            h: RESIZE_HANDLE_HEIGHT,
+            ..item_rect
+        };
+        let buffers = ui_api.buffers(args.ui);
+        let input = &*buffers.input;
+        let id = ui_api.make_id(args.ui);
+        if ui_api.is_hovering(args.ui, handle_rect, (*args.uistyle).clip) {
+            (*buffers.activation).next_hover = id;
+        }
+        let is_hovering = (*buffers.activation).hover == id;
+
+        if is_hovering && input.left_mouse_pressed {
+            editor.resize_drag = Some((input.mouse_pos.y, height));
+        }
+        if !input.left_mouse_is_down {
+            editor.resize_drag = None;
+        }
+        if let Some((start_y, start_height)) = editor.resize_drag {
+            editor.height = Some(start_height + input.mouse_pos.y - start_y);
+        }
+        if is_hovering || editor.resize_drag.is_some() {
+            ui_api.set_cursor(args.ui, TM_UI_CURSOR_ROW_RESIZE);
+        }
+
+        let ibuffer = *buffers.ibuffers.offset((*args.uistyle).buffer as isize);
+        let style = Draw2dStyleT {
+            color: *buffers.colors.offset(TM_UI_COLOR_THIN_LINES as isize),
+            clip: (*args.uistyle).clip,
+            ..Default::default()
+        };
+        let line_rect = RectT {
+            y: handle_rect.y + (handle_rect.h * 0.5).floor(),
+            h: 1.0,
+            ..handle_rect
+        };
+        (*self.data.apis.draw2d).fill_rect(buffers.vbuffer, ibuffer, &style, line_rect);
+
+        handle_rect.y + handle_rect.h + margin
+    }
+}
@@ -0,0 +1,415 @@
+use std::{
+    ffi::{c_void, CStr, CString},
+    os::raw::c_char,
+    ptr::null,
+};
+
+use const_cstr::const_cstr;
+use machinery::{export_singleton_fns, identifier, tt_id_type, Identifier, RegistryStorage};
+use machinery_api::{
+    foundation::{
+        ApiRegistryApi, ApplicationO, TheTruthEditorEnumT, TheTruthO, TheTruthObjectO,
+        TheTruthPropertyDefinitionT, TheTruthPropertyDefinitionTBindgenTy1, TtIdT, TtUndoScopeT,
+        UiO, TM_THE_TRUTH_CREATE_TYPES_I_VERSION, TM_THE_TRUTH_PROPERTY_TYPE_FLOAT,
+        TM_THE_TRUTH_PROPERTY_TYPE_STRING, TM_THE_TRUTH_PROPERTY_TYPE_SUBOBJECT,
+        TM_THE_TRUTH_PROPERTY_TYPE_UINT32_T, TM_THE_TRUTH__EDITOR__UINT32_T__ENUM,
+    },
+    the_machinery::{TM_PROPERTIES_TAB_VT_NAME, TM_TT_TYPE_HASH__APPLICATION_SETTINGS},
+};
+use tracing::{event, Level};
+
+use crate::{
+    lsp::LanguageServerConfig,
+    plugin::{AnodePlugin, PluginData},
+    theme::Theme,
+};
+
+pub fn register(registry: &ApiRegistryApi, registry_storage: &mut RegistryStorage) {
+    unsafe {
+        registry_storage.add_raw_implementation(
+            registry,
+            const_cstr!("tm_the_truth_create_types_i").as_ptr(),
+            TM_THE_TRUTH_CREATE_TYPES_I_VERSION,
+            AnodePlugin::settings_create_types as *const c_void,
+        );
+    }
+}
+
+#[export_singleton_fns]
+impl AnodePlugin {
+    unsafe fn settings_create_types(&self, tt: *mut TheTruthO) {
+        let truth = &*self.data.apis.truth;
+
+        // The theme names have to outlive the type, so keep them in the registry storage
+        let mut registry_storage = self.data.registry_storage.lock().unwrap();
+        let theme_names =
+            registry_storage.add([const_cstr!("Dark").as_ptr(), const_cstr!("Light").as_ptr()]);
+
+        let properties = [
+            TheTruthPropertyDefinitionT {
+                name: const_cstr!("font_size").as_ptr(),
+                type_: TM_THE_TRUTH_PROPERTY_TYPE_UINT32_T,
+                tooltip: const_cstr!("Size of the code font, in pixels.").as_ptr(),
+                ..Default::default()
+            },
+            TheTruthPropertyDefinitionT {
+                name: const_cstr!("tab_width").as_ptr(),
+                type_: TM_THE_TRUTH_PROPERTY_TYPE_UINT32_T,
+                tooltip: const_cstr!("Amount of columns between tab stops.").as_ptr(),
+                ..Default::default()
+            },
+            TheTruthPropertyDefinitionT {
+                name: const_cstr!("scroll_speed").as_ptr(),
+                type_: TM_THE_TRUTH_PROPERTY_TYPE_FLOAT,
+                tooltip: const_cstr!("Pixels scrolled per mouse wheel step.").as_ptr(),
+                ..Default::default()
+            },
+            TheTruthPropertyDefinitionT {
+                name: const_cstr!("rulers").as_ptr(),
+                type_: TM_THE_TRUTH_PROPERTY_TYPE_STRING,
+                tooltip: const_cstr!(
+                    "Comma separated ruler columns, for assets that don't specify their own."
+                )
+                .as_ptr(),
+                ..Default::default()
+            },
+            TheTruthPropertyDefinitionT {
+                name: const_cstr!("theme").as_ptr(),
+                type_: TM_THE_TRUTH_PROPERTY_TYPE_UINT32_T,
+                editor: TM_THE_TRUTH__EDITOR__UINT32_T__ENUM as u32,
+                __bindgen_anon_1: TheTruthPropertyDefinitionTBindgenTy1 {
+                    enum_editor: TheTruthEditorEnumT {
+                        count: (*theme_names).len() as u32,
+                        names: theme_names as *const *const c_char,
+                        ..Default::default()
+                    },
+                },
+                ..Default::default()
+            },
+            TheTruthPropertyDefinitionT {
+                name: const_cstr!("font_family").as_ptr(),
+                type_: TM_THE_TRUTH_PROPERTY_TYPE_STRING,
+                tooltip: const_cstr!(
+                    "Code font family, falls back to common monospace fonts if not found."
+                )
+                .as_ptr(),
+                ..Default::default()
+            },
+            TheTruthPropertyDefinitionT {
+                name: const_cstr!("commit_delay").as_ptr(),
+                type_: TM_THE_TRUTH_PROPERTY_TYPE_FLOAT,
+                tooltip: const_cstr!(
+                    "Seconds without typing before edits are committed to the asset."
+                )
+                .as_ptr(),
+                ..Default::default()
+            },
+            TheTruthPropertyDefinitionT {
+                name: const_cstr!("language_servers").as_ptr(),
+                type_: TM_THE_TRUTH_PROPERTY_TYPE_STRING,
+                tooltip: const_cstr!(
+                    "Language server commands by file extension, like \"c,h=clangd; lua=lua-language-server\"."
+                )
+                .as_ptr(),
+                ..Default::default()
+            },
+            TheTruthPropertyDefinitionT {
+                name: const_cstr!("language_server_root").as_ptr(),
+                type_: TM_THE_TRUTH_PROPERTY_TYPE_STRING,
+                tooltip: const_cstr!(
+                    "Directory language servers see documents in, defaults to the working directory."
+                )
+                .as_ptr(),
+                ..Default::default()
+            },
+        ];
+
+        let settings_type = truth.create_object_type(
+            tt,
+            ANODE_SETTINGS.name.as_ptr(),
+            properties.as_ptr(),
+            properties.len() as u32,
+        );
+
+        // Create the default object, new settings objects will start with these values
+        let defaults = EditorSettings::default();
+        let rulers = CString::new(format_rulers(&defaults.rulers)).unwrap();
+        let font_family = CString::new(defaults.font_family.as_str()).unwrap();
+        let language_servers =
+            CString::new(format_language_servers(&defaults.language_servers)).unwrap();
+
+        let object = truth.create_object_of_type(tt, settings_type, TtUndoScopeT { u64_: 0 });
+        let object_w = truth.write(tt, object);
+        truth.set_uint32_t(tt, object_w, SETTINGS_FONT_SIZE, defaults.font_size);
+        truth.set_uint32_t(tt, object_w, SETTINGS_TAB_WIDTH, defaults.tab_width as u32);
+        truth.set_float(tt, object_w, SETTINGS_SCROLL_SPEED, defaults.scroll_speed);
+        truth.set_string(tt, object_w, SETTINGS_RULERS, rulers.as_ptr());
+        truth.set_uint32_t(tt, object_w, SETTINGS_THEME, defaults.theme);
+        truth.set_string(tt, object_w, SETTINGS_FONT_FAMILY, font_family.as_ptr());
+        truth.set_float(tt, object_w, SETTINGS_COMMIT_DELAY, defaults.commit_delay);
+        truth.set_string(
+            tt,
+            object_w,
+            SETTINGS_LANGUAGE_SERVERS,
+            language_servers.as_ptr(),
+        );
+        truth.commit(tt, object_w, TtUndoScopeT { u64_: 0 });
+        truth.set_default_object(tt, settings_type, object);
+
+        // The settings are stored in the application settings, which only keep the values of
+        // properties that exist when they're loaded, so the property can't be added later
+        let application_settings =
+            truth.optional_object_type_from_name_hash(tt, TM_TT_TYPE_HASH__APPLICATION_SETTINGS);
+        if application_settings.u64_ != 0 {
+            truth.add_properties(tt, application_settings, &settings_property_definition(), 1);
+        }
+    }
+}
+
+/// Editor settings, shared by all code editor tabs.
+pub struct EditorSettings {
+    pub font_family: String,
+    pub font_size: u32,
+    pub tab_width: usize,
+    pub scroll_speed: f32,
+    /// Default ruler columns, used if the asset doesn't specify any.
+    pub rulers: Vec<u32>,
+    /// Index into the plugin's themes.
+    pub theme: u32,
+    /// Seconds of idle time before edits are committed.
+    pub commit_delay: f32,
+    /// Language servers to start for documents, by file extension.
+    pub language_servers: Vec<LanguageServerConfig>,
+    /// Directory documents are placed in for language servers, empty for the working directory.
+    pub language_server_root: String,
+}
+
+impl Default for EditorSettings {
+    fn default() -> Self {
+        Self {
+            font_family: "Consolas".to_string(),
+            font_size: 10,
+            tab_width: 4,
+            scroll_speed: 10.0,
+            rulers: vec![100],
+            theme: 0,
+            commit_delay: 0.5,
+            language_servers: Vec::new(),
+            language_server_root: String::new(),
+        }
+    }
+}
+
+impl EditorSettings {
+    /// Reads the current settings from the application settings.
+    ///
+    /// This is cheap enough to be done every frame, so changes apply immediately.
+    pub unsafe fn read(data: &PluginData, app: *mut ApplicationO) -> Self {
+        let (tt, id) = settings_object(data, app);
+        let truth = &*data.apis.truth;
+        let object = truth.read(tt, id);
+
+        let rulers = parse_rulers(&get_string(data, tt, object, SETTINGS_RULERS));
+        let language_servers =
+            parse_language_servers(&get_string(data, tt, object, SETTINGS_LANGUAGE_SERVERS));
+
+        Self {
+            font_family: get_string(data, tt, object, SETTINGS_FONT_FAMILY),
+            font_size: truth.get_uint32_t(tt, object, SETTINGS_FONT_SIZE).max(1),
+            tab_width: truth.get_uint32_t(tt, object, SETTINGS_TAB_WIDTH).max(1) as usize,
+            scroll_speed: truth.get_float(tt, object, SETTINGS_SCROLL_SPEED),
+            rulers,
+            theme: truth.get_uint32_t(tt, object, SETTINGS_THEME),
+            commit_delay: truth.get_float(tt, object, SETTINGS_COMMIT_DELAY).max(0.0),
+            language_servers,
+            language_server_root: get_string(data, tt, object, SETTINGS_LANGUAGE_SERVER_ROOT),
+        }
+    }
+
+    pub fn theme<'a>(&self, data: &'a PluginData) -> &'a Theme {
+        data.themes
+            .get(self.theme as usize)
+            .unwrap_or(&data.themes[0])
+    }
+}
+
+/// Opens the settings in a properties tab, so the user can edit them.
+pub unsafe fn open_settings(data: &PluginData, app: *mut ApplicationO, ui: *mut UiO) {
+    let (tt, id) = settings_object(data, app);
+
+    let tab = (*data.apis.machinery).create_or_select_tab(
+        app,
+        ui,
+        TM_PROPERTIES_TAB_VT_NAME.as_ptr() as *const c_char,
+        null(),
+    );
+    (*(*tab).vt).set_root.unwrap()((*tab).inst, tt, id);
+}
+
+/// Finds the settings object in the application settings, creating it if it doesn't exist yet.
+unsafe fn settings_object(data: &PluginData, app: *mut ApplicationO) -> (*mut TheTruthO, TtIdT) {
+    let mut root = TtIdT::default();
+    let tt = (*data.apis.machinery).settings(app, &mut root);
+
+    // Use the cached object if it's still valid
+    let mut cached = data.settings_object.lock().unwrap();
+    if let Some(object) = *cached {
+        if object.tt == tt && (*data.apis.truth).is_alive(tt, object.id) {
+            return (tt, object.id);
+        }
+    }
+
+    let truth = &*data.apis.truth;
+    let settings_type = truth.object_type_from_name_hash(tt, ANODE_SETTINGS.hash);
+    let property = settings_property(data, tt, root);
+
+    // Look for settings stored previously, they're saved along with the application settings
+    let id = truth.get_subobject(tt, truth.read(tt, root), property);
+    let id = if truth.is_alive(tt, id) {
+        id
+    } else {
+        let id = truth.create_object_of_type(tt, settings_type, TtUndoScopeT { u64_: 0 });
+        let root_w = truth.write(tt, root);
+        truth.set_subobject_id(tt, root_w, property, id, TtUndoScopeT { u64_: 0 });
+        truth.commit(tt, root_w, TtUndoScopeT { u64_: 0 });
+        id
+    };
+
+    *cached = Some(SettingsObject { tt, id });
+    (tt, id)
+}
+
+/// Finds the property of the application settings the settings object is stored in.
+unsafe fn settings_property(data: &PluginData, tt: *mut TheTruthO, root: TtIdT) -> u32 {
+    let truth = &*data.apis.truth;
+    let root_type = tt_id_type(root);
+
+    let find = || {
+        let mut property = 0;
+        let found = truth.find_property(
+            tt,
+            root_type,
+            ANODE_SETTINGS_PROPERTY.hash,
+            TM_THE_TRUTH_PROPERTY_TYPE_SUBOBJECT,
+            &mut property,
+        );
+        found.then_some(property)
+    };
+    if let Some(property) = find() {
+        return property;
+    }
+
+    // The property is added when the types are created, unless the application settings type
+    // didn't exist yet, the settings then only last until the application closes
+    event!(
+        Level::WARN,
+        "Application settings have no code editor property, settings won't be saved"
+    );
+    truth.add_properties(tt, root_type, &settings_property_definition(), 1);
+    find().expect("property was just added")
+}
+
+/// Property of the application settings holding the settings object, shown in the settings UI.
+fn settings_property_definition() -> TheTruthPropertyDefinitionT {
+    TheTruthPropertyDefinitionT {
+        name: ANODE_SETTINGS_PROPERTY.name.as_ptr(),
+        type_: TM_THE_TRUTH_PROPERTY_TYPE_SUBOBJECT,
+        type_hash: ANODE_SETTINGS.hash,
+        ui_name: const_cstr!("Code Editor").as_ptr(),
+        ..Default::default()
+    }
+}
+
+/// Location of the settings object in the application settings.
+#[derive(Clone, Copy)]
+pub struct SettingsObject {
+    tt: *mut TheTruthO,
+    id: TtIdT,
+}
+
+unsafe impl Send for SettingsObject {}
+
+unsafe fn get_string(
+    data: &PluginData,
+    tt: *mut TheTruthO,
+    object: *const TheTruthObjectO,
+    property: u32,
+) -> String {
+    let value = (*data.apis.truth).get_string(tt, object, property);
+    if value.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(value).to_string_lossy().into_owned()
+    }
+}
+
+fn parse_rulers(value: &str) -> Vec<u32> {
+    let mut rulers: Vec<u32> = value
+        .split(',')
+        .filter_map(|v| v.trim().parse().ok())
+        .collect();
+    rulers.sort_unstable();
+    rulers
+}
+
+fn format_rulers(rulers: &[u32]) -> String {
+    rulers
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Parses "ext,ext=command args; ext=command" into server configurations.
+fn parse_language_servers(value: &str) -> Vec<LanguageServerConfig> {
+    value
+        .split(';')
+        .filter_map(|entry| {
+            let (extensions, command) = entry.split_once('=')?;
+            let extensions: Vec<String> = extensions
+                .split(',')
+                .map(|v| v.trim().trim_start_matches('.').to_lowercase())
+                .filter(|v| !v.is_empty())
+                .collect();
+            let command: Vec<String> = command.split_whitespace().map(str::to_string).collect();
+
+            if extensions.is_empty() || command.is_empty() {
+                None
+            } else {
+                Some(LanguageServerConfig {
+                    extensions,
+                    command,
+                })
+            }
+        })
+        .collect()
+}
+
+fn format_language_servers(servers: &[LanguageServerConfig]) -> String {
+    servers
+        .iter()
+        .map(|server| {
+            format!(
+                "{}={}",
+                server.extensions.join(","),
+                server.command.join(" ")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+pub const ANODE_SETTINGS: Identifier = identifier!("tm_anode_settings");
+
+/// Property of the application settings holding the editor settings.
+const ANODE_SETTINGS_PROPERTY: Identifier = identifier!("anode");
+
+const SETTINGS_FONT_SIZE: u32 = 0;
+const SETTINGS_TAB_WIDTH: u32 = 1;
+const SETTINGS_SCROLL_SPEED: u32 = 2;
+const SETTINGS_RULERS: u32 = 3;
+const SETTINGS_THEME: u32 = 4;
+const SETTINGS_FONT_FAMILY: u32 = 5;
+const SETTINGS_COMMIT_DELAY: u32 = 6;
+const SETTINGS_LANGUAGE_SERVERS: u32 = 7;
+const SETTINGS_LANGUAGE_SERVER_ROOT: u32 = 8;
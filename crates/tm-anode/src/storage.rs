@@ -0,0 +1,504 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{c_void, CStr, CString},
+};
+
+use const_cstr::const_cstr;
+use eyre::{eyre, Result};
+use machinery::{export_singleton_fns, tt_id_type, CArrayHeaderT, RegistryStorage};
+use machinery_api::foundation::{
+    ApiRegistryApi, TheTruthO, TheTruthObjectO, TheTruthPropertyDefinitionT, TtIdT, TtUndoScopeT,
+    TM_THE_TRUTH_CREATE_TYPES_I_VERSION, TM_THE_TRUTH_PROPERTY_TYPE_BUFFER,
+    TM_THE_TRUTH_PROPERTY_TYPE_STRING, TM_THE_TRUTH_PROPERTY_TYPE_UINT64_T,
+};
+use tm_anode_api::{AnodeAspectI, TEXT_CHUNK};
+
+use crate::{
+    encoding::Encoding,
+    plugin::{AnodePlugin, PluginData},
+};
+
+pub fn register(registry: &ApiRegistryApi, registry_storage: &mut RegistryStorage) {
+    unsafe {
+        registry_storage.add_raw_implementation(
+            registry,
+            const_cstr!("tm_the_truth_create_types_i").as_ptr(),
+            TM_THE_TRUTH_CREATE_TYPES_I_VERSION,
+            AnodePlugin::storage_create_types as *const c_void,
+        );
+    }
+}
+
+#[export_singleton_fns]
+impl AnodePlugin {
+    unsafe fn storage_create_types(&self, tt: *mut TheTruthO) {
+        let properties = [
+            TheTruthPropertyDefinitionT {
+                name: const_cstr!("hash").as_ptr(),
+                type_: TM_THE_TRUTH_PROPERTY_TYPE_UINT64_T,
+                ..Default::default()
+            },
+            TheTruthPropertyDefinitionT {
+                name: const_cstr!("data").as_ptr(),
+                type_: TM_THE_TRUTH_PROPERTY_TYPE_BUFFER,
+                ..Default::default()
+            },
+        ];
+
+        (*self.data.apis.truth).create_object_type(
+            tt,
+            TEXT_CHUNK.name.as_ptr(),
+            properties.as_ptr(),
+            properties.len() as u32,
+        );
+    }
+}
+
+/// Where the text of an asset is stored.
+#[derive(Clone, Copy)]
+pub enum TextStorage {
+    /// The entire text in one buffer property.
+    Buffer(u32),
+    /// Chunks in a subobject set, and their order as a buffer of the keys they have, which are
+    /// their content hashes unless those collide.
+    Chunked { manifest: u32, chunks: u32 },
+    /// A string property, which can only hold UTF-8.
+    String(u32),
+}
+
+impl TextStorage {
+    /// Picks how to store text on an object, from the type of the aspect's text property.
+    pub unsafe fn detect(
+        data: &PluginData,
+        tt: *mut TheTruthO,
+        object: TtIdT,
+        aspect_i: &AnodeAspectI,
+    ) -> Result<Self> {
+        let truth = &*data.apis.truth;
+        let object_type = tt_id_type(object);
+
+        let property = aspect_i.property;
+        if property >= truth.num_properties(tt, object_type) {
+            return Err(eyre!("Text property {} does not exist", property));
+        }
+
+        let definition = &*truth.properties(tt, object_type).add(property as usize);
+        match definition.type_ {
+            TM_THE_TRUTH_PROPERTY_TYPE_BUFFER if aspect_i.chunked => Ok(Self::Chunked {
+                manifest: property,
+                chunks: aspect_i.chunks_property,
+            }),
+            TM_THE_TRUTH_PROPERTY_TYPE_BUFFER => Ok(Self::Buffer(property)),
+            TM_THE_TRUTH_PROPERTY_TYPE_STRING => Ok(Self::String(property)),
+            _ => Err(eyre!(
+                "Text property {} is not a buffer or string property",
+                property
+            )),
+        }
+    }
+
+    pub fn supports_encoding(self, encoding: Encoding) -> bool {
+        match self {
+            Self::String(_) => encoding == Encoding::Utf8,
+            _ => true,
+        }
+    }
+
+    /// Checks if content can be stored, before it's written.
+    pub fn check(self, bytes: &[u8]) -> Result<()> {
+        match self {
+            Self::String(_) if bytes.contains(&0) => {
+                Err(eyre!("Text property can't hold nul characters"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// ID that changes when the content does.
+    pub unsafe fn id(self, data: &PluginData, tt: *mut TheTruthO, object: TtIdT) -> u64 {
+        let truth = &*data.apis.truth;
+        match self {
+            Self::Buffer(property)
+            | Self::Chunked {
+                manifest: property, ..
+            } => truth.get_buffer(tt, truth.read(tt, object), property).id as u64,
+            // Strings don't have an ID, but a hash of the content works the same for this
+            Self::String(property) => content_hash(&read_string(data, tt, object, property)),
+        }
+    }
+
+    /// Reads the stored content, along with its [`Self::id`].
+    pub unsafe fn read(
+        self,
+        data: &PluginData,
+        tt: *mut TheTruthO,
+        object: TtIdT,
+    ) -> Result<(u64, Vec<u8>)> {
+        let (manifest, chunks) = match self {
+            Self::Buffer(property) => {
+                let (id, bytes) = read_buffer(data, tt, object, property);
+                return Ok((id as u64, bytes));
+            }
+            Self::Chunked { manifest, chunks } => (manifest, chunks),
+            Self::String(property) => {
+                let bytes = read_string(data, tt, object, property);
+                return Ok((content_hash(&bytes), bytes));
+            }
+        };
+
+        let (manifest_id, manifest) = read_buffer(data, tt, object, manifest);
+        let mut by_key = HashMap::new();
+        for chunk in chunk_objects(data, tt, (*data.apis.truth).read(tt, object), chunks) {
+            let key =
+                (*data.apis.truth).get_uint64_t(tt, (*data.apis.truth).read(tt, chunk), CHUNK_HASH);
+            by_key.insert(key, chunk);
+        }
+
+        let bytes = reassemble(&manifest, |key| {
+            let chunk = by_key.get(&key)?;
+            Some(read_buffer(data, tt, *chunk, CHUNK_DATA).1)
+        })?;
+        Ok((manifest_id as u64, bytes))
+    }
+
+    /// Writes the content to an object that's open for writing, returning its [`Self::id`].
+    ///
+    /// The content must have passed [`Self::check`]. When chunked, only chunks with content that
+    /// isn't stored yet get new buffers.
+    pub unsafe fn write(
+        self,
+        data: &PluginData,
+        tt: *mut TheTruthO,
+        object: *mut TheTruthObjectO,
+        bytes: &[u8],
+        undo_scope: TtUndoScopeT,
+    ) -> u64 {
+        let truth = &*data.apis.truth;
+        let (manifest, chunks) = match self {
+            Self::Buffer(property) => {
+                let buffer_id = add_buffer(data, tt, bytes);
+                truth.set_buffer(tt, object, property, buffer_id);
+                return buffer_id as u64;
+            }
+            Self::Chunked { manifest, chunks } => (manifest, chunks),
+            Self::String(property) => {
+                let value = CString::new(bytes).expect("text was checked for nul characters");
+                truth.set_string(tt, object, property, value.as_ptr());
+                return content_hash(bytes);
+            }
+        };
+
+        let mut existing = HashMap::new();
+        for chunk in chunk_objects(data, tt, object, chunks) {
+            let key = truth.get_uint64_t(tt, truth.read(tt, chunk), CHUNK_HASH);
+            existing.insert(key, chunk);
+        }
+
+        let plan = plan_chunks(bytes, |key| {
+            let chunk = existing.get(&key)?;
+            Some(read_buffer(data, tt, *chunk, CHUNK_DATA).1)
+        });
+
+        for (key, chunk) in &plan.new {
+            let chunk_id = truth.create_object_of_hash(tt, TEXT_CHUNK.hash, undo_scope);
+            let mut chunk_w = truth.write(tt, chunk_id);
+            truth.set_uint64_t(tt, chunk_w, CHUNK_HASH, *key);
+            truth.set_buffer(tt, chunk_w, CHUNK_DATA, add_buffer(data, tt, chunk));
+            truth.add_to_subobject_set(tt, object, chunks, &mut chunk_w, 1);
+            truth.commit(tt, chunk_w, undo_scope);
+        }
+
+        // Chunks of content that no longer exists aren't needed anymore
+        let unused: Vec<TtIdT> = existing
+            .iter()
+            .filter(|(key, _)| !plan.used.contains(key))
+            .map(|(_, chunk)| *chunk)
+            .collect();
+        if !unused.is_empty() {
+            truth.remove_from_subobject_set(
+                tt,
+                object,
+                chunks,
+                unused.as_ptr(),
+                unused.len() as u32,
+            );
+        }
+
+        let manifest_bytes: Vec<u8> = plan.keys.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let manifest_id = add_buffer(data, tt, &manifest_bytes);
+        truth.set_buffer(tt, object, manifest, manifest_id);
+        manifest_id as u64
+    }
+}
+
+/// Reads a buffer property, returning the buffer's ID and a copy of its content.
+pub unsafe fn read_buffer(
+    data: &PluginData,
+    tt: *mut TheTruthO,
+    object: TtIdT,
+    property: u32,
+) -> (u32, Vec<u8>) {
+    let truth = &*data.apis.truth;
+    let buffer = truth.get_buffer(tt, truth.read(tt, object), property);
+
+    let mut size = 0;
+    let buffers = truth.buffers(tt);
+    let buffer_ptr = (*buffers).get.unwrap()((*buffers).inst, buffer.id, &mut size);
+
+    // Properties that were never written don't have a buffer
+    if buffer_ptr.is_null() {
+        return (buffer.id, Vec::new());
+    }
+
+    let bytes = std::slice::from_raw_parts(buffer_ptr as *const u8, size as usize);
+    (buffer.id, bytes.to_vec())
+}
+
+unsafe fn read_string(
+    data: &PluginData,
+    tt: *mut TheTruthO,
+    object: TtIdT,
+    property: u32,
+) -> Vec<u8> {
+    let truth = &*data.apis.truth;
+    let value = truth.get_string(tt, truth.read(tt, object), property);
+    if value.is_null() {
+        Vec::new()
+    } else {
+        CStr::from_ptr(value).to_bytes().to_vec()
+    }
+}
+
+pub unsafe fn add_buffer(data: &PluginData, tt: *mut TheTruthO, bytes: &[u8]) -> u32 {
+    let buffers = (*data.apis.truth).buffers(tt);
+    let buffer_ptr = (*buffers).allocate.unwrap()(
+        (*buffers).inst,
+        bytes.len() as u64,
+        bytes.as_ptr() as *const c_void,
+    );
+    (*buffers).add.unwrap()((*buffers).inst, buffer_ptr, bytes.len() as u64, 0)
+}
+
+unsafe fn chunk_objects(
+    data: &PluginData,
+    tt: *mut TheTruthO,
+    object: *const TheTruthObjectO,
+    property: u32,
+) -> Vec<TtIdT> {
+    let temp_allocator = &*data.apis.temp_allocator;
+    let ta = temp_allocator.create(temp_allocator.frame_allocator());
+
+    let items = (*data.apis.truth).get_subobject_set(tt, object, property, ta);
+    let chunks = if items.is_null() {
+        Vec::new()
+    } else {
+        let header = (items as *const CArrayHeaderT).offset(-1);
+        std::slice::from_raw_parts(items, (*header).size as usize).to_vec()
+    };
+
+    temp_allocator.destroy(ta);
+    chunks
+}
+
+/// How content is stored as chunks, found by keys in the manifest.
+struct ChunkPlan<'a> {
+    /// Keys of the chunks in order, making up the manifest.
+    keys: Vec<u64>,
+    /// Chunks that aren't stored yet, and their keys.
+    new: Vec<(u64, &'a [u8])>,
+    /// Keys of all chunks of the content, stored or new.
+    used: HashSet<u64>,
+}
+
+/// Splits content into chunks, reusing stored chunks with the same content.
+///
+/// A chunk's key is its content hash. Hashes can collide, so a stored chunk is only reused if its
+/// bytes are the same, and otherwise the chunk gets the next key that's free.
+fn plan_chunks<'a>(
+    bytes: &'a [u8],
+    mut stored: impl FnMut(u64) -> Option<Vec<u8>>,
+) -> ChunkPlan<'a> {
+    let mut plan = ChunkPlan {
+        keys: Vec::new(),
+        new: Vec::new(),
+        used: HashSet::new(),
+    };
+    let mut contents: HashMap<u64, &[u8]> = HashMap::new();
+    // Whether the stored chunk of a key has the same content, if there is one
+    let mut stored_matches: HashMap<u64, Option<bool>> = HashMap::new();
+
+    for chunk in split(bytes) {
+        let mut key = content_hash(chunk);
+        loop {
+            // Identical chunks, changed or not, share one object
+            if let Some(content) = contents.get(&key) {
+                if *content == chunk {
+                    break;
+                }
+                key = key.wrapping_add(1);
+                continue;
+            }
+
+            let matches = *stored_matches
+                .entry(key)
+                .or_insert_with(|| stored(key).map(|stored| stored == chunk));
+            match matches {
+                Some(true) => {}
+                Some(false) => {
+                    key = key.wrapping_add(1);
+                    continue;
+                }
+                None => plan.new.push((key, chunk)),
+            }
+
+            contents.insert(key, chunk);
+            plan.used.insert(key);
+            break;
+        }
+        plan.keys.push(key);
+    }
+
+    plan
+}
+
+/// Puts content back together from the chunks listed in a manifest.
+fn reassemble(manifest: &[u8], mut chunk: impl FnMut(u64) -> Option<Vec<u8>>) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for key in manifest.chunks_exact(8) {
+        let mut key_bytes = [0; 8];
+        key_bytes.copy_from_slice(key);
+        let key = u64::from_le_bytes(key_bytes);
+
+        let content =
+            chunk(key).ok_or_else(|| eyre!("Text chunk {:016x} is missing from the asset", key))?;
+        bytes.extend(content);
+    }
+    Ok(bytes)
+}
+
+/// Splits content into chunks that end at lines picked by their content.
+///
+/// Unlike splitting every N lines, inserting a line then only changes the chunk it's in, rather
+/// than moving every chunk boundary after it.
+fn split(bytes: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut line_start = 0;
+
+    for (i, byte) in bytes.iter().enumerate() {
+        if *byte != b'\n' {
+            continue;
+        }
+
+        let line = &bytes[line_start..=i];
+        line_start = i + 1;
+
+        // `is_multiple_of` isn't available on older compilers
+        #[allow(unknown_lints, clippy::manual_is_multiple_of)]
+        let is_boundary = content_hash(line) % CHUNK_LINES == 0;
+        if is_boundary || line_start - start >= MAX_CHUNK_SIZE {
+            chunks.push(&bytes[start..line_start]);
+            start = line_start;
+        }
+    }
+
+    if start < bytes.len() {
+        chunks.push(&bytes[start..]);
+    }
+
+    chunks
+}
+
+/// FNV-1a, which unlike the standard library's hasher is stable across versions.
+fn content_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xCBF29CE484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001B3)
+    })
+}
+
+/// Average amount of lines in a chunk.
+const CHUNK_LINES: u64 = 32;
+
+/// Size in bytes after which a chunk is ended at the next line, regardless of content.
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+const CHUNK_HASH: u32 = 0;
+const CHUNK_DATA: u32 = 1;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stores the new chunks of a plan, like writing them to the asset.
+    fn store(stored: &mut HashMap<u64, Vec<u8>>, plan: &ChunkPlan) {
+        stored.retain(|key, _| plan.used.contains(key));
+        for (key, chunk) in &plan.new {
+            stored.insert(*key, chunk.to_vec());
+        }
+    }
+
+    fn manifest(plan: &ChunkPlan) -> Vec<u8> {
+        plan.keys.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn numbered_lines(count: usize) -> String {
+        (0..count).map(|i| format!("line {}\n", i)).collect()
+    }
+
+    #[test]
+    fn chunks_reassemble_to_content() {
+        let text = numbered_lines(1000);
+        let mut stored = HashMap::new();
+        let plan = plan_chunks(text.as_bytes(), |key| stored.get(&key).cloned());
+        assert!(plan.keys.len() > 1);
+        store(&mut stored, &plan);
+
+        let bytes = reassemble(&manifest(&plan), |key| stored.get(&key).cloned()).unwrap();
+        assert_eq!(bytes, text.as_bytes());
+    }
+
+    #[test]
+    fn unchanged_chunks_are_reused() {
+        let text = numbered_lines(1000);
+        let mut stored = HashMap::new();
+        let plan = plan_chunks(text.as_bytes(), |key| stored.get(&key).cloned());
+        store(&mut stored, &plan);
+
+        let edited = text.replace("line 500\n", "line 500 edited\n");
+        let edited_plan = plan_chunks(edited.as_bytes(), |key| stored.get(&key).cloned());
+        assert_eq!(edited_plan.new.len(), 1);
+        assert_eq!(edited_plan.keys.len(), plan.keys.len());
+        store(&mut stored, &edited_plan);
+
+        let bytes = reassemble(&manifest(&edited_plan), |key| stored.get(&key).cloned()).unwrap();
+        assert_eq!(bytes, edited.as_bytes());
+    }
+
+    #[test]
+    fn colliding_chunks_are_not_reused() {
+        let text = "a\n";
+        let key = content_hash(text.as_bytes());
+        let mut stored = HashMap::new();
+        stored.insert(key, b"different\n".to_vec());
+
+        let plan = plan_chunks(text.as_bytes(), |key| stored.get(&key).cloned());
+        assert_eq!(plan.keys, vec![key.wrapping_add(1)]);
+        store(&mut stored, &plan);
+
+        let bytes = reassemble(&manifest(&plan), |key| stored.get(&key).cloned()).unwrap();
+        assert_eq!(bytes, text.as_bytes());
+    }
+
+    #[test]
+    fn missing_chunks_fail_to_reassemble() {
+        let manifest = 1u64.to_le_bytes();
+        assert!(reassemble(&manifest, |_| None).is_err());
+    }
+
+    #[test]
+    fn string_storage_refuses_nul_characters() {
+        assert!(TextStorage::String(0).check(b"a\0b").is_err());
+        assert!(TextStorage::String(0).check(b"ab").is_ok());
+        assert!(TextStorage::Buffer(0).check(b"a\0b").is_ok());
+    }
+}
@@ -17,6 +17,6 @@ pub fn register(registry: &ApiRegistryApi, registry_storage: &mut RegistryStorag
             const_cstr!("tm_tab_vt").as_ptr(),
             TM_TAB_VT_VERSION,
             code_editor_tab_vtable,
-        ) as *const TabVt
+        )
     }
 }
@@ -0,0 +1,140 @@
+use unicode_segmentation::{GraphemeCursor, UnicodeSegmentation};
+use unicode_width::UnicodeWidthStr;
+
+/// Amount of cells a grapheme cluster takes up, East-Asian wide characters take up two.
+pub fn grapheme_width(grapheme: &str) -> usize {
+    // Control characters like tabs have no width, but still need a cell to be visible, and
+    // sequences like joined emoji report the sum of their parts
+    grapheme.width().clamp(1, 2)
+}
+
+/// Amount of cells a line of text takes up.
+pub fn line_width(line: &str) -> usize {
+    line.graphemes(true).map(grapheme_width).sum()
+}
+
+/// Byte offset within the line closest to a column.
+///
+/// Columns falling in the second half of a wide grapheme resolve to after it.
+pub fn column_to_offset(line: &str, column: usize) -> usize {
+    let mut cell = 0;
+    for (offset, grapheme) in line.grapheme_indices(true) {
+        let width = grapheme_width(grapheme);
+        if column * 2 < (cell * 2) + width {
+            return offset;
+        }
+
+        cell += width;
+    }
+
+    line.len()
+}
+
+/// Byte offset of the grapheme boundary before the offset.
+pub fn previous_boundary(text: &str, offset: usize) -> usize {
+    let mut cursor = GraphemeCursor::new(offset, text.len(), true);
+    cursor.prev_boundary(text, 0).ok().flatten().unwrap_or(0)
+}
+
+/// Byte offset of the grapheme boundary after the offset.
+pub fn next_boundary(text: &str, offset: usize) -> usize {
+    let mut cursor = GraphemeCursor::new(offset, text.len(), true);
+    cursor
+        .next_boundary(text, 0)
+        .ok()
+        .flatten()
+        .unwrap_or(text.len())
+}
+
+pub fn is_boundary(text: &str, offset: usize) -> bool {
+    let mut cursor = GraphemeCursor::new(offset, text.len(), true);
+    cursor.is_boundary(text, 0).unwrap_or(true)
+}
+
+/// Amount of characters before a byte offset.
+pub fn char_offset(text: &str, offset: usize) -> usize {
+    text[..offset].chars().count()
+}
+
+/// Byte offset of a character offset, clamped to the end of the text.
+pub fn byte_offset(text: &str, chars: usize) -> usize {
+    text.char_indices()
+        .nth(chars)
+        .map(|(i, _)| i)
+        .unwrap_or(text.len())
+}
+
+/// If a character can be part of an identifier.
+pub fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Byte offset the identifier ending at the offset starts at.
+pub fn word_start(text: &str, offset: usize) -> usize {
+    text[..offset]
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| is_identifier_char(*c))
+        .last()
+        .map(|(i, _)| i)
+        .unwrap_or(offset)
+}
+
+/// Byte offset the identifier starting at or containing the offset ends at.
+pub fn word_end(text: &str, offset: usize) -> usize {
+    text[offset..]
+        .char_indices()
+        .find(|(_, c)| !is_identifier_char(*c))
+        .map(|(i, _)| offset + i)
+        .unwrap_or(text.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn graphemes_take_one_or_two_cells() {
+        assert_eq!(grapheme_width("a"), 1);
+        assert_eq!(grapheme_width("\t"), 1);
+        assert_eq!(grapheme_width("e\u{301}"), 1);
+        assert_eq!(grapheme_width("漢"), 2);
+        assert_eq!(grapheme_width("🦀"), 2);
+        // Joined emoji are drawn as one glyph, not as their parts
+        assert_eq!(grapheme_width("👨\u{200D}👩\u{200D}👧"), 2);
+
+        assert_eq!(line_width("a漢e\u{301}🦀"), 6);
+    }
+
+    #[test]
+    fn columns_resolve_to_the_closest_grapheme() {
+        let line = "a漢b";
+        assert_eq!(column_to_offset(line, 0), 0);
+        assert_eq!(column_to_offset(line, 1), 1);
+        // The second half of the wide character is after it
+        assert_eq!(column_to_offset(line, 2), 4);
+        assert_eq!(column_to_offset(line, 3), 4);
+        assert_eq!(column_to_offset(line, 4), 5);
+        assert_eq!(column_to_offset(line, 10), 5);
+    }
+
+    #[test]
+    fn boundaries_skip_whole_graphemes() {
+        let text = "ae\u{301}👨\u{200D}👩\u{200D}👧b";
+        let family = 4 + "👨\u{200D}👩\u{200D}👧".len();
+
+        assert_eq!(next_boundary(text, 0), 1);
+        assert_eq!(next_boundary(text, 1), 4);
+        assert_eq!(next_boundary(text, 4), family);
+        assert_eq!(next_boundary(text, family), text.len());
+        assert_eq!(next_boundary(text, text.len()), text.len());
+
+        assert_eq!(previous_boundary(text, text.len()), family);
+        assert_eq!(previous_boundary(text, family), 4);
+        assert_eq!(previous_boundary(text, 4), 1);
+        assert_eq!(previous_boundary(text, 0), 0);
+
+        assert!(is_boundary(text, 4));
+        assert!(!is_boundary(text, 2));
+    }
+}
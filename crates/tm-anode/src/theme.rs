@@ -0,0 +1,116 @@
+use machinery_api::foundation::ColorSrgbT;
+use tm_anode_api::AnodeSeverity;
+
+use crate::{hex_color, hex_token_color, TokenColor};
+
+/// Highlight scopes tree-sitter will be configured to recognize, in highlight index order.
+pub const HIGHLIGHT_SCOPES: [&str; 8] = [
+    "comment", "function", "string", "number", "type", "variable", "property", "keyword",
+];
+
+/// Colors used for drawing the code editor.
+pub struct Theme {
+    pub background: ColorSrgbT,
+    pub text: ColorSrgbT,
+    pub caret: ColorSrgbT,
+    pub line_number: ColorSrgbT,
+    pub ruler: ColorSrgbT,
+    pub minimap_background: ColorSrgbT,
+    pub token_colors: Vec<TokenColor>,
+    /// Colors to tell the carets of collaborators apart.
+    pub collaborators: Vec<ColorSrgbT>,
+    pub error: ColorSrgbT,
+    pub warning: ColorSrgbT,
+    pub information: ColorSrgbT,
+    pub hint: ColorSrgbT,
+}
+
+impl Theme {
+    pub fn dark() -> Self {
+        Self {
+            background: hex_color(0x1E1E1EFF),
+            text: hex_color(0xDCDCDCFF),
+            caret: hex_color(0xC8C8C8FF),
+            line_number: hex_color(0x787878FF),
+            ruler: hex_color(0x505050FF),
+            minimap_background: hex_color(0x222222FF),
+            token_colors: vec![
+                hex_token_color("comment", 0x6A9955FF),
+                hex_token_color("function", 0xDCDCAAFF),
+                hex_token_color("string", 0xCE9178FF),
+                hex_token_color("number", 0xB5CEA8FF),
+                hex_token_color("type", 0x4EC9B0FF),
+                hex_token_color("variable", 0x9CDCFEFF),
+                hex_token_color("property", 0x9CDCFEFF),
+                hex_token_color("keyword", 0x569CD6FF),
+            ],
+            collaborators: vec![
+                hex_color(0xE06C75FF),
+                hex_color(0x61AFEFFF),
+                hex_color(0xE5C07BFF),
+                hex_color(0xC678DDFF),
+                hex_color(0x98C379FF),
+            ],
+            error: hex_color(0xF14C4CFF),
+            warning: hex_color(0xCCA700FF),
+            information: hex_color(0x3794FFFF),
+            hint: hex_color(0x8A8A8AFF),
+        }
+    }
+
+    pub fn light() -> Self {
+        Self {
+            background: hex_color(0xFFFFFFFF),
+            text: hex_color(0x000000FF),
+            caret: hex_color(0x202020FF),
+            line_number: hex_color(0x237893FF),
+            ruler: hex_color(0xD3D3D3FF),
+            minimap_background: hex_color(0xF3F3F3FF),
+            token_colors: vec![
+                hex_token_color("comment", 0x008000FF),
+                hex_token_color("function", 0x795E26FF),
+                hex_token_color("string", 0xA31515FF),
+                hex_token_color("number", 0x098658FF),
+                hex_token_color("type", 0x267F99FF),
+                hex_token_color("variable", 0x001080FF),
+                hex_token_color("property", 0x001080FF),
+                hex_token_color("keyword", 0x0000FFFF),
+            ],
+            collaborators: vec![
+                hex_color(0xC0392BFF),
+                hex_color(0x2471A3FF),
+                hex_color(0xB9770EFF),
+                hex_color(0x7D3C98FF),
+                hex_color(0x1E8449FF),
+            ],
+            error: hex_color(0xE51400FF),
+            warning: hex_color(0xBF8803FF),
+            information: hex_color(0x1A85FFFF),
+            hint: hex_color(0x6C6C6CFF),
+        }
+    }
+
+    /// Get the color for a tree-sitter highlight index, falling back to the text color.
+    pub fn token_color(&self, highlight: usize) -> ColorSrgbT {
+        let scope = HIGHLIGHT_SCOPES[highlight];
+        self.token_colors
+            .iter()
+            .find(|v| v.scope == scope)
+            .map(|v| v.color)
+            .unwrap_or(self.text)
+    }
+
+    pub fn severity_color(&self, severity: AnodeSeverity) -> ColorSrgbT {
+        match severity {
+            AnodeSeverity::Error => self.error,
+            AnodeSeverity::Warning => self.warning,
+            AnodeSeverity::Information => self.information,
+            AnodeSeverity::Hint => self.hint,
+        }
+    }
+
+    /// Get the color for a collaborator's site, the same site always gets the same color.
+    pub fn collaborator_color(&self, site: u64) -> ColorSrgbT {
+        self.collaborators[(site % self.collaborators.len() as u64) as usize]
+    }
+}
@@ -0,0 +1,50 @@
+//! Checks the mock language server the client tests launch, which also has cargo build it for
+//! them.
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    process::{Command, Stdio},
+};
+
+#[test]
+fn mock_language_server_answers_and_exits() {
+    let log = std::env::temp_dir().join(format!("anode-mock-server-{}.log", std::process::id()));
+    let _ = fs::remove_file(&log);
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mock-language-server"))
+        .arg(&log)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+
+    for body in [
+        r#"{"jsonrpc":"2.0","id":0,"method":"initialize","params":{}}"#,
+        r#"{"jsonrpc":"2.0","id":1,"method":"shutdown","params":null}"#,
+    ] {
+        write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        stdin.flush().unwrap();
+
+        let mut header = String::new();
+        stdout.read_line(&mut header).unwrap();
+        let length: usize = header["Content-Length:".len()..].trim().parse().unwrap();
+        stdout.read_line(&mut String::new()).unwrap();
+        let mut response = vec![0; length];
+        stdout.read_exact(&mut response).unwrap();
+        assert!(String::from_utf8(response).unwrap().contains(r#""result""#));
+    }
+
+    let body = r#"{"jsonrpc":"2.0","method":"exit","params":null}"#;
+    write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    stdin.flush().unwrap();
+    assert!(child.wait().unwrap().success());
+
+    let log = fs::read_to_string(&log).unwrap();
+    assert_eq!(
+        log.lines().collect::<Vec<_>>(),
+        ["initialize", "shutdown", "exit", "exited"]
+    );
+}
@@ -1,8 +1,4 @@
-use std::{
-    ffi::c_void,
-    ptr::{null, null_mut},
-    sync::Mutex,
-};
+use std::{ffi::c_void, ptr::null_mut, sync::Mutex};
 
 use const_cstr::const_cstr;
 use machinery::{
@@ -12,8 +8,8 @@ use machinery::{
 use machinery_api::{
     foundation::{
         ApiRegistryApi, ApplicationO, TheTruthApi, TheTruthO, TheTruthPropertyDefinitionT, TtIdT,
-        TtUndoScopeT, UiO, TM_THE_TRUTH_CREATE_TYPES_I_VERSION, TM_THE_TRUTH_PROPERTY_TYPE_BUFFER,
-        TM_TT_ASPECT__FILE_EXTENSION,
+        TtUndoScopeT, UiO, TM_THE_TRUTH_CREATE_TYPES_I_VERSION, TM_THE_TRUTH_PROPERTY_TYPE_BOOL,
+        TM_THE_TRUTH_PROPERTY_TYPE_BUFFER, TM_TT_ASPECT__FILE_EXTENSION,
     },
     plugins::{
         editor_views::{
@@ -89,11 +85,23 @@ impl Drop for TextFilePlugin {
 impl TextFilePlugin {
     unsafe fn truth_create_types(&self, tt: *mut TheTruthO) {
         // Create the truth type for the asset
-        let properties = vec![TheTruthPropertyDefinitionT {
-            name: const_cstr!("data").as_ptr(),
-            type_: TM_THE_TRUTH_PROPERTY_TYPE_BUFFER,
-            ..Default::default()
-        }];
+        let properties = [
+            TheTruthPropertyDefinitionT {
+                name: const_cstr!("data").as_ptr(),
+                type_: TM_THE_TRUTH_PROPERTY_TYPE_BUFFER,
+                ..Default::default()
+            },
+            TheTruthPropertyDefinitionT {
+                name: const_cstr!("history").as_ptr(),
+                type_: TM_THE_TRUTH_PROPERTY_TYPE_BUFFER,
+                ..Default::default()
+            },
+            TheTruthPropertyDefinitionT {
+                name: const_cstr!("collaborative").as_ptr(),
+                type_: TM_THE_TRUTH_PROPERTY_TYPE_BOOL,
+                ..Default::default()
+            },
+        ];
 
         let asset_type = (*self.truth).create_object_type(
             tt,
@@ -122,12 +130,17 @@ impl TextFilePlugin {
             open_i as *const c_void,
         );
 
-        // Register anode for this asset
+        // Register anode for this asset, collaboration is turned on per file
         let anode = registry_storage.add(AnodeAspectI {
             property: 0,
-            highlighting: null(),
+            history_property: 1,
+            is_collaborative: Some(TextFilePlugin::is_collaborative),
+            ..Default::default()
         });
         (*self.truth).set_aspect(tt, asset_type, ASPECT_ANODE.hash, anode as *const c_void);
+
+        // Edit short files inline in the properties view
+        ((*self.anode).set_properties_ui)(tt, asset_type);
     }
 
     unsafe fn create_asset(
@@ -159,6 +172,11 @@ impl TextFilePlugin {
         asset
     }
 
+    unsafe fn is_collaborative(&self, tt: *mut TheTruthO, asset: TtIdT) -> bool {
+        let object = (*self.truth).read(tt, asset);
+        (*self.truth).get_bool(tt, object, 2)
+    }
+
     unsafe fn open_asset(
         &self,
         app: *mut ApplicationO,